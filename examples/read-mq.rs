@@ -47,28 +47,43 @@ async fn async_main() -> Result<()> {
     let tun2 = tuns.next().unwrap();
 
     task::spawn(async move {
-        let mut reader = tun0.reader();
+        let mut reader = tun0.io_reader();
         let mut buf = [0u8; 1024];
         loop {
             let n = reader.read(&mut buf).await.unwrap();
-            println!("reading {} bytes from tuns[0]: {:?}", n, &buf[..n]);
+            println!(
+                "reading {} bytes from tuns[0] ({} packets so far): {:?}",
+                n,
+                tun0.queue_stats().packets_read,
+                &buf[..n]
+            );
         }
     });
 
     task::spawn(async move {
-        let mut reader = tun1.reader();
+        let mut reader = tun1.io_reader();
         let mut buf = [0u8; 1024];
         loop {
             let n = reader.read(&mut buf).await.unwrap();
-            println!("reading {} bytes from tuns[1]: {:?}", n, &buf[..n]);
+            println!(
+                "reading {} bytes from tuns[1] ({} packets so far): {:?}",
+                n,
+                tun1.queue_stats().packets_read,
+                &buf[..n]
+            );
         }
     });
 
-    let mut reader = tun2.reader();
+    let mut reader = tun2.io_reader();
     let mut buf = [0u8; 1024];
     loop {
         let n = reader.read(&mut buf).await.unwrap();
-        println!("reading {} bytes from tuns[2]: {:?}", n, &buf[..n]);
+        println!(
+            "reading {} bytes from tuns[2] ({} packets so far): {:?}",
+            n,
+            tun2.queue_stats().packets_read,
+            &buf[..n]
+        );
     }
 }
 
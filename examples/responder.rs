@@ -0,0 +1,37 @@
+use async_std::sync::Arc;
+use async_std::task;
+use async_tun::result::Result;
+use async_tun::{Responder, TunBuilder};
+use std::net::Ipv4Addr;
+
+async fn async_main() -> Result<()> {
+    let tun = TunBuilder::new()
+        .name("")
+        .tap(false)
+        .packet_info(false)
+        .up()
+        .address(Ipv4Addr::new(10, 0, 0, 1))
+        .destination(Ipv4Addr::new(10, 1, 0, 1))
+        .netmask(Ipv4Addr::new(255, 255, 255, 0))
+        .try_build()
+        .await?;
+
+    println!("---------------------------------------");
+    println!("ping 10.1.0.1 to get replies from {}", tun.name());
+    println!("---------------------------------------");
+
+    let tun = Arc::new(tun);
+    let packets = Responder::for_tun(&tun)
+        .echo(Ipv4Addr::new(10, 1, 0, 1).into())
+        .spawn(tun.clone(), 64);
+
+    while let Ok(packet) = packets.recv().await {
+        let packet = packet?;
+        println!("passing {} bytes: {:?}", packet.len(), packet);
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    task::block_on(async_main())
+}
@@ -0,0 +1,71 @@
+use async_std::task;
+use async_tun::result::Result;
+use async_tun::TunBuilder;
+use smoltcp::iface::{Config, Interface, SocketSet};
+use smoltcp::socket::tcp;
+use smoltcp::time::Instant;
+use smoltcp::wire::{HardwareAddress, IpAddress, IpCidr};
+use std::net::Ipv4Addr;
+
+const RESPONSE: &[u8] =
+    b"HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 14\r\n\r\nHello, world!\n";
+
+async fn async_main() -> Result<()> {
+    let mut tun = TunBuilder::new()
+        .name("")
+        .tap(false)
+        .packet_info(false)
+        .backpressure(true)
+        .up()
+        .address(Ipv4Addr::new(10, 0, 0, 1))
+        .destination(Ipv4Addr::new(10, 0, 0, 2))
+        .netmask(Ipv4Addr::new(255, 255, 255, 0))
+        .try_build()
+        .await?;
+
+    println!("---------------------------------------");
+    println!("curl http://10.0.0.2 to query {}", tun.name());
+    println!("---------------------------------------");
+
+    let mut iface = Interface::new(Config::new(HardwareAddress::Ip), &mut tun, Instant::now());
+    iface.update_ip_addrs(|addrs| {
+        let _ = addrs.push(IpCidr::new(IpAddress::v4(10, 0, 0, 2), 24));
+    });
+    let mut sockets = SocketSet::new(vec![]);
+    let handle = sockets.add(tcp::Socket::new(
+        tcp::SocketBuffer::new(vec![0; 4096]),
+        tcp::SocketBuffer::new(vec![0; 4096]),
+    ));
+
+    loop {
+        let now = Instant::now();
+        iface.poll(now, &mut tun, &mut sockets);
+
+        let socket = sockets.get_mut::<tcp::Socket>(handle);
+        if !socket.is_open() {
+            socket.listen(80)?;
+        }
+        if socket.can_recv() {
+            // Answers every request once its first segment arrived.
+            let request = socket.recv(|buf| (buf.len(), buf.to_vec()))?;
+            println!(
+                "{}",
+                String::from_utf8_lossy(&request)
+                    .lines()
+                    .next()
+                    .unwrap_or("")
+            );
+            if socket.can_send() {
+                socket.send_slice(RESPONSE)?;
+            }
+            socket.close();
+        }
+
+        let delay = iface.poll_delay(now, &sockets).map(Into::into);
+        tun.wait(delay).await?;
+    }
+}
+
+fn main() -> Result<()> {
+    task::block_on(async_main())
+}
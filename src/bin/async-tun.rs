@@ -0,0 +1,489 @@
+use async_io::Async;
+use async_std::future;
+use async_std::io::ReadExt;
+use async_std::task;
+use async_tun::packet::arp::{self, ArpPacket};
+use async_tun::packet::ethernet::EthernetFrame;
+use async_tun::packet::icmp::{Icmpv4Packet, Icmpv6Packet};
+use async_tun::packet::tcp::{TcpFlags, TcpPacket};
+use async_tun::packet::udp::UdpPacket;
+use async_tun::packet::{ethertype, protocol, IpPacket};
+use async_tun::result::Result;
+use async_tun::{
+    ensure_bridge, interface, DeviceStats, Group, Ipv4Cidr, Ipv6Cidr, Owner, Tun, TunBuilder,
+    TunConfig,
+};
+use clap::{Args, Parser, Subcommand};
+use mac_address::{mac_address_by_name, MacAddress};
+use nix::unistd::{Group as UnixGroup, User};
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::str::FromStr;
+
+const IFF_NAMES: &[(i32, &str)] = &[
+    (libc::IFF_UP, "UP"),
+    (libc::IFF_BROADCAST, "BROADCAST"),
+    (libc::IFF_DEBUG, "DEBUG"),
+    (libc::IFF_LOOPBACK, "LOOPBACK"),
+    (libc::IFF_POINTOPOINT, "POINTOPOINT"),
+    (libc::IFF_RUNNING, "RUNNING"),
+    (libc::IFF_NOARP, "NOARP"),
+    (libc::IFF_PROMISC, "PROMISC"),
+    (libc::IFF_ALLMULTI, "ALLMULTI"),
+    (libc::IFF_MULTICAST, "MULTICAST"),
+];
+
+const TUN_NAMES: &[(i32, &str)] = &[
+    (libc::IFF_TUN, "TUN"),
+    (libc::IFF_TAP, "TAP"),
+    (libc::IFF_NAPI, "NAPI"),
+    (libc::IFF_NAPI_FRAGS, "NAPI_FRAGS"),
+    (libc::IFF_NO_CARRIER, "NO_CARRIER"),
+    (libc::IFF_NO_PI, "NO_PI"),
+    (libc::IFF_ONE_QUEUE, "ONE_QUEUE"),
+    (libc::IFF_VNET_HDR, "VNET_HDR"),
+    (libc::IFF_TUN_EXCL, "TUN_EXCL"),
+    (libc::IFF_MULTI_QUEUE, "MULTI_QUEUE"),
+    (libc::IFF_ATTACH_QUEUE, "ATTACH_QUEUE"),
+    (libc::IFF_DETACH_QUEUE, "DETACH_QUEUE"),
+    (libc::IFF_PERSIST, "PERSIST"),
+    (libc::IFF_NOFILTER, "NOFILTER"),
+];
+
+/// Creates, inspects and deletes Tun/Tap devices.
+#[derive(Parser)]
+#[command(name = "async-tun", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Creates a new device; unless `--persist` is given, it is deleted once interrupted.
+    Create(CreateArgs),
+    /// Shows the kind, flags, owner, addresses and counters of an existing device.
+    Show {
+        /// The name of device.
+        name: String,
+    },
+    /// Deletes a persistent device.
+    Delete {
+        /// The name of device.
+        name: String,
+    },
+    /// Prints the packets sent and received by an existing device, without taking them from its readers.
+    Dump {
+        /// The name of device.
+        name: String,
+        /// Exits after `count` packets.
+        #[arg(short, long)]
+        count: Option<usize>,
+    },
+}
+
+#[derive(Args)]
+struct CreateArgs {
+    /// The name of device, e.g. `tun0` or `tun%d`; if it is omitted, then device name is set by kernel.
+    #[arg(long, default_value = "")]
+    name: String,
+    /// Creates a TAP device instead of a TUN device.
+    #[arg(long)]
+    tap: bool,
+    /// Does not prefix packets with packet information.
+    #[arg(long)]
+    no_packet_info: bool,
+    /// The MTU of device.
+    #[arg(long)]
+    mtu: Option<i32>,
+    /// The length of the transmit queue of device.
+    #[arg(long)]
+    txqueuelen: Option<u32>,
+    /// The size of the send buffer of device in bytes.
+    #[arg(long)]
+    sndbuf: Option<u32>,
+    /// The link type of device (`ARPHRD_*`), e.g. 65534 for none.
+    #[arg(long)]
+    link_type: Option<u16>,
+    /// Receives written packets through NAPI.
+    #[arg(long)]
+    napi: bool,
+    /// Passes fragmented frames to NAPI (for tap mode, requires `--napi`).
+    #[arg(long)]
+    napi_frags: bool,
+    /// Keeps the carrier off until a queue is attached.
+    #[arg(long)]
+    no_carrier: bool,
+    /// Prefixes packets with a `virtio_net_hdr`.
+    #[arg(long)]
+    vnet_hdr: bool,
+    /// Fails if the device already exists.
+    #[arg(long)]
+    exclusive: bool,
+    /// The IPv4 address and prefix length of device, e.g. `10.0.0.1/24`.
+    #[arg(long)]
+    addr: Option<Ipv4Cidr>,
+    /// The IPv4 destination address of device.
+    #[arg(long)]
+    dest: Option<Ipv4Addr>,
+    /// An IPv6 address and prefix length of device, may be repeated.
+    #[arg(long)]
+    addr6: Vec<Ipv6Cidr>,
+    /// The Ethernet MAC address of device (for tap mode).
+    #[arg(long)]
+    mac: Option<MacAddress>,
+    /// The owner of device, as a numeric id or a user name.
+    #[arg(long, value_parser = Owner::from_str)]
+    owner: Option<Owner>,
+    /// The group of device, as a numeric id or a group name.
+    #[arg(long, value_parser = Group::from_str)]
+    group: Option<Group>,
+    /// The bridge, bond or VRF to enslave device to.
+    #[arg(long)]
+    master: Option<String>,
+    /// Creates the bridge given by `--master` if it does not exist.
+    #[arg(long, requires = "master")]
+    create_bridge: bool,
+    /// The number of queues.
+    #[arg(long, default_value_t = 1)]
+    queues: usize,
+    /// Makes the device persistent.
+    #[arg(long)]
+    persist: bool,
+    /// Sets up the device.
+    #[arg(long)]
+    up: bool,
+}
+
+impl From<CreateArgs> for TunConfig {
+    fn from(args: CreateArgs) -> Self {
+        Self {
+            name: args.name,
+            tap: args.tap,
+            packet_info: !args.no_packet_info,
+            persist: args.persist,
+            up: args.up,
+            mtu: args.mtu,
+            txqueuelen: args.txqueuelen,
+            link_type: args.link_type,
+            sndbuf: args.sndbuf,
+            napi: args.napi,
+            napi_frags: args.napi_frags,
+            no_carrier: args.no_carrier,
+            vnet_hdr: args.vnet_hdr,
+            exclusive: args.exclusive,
+            owner: args.owner,
+            group: args.group,
+            address: args.addr,
+            destination: args.dest,
+            mac: args.mac,
+            ipv6: args.addr6,
+            master: args.master,
+            queues: args.queues,
+            ..Default::default()
+        }
+    }
+}
+
+fn names(flags: i32, table: &[(i32, &str)]) -> String {
+    let names: Vec<&str> = table
+        .iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    names.join(",")
+}
+
+fn tcp_flags(flags: TcpFlags) -> String {
+    [
+        (TcpFlags::SYN, 'S'),
+        (TcpFlags::FIN, 'F'),
+        (TcpFlags::RST, 'R'),
+        (TcpFlags::PSH, 'P'),
+        (TcpFlags::URG, 'U'),
+        (TcpFlags::ACK, '.'),
+    ]
+    .iter()
+    .filter(|(flag, _)| flags.contains(*flag))
+    .map(|(_, c)| *c)
+    .collect()
+}
+
+/// Returns a socket of `domain`, closed once dropped.
+fn socket(domain: i32, kind: i32, protocol: i32) -> Result<File> {
+    let fd = unsafe { libc::socket(domain, kind | libc::SOCK_CLOEXEC, protocol) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Issues the socket ioctl `request` about device `name`, which does not attach to it.
+fn ifreq(socket: &File, name: &str, request: libc::Ioctl) -> Result<libc::ifreq> {
+    let mut req: libc::ifreq = unsafe { mem::zeroed() };
+    if name.len() >= req.ifr_name.len() {
+        return Err(format!("Invalid name: {}", name).into());
+    }
+    for (dst, src) in req.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as _;
+    }
+    if unsafe { libc::ioctl(socket.as_raw_fd(), request, &mut req) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(req)
+}
+
+fn ifreq_addr(socket: &File, name: &str, request: libc::Ioctl) -> Result<Ipv4Addr> {
+    let req = ifreq(socket, name, request)?;
+    let addr = unsafe { &*(&req.ifr_ifru.ifru_addr as *const _ as *const libc::sockaddr_in) };
+    Ok(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)))
+}
+
+/// Attaches to the existing device `name` with the flags it was created with, as the kernel
+/// replaces the optional flags of device with those of the last attached queue.
+async fn attach(name: &str) -> Result<Tun> {
+    let flags = interface::tun_flags(name)?;
+    TunBuilder::new()
+        .name(name)
+        .tap(flags & libc::IFF_TAP != 0)
+        .packet_info(flags & libc::IFF_NO_PI == 0)
+        .multi_queue(flags & libc::IFF_MULTI_QUEUE != 0)
+        .napi(flags & libc::IFF_NAPI != 0)
+        .napi_frags(flags & libc::IFF_NAPI_FRAGS != 0)
+        .vnet_hdr(flags & libc::IFF_VNET_HDR != 0)
+        .try_build()
+        .await
+}
+
+async fn create(args: CreateArgs) -> Result<()> {
+    if let (true, Some(master)) = (args.create_bridge, &args.master) {
+        ensure_bridge(master)?;
+    }
+    let config = TunConfig::from(args);
+    let tuns = config.build().await?;
+    println!("{}", tuns[0].name());
+    if !config.persist {
+        future::pending::<()>().await;
+    }
+    Ok(())
+}
+
+/// Shows device `name` from sysfs and socket ioctls, as attaching to it would fail while it is
+/// in use and replace its optional flags.
+async fn show(name: &str) -> Result<()> {
+    let tun_flags = interface::tun_flags(name)?;
+    let socket = socket(libc::AF_INET, libc::SOCK_DGRAM, 0)?;
+    let tap = tun_flags & libc::IFF_TAP != 0;
+    println!("{}: {}", name, if tap { "tap" } else { "tun" });
+    let flags = unsafe {
+        ifreq(&socket, name, libc::SIOCGIFFLAGS)?
+            .ifr_ifru
+            .ifru_flags
+    };
+    println!("  flags: {}", names(flags.into(), IFF_NAMES));
+    println!("  tun flags: {}", names(tun_flags, TUN_NAMES));
+    println!("  features: {}", names(interface::features()?, TUN_NAMES));
+    let mtu = unsafe { ifreq(&socket, name, libc::SIOCGIFMTU)?.ifr_ifru.ifru_mtu };
+    println!("  mtu: {}", mtu);
+    println!(
+        "  txqueuelen: {}",
+        interface::read::<u32>(name, "tx_queue_len")?
+    );
+    let owner = interface::owner(name)?.map(|uid| match User::from_uid(uid) {
+        Ok(Some(user)) => format!("{} ({})", uid, user.name),
+        _ => uid.to_string(),
+    });
+    let group = interface::group(name)?.map(|gid| match UnixGroup::from_gid(gid) {
+        Ok(Some(group)) => format!("{} ({})", gid, group.name),
+        _ => gid.to_string(),
+    });
+    println!("  owner: {}", owner.as_deref().unwrap_or("-"));
+    println!("  group: {}", group.as_deref().unwrap_or("-"));
+    let master = interface::master(name)?;
+    println!("  master: {}", master.as_deref().unwrap_or("-"));
+    if let Ok(address) = ifreq_addr(&socket, name, libc::SIOCGIFADDR) {
+        let netmask = ifreq_addr(&socket, name, libc::SIOCGIFNETMASK)?;
+        println!("  inet: {}/{}", address, u32::from(netmask).count_ones());
+    }
+    if let Ok(destination) = ifreq_addr(&socket, name, libc::SIOCGIFDSTADDR) {
+        if !destination.is_unspecified() {
+            println!("  peer: {}", destination);
+        }
+    }
+    for address in interface::ipv6_addresses(name)? {
+        println!("  inet6: {}", address);
+    }
+    if tap {
+        if let Some(mac) = mac_address_by_name(name)? {
+            println!("  ether: {}", mac);
+        }
+    }
+    let stats = DeviceStats::read(name)?;
+    println!(
+        "  rx: {} packets, {} bytes, {} errors, {} dropped",
+        stats.rx_packets, stats.rx_bytes, stats.rx_errors, stats.rx_dropped
+    );
+    println!(
+        "  tx: {} packets, {} bytes, {} errors, {} dropped",
+        stats.tx_packets, stats.tx_bytes, stats.tx_errors, stats.tx_dropped
+    );
+    Ok(())
+}
+
+async fn delete(name: &str) -> Result<()> {
+    attach(name).await?.set_persist(false)
+}
+
+fn describe_ip(packet: &[u8], ethertype: Option<u16>) -> String {
+    let ip = match ethertype {
+        Some(ethertype) => match IpPacket::with_ethertype(ethertype, packet) {
+            Some(ip) => ip,
+            None => return format!("ethertype {:#06x}, length {}", ethertype, packet.len()),
+        },
+        None => IpPacket::new_checked(packet),
+    };
+    let ip = match ip {
+        Ok(ip) => ip,
+        Err(err) => return format!("{}, length {}", err, packet.len()),
+    };
+    let (src, dst) = (ip.src_addr(), ip.dst_addr());
+    let transport = ip.transport().unwrap_or_default();
+    let detail = match ip.protocol() {
+        Ok(protocol::TCP) => match TcpPacket::new_checked(transport) {
+            Ok(tcp) => format!(
+                "TCP {}:{} > {}:{} flags [{}] seq {} ack {} win {}",
+                src,
+                tcp.src_port(),
+                dst,
+                tcp.dst_port(),
+                tcp_flags(tcp.flags()),
+                tcp.seq_number(),
+                tcp.ack_number(),
+                tcp.window()
+            ),
+            Err(err) => format!("TCP {} > {}: {}", src, dst, err),
+        },
+        Ok(protocol::UDP) => match UdpPacket::new_checked(transport) {
+            Ok(udp) => format!(
+                "UDP {}:{} > {}:{} length {}",
+                src,
+                udp.src_port(),
+                dst,
+                udp.dst_port(),
+                udp.payload().len()
+            ),
+            Err(err) => format!("UDP {} > {}: {}", src, dst, err),
+        },
+        Ok(protocol::ICMP) => match Icmpv4Packet::new_checked(transport) {
+            Ok(icmp) => format!(
+                "ICMP {} > {} type {} code {}",
+                src,
+                dst,
+                icmp.msg_type(),
+                icmp.msg_code()
+            ),
+            Err(err) => format!("ICMP {} > {}: {}", src, dst, err),
+        },
+        Ok(protocol::ICMPV6) => match Icmpv6Packet::new_checked(transport) {
+            Ok(icmp) => format!(
+                "ICMPv6 {} > {} type {} code {}",
+                src,
+                dst,
+                icmp.msg_type(),
+                icmp.msg_code()
+            ),
+            Err(err) => format!("ICMPv6 {} > {}: {}", src, dst, err),
+        },
+        Ok(protocol) => format!("{} > {} protocol {}", src, dst, protocol),
+        Err(err) => format!("{} > {}: {}", src, dst, err),
+    };
+    format!("{}, length {}", detail, ip.total_len())
+}
+
+fn describe(packet: &[u8], tap: bool) -> String {
+    if !tap {
+        return describe_ip(packet, None);
+    }
+    let frame = match EthernetFrame::new_checked(packet) {
+        Ok(frame) => frame,
+        Err(err) => return format!("Ethernet: {}", err),
+    };
+    let link = format!("{} > {}", frame.src_addr(), frame.dst_addr());
+    let ethertype = frame.payload_ethertype();
+    if ethertype != ethertype::ARP {
+        return format!(
+            "{}, {}",
+            link,
+            describe_ip(frame.payload(), Some(ethertype))
+        );
+    }
+    match ArpPacket::new_checked(frame.payload()) {
+        Ok(arp) if arp.operation() == arp::REQUEST => format!(
+            "{}, ARP who-has {} tell {}",
+            link,
+            arp.target_protocol_addr(),
+            arp.sender_protocol_addr()
+        ),
+        Ok(arp) if arp.operation() == arp::REPLY => format!(
+            "{}, ARP {} is-at {}",
+            link,
+            arp.sender_protocol_addr(),
+            arp.sender_hardware_addr()
+        ),
+        Ok(arp) => format!("{}, ARP operation {}", link, arp.operation()),
+        Err(err) => format!("{}, ARP: {}", link, err),
+    }
+}
+
+/// Prints the packets of device `name` from a packet socket, which sees both directions and
+/// leaves the packets to the readers of device.
+async fn dump(name: &str, count: Option<usize>) -> Result<()> {
+    let tap = interface::tun_flags(name)? & libc::IFF_TAP != 0;
+    let protocol = (libc::ETH_P_ALL as u16).to_be();
+    let socket = socket(libc::AF_PACKET, libc::SOCK_RAW, protocol as _)?;
+    let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as _;
+    addr.sll_protocol = protocol;
+    addr.sll_ifindex = unsafe { libc::if_nametoindex(CString::new(name)?.as_ptr()) } as _;
+    if addr.sll_ifindex == 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let result = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &addr as *const _ as *const libc::sockaddr,
+            mem::size_of_val(&addr) as _,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let mut socket = Async::new(socket)?;
+    // Segmentation offloads may deliver packets larger than the MTU.
+    let mut buf = vec![0; u16::MAX as usize];
+    for _ in 0..count.unwrap_or(usize::MAX) {
+        let n = socket.read(&mut buf).await?;
+        println!("{}", describe(&buf[..n], tap));
+    }
+    Ok(())
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let result = task::block_on(async {
+        match cli.command {
+            Command::Create(args) => create(args).await,
+            Command::Show { name } => show(&name).await,
+            Command::Delete { name } => delete(&name).await,
+            Command::Dump { name, count } => dump(&name, count).await,
+        }
+    });
+    if let Err(err) = result {
+        eprintln!("async-tun: {}", err);
+        std::process::exit(1);
+    }
+}
@@ -1,58 +1,102 @@
 use super::result::Result;
 use super::tun::Tun;
+use crate::config::ConfigError;
 #[cfg(target_os = "linux")]
 use crate::linux::params::Params;
-use core::convert::From;
-use libc::{IFF_NO_PI, IFF_TAP, IFF_TUN};
+use crate::net::{IpCidr, Route};
+use crate::owner::{Group, Owner};
+use core::convert::TryFrom;
+use libc::{
+    IFF_MULTI_QUEUE, IFF_NAPI, IFF_NAPI_FRAGS, IFF_NO_CARRIER, IFF_NO_PI, IFF_TAP, IFF_TUN,
+    IFF_TUN_EXCL, IFF_VNET_HDR,
+};
 use mac_address::MacAddress;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const MAX_NAME_LEN: usize = 15;
+pub(crate) const MIN_MTU: i32 = 68;
+const MIN_MTU_V6: i32 = 1280;
+pub(crate) const MAX_MTU: i32 = 65535;
 
 /// Represents a factory to build new instances of [`Tun`](struct.Tun.html).
-pub struct TunBuilder<'a> {
-    name: &'a str,
+#[derive(Debug, Clone)]
+pub struct TunBuilder {
+    name: String,
     is_tap: bool,
     packet_info: bool,
+    multi_queue: bool,
+    napi: bool,
+    napi_frags: bool,
+    no_carrier: bool,
+    vnet_hdr: bool,
+    exclusive: bool,
     persist: bool,
     up: bool,
     mtu: Option<i32>,
-    owner: Option<i32>,
-    group: Option<i32>,
+    txqueuelen: Option<u32>,
+    link_type: Option<u16>,
+    sndbuf: Option<u32>,
+    backpressure: bool,
+    owner: Option<Owner>,
+    group: Option<Group>,
     address: Option<Ipv4Addr>,
     destination: Option<Ipv4Addr>,
     broadcast: Option<Ipv4Addr>,
     netmask: Option<Ipv4Addr>,
     mac: Option<MacAddress>,
+    ipv6: Vec<(Ipv6Addr, u8)>,
+    routes: Vec<Route>,
+    master: Option<String>,
 }
 
-impl<'a> Default for TunBuilder<'a> {
+impl Default for TunBuilder {
     fn default() -> Self {
         Self {
-            name: "",
+            name: String::new(),
             owner: None,
             group: None,
             is_tap: false,
+            multi_queue: false,
+            napi: false,
+            napi_frags: false,
+            no_carrier: false,
+            vnet_hdr: false,
+            exclusive: false,
             persist: false,
             up: false,
             mtu: None,
+            txqueuelen: None,
+            link_type: None,
+            sndbuf: None,
+            backpressure: false,
             packet_info: true,
             address: None,
             destination: None,
             broadcast: None,
             netmask: None,
             mac: None,
+            ipv6: Vec::new(),
+            routes: Vec::new(),
+            master: None,
         }
     }
 }
 
-impl<'a> TunBuilder<'a> {
+impl TunBuilder {
     /// Creates a new instance of [`TunBuilder`](struct.TunBuilder.html).
     pub fn new() -> Self {
         Default::default()
     }
 
-    /// Sets the name of device (max length: 16 characters), if it is empty, then device name is set by kernel. Default value is empty.
-    pub fn name(mut self, name: &'a str) -> Self {
-        self.name = name;
+    /// Sets the name of device (max length: 15 characters), if it is empty, then device name is set by kernel. Default value is empty.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Sets the name of device to the kernel template `<prefix>%d`, e.g. `vpn` for the first free name of `vpn0`, `vpn1`, ...
+    pub fn name_template(mut self, prefix: &str) -> Self {
+        self.name = format!("{}%d", prefix);
         self
     }
 
@@ -68,21 +112,87 @@ impl<'a> TunBuilder<'a> {
         self
     }
 
+    /// If `multi_queue` is true, then `IFF_MULTI_QUEUE` flag is set even for a single queue, e.g. to
+    /// attach to an existing multi-queue device. It is always set by `try_build_mq`. Default value is `false`.
+    pub fn multi_queue(mut self, multi_queue: bool) -> Self {
+        self.multi_queue = multi_queue;
+        self
+    }
+
+    /// If `napi` is true, then `IFF_NAPI` flag is set to receive written packets through NAPI. Default value is `false`.
+    pub fn napi(mut self, napi: bool) -> Self {
+        self.napi = napi;
+        self
+    }
+
+    /// If `napi_frags` is true, then `IFF_NAPI_FRAGS` flag is set to pass fragmented frames to NAPI (for tap mode, requires `napi`). Default value is `false`.
+    pub fn napi_frags(mut self, napi_frags: bool) -> Self {
+        self.napi_frags = napi_frags;
+        self
+    }
+
+    /// If `no_carrier` is true, then `IFF_NO_CARRIER` flag is set and the carrier stays off until a queue is attached. Default value is `false`.
+    pub fn no_carrier(mut self, no_carrier: bool) -> Self {
+        self.no_carrier = no_carrier;
+        self
+    }
+
+    /// If `vnet_hdr` is true, then `IFF_VNET_HDR` flag is set and packets are prefixed with a `virtio_net_hdr`. Default value is `false`.
+    pub fn vnet_hdr(mut self, vnet_hdr: bool) -> Self {
+        self.vnet_hdr = vnet_hdr;
+        self
+    }
+
+    /// If `exclusive` is true, then `IFF_TUN_EXCL` flag is set and building fails if the device already exists. Default value is `false`.
+    pub fn exclusive(mut self, exclusive: bool) -> Self {
+        self.exclusive = exclusive;
+        self
+    }
+
+    /// Sets the length of the transmit queue of device.
+    pub fn txqueuelen(mut self, txqueuelen: u32) -> Self {
+        self.txqueuelen = Some(txqueuelen);
+        self
+    }
+
+    /// Sets the link type of device (`ARPHRD_*`, e.g. `libc::ARPHRD_NONE`) through `TUNSETLINK`.
+    pub fn link_type(mut self, link_type: u16) -> Self {
+        self.link_type = Some(link_type);
+        self
+    }
+
+    /// Sets the size of the send buffer of device in bytes (`TUNSETSNDBUF`), which bounds the
+    /// written packets not yet consumed by the kernel.
+    pub fn sndbuf(mut self, sndbuf: u32) -> Self {
+        self.sndbuf = Some(sndbuf);
+        self
+    }
+
+    /// If `backpressure` is true, then the queues are switched to `O_NONBLOCK`: once the send
+    /// buffer is full, [`TunDevice::send`](trait.TunDevice.html#method.send) stays pending until
+    /// the kernel consumes packets, instead of blocking the thread of the task. The same applies
+    /// to `io`, `io_reader`, `io_writer`, `io_split` and `with_capture`, while `reader`, `writer`
+    /// and `split` fail with `WouldBlock`. Default value is `false`.
+    pub fn backpressure(mut self, backpressure: bool) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
     /// Sets the MTU of device.
     pub fn mtu(mut self, mtu: i32) -> Self {
         self.mtu = Some(mtu);
         self
     }
 
-    /// Sets the owner of device.
-    pub fn owner(mut self, owner: i32) -> Self {
-        self.owner = Some(owner);
+    /// Sets the owner of device, as a numeric id, a [`Uid`](https://docs.rs/nix/*/nix/unistd/struct.Uid.html) or a user name.
+    pub fn owner(mut self, owner: impl Into<Owner>) -> Self {
+        self.owner = Some(owner.into());
         self
     }
 
-    /// Sets the group of device.
-    pub fn group(mut self, group: i32) -> Self {
-        self.group = Some(group);
+    /// Sets the group of device, as a numeric id, a [`Gid`](https://docs.rs/nix/*/nix/unistd/struct.Gid.html) or a group name.
+    pub fn group(mut self, group: impl Into<Group>) -> Self {
+        self.group = Some(group.into());
         self
     }
 
@@ -116,6 +226,24 @@ impl<'a> TunBuilder<'a> {
         self
     }
 
+    /// Adds an IPv6 address with prefix length `prefix` to device, may be called multiple times.
+    pub fn ipv6(mut self, address: Ipv6Addr, prefix: u8) -> Self {
+        self.ipv6.push((address, prefix));
+        self
+    }
+
+    /// Adds a route through device, may be called multiple times. Routes are added once the device is up.
+    pub fn route(mut self, route: Route) -> Self {
+        self.routes.push(route);
+        self
+    }
+
+    /// Enslaves the device to the bridge, bond or VRF `master` before it is set up.
+    pub fn master(mut self, master: impl Into<String>) -> Self {
+        self.master = Some(master.into());
+        self
+    }
+
     /// Makes the device persistent.
     pub fn persist(mut self) -> Self {
         self.persist = true;
@@ -128,49 +256,256 @@ impl<'a> TunBuilder<'a> {
         self
     }
 
+    /// Checks the options and returns the first invalid one, which `try_build` would reject.
+    pub fn validate(&self) -> std::result::Result<(), ConfigError> {
+        validate_name("name", &self.name, true)?;
+        if let Some(master) = &self.master {
+            if master.is_empty() {
+                return Err(ConfigError::new("master", "must not be empty"));
+            }
+            validate_name("master", master, false)?;
+        }
+        if let Some(mtu) = self.mtu {
+            let min = if self.ipv6.is_empty() {
+                MIN_MTU
+            } else {
+                MIN_MTU_V6
+            };
+            if !(min..=MAX_MTU).contains(&mtu) {
+                return Err(ConfigError::new(
+                    "mtu",
+                    format!("must be between {} and {}", min, MAX_MTU),
+                ));
+            }
+        }
+        for (i, (_, prefix)) in self.ipv6.iter().enumerate() {
+            if *prefix > 128 {
+                return Err(ConfigError::new(
+                    format!("ipv6[{}]", i),
+                    "prefix length must be at most 128",
+                ));
+            }
+        }
+        if self.napi_frags && !(self.napi && self.is_tap) {
+            return Err(ConfigError::new("napi_frags", "requires `tap` and `napi`"));
+        }
+        if self
+            .txqueuelen
+            .is_some_and(|txqueuelen| txqueuelen > i32::MAX as u32)
+        {
+            return Err(ConfigError::new(
+                "txqueuelen",
+                format!("must be at most {}", i32::MAX),
+            ));
+        }
+        if self
+            .sndbuf
+            .is_some_and(|sndbuf| sndbuf == 0 || sndbuf > i32::MAX as u32)
+        {
+            return Err(ConfigError::new(
+                "sndbuf",
+                format!("must be between 1 and {}", i32::MAX),
+            ));
+        }
+        if self.mac.is_some() && !self.is_tap {
+            return Err(ConfigError::new("mac", "requires `tap`"));
+        }
+        if !self.routes.is_empty() && !self.up {
+            return Err(ConfigError::new("routes", "requires `up`"));
+        }
+        for (i, route) in self.routes.iter().enumerate() {
+            match (route.destination, route.gateway) {
+                (IpCidr::V4(_), Some(IpAddr::V6(_))) | (IpCidr::V6(_), Some(IpAddr::V4(_))) => {
+                    return Err(ConfigError::new(
+                        format!("routes[{}].gateway", i),
+                        "must have the address family of `destination`",
+                    ))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
     /// Builds a new instance of [`Tun`](struct.Tun.html).
     pub async fn try_build(self) -> Result<Tun> {
-        Tun::new(self.into()).await
+        self.validate()?;
+        Tun::new(self.try_into()?).await
     }
 
     /// Builds multiple instances of [`Tun`](struct.Tun.html) with `IFF_MULTI_QUEUE` flag.
     #[cfg(target_os = "linux")]
     pub async fn try_build_mq(self, queues: usize) -> Result<Vec<Tun>> {
-        Tun::new_mq(self.into(), queues).await
+        self.validate()?;
+        if queues == 0 {
+            return Err(ConfigError::new("queues", "must be at least 1").into());
+        }
+        Tun::new_mq(self.try_into()?, queues).await
+    }
+}
+
+/// Checks `name` the way the kernel does, allowing a single `%d` for templates if `template`.
+pub(crate) fn validate_name(
+    field: &str,
+    name: &str,
+    template: bool,
+) -> std::result::Result<(), ConfigError> {
+    let error = |message: &str| Err(ConfigError::new(field, message));
+    if name.len() > MAX_NAME_LEN {
+        return error(&format!("must be at most {} bytes long", MAX_NAME_LEN));
+    }
+    if name == "." || name == ".." {
+        return error("must not be `.` or `..`");
+    }
+    if name.contains(|c: char| c == '/' || c == ':' || c.is_whitespace()) {
+        return error("must not contain `/`, `:` or whitespace");
+    }
+    if !template && name.contains('%') {
+        return error("must not contain `%`");
     }
+    if name.matches('%').count() > name.matches("%d").count() || name.matches("%d").count() > 1 {
+        return error("must not contain `%` except a single `%d` template");
+    }
+    Ok(())
 }
 
-impl<'a> From<TunBuilder<'a>> for Params {
+impl TryFrom<TunBuilder> for Params {
+    type Error = ConfigError;
+
     #[cfg(target_os = "linux")]
-    fn from(builder: TunBuilder) -> Self {
-        Params {
+    fn try_from(builder: TunBuilder) -> std::result::Result<Self, Self::Error> {
+        Ok(Params {
             name: if builder.name.is_empty() {
                 None
             } else {
-                Some(builder.name.into())
+                Some(builder.name)
             },
             flags: {
                 let mut flags = if builder.is_tap { IFF_TAP } else { IFF_TUN } as _;
                 if !builder.packet_info {
                     flags |= IFF_NO_PI as i16;
                 }
+                for (enabled, flag) in [
+                    (builder.multi_queue, IFF_MULTI_QUEUE),
+                    (builder.napi, IFF_NAPI),
+                    (builder.napi_frags, IFF_NAPI_FRAGS),
+                    (builder.no_carrier, IFF_NO_CARRIER),
+                    (builder.vnet_hdr, IFF_VNET_HDR),
+                    (builder.exclusive, IFF_TUN_EXCL),
+                ] {
+                    if enabled {
+                        flags |= flag as i16;
+                    }
+                }
                 flags
             },
             persist: builder.persist,
             up: builder.up,
             mtu: builder.mtu,
-            owner: builder.owner,
-            group: builder.group,
+            txqueuelen: builder.txqueuelen,
+            link_type: builder.link_type,
+            sndbuf: builder.sndbuf.map(|sndbuf| sndbuf as _),
+            backpressure: builder.backpressure,
+            owner: match builder.owner {
+                Some(owner) => Some(owner.resolve()?.as_raw() as _),
+                None => None,
+            },
+            group: match builder.group {
+                Some(group) => Some(group.resolve()?.as_raw() as _),
+                None => None,
+            },
             address: builder.address,
             destination: builder.destination,
             broadcast: builder.broadcast,
             netmask: builder.netmask,
             mac: builder.mac,
-        }
+            ipv6: builder.ipv6,
+            routes: builder.routes,
+            master: builder.master,
+        })
     }
 
     #[cfg(not(any(target_os = "linux")))]
-    fn from(builder: TunBuilder) -> Self {
+    fn try_from(builder: TunBuilder) -> std::result::Result<Self, Self::Error> {
         unimplemented!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(builder: TunBuilder) -> String {
+        builder.validate().unwrap_err().field().to_string()
+    }
+
+    #[test]
+    fn names() {
+        for name in ["", "tun0", "tun%d", "vpn-backbone-01", "a.b_c@d"] {
+            assert!(validate_name("name", name, true).is_ok(), "{}", name);
+        }
+        for name in [
+            "vpn-backbone-012",
+            "tun/0",
+            "tun 0",
+            "tun:0",
+            ".",
+            "..",
+            "tun%",
+            "tun%s",
+            "t%d%d",
+        ] {
+            assert!(validate_name("name", name, true).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn owned() {
+        let name = String::from("tun0");
+        let builder = TunBuilder::new().name(name).mtu(1400);
+        let handle = std::thread::spawn(move || builder.validate());
+        assert!(handle.join().unwrap().is_ok());
+        assert_eq!(TunBuilder::new().name_template("vpn").name, "vpn%d");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn flags() {
+        let params = Params::try_from(
+            TunBuilder::new()
+                .tap(true)
+                .packet_info(false)
+                .napi(true)
+                .napi_frags(true)
+                .vnet_hdr(true)
+                .exclusive(true),
+        )
+        .unwrap();
+        let flags = params.flags as i32 & 0xffff;
+        let expected =
+            IFF_TAP | IFF_NO_PI | IFF_NAPI | IFF_NAPI_FRAGS | IFF_VNET_HDR | IFF_TUN_EXCL;
+        assert_eq!(flags, expected);
+    }
+
+    #[test]
+    fn validate() {
+        assert_eq!(field(TunBuilder::new().name("tun\t0")), "name");
+        assert_eq!(field(TunBuilder::new().mtu(67)), "mtu");
+        assert_eq!(field(TunBuilder::new().mtu(65536)), "mtu");
+        assert!(TunBuilder::new().mtu(1000).validate().is_ok());
+        assert_eq!(
+            field(TunBuilder::new().mtu(1000).ipv6(Ipv6Addr::LOCALHOST, 64)),
+            "mtu"
+        );
+        assert_eq!(
+            field(TunBuilder::new().ipv6(Ipv6Addr::LOCALHOST, 129)),
+            "ipv6[0]"
+        );
+        assert_eq!(field(TunBuilder::new().napi_frags(true)), "napi_frags");
+        assert_eq!(field(TunBuilder::new().sndbuf(0)), "sndbuf");
+        assert_eq!(field(TunBuilder::new().txqueuelen(u32::MAX)), "txqueuelen");
+        assert_eq!(field(TunBuilder::new().master("")), "master");
+        assert_eq!(field(TunBuilder::new().master("br%d")), "master");
+        assert!(TunBuilder::new().master("br0").validate().is_ok());
+    }
+}
@@ -0,0 +1,576 @@
+//! Capture of the packets read from and written to a [`Tun`](../struct.Tun.html) into pcap or
+//! pcapng streams, readable by tcpdump and Wireshark.
+
+use crate::device::{DeviceKind, TunDevice};
+use crate::result::Result;
+use async_std::io::{Read, Write};
+use async_std::sync::Arc;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::task::{ready, Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const PCAPNG_ENHANCED_PACKET: u32 = 0x0000_0006;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const PCAPNG_OPT_END: u16 = 0;
+const PCAPNG_OPT_IF_NAME: u16 = 2;
+const PCAPNG_OPT_EPB_FLAGS: u16 = 2;
+const PCAPNG_EPB_INBOUND: u32 = 0x01;
+const PCAPNG_EPB_OUTBOUND: u32 = 0x02;
+
+/// Default maximum number of bytes stored per packet.
+pub const DEFAULT_SNAPLEN: u32 = 262_144;
+
+/// Represents the file format of a capture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The classic libpcap format, which has no direction annotation.
+    Pcap,
+    /// The pcapng format, annotating each packet with its direction.
+    Pcapng,
+}
+
+/// Represents the link-layer header type of the captured packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkType {
+    /// Ethernet frames, as exchanged with TAP devices.
+    Ethernet,
+    /// IPv4 or IPv6 packets, as exchanged with TUN devices.
+    Raw,
+    /// IPv4 packets only.
+    Ipv4,
+    /// IPv6 packets only.
+    Ipv6,
+}
+
+impl LinkType {
+    /// Returns the `LINKTYPE_*` value of the link type.
+    pub fn value(self) -> u16 {
+        match self {
+            Self::Ethernet => 1,
+            Self::Raw => 101,
+            Self::Ipv4 => 228,
+            Self::Ipv6 => 229,
+        }
+    }
+}
+
+/// Represents the direction of a captured packet, relative to the application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The packet was read from the device, i.e. sent out of the interface by the kernel.
+    Read,
+    /// The packet was written to the device, i.e. received on the interface by the kernel.
+    Write,
+}
+
+type Rotate<W> = Box<dyn FnMut(usize) -> io::Result<W> + Send>;
+
+struct State<W> {
+    sink: W,
+    header_written: bool,
+    written: u64,
+    index: usize,
+}
+
+/// Represents a capture sink which records packets into a pcap or pcapng stream.
+///
+/// Records are written synchronously into `W`, which should therefore be buffered (e.g. a
+/// `std::io::BufWriter<std::fs::File>`). The readers, writers and devices recording into a
+/// capture do not fail when a packet cannot be recorded, which is counted by
+/// [`errors`](#method.errors) instead.
+pub struct Capture<W> {
+    state: Mutex<State<W>>,
+    errors: AtomicU64,
+    format: Format,
+    link_type: LinkType,
+    header_len: usize,
+    snaplen: u32,
+    name: Option<String>,
+    rotation: Option<(u64, Mutex<Rotate<W>>)>,
+}
+
+impl<W: io::Write + Send> Capture<W> {
+    /// Creates a new instance of [`Capture`](struct.Capture.html) writing a pcapng stream of raw IP packets to `sink`.
+    pub fn new(sink: W) -> Self {
+        Self {
+            state: Mutex::new(State {
+                sink,
+                header_written: false,
+                written: 0,
+                index: 0,
+            }),
+            errors: Default::default(),
+            format: Format::Pcapng,
+            link_type: LinkType::Raw,
+            header_len: 0,
+            snaplen: DEFAULT_SNAPLEN,
+            name: None,
+            rotation: None,
+        }
+    }
+
+    /// Creates a new instance of [`Capture`](struct.Capture.html) writing to `sink`, configured
+    /// with the link type, header length and name of `device`.
+    pub fn for_device<D: TunDevice + ?Sized>(device: &D, sink: W) -> Self {
+        Self::new(sink)
+            .link_type(match device.kind() {
+                DeviceKind::Tap => LinkType::Ethernet,
+                DeviceKind::Tun => LinkType::Raw,
+            })
+            .header_len(device.header_len())
+            .name(device.name())
+    }
+
+    /// Sets the file format. Default value is `Format::Pcapng`.
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets the link-layer header type. Default value is `LinkType::Raw`.
+    pub fn link_type(mut self, link_type: LinkType) -> Self {
+        self.link_type = link_type;
+        self
+    }
+
+    /// Sets the length of the header (e.g. packet information) stripped from every packet before it is recorded.
+    pub fn header_len(mut self, header_len: usize) -> Self {
+        self.header_len = header_len;
+        self
+    }
+
+    /// Sets the maximum number of bytes stored per packet. Default value is `DEFAULT_SNAPLEN`.
+    pub fn snaplen(mut self, snaplen: u32) -> Self {
+        self.snaplen = snaplen;
+        self
+    }
+
+    /// Sets the interface name recorded in pcapng streams.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Switches to the sink returned by `next` once `max_bytes` were written to the current
+    /// one. `next` receives the index of the new sink, starting at 1.
+    pub fn rotate<F>(mut self, max_bytes: u64, next: F) -> Self
+    where
+        F: FnMut(usize) -> io::Result<W> + Send + 'static,
+    {
+        self.rotation = Some((max_bytes, Mutex::new(Box::new(next))));
+        self
+    }
+
+    /// Records `packet` with the current time.
+    pub fn record(&self, direction: Direction, packet: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let packet = packet.get(self.header_len..).unwrap_or_default();
+        if let Some((max_bytes, next)) = &self.rotation {
+            if state.header_written && state.written >= *max_bytes {
+                state.sink.flush()?;
+                state.index += 1;
+                state.sink = (next.lock().unwrap_or_else(|e| e.into_inner()))(state.index)?;
+                state.header_written = false;
+                state.written = 0;
+            }
+        }
+        if !state.header_written {
+            let header = self.file_header();
+            state.sink.write_all(&header)?;
+            state.written += header.len() as u64;
+            state.header_written = true;
+        }
+        let record = self.packet_record(direction, packet);
+        state.sink.write_all(&record)?;
+        state.written += record.len() as u64;
+        Ok(())
+    }
+
+    /// Returns the number of packets the readers, writers and devices failed to record.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Records `packet` on behalf of a reader, writer or device, whose I/O does not fail if it
+    /// cannot be recorded.
+    fn capture(&self, direction: Direction, packet: &[u8]) {
+        if let Err(_error) = self.record(direction, packet) {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+            #[cfg(feature = "tracing")]
+            tracing::warn!(name = self.name.as_deref(), error = %_error, "packet not captured");
+        }
+    }
+
+    /// Flushes the current sink.
+    pub fn flush(&self) -> io::Result<()> {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .sink
+            .flush()
+    }
+
+    /// Wraps `reader` to record every packet read from it.
+    pub fn reader<R>(self: &Arc<Self>, reader: R) -> CaptureReader<R, W> {
+        CaptureReader {
+            inner: reader,
+            capture: self.clone(),
+        }
+    }
+
+    /// Wraps `writer` to record every packet written to it.
+    pub fn writer<R>(self: &Arc<Self>, writer: R) -> CaptureWriter<R, W> {
+        CaptureWriter {
+            inner: writer,
+            capture: self.clone(),
+        }
+    }
+
+    /// Wraps `device` to record every packet received from and sent to it.
+    pub fn device<D>(self: &Arc<Self>, device: D) -> Captured<D, W> {
+        Captured {
+            device,
+            capture: self.clone(),
+        }
+    }
+
+    fn file_header(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self.format {
+            Format::Pcap => {
+                buf.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+                buf.extend_from_slice(&2u16.to_le_bytes());
+                buf.extend_from_slice(&4u16.to_le_bytes());
+                buf.extend_from_slice(&0i32.to_le_bytes());
+                buf.extend_from_slice(&0u32.to_le_bytes());
+                buf.extend_from_slice(&self.snaplen.to_le_bytes());
+                buf.extend_from_slice(&(self.link_type.value() as u32).to_le_bytes());
+            }
+            Format::Pcapng => {
+                let mut body = Vec::new();
+                body.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+                body.extend_from_slice(&1u16.to_le_bytes());
+                body.extend_from_slice(&0u16.to_le_bytes());
+                body.extend_from_slice(&(-1i64).to_le_bytes());
+                pcapng_block(&mut buf, PCAPNG_SECTION_HEADER, &body);
+
+                let mut body = Vec::new();
+                body.extend_from_slice(&self.link_type.value().to_le_bytes());
+                body.extend_from_slice(&0u16.to_le_bytes());
+                body.extend_from_slice(&self.snaplen.to_le_bytes());
+                if let Some(name) = &self.name {
+                    pcapng_option(&mut body, PCAPNG_OPT_IF_NAME, name.as_bytes());
+                    pcapng_option(&mut body, PCAPNG_OPT_END, &[]);
+                }
+                pcapng_block(&mut buf, PCAPNG_INTERFACE_DESCRIPTION, &body);
+            }
+        }
+        buf
+    }
+
+    fn packet_record(&self, direction: Direction, packet: &[u8]) -> Vec<u8> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let captured = &packet[..packet.len().min(self.snaplen as usize)];
+        let mut buf = Vec::with_capacity(captured.len() + 48);
+        match self.format {
+            Format::Pcap => {
+                buf.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+                buf.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+                buf.extend_from_slice(&(captured.len() as u32).to_le_bytes());
+                buf.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+                buf.extend_from_slice(captured);
+            }
+            Format::Pcapng => {
+                let micros = timestamp.as_micros() as u64;
+                let mut body = Vec::with_capacity(captured.len() + 32);
+                body.extend_from_slice(&0u32.to_le_bytes());
+                body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+                body.extend_from_slice(&(micros as u32).to_le_bytes());
+                body.extend_from_slice(&(captured.len() as u32).to_le_bytes());
+                body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+                body.extend_from_slice(captured);
+                pad(&mut body);
+                let flags = match direction {
+                    Direction::Read => PCAPNG_EPB_OUTBOUND,
+                    Direction::Write => PCAPNG_EPB_INBOUND,
+                };
+                pcapng_option(&mut body, PCAPNG_OPT_EPB_FLAGS, &flags.to_le_bytes());
+                pcapng_option(&mut body, PCAPNG_OPT_END, &[]);
+                pcapng_block(&mut buf, PCAPNG_ENHANCED_PACKET, &body);
+            }
+        }
+        buf
+    }
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize((buf.len() + 3) & !3, 0);
+}
+
+fn pcapng_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+    pad(buf);
+}
+
+fn pcapng_block(buf: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+    let len = (12 + body.len()) as u32;
+    buf.extend_from_slice(&block_type.to_le_bytes());
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(body);
+    buf.extend_from_slice(&len.to_le_bytes());
+}
+
+/// Represents a reader which records every packet read from the inner reader.
+pub struct CaptureReader<R, W> {
+    inner: R,
+    capture: Arc<Capture<W>>,
+}
+
+impl<R: Read + Unpin, W: io::Write + Send> Read for CaptureReader<R, W> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if n > 0 {
+            self.capture.capture(Direction::Read, &buf[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Represents a writer which records every packet written to the inner writer.
+pub struct CaptureWriter<R, W> {
+    inner: R,
+    capture: Arc<Capture<W>>,
+}
+
+impl<R: Write + Unpin, W: io::Write + Send> Write for CaptureWriter<R, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        if n > 0 {
+            self.capture.capture(Direction::Write, &buf[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Represents a [`TunDevice`](../trait.TunDevice.html) which records every packet received from
+/// and sent to the inner device.
+pub struct Captured<D, W> {
+    device: D,
+    capture: Arc<Capture<W>>,
+}
+
+impl<D: TunDevice, W: io::Write + Send> TunDevice for Captured<D, W> {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn mtu(&self) -> Result<i32> {
+        self.device.mtu()
+    }
+
+    fn kind(&self) -> DeviceKind {
+        self.device.kind()
+    }
+
+    fn packet_info(&self) -> bool {
+        self.device.packet_info()
+    }
+
+    fn header_len(&self) -> usize {
+        self.device.header_len()
+    }
+
+    fn poll_recv(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let n = ready!(self.device.poll_recv(cx, buf))?;
+        if n > 0 {
+            self.capture.capture(Direction::Read, &buf[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_send(&self, cx: &mut Context<'_>, packet: &[u8]) -> Poll<io::Result<usize>> {
+        let n = ready!(self.device.poll_send(cx, packet))?;
+        if n > 0 {
+            self.capture.capture(Direction::Write, &packet[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTun;
+    use async_std::io::{ReadExt, WriteExt};
+    use async_std::task;
+
+    fn sink(capture: &Capture<Vec<u8>>) -> Vec<u8> {
+        capture.state.lock().unwrap().sink.clone()
+    }
+
+    /// Returns `record` without its timestamp, i.e. the 8 bytes at `at`.
+    fn without_timestamp(record: &[u8], at: usize) -> Vec<u8> {
+        [&record[..at], &record[at + 8..]].concat()
+    }
+
+    #[test]
+    fn pcap() {
+        let capture = Capture::new(Vec::new())
+            .format(Format::Pcap)
+            .link_type(LinkType::Ethernet)
+            .header_len(1)
+            .snaplen(4);
+        capture
+            .record(Direction::Read, &[1, 2, 3, 4, 5, 6])
+            .unwrap();
+        let buf = sink(&capture);
+        #[rustfmt::skip]
+        assert_eq!(
+            buf[..24],
+            [
+                0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                4, 0, 0, 0, 1, 0, 0, 0,
+            ]
+        );
+        // The packet is stripped of its header, then truncated to the snapshot length.
+        assert_eq!(
+            without_timestamp(&buf[24..], 0),
+            [4, 0, 0, 0, 5, 0, 0, 0, 2, 3, 4, 5]
+        );
+
+        // The file header is only written once.
+        capture.record(Direction::Write, &[0, 7]).unwrap();
+        let buf = sink(&capture);
+        assert_eq!(
+            without_timestamp(&buf[44..], 0),
+            [1, 0, 0, 0, 1, 0, 0, 0, 7]
+        );
+    }
+
+    #[test]
+    fn pcapng() {
+        let capture = Capture::new(Vec::new()).snaplen(64).name("tun0");
+        capture.record(Direction::Read, &[9, 9, 9]).unwrap();
+        capture.record(Direction::Write, &[8; 4]).unwrap();
+        let buf = sink(&capture);
+        #[rustfmt::skip]
+        assert_eq!(
+            buf[..28],
+            [
+                0x0a, 0x0d, 0x0d, 0x0a, 28, 0, 0, 0,
+                0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0,
+                0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                28, 0, 0, 0,
+            ]
+        );
+        #[rustfmt::skip]
+        assert_eq!(
+            buf[28..60],
+            [
+                1, 0, 0, 0, 32, 0, 0, 0,
+                101, 0, 0, 0, 64, 0, 0, 0,
+                2, 0, 4, 0, b't', b'u', b'n', b'0',
+                0, 0, 0, 0,
+                32, 0, 0, 0,
+            ]
+        );
+        // Packets are padded to 32 bits and annotated with their direction.
+        #[rustfmt::skip]
+        assert_eq!(
+            without_timestamp(&buf[60..108], 12),
+            [
+                6, 0, 0, 0, 48, 0, 0, 0, 0, 0, 0, 0,
+                3, 0, 0, 0, 3, 0, 0, 0, 9, 9, 9, 0,
+                2, 0, 4, 0, 2, 0, 0, 0,
+                0, 0, 0, 0,
+                48, 0, 0, 0,
+            ]
+        );
+        assert_eq!(buf[108..112], [6, 0, 0, 0]);
+        assert_eq!(buf[144..148], PCAPNG_EPB_INBOUND.to_le_bytes());
+        assert_eq!(buf.len(), 156);
+    }
+
+    #[test]
+    fn rotate() {
+        let sinks = Arc::new(Mutex::new(Vec::new()));
+        let opened = sinks.clone();
+        let capture = Capture::new(Vec::new())
+            .format(Format::Pcap)
+            .rotate(30, move |index| {
+                opened.lock().unwrap().push(index);
+                Ok(Vec::new())
+            });
+        capture.record(Direction::Read, &[1; 4]).unwrap();
+        capture.record(Direction::Read, &[1; 4]).unwrap();
+        assert_eq!(*sinks.lock().unwrap(), [1]);
+        // Every sink starts with a file header.
+        let buf = sink(&capture);
+        assert_eq!(buf.len(), 24 + 16 + 4);
+        assert_eq!(buf[..4], PCAP_MAGIC.to_le_bytes());
+    }
+
+    struct Broken;
+
+    impl io::Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::StorageFull.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn errors() {
+        task::block_on(async {
+            let capture = Arc::new(Capture::new(Broken));
+            assert!(capture.record(Direction::Read, &[1]).is_err());
+            assert_eq!(capture.errors(), 0);
+
+            // Packets are exchanged although they cannot be recorded.
+            let tun = MockTun::new("tun0");
+            let kernel = tun.kernel();
+            let device = capture.device(tun);
+            kernel.inject(&[1, 2, 3]).await;
+            let mut buf = [0; 16];
+            assert_eq!(device.recv(&mut buf).await.unwrap(), 3);
+            assert_eq!(device.send(&[4, 5]).await.unwrap(), 2);
+            assert_eq!(kernel.recv().await.unwrap(), [4, 5]);
+
+            let (reader, writer) = async_std::os::unix::net::UnixStream::pair().unwrap();
+            let mut writer = capture.writer(writer);
+            let mut reader = capture.reader(reader);
+            writer.write_all(&[6]).await.unwrap();
+            assert_eq!(reader.read(&mut buf).await.unwrap(), 1);
+            assert_eq!(capture.errors(), 4);
+        });
+    }
+}
@@ -0,0 +1,326 @@
+use crate::builder::TunBuilder;
+use crate::net::{Ipv4Cidr, Ipv6Cidr, Route};
+use crate::owner::{Group, Owner};
+use crate::result::Result;
+use crate::tun::Tun;
+use mac_address::MacAddress;
+use std::fmt;
+use std::net::Ipv4Addr;
+
+/// Represents an invalid option of a [`TunBuilder`](struct.TunBuilder.html) or an invalid field
+/// of a [`TunConfig`](struct.TunConfig.html).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    field: String,
+    message: String,
+}
+
+impl ConfigError {
+    pub(crate) fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Returns the path of the offending field, e.g. `routes[1].gateway`.
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+
+    /// Returns the reason why the value is invalid.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid `{}`: {}", self.field, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Represents the configuration of a Tun/Tap device, e.g. as loaded from a configuration file
+/// with the `serde` feature.
+///
+/// Addresses are given in CIDR notation: `address = "10.0.0.1/24"` sets both the address and
+/// the netmask of device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct TunConfig {
+    /// The name of device; if it is empty, then device name is set by kernel.
+    pub name: String,
+    /// Allocates a TAP device instead of a TUN device.
+    pub tap: bool,
+    /// Prefixes packets with packet information. Default value is `true`.
+    pub packet_info: bool,
+    /// Sets `IFF_NAPI`.
+    pub napi: bool,
+    /// Sets `IFF_NAPI_FRAGS` (for tap mode, requires `napi`).
+    pub napi_frags: bool,
+    /// Sets `IFF_NO_CARRIER`.
+    pub no_carrier: bool,
+    /// Prefixes packets with a `virtio_net_hdr`.
+    pub vnet_hdr: bool,
+    /// Fails if the device already exists.
+    pub exclusive: bool,
+    /// Makes the device persistent.
+    pub persist: bool,
+    /// Sets up the device.
+    pub up: bool,
+    /// The MTU of device.
+    pub mtu: Option<i32>,
+    /// The length of the transmit queue of device.
+    pub txqueuelen: Option<u32>,
+    /// The link type of device (`ARPHRD_*`).
+    pub link_type: Option<u16>,
+    /// The size of the send buffer of device in bytes.
+    pub sndbuf: Option<u32>,
+    /// Makes sends stay pending while the send buffer is full, see [`TunBuilder::backpressure`](struct.TunBuilder.html#method.backpressure).
+    pub backpressure: bool,
+    /// The owner of device, as a numeric id or a user name.
+    pub owner: Option<Owner>,
+    /// The group of device, as a numeric id or a group name.
+    pub group: Option<Group>,
+    /// The IPv4 address and netmask of device.
+    pub address: Option<Ipv4Cidr>,
+    /// The IPv4 destination address of device.
+    pub destination: Option<Ipv4Addr>,
+    /// The IPv4 broadcast address of device.
+    pub broadcast: Option<Ipv4Addr>,
+    /// The Ethernet MAC address of device (for tap mode).
+    pub mac: Option<MacAddress>,
+    /// The IPv6 addresses of device.
+    pub ipv6: Vec<Ipv6Cidr>,
+    /// The routes through device, added once the device is up.
+    pub routes: Vec<Route>,
+    /// The bridge, bond or VRF to enslave device to.
+    pub master: Option<String>,
+    /// The number of queues; more than one sets `IFF_MULTI_QUEUE`. Default value is `1`.
+    pub queues: usize,
+}
+
+impl Default for TunConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            tap: false,
+            packet_info: true,
+            napi: false,
+            napi_frags: false,
+            no_carrier: false,
+            vnet_hdr: false,
+            exclusive: false,
+            persist: false,
+            up: false,
+            mtu: None,
+            txqueuelen: None,
+            link_type: None,
+            sndbuf: None,
+            backpressure: false,
+            owner: None,
+            group: None,
+            address: None,
+            destination: None,
+            broadcast: None,
+            mac: None,
+            ipv6: Vec::new(),
+            routes: Vec::new(),
+            master: None,
+            queues: 1,
+        }
+    }
+}
+
+impl TunConfig {
+    /// Checks the configuration and returns the first invalid field.
+    pub fn validate(&self) -> std::result::Result<(), ConfigError> {
+        if self.queues == 0 {
+            return Err(ConfigError::new("queues", "must be at least 1"));
+        }
+        self.to_builder().validate()
+    }
+
+    /// Returns a [`TunBuilder`](struct.TunBuilder.html) configured with this configuration.
+    pub fn builder(&self) -> std::result::Result<TunBuilder, ConfigError> {
+        self.validate()?;
+        Ok(self.to_builder())
+    }
+
+    fn to_builder(&self) -> TunBuilder {
+        let mut builder = TunBuilder::new()
+            .name(self.name.clone())
+            .tap(self.tap)
+            .packet_info(self.packet_info)
+            .napi(self.napi)
+            .napi_frags(self.napi_frags)
+            .no_carrier(self.no_carrier)
+            .vnet_hdr(self.vnet_hdr)
+            .exclusive(self.exclusive)
+            .backpressure(self.backpressure);
+        if self.persist {
+            builder = builder.persist();
+        }
+        if self.up {
+            builder = builder.up();
+        }
+        if let Some(mtu) = self.mtu {
+            builder = builder.mtu(mtu);
+        }
+        if let Some(txqueuelen) = self.txqueuelen {
+            builder = builder.txqueuelen(txqueuelen);
+        }
+        if let Some(link_type) = self.link_type {
+            builder = builder.link_type(link_type);
+        }
+        if let Some(sndbuf) = self.sndbuf {
+            builder = builder.sndbuf(sndbuf);
+        }
+        if let Some(owner) = &self.owner {
+            builder = builder.owner(owner.clone());
+        }
+        if let Some(group) = &self.group {
+            builder = builder.group(group.clone());
+        }
+        if let Some(address) = self.address {
+            builder = builder.address(address.addr()).netmask(address.netmask());
+        }
+        if let Some(destination) = self.destination {
+            builder = builder.destination(destination);
+        }
+        if let Some(broadcast) = self.broadcast {
+            builder = builder.broadcast(broadcast);
+        }
+        if let Some(mac) = self.mac {
+            builder = builder.mac(mac);
+        }
+        for address in self.ipv6.iter() {
+            builder = builder.ipv6(address.addr(), address.prefix());
+        }
+        for route in self.routes.iter() {
+            builder = builder.route(route.clone());
+        }
+        if let Some(master) = &self.master {
+            builder = builder.master(master.clone());
+        }
+        builder
+    }
+
+    /// Builds the instances of [`Tun`](struct.Tun.html), one per queue.
+    pub async fn build(&self) -> Result<Vec<Tun>> {
+        let builder = self.builder()?;
+        if self.queues == 1 {
+            return Ok(vec![builder.try_build().await?]);
+        }
+        #[cfg(target_os = "linux")]
+        return builder.try_build_mq(self.queues).await;
+        #[cfg(not(target_os = "linux"))]
+        unimplemented!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::IpCidr;
+
+    fn field(config: &TunConfig) -> String {
+        config.validate().unwrap_err().field().to_string()
+    }
+
+    #[test]
+    fn cidr() {
+        let cidr: Ipv4Cidr = "10.0.0.7/24".parse().unwrap();
+        assert_eq!(cidr.addr(), Ipv4Addr::new(10, 0, 0, 7));
+        assert_eq!(cidr.netmask(), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(cidr.network(), Ipv4Addr::new(10, 0, 0, 0));
+        assert_eq!("10.0.0.7".parse::<Ipv4Cidr>().unwrap().prefix(), 32);
+        assert!("10.0.0.7/33".parse::<Ipv4Cidr>().is_err());
+        assert!("10.0.0/24".parse::<Ipv4Cidr>().is_err());
+        let cidr: IpCidr = "fd00::1/64".parse().unwrap();
+        assert!(cidr.contains("fd00::ff".parse().unwrap()));
+        assert!(!cidr.contains("fd01::1".parse().unwrap()));
+        assert_eq!(cidr.to_string(), "fd00::1/64");
+    }
+
+    #[test]
+    fn validate() {
+        assert!(TunConfig::default().validate().is_ok());
+        let config = TunConfig {
+            name: "a-very-long-name0".into(),
+            ..Default::default()
+        };
+        assert_eq!(field(&config), "name");
+        let config = TunConfig {
+            queues: 0,
+            ..Default::default()
+        };
+        assert_eq!(field(&config), "queues");
+        let config = TunConfig {
+            mtu: Some(1000),
+            ipv6: vec!["fd00::1/64".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(field(&config), "mtu");
+        let config = TunConfig {
+            mac: Some(MacAddress::new([2, 0, 0, 0, 0, 1])),
+            ..Default::default()
+        };
+        assert_eq!(field(&config), "mac");
+        let config = TunConfig {
+            up: true,
+            routes: vec![
+                Route::new("10.1.0.0/16".parse::<Ipv4Cidr>().unwrap()),
+                Route::new("10.2.0.0/16".parse::<Ipv4Cidr>().unwrap())
+                    .gateway("fd00::1".parse().unwrap()),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(field(&config), "routes[1].gateway");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn deserialize() {
+        let config: TunConfig = toml::from_str(
+            r#"
+            name = "tun7"
+            up = true
+            packet_info = false
+            owner = "vpnsvc"
+            group = 100
+            address = "10.0.0.1/24"
+            ipv6 = ["fd00::1/64"]
+            queues = 2
+
+            [[routes]]
+            destination = "0.0.0.0/0"
+            gateway = "10.0.0.254"
+            metric = 10
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.name, "tun7");
+        assert!(!config.packet_info);
+        assert_eq!(config.owner, Some(Owner::Name("vpnsvc".into())));
+        assert_eq!(config.group, Some(Group::from(100)));
+        assert_eq!(
+            config.address.unwrap().netmask(),
+            Ipv4Addr::new(255, 255, 255, 0)
+        );
+        assert_eq!(config.routes[0].metric, 10);
+        assert_eq!(config.queues, 2);
+        assert!(config.validate().is_ok());
+        let roundtrip: TunConfig = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+        assert_eq!(roundtrip, config);
+
+        assert!(toml::from_str::<TunConfig>("address = \"10.0.0.1/40\"").is_err());
+        assert!(toml::from_str::<TunConfig>("adress = \"10.0.0.1/24\"").is_err());
+    }
+}
@@ -0,0 +1,256 @@
+use crate::packet::PacketInfo;
+use crate::result::Result;
+use async_std::stream::Stream;
+use async_std::sync::Arc;
+use std::cell::Cell;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+/// Length of the Ethernet header (including one VLAN tag) on top of the MTU of TAP devices.
+const ETHERNET_HEADER_LEN: usize = 18;
+
+/// Represents the kind of a tun-like device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceKind {
+    /// The device exchanges IP packets.
+    Tun,
+    /// The device exchanges Ethernet frames.
+    Tap,
+}
+
+/// Represents a device which exchanges one packet per receive or send, such as
+/// [`Tun`](struct.Tun.html), so that adapters work with real, mock and userspace-only devices alike.
+pub trait TunDevice {
+    /// Returns the name of device.
+    fn name(&self) -> &str;
+
+    /// Returns the value of MTU.
+    fn mtu(&self) -> Result<i32>;
+
+    /// Returns the kind of device.
+    fn kind(&self) -> DeviceKind;
+
+    /// Returns `true` if packets are prefixed with packet information.
+    fn packet_info(&self) -> bool;
+
+    /// Attempts to receive one packet into `buf`, returning its length.
+    fn poll_recv(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>>;
+
+    /// Attempts to send `packet` as one packet, returning the number of bytes written.
+    fn poll_send(&self, cx: &mut Context<'_>, packet: &[u8]) -> Poll<io::Result<usize>>;
+
+    /// Returns the length of the header prefixing every packet.
+    fn header_len(&self) -> usize {
+        if self.packet_info() {
+            PacketInfo::<&[u8]>::LEN
+        } else {
+            0
+        }
+    }
+
+    /// Receives one packet into `buf`, returning its length.
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> RecvFuture<'a, Self> {
+        RecvFuture { device: self, buf }
+    }
+
+    /// Sends `packet` as one packet, returning the number of bytes written.
+    fn send<'a>(&'a self, packet: &'a [u8]) -> SendFuture<'a, Self> {
+        SendFuture {
+            device: self,
+            packet,
+            sending: None,
+        }
+    }
+}
+
+/// Represents the future returned by [`TunDevice::recv`](trait.TunDevice.html#method.recv).
+pub struct RecvFuture<'a, D: ?Sized> {
+    device: &'a D,
+    buf: &'a mut [u8],
+}
+
+impl<D: TunDevice + ?Sized> Future for RecvFuture<'_, D> {
+    type Output = io::Result<usize>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        this.device.poll_recv(cx, this.buf)
+    }
+}
+
+/// Represents the future returned by [`TunDevice::send`](trait.TunDevice.html#method.send).
+pub struct SendFuture<'a, D: ?Sized> {
+    device: &'a D,
+    packet: &'a [u8],
+    sending: Option<Sending>,
+}
+
+impl<D: TunDevice + ?Sized> Future for SendFuture<'_, D> {
+    type Output = io::Result<usize>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let sending = *this.sending.get_or_insert_with(Sending::new);
+        let _current = Current(SENDING.replace(Some(sending)));
+        this.device.poll_send(cx, this.packet)
+    }
+}
+
+/// Identifies one send made through [`TunDevice::send`](trait.TunDevice.html#method.send), so
+/// that wrappers can tell the polls of a pending send from those of another send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Sending {
+    id: u64,
+    /// The instant of the first poll of the send.
+    pub(crate) started: Instant,
+}
+
+impl Sending {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            started: Instant::now(),
+        }
+    }
+
+    /// Returns the send being polled on this thread, or `None` if `poll_send` is called
+    /// directly.
+    pub(crate) fn current() -> Option<Self> {
+        SENDING.get()
+    }
+}
+
+thread_local! {
+    static SENDING: Cell<Option<Sending>> = const { Cell::new(None) };
+}
+
+/// Restores the send being polled before, even if `poll_send` panics.
+struct Current(Option<Sending>);
+
+impl Drop for Current {
+    fn drop(&mut self) {
+        SENDING.set(self.0);
+    }
+}
+
+macro_rules! forward_tun_device {
+    ($($ty:ty),*) => {
+        $(
+            impl<D: TunDevice + ?Sized> TunDevice for $ty {
+                fn name(&self) -> &str {
+                    (**self).name()
+                }
+
+                fn mtu(&self) -> Result<i32> {
+                    (**self).mtu()
+                }
+
+                fn kind(&self) -> DeviceKind {
+                    (**self).kind()
+                }
+
+                fn packet_info(&self) -> bool {
+                    (**self).packet_info()
+                }
+
+                fn header_len(&self) -> usize {
+                    (**self).header_len()
+                }
+
+                fn poll_recv(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+                    (**self).poll_recv(cx, buf)
+                }
+
+                fn poll_send(&self, cx: &mut Context<'_>, packet: &[u8]) -> Poll<io::Result<usize>> {
+                    (**self).poll_send(cx, packet)
+                }
+            }
+        )*
+    };
+}
+
+forward_tun_device!(&D, Box<D>, Arc<D>);
+
+/// Returns an error if `device` is not a TUN device without vnet header, i.e. if its packets
+/// are not IP packets, possibly prefixed with packet information.
+pub(crate) fn check_tun<D: TunDevice + ?Sized>(device: &D) -> Result<()> {
+    let info_len = if device.packet_info() {
+        PacketInfo::<&[u8]>::LEN
+    } else {
+        0
+    };
+    if device.kind() != DeviceKind::Tun || device.header_len() != info_len {
+        return Err(format!("{} is not a TUN device without vnet header", device.name()).into());
+    }
+    Ok(())
+}
+
+/// Represents a stream of the packets received from a [`TunDevice`](trait.TunDevice.html),
+/// each one in its own buffer.
+pub struct Framed<D> {
+    device: D,
+    buf: Vec<u8>,
+}
+
+impl<D: TunDevice> Framed<D> {
+    /// Creates a new instance of [`Framed`](struct.Framed.html) receiving packets of up to the MTU of `device`.
+    pub fn new(device: D) -> Result<Self> {
+        let len = device.mtu()? as usize + device.header_len() + ETHERNET_HEADER_LEN;
+        Ok(Self::with_capacity(device, len))
+    }
+
+    /// Creates a new instance of [`Framed`](struct.Framed.html) receiving packets of up to `len` bytes.
+    pub fn with_capacity(device: D, len: usize) -> Self {
+        Self {
+            device,
+            buf: vec![0u8; len],
+        }
+    }
+
+    /// Returns the underlying device.
+    pub fn get_ref(&self) -> &D {
+        &self.device
+    }
+
+    /// Returns the underlying device.
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Sends `packet` to the underlying device.
+    pub async fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.device.send(packet).await
+    }
+}
+
+impl<D: TunDevice + Unpin> Stream for Framed<D> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        match this.device.poll_recv(cx, &mut this.buf) {
+            Poll::Ready(Ok(0)) => Poll::Ready(None),
+            Poll::Ready(Ok(n)) => Poll::Ready(Some(Ok(this.buf[..n].to_vec()))),
+            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTun;
+
+    #[test]
+    fn check() {
+        assert!(check_tun(&MockTun::new("tun0")).is_ok());
+        assert!(check_tun(&MockTun::new("tun0").with_packet_info(false)).is_ok());
+        assert!(check_tun(&MockTun::new("tap0").with_tap(true)).is_err());
+    }
+}
@@ -0,0 +1,1067 @@
+//! A stateful packet filter enforced on the reads and writes of a TUN device, without netfilter.
+//!
+//! A [`RuleSet`](struct.RuleSet.html) is an ordered list of [`Rule`](struct.Rule.html)s, each
+//! matching packets on their direction, protocol, addresses, ports, ICMP type, TCP flags and
+//! connection state, plus a default policy per direction. The first matching rule decides on a
+//! packet. A [`Firewall`](struct.Firewall.html) tracks the connections of the accepted packets,
+//! counts the packets matched by every rule and can be reloaded with a new rule set while in
+//! use. [`Filtered`](struct.Filtered.html) enforces it on a device:
+//!
+//! ```no_run
+//! # async fn run() -> async_tun::result::Result<()> {
+//! use async_std::sync::Arc;
+//! use async_tun::firewall::{Action, Direction, Filtered, Firewall, Rule, RuleSet, State};
+//! use async_tun::packet::protocol;
+//! use async_tun::{TunBuilder, TunDevice};
+//!
+//! let rules = RuleSet::new()
+//!     .policy(Direction::Ingress, Action::Drop)
+//!     .rule(Rule::new(Action::Accept).state(State::Established).state(State::Related))
+//!     .rule(
+//!         Rule::new(Action::Accept)
+//!             .direction(Direction::Ingress)
+//!             .protocol(protocol::TCP)
+//!             .destination("10.0.0.2/32".parse()?)
+//!             .dst_ports(22..=22),
+//!     )
+//!     .rule(Rule::new(Action::Reject).direction(Direction::Ingress).protocol(protocol::TCP));
+//! let firewall = Arc::new(Firewall::new(rules));
+//! let tun = TunBuilder::new().packet_info(false).up().try_build().await?;
+//! let tun = Filtered::new(tun, firewall.clone())?;
+//! let mut buf = [0u8; 1500];
+//! loop {
+//!     let n = tun.recv(&mut buf).await?;
+//!     // Only accepted packets make it here.
+//! #   let _ = n;
+//! }
+//! # }
+//! ```
+
+use crate::device::{check_tun, DeviceKind, Sending, TunDevice};
+use crate::flow::{key, opens, Key, TcpState, Timeouts, Tuple};
+use crate::net::IpCidr;
+use crate::packet::{
+    checksum, ethertype, icmp, ipv4, ipv6, protocol, write_u16, IpPacket, PacketInfo, TcpFlags,
+    TcpPacket, UdpPacket,
+};
+use crate::result::Result;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+const HOP_LIMIT: u8 = 64;
+/// The interval at which expired connections are dropped.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// The largest ICMPv4 error, which every host accepts (RFC 1812).
+const MAX_ICMPV4_ERROR_LEN: usize = 576;
+/// The largest ICMPv6 error, the minimum MTU of IPv6 (RFC 4443).
+const MAX_ICMPV6_ERROR_LEN: usize = 1280;
+const ICMPV4_ADMIN_PROHIBITED: u8 = 13;
+const ICMPV6_ADMIN_PROHIBITED: u8 = 1;
+
+/// Represents the direction of a packet through a [`Filtered`](struct.Filtered.html) device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Packets received from the device.
+    Ingress,
+    /// Packets sent to the device.
+    Egress,
+}
+
+/// Represents what happens to a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    /// Passes the packet.
+    Accept,
+    /// Discards the packet silently.
+    Drop,
+    /// Discards the packet and answers it with a TCP reset, or an ICMP destination unreachable
+    /// message (administratively prohibited) for other protocols.
+    Reject,
+}
+
+/// Represents the state of the connection of a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum State {
+    /// The packet opens a connection, or is not part of a tracked one.
+    New,
+    /// The packet is part of a connection opened by an accepted packet.
+    Established,
+    /// The packet is an ICMP error about a tracked connection.
+    Related,
+}
+
+/// Represents a filter rule, which matches the packets meeting all of its conditions.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    action: Action,
+    direction: Option<Direction>,
+    protocol: Option<u8>,
+    source: Option<IpCidr>,
+    destination: Option<IpCidr>,
+    src_ports: Option<RangeInclusive<u16>>,
+    dst_ports: Option<RangeInclusive<u16>>,
+    icmp_type: Option<u8>,
+    tcp_flags: Option<(TcpFlags, TcpFlags)>,
+    states: Vec<State>,
+}
+
+impl Rule {
+    /// Creates a new instance of [`Rule`](struct.Rule.html) applying `action` to every packet.
+    pub fn new(action: Action) -> Self {
+        Self {
+            action,
+            direction: None,
+            protocol: None,
+            source: None,
+            destination: None,
+            src_ports: None,
+            dst_ports: None,
+            icmp_type: None,
+            tcp_flags: None,
+            states: Vec::new(),
+        }
+    }
+
+    /// Matches packets travelling in `direction`.
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.direction = Some(direction);
+        self
+    }
+
+    /// Matches packets of the transport `protocol`, e.g. `packet::protocol::TCP`.
+    pub fn protocol(mut self, protocol: u8) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    /// Matches packets sent from an address of `source`.
+    pub fn source(mut self, source: IpCidr) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Matches packets sent to an address of `destination`.
+    pub fn destination(mut self, destination: IpCidr) -> Self {
+        self.destination = Some(destination);
+        self
+    }
+
+    /// Matches TCP and UDP packets sent from a port of `ports`.
+    pub fn src_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.src_ports = Some(ports);
+        self
+    }
+
+    /// Matches TCP and UDP packets sent to a port of `ports`.
+    pub fn dst_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.dst_ports = Some(ports);
+        self
+    }
+
+    /// Matches ICMP or ICMPv6 messages of `msg_type`, e.g. `packet::icmp::v4::ECHO_REQUEST`.
+    pub fn icmp_type(mut self, msg_type: u8) -> Self {
+        self.icmp_type = Some(msg_type);
+        self
+    }
+
+    /// Matches TCP segments whose flags masked with `mask` equal `flags`, e.g. `SYN` out of
+    /// `SYN | ACK` for segments opening a connection.
+    pub fn tcp_flags(mut self, mask: TcpFlags, flags: TcpFlags) -> Self {
+        self.tcp_flags = Some((mask, flags));
+        self
+    }
+
+    /// Matches packets whose connection is in `state`. Several states may be given.
+    pub fn state(mut self, state: State) -> Self {
+        self.states.push(state);
+        self
+    }
+
+    fn matches(&self, direction: Direction, meta: &Meta, state: State) -> bool {
+        let ports = |ports: &Option<RangeInclusive<u16>>, port: fn(&(u16, u16)) -> u16| {
+            ports.as_ref().is_none_or(|ports| {
+                meta.ports
+                    .as_ref()
+                    .is_some_and(|p| ports.contains(&port(p)))
+            })
+        };
+        self.direction.is_none_or(|d| d == direction)
+            && self.protocol.is_none_or(|p| p == meta.protocol)
+            && self.source.is_none_or(|cidr| cidr.contains(meta.src))
+            && self.destination.is_none_or(|cidr| cidr.contains(meta.dst))
+            && ports(&self.src_ports, |p| p.0)
+            && ports(&self.dst_ports, |p| p.1)
+            && self.icmp_type.is_none_or(|t| meta.icmp_type == Some(t))
+            && self.tcp_flags.is_none_or(|(mask, flags)| {
+                meta.protocol == protocol::TCP && meta.flags.0 & mask.0 == flags.0
+            })
+            && (self.states.is_empty() || self.states.contains(&state))
+    }
+}
+
+/// Represents an ordered list of rules and the default policies applied to the packets no rule
+/// matches.
+#[derive(Debug, Clone)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+    ingress: Action,
+    egress: Action,
+}
+
+impl Default for RuleSet {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            ingress: Action::Accept,
+            egress: Action::Accept,
+        }
+    }
+}
+
+impl RuleSet {
+    /// Creates a new instance of [`RuleSet`](struct.RuleSet.html) without rules, accepting
+    /// every packet.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Appends `rule`, which is evaluated after the rules added before.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Sets the action for the packets travelling in `direction` no rule matches. Default
+    /// value is `Action::Accept`.
+    pub fn policy(mut self, direction: Direction, action: Action) -> Self {
+        match direction {
+            Direction::Ingress => self.ingress = action,
+            Direction::Egress => self.egress = action,
+        }
+        self
+    }
+}
+
+/// Represents the counters of a rule.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RuleStats {
+    /// Number of packets matched by the rule.
+    pub packets: u64,
+    /// Number of bytes of the packets matched by the rule.
+    pub bytes: u64,
+}
+
+/// Represents the decision of a [`Firewall`](struct.Firewall.html) on a packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// The packet passes.
+    Accept,
+    /// The packet is discarded.
+    Drop,
+    /// The packet is discarded and answered with the given IP packet, to be sent the opposite
+    /// way. Packets which must not be answered, e.g. ICMP errors, are dropped instead.
+    Reject(Vec<u8>),
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Compiled {
+    rules: RuleSet,
+    counters: Vec<Counters>,
+}
+
+/// Represents a tracked connection, keyed by the tuple of the packet which opened it.
+#[derive(Debug)]
+struct Conn {
+    state: TcpState,
+    last_seen: Instant,
+}
+
+#[derive(Debug)]
+struct Conns {
+    conns: HashMap<Tuple, Conn>,
+    last_sweep: Instant,
+}
+
+/// The fields of a packet rules match on.
+struct Meta {
+    protocol: u8,
+    src: IpAddr,
+    dst: IpAddr,
+    ports: Option<(u16, u16)>,
+    icmp_type: Option<u8>,
+    flags: TcpFlags,
+}
+
+/// Represents a stateful packet filter, which can be shared by several devices or queues.
+#[derive(Debug)]
+pub struct Firewall {
+    rules: RwLock<Arc<Compiled>>,
+    conns: Mutex<Conns>,
+    timeouts: Timeouts,
+}
+
+impl Firewall {
+    /// Creates a new instance of [`Firewall`](struct.Firewall.html) enforcing `rules`.
+    pub fn new(rules: RuleSet) -> Self {
+        Self {
+            rules: RwLock::new(Arc::new(compile(rules))),
+            conns: Mutex::new(Conns {
+                conns: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+            timeouts: Default::default(),
+        }
+    }
+
+    /// Sets the time after which an idle established TCP connection is forgotten. Default value
+    /// is 2 hours.
+    pub fn tcp_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.tcp = timeout;
+        self
+    }
+
+    /// Sets the time after which an idle TCP connection is forgotten while it is being opened
+    /// or closed. Default value is 2 minutes.
+    pub fn tcp_transitory_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.tcp_transitory = timeout;
+        self
+    }
+
+    /// Sets the time after which an idle UDP connection is forgotten. Default value is 60
+    /// seconds.
+    pub fn udp_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.udp = timeout;
+        self
+    }
+
+    /// Sets the time after which an idle ICMP echo connection is forgotten. Default value is
+    /// 30 seconds.
+    pub fn icmp_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.icmp = timeout;
+        self
+    }
+
+    /// Replaces the rules with `rules`, resetting the counters. Tracked connections are kept,
+    /// so established connections survive unless the new rules drop them.
+    pub fn reload(&self, rules: RuleSet) {
+        *self.rules.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(compile(rules));
+    }
+
+    /// Returns the counters of the rules, in the order of the rule set.
+    pub fn stats(&self) -> Vec<RuleStats> {
+        self.compiled()
+            .counters
+            .iter()
+            .map(|counters| RuleStats {
+                packets: counters.packets.load(Ordering::Relaxed),
+                bytes: counters.bytes.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Returns the number of tracked connections.
+    pub fn connections(&self) -> usize {
+        self.lock_conns().conns.len()
+    }
+
+    /// Decides on `packet` travelling in `direction`, an IP packet without packet information.
+    /// Packets which are not valid IP packets are dropped.
+    pub fn filter(&self, direction: Direction, packet: &[u8]) -> Verdict {
+        let Some(meta) = meta(packet) else {
+            return Verdict::Drop;
+        };
+        let key = key(packet);
+        let now = Instant::now();
+        let mut conns = self.lock_conns();
+        if now.duration_since(conns.last_sweep) >= SWEEP_INTERVAL {
+            let timeouts = self.timeouts;
+            conns.conns.retain(|tuple, conn| {
+                now.duration_since(conn.last_seen) <= timeouts.get(tuple.protocol, conn.state)
+            });
+            conns.last_sweep = now;
+        }
+        let (state, tracked) = match &key {
+            Some(Key::Query { tuple, .. }) => match self.lookup(&mut conns, *tuple, now) {
+                Some(opened) => (State::Established, Some(opened)),
+                None => (State::New, None),
+            },
+            Some(Key::Error { tuple, .. }) => match self.lookup(&mut conns, *tuple, now) {
+                Some(_) => (State::Related, None),
+                None => (State::New, None),
+            },
+            None => (State::New, None),
+        };
+
+        let compiled = self.compiled();
+        let action = match compiled
+            .rules
+            .rules
+            .iter()
+            .position(|rule| rule.matches(direction, &meta, state))
+        {
+            Some(index) => {
+                let counters = &compiled.counters[index];
+                counters.packets.fetch_add(1, Ordering::Relaxed);
+                counters
+                    .bytes
+                    .fetch_add(packet.len() as u64, Ordering::Relaxed);
+                compiled.rules.rules[index].action
+            }
+            None => match direction {
+                Direction::Ingress => compiled.rules.ingress,
+                Direction::Egress => compiled.rules.egress,
+            },
+        };
+        match action {
+            Action::Accept => {
+                if let Some(Key::Query { tuple, flags }) = key {
+                    match tracked {
+                        Some(opened) => {
+                            if let Some(conn) = conns.conns.get_mut(&opened) {
+                                conn.last_seen = now;
+                                if tuple.protocol == protocol::TCP {
+                                    conn.state = conn.state.next(flags, tuple != opened);
+                                }
+                            }
+                        }
+                        None if opens(tuple.protocol, flags) => {
+                            conns.conns.insert(
+                                tuple,
+                                Conn {
+                                    state: TcpState::Opening,
+                                    last_seen: now,
+                                },
+                            );
+                        }
+                        None => {}
+                    }
+                }
+                Verdict::Accept
+            }
+            Action::Drop => Verdict::Drop,
+            Action::Reject => match reject(&meta, packet) {
+                Some(reply) => Verdict::Reject(reply),
+                None => Verdict::Drop,
+            },
+        }
+    }
+
+    /// Returns the tuple of the packet which opened the connection of `tuple`, if it is
+    /// tracked, forgetting it if it expired.
+    fn lookup(&self, conns: &mut Conns, tuple: Tuple, now: Instant) -> Option<Tuple> {
+        let opened = [tuple, tuple.reverse()]
+            .into_iter()
+            .find(|tuple| conns.conns.contains_key(tuple))?;
+        let conn = &conns.conns[&opened];
+        if now.duration_since(conn.last_seen) > self.timeouts.get(opened.protocol, conn.state) {
+            conns.conns.remove(&opened);
+            return None;
+        }
+        Some(opened)
+    }
+
+    fn compiled(&self) -> Arc<Compiled> {
+        self.rules.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn lock_conns(&self) -> std::sync::MutexGuard<'_, Conns> {
+        self.conns.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn compile(rules: RuleSet) -> Compiled {
+    let counters = rules.rules.iter().map(|_| Default::default()).collect();
+    Compiled { rules, counters }
+}
+
+fn meta(packet: &[u8]) -> Option<Meta> {
+    let ip = IpPacket::new_checked(packet).ok()?;
+    let protocol = ip.protocol().ok()?;
+    let segment = ip.transport().ok()?;
+    let mut meta = Meta {
+        protocol,
+        src: ip.src_addr(),
+        dst: ip.dst_addr(),
+        ports: None,
+        icmp_type: None,
+        flags: TcpFlags(0),
+    };
+    match protocol {
+        protocol::TCP => {
+            if let Ok(segment) = TcpPacket::new_checked(segment) {
+                meta.ports = Some((segment.src_port(), segment.dst_port()));
+                meta.flags = segment.flags();
+            }
+        }
+        protocol::UDP => {
+            if let Ok(datagram) = UdpPacket::new_checked(segment) {
+                meta.ports = Some((datagram.src_port(), datagram.dst_port()));
+            }
+        }
+        protocol::ICMP | protocol::ICMPV6 => meta.icmp_type = segment.first().copied(),
+        _ => {}
+    }
+    Some(meta)
+}
+
+/// Returns the answer to a rejected `packet`, or `None` if it must not be answered.
+fn reject(meta: &Meta, packet: &[u8]) -> Option<Vec<u8>> {
+    let ip = IpPacket::new_checked(packet).ok()?;
+    let broadcast = meta.dst == IpAddr::V4(Ipv4Addr::BROADCAST);
+    if meta.dst.is_multicast() || broadcast || meta.src.is_unspecified() {
+        return None;
+    }
+    match (meta.protocol, meta.ports) {
+        (protocol::TCP, Some(_)) => {
+            let segment = TcpPacket::new_checked(ip.transport().ok()?).ok()?;
+            reset(meta, &segment)
+        }
+        // Only queries are answered with errors, never other ICMP errors.
+        (protocol::ICMP, _) if meta.icmp_type != Some(icmp::v4::ECHO_REQUEST) => None,
+        (protocol::ICMPV6, _) if meta.icmp_type != Some(icmp::v6::ECHO_REQUEST) => None,
+        // Later fragments and segments without a header are not answered.
+        (protocol::TCP | protocol::UDP, None) => None,
+        _ => unreachable_message(meta, &packet[..ip.total_len()]),
+    }
+}
+
+fn reset(meta: &Meta, segment: &TcpPacket<&[u8]>) -> Option<Vec<u8>> {
+    let flags = segment.flags();
+    if flags.contains(TcpFlags::RST) {
+        return None;
+    }
+    let (src_port, dst_port) = meta.ports?;
+    let mut reply = [0u8; 20];
+    let mut reset = TcpPacket::new_unchecked(&mut reply[..]);
+    reset.set_header_len(20);
+    reset.set_src_port(dst_port);
+    reset.set_dst_port(src_port);
+    if flags.contains(TcpFlags::ACK) {
+        reset.set_seq_number(segment.ack_number());
+        reset.set_flags(TcpFlags::RST);
+    } else {
+        let len = segment.payload().len() as u32
+            + flags.contains(TcpFlags::SYN) as u32
+            + flags.contains(TcpFlags::FIN) as u32;
+        reset.set_ack_number(segment.seq_number().wrapping_add(len));
+        reset.set_flags(TcpFlags::RST | TcpFlags::ACK);
+    }
+    reset.fill_checksum(meta.dst, meta.src);
+    Some(ip_packet(meta.dst, meta.src, protocol::TCP, &reply))
+}
+
+/// Returns an ICMP destination unreachable message about `packet`, quoting as much of it as fits.
+fn unreachable_message(meta: &Meta, packet: &[u8]) -> Option<Vec<u8>> {
+    let (protocol, msg_type, code, max_len, header_len) = match meta.src {
+        IpAddr::V4(_) => (
+            protocol::ICMP,
+            icmp::v4::DESTINATION_UNREACHABLE,
+            ICMPV4_ADMIN_PROHIBITED,
+            MAX_ICMPV4_ERROR_LEN,
+            ipv4::HEADER_LEN,
+        ),
+        IpAddr::V6(_) => (
+            protocol::ICMPV6,
+            icmp::v6::DESTINATION_UNREACHABLE,
+            ICMPV6_ADMIN_PROHIBITED,
+            MAX_ICMPV6_ERROR_LEN,
+            ipv6::HEADER_LEN,
+        ),
+    };
+    let quoted = &packet[..packet.len().min(max_len - header_len - icmp::HEADER_LEN)];
+    let mut message = vec![0u8; icmp::HEADER_LEN + quoted.len()];
+    message[0] = msg_type;
+    message[1] = code;
+    message[icmp::HEADER_LEN..].copy_from_slice(quoted);
+    let checksum = match protocol {
+        protocol::ICMP => checksum::checksum(&message),
+        _ => checksum::transport(meta.dst, meta.src, protocol, &message),
+    };
+    write_u16(&mut message, 2, checksum);
+    Some(ip_packet(meta.dst, meta.src, protocol, &message))
+}
+
+/// Builds an IP packet carrying `segment`, whose checksum is expected to be filled.
+fn ip_packet(src: IpAddr, dst: IpAddr, protocol: u8, segment: &[u8]) -> Vec<u8> {
+    let header_len = match src {
+        IpAddr::V4(_) => ipv4::HEADER_LEN,
+        IpAddr::V6(_) => ipv6::HEADER_LEN,
+    };
+    let mut buf = vec![0u8; header_len + segment.len()];
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            buf[0] = 0x45;
+            write_u16(&mut buf, 2, (header_len + segment.len()) as u16);
+            buf[8] = HOP_LIMIT;
+            buf[9] = protocol;
+            buf[12..16].copy_from_slice(&src.octets());
+            buf[16..20].copy_from_slice(&dst.octets());
+            crate::packet::Ipv4Packet::new_unchecked(&mut buf[..]).fill_checksum();
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            buf[0] = 0x60;
+            write_u16(&mut buf, 4, segment.len() as u16);
+            buf[6] = protocol;
+            buf[7] = HOP_LIMIT;
+            buf[8..24].copy_from_slice(&src.octets());
+            buf[24..40].copy_from_slice(&dst.octets());
+        }
+        _ => unreachable!("the addresses of a packet share their family"),
+    }
+    buf[header_len..].copy_from_slice(segment);
+    buf
+}
+
+/// Represents a [`TunDevice`](../trait.TunDevice.html) whose received and sent packets are
+/// filtered by a [`Firewall`](struct.Firewall.html).
+///
+/// The device must be a TUN device without vnet header; packet information is handled if
+/// present. Answers to rejected packets received from the device are sent back to it, on a best
+/// effort basis, while answers to rejected packets sent to the device are received from it like
+/// any other packet. Dropped and rejected sends complete as if the packet was sent. A send made
+/// through `send` is filtered once, even if it is polled again after `Poll::Pending`, while a
+/// direct call to `poll_send` filters the packet on every call.
+pub struct Filtered<D> {
+    device: D,
+    firewall: Arc<Firewall>,
+    replies: Mutex<VecDeque<Vec<u8>>>,
+    waker: Mutex<Option<Waker>>,
+    /// The accepted send which returned `Poll::Pending`.
+    pending: Mutex<Option<Sending>>,
+}
+
+impl<D: TunDevice> Filtered<D> {
+    /// Creates a new instance of [`Filtered`](struct.Filtered.html) enforcing `firewall` on
+    /// `device`.
+    pub fn new(device: D, firewall: Arc<Firewall>) -> Result<Self> {
+        check_tun(&device)?;
+        Ok(Self {
+            device,
+            firewall,
+            replies: Default::default(),
+            waker: Default::default(),
+            pending: Default::default(),
+        })
+    }
+
+    /// Returns the firewall, e.g. to reload its rules.
+    pub fn firewall(&self) -> &Arc<Firewall> {
+        &self.firewall
+    }
+
+    /// Returns the underlying device.
+    pub fn get_ref(&self) -> &D {
+        &self.device
+    }
+
+    /// Returns the underlying device.
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    /// Prepends packet information to `packet` if device expects it.
+    fn frame(&self, packet: Vec<u8>) -> Vec<u8> {
+        if !self.device.packet_info() {
+            return packet;
+        }
+        let mut frame = vec![0u8; PacketInfo::<&[u8]>::LEN];
+        let protocol = match packet.first().map(|b| b >> 4) {
+            Some(6) => ethertype::IPV6,
+            _ => ethertype::IPV4,
+        };
+        PacketInfo::new_unchecked(&mut frame[..]).set_protocol(protocol);
+        frame.extend_from_slice(&packet);
+        frame
+    }
+}
+
+impl<D: TunDevice> TunDevice for Filtered<D> {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn mtu(&self) -> Result<i32> {
+        self.device.mtu()
+    }
+
+    fn kind(&self) -> DeviceKind {
+        self.device.kind()
+    }
+
+    fn packet_info(&self) -> bool {
+        self.device.packet_info()
+    }
+
+    fn header_len(&self) -> usize {
+        self.device.header_len()
+    }
+
+    fn poll_recv(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        // The waker is stored first, so an answer queued by a concurrent send is not missed.
+        {
+            let mut waker = self.waker.lock().unwrap_or_else(|e| e.into_inner());
+            if !waker
+                .as_ref()
+                .is_some_and(|waker| waker.will_wake(cx.waker()))
+            {
+                *waker = Some(cx.waker().clone());
+            }
+        }
+        let reply = self
+            .replies
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop_front();
+        if let Some(reply) = reply {
+            let len = reply.len().min(buf.len());
+            buf[..len].copy_from_slice(&reply[..len]);
+            return Poll::Ready(Ok(len));
+        }
+        loop {
+            let n = match self.device.poll_recv(cx, buf) {
+                Poll::Ready(Ok(n)) => n,
+                result => return result,
+            };
+            let Some(packet) = buf.get(self.header_len()..n) else {
+                return Poll::Ready(Ok(n));
+            };
+            match self.firewall.filter(Direction::Ingress, packet) {
+                Verdict::Accept => return Poll::Ready(Ok(n)),
+                Verdict::Drop => {}
+                Verdict::Reject(reply) => {
+                    let _ = self.device.poll_send(cx, &self.frame(reply));
+                }
+            }
+        }
+    }
+
+    fn poll_send(&self, cx: &mut Context<'_>, packet: &[u8]) -> Poll<io::Result<usize>> {
+        let Some(payload) = packet.get(self.header_len()..) else {
+            return self.device.poll_send(cx, packet);
+        };
+        let sending = Sending::current();
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        let verdict = if sending.is_some() && *pending == sending {
+            Verdict::Accept
+        } else {
+            self.firewall.filter(Direction::Egress, payload)
+        };
+        match verdict {
+            Verdict::Accept => {
+                let result = self.device.poll_send(cx, packet);
+                *pending = if result.is_pending() { sending } else { None };
+                result
+            }
+            Verdict::Drop => Poll::Ready(Ok(packet.len())),
+            Verdict::Reject(reply) => {
+                let reply = self.frame(reply);
+                self.replies
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push_back(reply);
+                if let Some(waker) = self.waker.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                    waker.wake();
+                }
+                Poll::Ready(Ok(packet.len()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::tests::{addr, echo, endpoints, error, tcp, udp};
+    use crate::mock::MockTun;
+    use async_std::task;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::AtomicBool;
+
+    fn ingress_drop() -> RuleSet {
+        RuleSet::new()
+            .policy(Direction::Ingress, Action::Drop)
+            .rule(
+                Rule::new(Action::Accept)
+                    .state(State::Established)
+                    .state(State::Related),
+            )
+    }
+
+    #[test]
+    fn rules() {
+        let rules = RuleSet::new()
+            .policy(Direction::Ingress, Action::Drop)
+            .rule(
+                Rule::new(Action::Accept)
+                    .direction(Direction::Ingress)
+                    .protocol(protocol::TCP)
+                    .source("10.0.0.0/8".parse().unwrap())
+                    .dst_ports(22..=22),
+            )
+            .rule(
+                Rule::new(Action::Accept)
+                    .icmp_type(icmp::v4::ECHO_REQUEST)
+                    .destination("192.0.2.1/32".parse().unwrap()),
+            )
+            .rule(
+                Rule::new(Action::Drop)
+                    .protocol(protocol::TCP)
+                    .tcp_flags(TcpFlags::SYN | TcpFlags::ACK, TcpFlags::SYN),
+            );
+        let firewall = Firewall::new(rules);
+        let ssh = tcp("10.1.2.3:40000", "192.0.2.1:22", TcpFlags::SYN);
+        assert_eq!(firewall.filter(Direction::Ingress, &ssh), Verdict::Accept);
+        let other = tcp("172.16.0.1:40000", "192.0.2.1:22", TcpFlags::SYN);
+        assert_eq!(firewall.filter(Direction::Ingress, &other), Verdict::Drop);
+        let ping = echo(icmp::v4::ECHO_REQUEST, "172.16.0.1:1", "192.0.2.1:1");
+        assert_eq!(firewall.filter(Direction::Ingress, &ping), Verdict::Accept);
+        let syn = tcp("192.0.2.1:40000", "198.51.100.1:80", TcpFlags::SYN);
+        assert_eq!(firewall.filter(Direction::Egress, &syn), Verdict::Drop);
+        let ack = tcp("192.0.2.1:40000", "198.51.100.1:80", TcpFlags::ACK);
+        assert_eq!(firewall.filter(Direction::Egress, &ack), Verdict::Accept);
+        assert_eq!(
+            firewall.filter(Direction::Egress, &[0x45, 0]),
+            Verdict::Drop
+        );
+
+        let stats = firewall.stats();
+        assert_eq!(stats[0].packets, 1);
+        assert_eq!(stats[0].bytes, ssh.len() as u64);
+        assert_eq!(stats[1].packets, 1);
+        assert_eq!(stats[2].packets, 2);
+    }
+
+    #[test]
+    fn states() {
+        let firewall = Firewall::new(ingress_drop());
+        let query = udp("10.0.0.2:5000", "192.0.2.1:53");
+        assert_eq!(firewall.filter(Direction::Egress, &query), Verdict::Accept);
+        let reply = udp("192.0.2.1:53", "10.0.0.2:5000");
+        assert_eq!(firewall.filter(Direction::Ingress, &reply), Verdict::Accept);
+        let other = udp("192.0.2.1:53", "10.0.0.2:5001");
+        assert_eq!(firewall.filter(Direction::Ingress, &other), Verdict::Drop);
+        let unreachable = error(
+            icmp::v4::DESTINATION_UNREACHABLE,
+            "192.0.2.1".parse().unwrap(),
+            "10.0.0.2".parse().unwrap(),
+            &query,
+        );
+        assert_eq!(
+            firewall.filter(Direction::Ingress, &unreachable),
+            Verdict::Accept
+        );
+        assert_eq!(firewall.stats()[0].packets, 2);
+        assert_eq!(firewall.connections(), 1);
+
+        // Established connections survive a reload, while counters start over.
+        firewall.reload(ingress_drop().rule(Rule::new(Action::Drop)));
+        assert_eq!(firewall.stats(), vec![RuleStats::default(); 2]);
+        assert_eq!(firewall.filter(Direction::Ingress, &reply), Verdict::Accept);
+        assert_eq!(firewall.filter(Direction::Egress, &query), Verdict::Accept);
+        let new = udp("10.0.0.2:5002", "192.0.2.1:53");
+        assert_eq!(firewall.filter(Direction::Egress, &new), Verdict::Drop);
+    }
+
+    #[test]
+    fn timeouts() {
+        let firewall = Firewall::new(ingress_drop()).udp_timeout(Duration::ZERO);
+        let query = udp("10.0.0.2:5000", "192.0.2.1:53");
+        assert_eq!(firewall.filter(Direction::Egress, &query), Verdict::Accept);
+        std::thread::sleep(Duration::from_millis(1));
+        let reply = udp("192.0.2.1:53", "10.0.0.2:5000");
+        assert_eq!(firewall.filter(Direction::Ingress, &reply), Verdict::Drop);
+        assert_eq!(firewall.connections(), 0);
+    }
+
+    #[test]
+    fn reject() {
+        let firewall = Firewall::new(RuleSet::new().rule(Rule::new(Action::Reject)));
+        let mut syn = tcp("10.0.0.2:40000", "192.0.2.1:80", TcpFlags::SYN);
+        TcpPacket::new_unchecked(&mut syn[ipv4::HEADER_LEN..]).set_seq_number(1000);
+        let Verdict::Reject(reset) = firewall.filter(Direction::Egress, &fix(syn)) else {
+            panic!("SYN not rejected");
+        };
+        assert_eq!(
+            endpoints(&reset),
+            (addr("192.0.2.1:80"), addr("10.0.0.2:40000"))
+        );
+        let segment = TcpPacket::new_checked(&reset[ipv4::HEADER_LEN..]).unwrap();
+        assert_eq!(segment.flags().0, (TcpFlags::RST | TcpFlags::ACK).0);
+        assert_eq!(segment.ack_number(), 1001);
+
+        let query = udp("[fd00::2]:5000", "[2001:db8::1]:53");
+        let Verdict::Reject(unreachable) = firewall.filter(Direction::Egress, &query) else {
+            panic!("datagram not rejected");
+        };
+        let ip = IpPacket::new_checked(&unreachable[..]).unwrap();
+        assert_eq!(ip.src_addr(), "2001:db8::1".parse::<IpAddr>().unwrap());
+        let message = crate::packet::Icmpv6Packet::new_checked(ip.transport().unwrap()).unwrap();
+        assert_eq!(message.msg_type(), icmp::v6::DESTINATION_UNREACHABLE);
+        assert!(message.verify_checksum("2001:db8::1".parse().unwrap(), "fd00::2".parse().unwrap()));
+        assert_eq!(message.payload(), &query[..]);
+
+        // Errors and resets are never answered.
+        let reset = tcp("10.0.0.2:40000", "192.0.2.1:80", TcpFlags::RST);
+        assert_eq!(firewall.filter(Direction::Egress, &reset), Verdict::Drop);
+        let error = error(
+            icmp::v4::DESTINATION_UNREACHABLE,
+            "10.0.0.2".parse().unwrap(),
+            "192.0.2.1".parse().unwrap(),
+            &udp("192.0.2.1:53", "10.0.0.2:5000"),
+        );
+        assert_eq!(firewall.filter(Direction::Egress, &error), Verdict::Drop);
+    }
+
+    /// Fills the checksum of a TCP packet again.
+    fn fix(mut packet: Vec<u8>) -> Vec<u8> {
+        let ip = IpPacket::new_checked(&packet[..]).unwrap();
+        let (src, dst) = (ip.src_addr(), ip.dst_addr());
+        TcpPacket::new_unchecked(&mut packet[ipv4::HEADER_LEN..]).fill_checksum(src, dst);
+        packet
+    }
+
+    #[test]
+    fn filtered() {
+        task::block_on(async {
+            let tun = MockTun::new("tun0").with_packet_info(false);
+            let kernel = tun.kernel();
+            let rules = ingress_drop().rule(
+                Rule::new(Action::Reject)
+                    .direction(Direction::Ingress)
+                    .protocol(protocol::TCP),
+            );
+            let tun = Filtered::new(tun, Arc::new(Firewall::new(rules))).unwrap();
+
+            // Rejected packets received from device are answered to it, dropped ones skipped.
+            kernel
+                .inject(&tcp("10.0.0.1:40000", "10.0.0.2:80", TcpFlags::SYN))
+                .await;
+            kernel.inject(&udp("10.0.0.1:5000", "10.0.0.2:53")).await;
+            let query = udp("10.0.0.2:5000", "10.0.0.1:53");
+            tun.send(&query).await.unwrap();
+            assert_eq!(kernel.recv().await.unwrap(), query);
+            kernel.inject(&udp("10.0.0.1:53", "10.0.0.2:5000")).await;
+            let mut buf = [0u8; 1500];
+            let n = tun.recv(&mut buf).await.unwrap();
+            assert_eq!(
+                endpoints(&buf[..n]),
+                (addr("10.0.0.1:53"), addr("10.0.0.2:5000"))
+            );
+            let reset = kernel.recv().await.unwrap();
+            assert_eq!(
+                endpoints(&reset),
+                (addr("10.0.0.2:80"), addr("10.0.0.1:40000"))
+            );
+
+            // Rejected packets sent to device are answered to the sender.
+            let firewall = tun.firewall().clone();
+            firewall.reload(RuleSet::new().rule(Rule::new(Action::Reject)));
+            tun.send(&udp("10.0.0.2:5000", "10.0.0.1:54"))
+                .await
+                .unwrap();
+            let n = tun.recv(&mut buf).await.unwrap();
+            let ip = IpPacket::new_checked(&buf[..n]).unwrap();
+            assert_eq!(ip.protocol().unwrap(), protocol::ICMP);
+            assert_eq!(ip.dst_addr(), "10.0.0.2".parse::<IpAddr>().unwrap());
+            assert!(kernel.try_recv().is_none());
+        });
+    }
+
+    /// Keeps the next send pending once when `stall` is set.
+    struct Stalled {
+        device: MockTun,
+        stall: AtomicBool,
+    }
+
+    impl TunDevice for Stalled {
+        fn name(&self) -> &str {
+            self.device.name()
+        }
+
+        fn mtu(&self) -> Result<i32> {
+            self.device.mtu()
+        }
+
+        fn kind(&self) -> DeviceKind {
+            TunDevice::kind(&self.device)
+        }
+
+        fn packet_info(&self) -> bool {
+            self.device.packet_info()
+        }
+
+        fn poll_recv(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            self.device.poll_recv(cx, buf)
+        }
+
+        fn poll_send(&self, cx: &mut Context<'_>, packet: &[u8]) -> Poll<io::Result<usize>> {
+            if self.stall.swap(false, Ordering::Relaxed) {
+                return Poll::Pending;
+            }
+            self.device.poll_send(cx, packet)
+        }
+    }
+
+    #[test]
+    fn pending_send() {
+        let device = Stalled {
+            device: MockTun::new("tun0").with_packet_info(false),
+            stall: AtomicBool::new(true),
+        };
+        let kernel = device.device.kernel();
+        let rules = RuleSet::new()
+            .policy(Direction::Egress, Action::Drop)
+            .rule(Rule::new(Action::Accept).dst_ports(53..=53));
+        let tun = Filtered::new(device, Arc::new(Firewall::new(rules.clone()))).unwrap();
+        let mut cx = Context::from_waker(Waker::noop());
+
+        // A send polled again after `Poll::Pending` is filtered once.
+        let query = udp("10.0.0.2:5000", "10.0.0.1:53");
+        let mut send = tun.send(&query);
+        assert!(Pin::new(&mut send).poll(&mut cx).is_pending());
+        assert_eq!(tun.firewall().stats()[0].packets, 1);
+        assert!(Pin::new(&mut send).poll(&mut cx).is_ready());
+        assert_eq!(tun.firewall().stats()[0].packets, 1);
+        assert_eq!(kernel.try_recv().unwrap(), query);
+
+        // Another send in the meantime is filtered, but not the pending one afterwards.
+        tun.get_ref().stall.store(true, Ordering::Relaxed);
+        let mut send = tun.send(&query);
+        assert!(Pin::new(&mut send).poll(&mut cx).is_pending());
+        let other = udp("10.0.0.2:5000", "10.0.0.1:54");
+        assert!(task::block_on(tun.send(&other)).is_ok());
+        assert!(kernel.try_recv().is_none());
+        assert!(Pin::new(&mut send).poll(&mut cx).is_ready());
+        assert_eq!(tun.firewall().stats()[0].packets, 2);
+        assert_eq!(kernel.try_recv().unwrap(), query);
+
+        // A later send of the same packet is filtered again, after the pending one was dropped.
+        tun.get_ref().stall.store(true, Ordering::Relaxed);
+        assert!(Pin::new(&mut tun.send(&query)).poll(&mut cx).is_pending());
+        tun.firewall()
+            .reload(RuleSet::new().policy(Direction::Egress, Action::Drop));
+        assert!(task::block_on(tun.send(&query)).is_ok());
+        assert!(kernel.try_recv().is_none());
+
+        // A direct call to `poll_send` is filtered every time.
+        tun.firewall().reload(rules);
+        tun.get_ref().stall.store(true, Ordering::Relaxed);
+        assert!(tun.poll_send(&mut cx, &query).is_pending());
+        assert!(tun.poll_send(&mut cx, &query).is_ready());
+        assert_eq!(tun.firewall().stats()[0].packets, 2);
+        assert_eq!(kernel.try_recv().unwrap(), query);
+    }
+}
@@ -0,0 +1,376 @@
+//! The connections of packets, as tracked by the NAT and the firewall.
+
+use crate::packet::{
+    icmp, protocol, read_u16, Icmpv4Packet, IpPacket, TcpFlags, TcpPacket, UdpPacket,
+};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+/// The time a TCP connection is kept after a reset.
+const TCP_CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Represents the endpoints of a packet. ICMP queries use their identifier as both ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Tuple {
+    pub(crate) protocol: u8,
+    pub(crate) src: SocketAddr,
+    pub(crate) dst: SocketAddr,
+}
+
+impl Tuple {
+    pub(crate) fn reverse(self) -> Self {
+        Self {
+            protocol: self.protocol,
+            src: self.dst,
+            dst: self.src,
+        }
+    }
+
+    pub(crate) fn with_src(self, addr: IpAddr, port: u16) -> Self {
+        let mut tuple = Self {
+            src: SocketAddr::new(addr, port),
+            ..self
+        };
+        if is_icmp(self.protocol) {
+            tuple.dst.set_port(port);
+        }
+        tuple
+    }
+
+    pub(crate) fn with_dst(self, addr: IpAddr, port: u16) -> Self {
+        let mut tuple = Self {
+            dst: SocketAddr::new(addr, port),
+            ..self
+        };
+        if is_icmp(self.protocol) {
+            tuple.src.set_port(port);
+        }
+        tuple
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TcpState {
+    Opening,
+    Established,
+    Closing,
+    Closed,
+}
+
+impl TcpState {
+    /// Returns the state following a segment with `flags`, which is a reply if it travels the
+    /// opposite way of the segment which opened the connection.
+    pub(crate) fn next(self, flags: TcpFlags, reply: bool) -> Self {
+        match self {
+            _ if flags.contains(TcpFlags::RST) => Self::Closed,
+            _ if flags.contains(TcpFlags::FIN) => Self::Closing,
+            Self::Opening if reply => Self::Established,
+            state => state,
+        }
+    }
+}
+
+/// Represents the idle timeouts of tracked connections per protocol.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Timeouts {
+    pub(crate) tcp: Duration,
+    pub(crate) tcp_transitory: Duration,
+    pub(crate) udp: Duration,
+    pub(crate) icmp: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            tcp: Duration::from_secs(7200),
+            tcp_transitory: Duration::from_secs(120),
+            udp: Duration::from_secs(60),
+            icmp: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Timeouts {
+    /// Returns the time after which an idle connection of `protocol` in `state` expires.
+    pub(crate) fn get(&self, protocol: u8, state: TcpState) -> Duration {
+        match (protocol, state) {
+            (protocol::TCP, TcpState::Established) => self.tcp,
+            (protocol::TCP, TcpState::Closed) => TCP_CLOSE_TIMEOUT.min(self.tcp_transitory),
+            (protocol::TCP, _) => self.tcp_transitory,
+            (protocol::UDP, _) => self.udp,
+            _ => self.icmp,
+        }
+    }
+}
+
+/// The part of a packet which identifies its connection.
+pub(crate) enum Key {
+    /// A TCP or UDP segment, or an ICMP echo request or reply.
+    Query { tuple: Tuple, flags: TcpFlags },
+    /// An ICMP error about the packet embedded at `offset`.
+    Error { tuple: Tuple, offset: usize },
+}
+
+pub(crate) fn is_icmp(protocol: u8) -> bool {
+    protocol == protocol::ICMP || protocol == protocol::ICMPV6
+}
+
+/// Returns `true` if a packet of `protocol` with `flags` may open a connection. ICMP echo
+/// requests are keyed with the SYN flag.
+pub(crate) fn opens(protocol: u8, flags: TcpFlags) -> bool {
+    match protocol {
+        protocol::TCP => !flags.contains(TcpFlags::RST),
+        protocol::UDP => true,
+        _ => flags.contains(TcpFlags::SYN),
+    }
+}
+
+/// Parses the key of `packet`, or `None` if it has no ports, e.g. as it is a later fragment.
+pub(crate) fn key(packet: &[u8]) -> Option<Key> {
+    let ip = IpPacket::new_checked(packet).ok()?;
+    let (protocol, offset) = match &ip {
+        IpPacket::V4(packet) => (packet.protocol(), packet.header_len()),
+        IpPacket::V6(packet) => packet.upper_layer().ok()?,
+    };
+    let segment = ip.transport().ok()?;
+    let (src, dst) = (ip.src_addr(), ip.dst_addr());
+    let tuple = |src_port, dst_port| Tuple {
+        protocol,
+        src: SocketAddr::new(src, src_port),
+        dst: SocketAddr::new(dst, dst_port),
+    };
+    match protocol {
+        protocol::TCP => {
+            let segment = TcpPacket::new_checked(segment).ok()?;
+            Some(Key::Query {
+                tuple: tuple(segment.src_port(), segment.dst_port()),
+                flags: segment.flags(),
+            })
+        }
+        protocol::UDP => {
+            let datagram = UdpPacket::new_checked(segment).ok()?;
+            Some(Key::Query {
+                tuple: tuple(datagram.src_port(), datagram.dst_port()),
+                flags: TcpFlags(0),
+            })
+        }
+        protocol::ICMP | protocol::ICMPV6 => {
+            // Both versions share the layout of the header.
+            let message = Icmpv4Packet::new_checked(segment).ok()?;
+            let id = message.echo_ident();
+            match (protocol, message.msg_type()) {
+                (protocol::ICMP, icmp::v4::ECHO_REQUEST)
+                | (protocol::ICMPV6, icmp::v6::ECHO_REQUEST) => Some(Key::Query {
+                    tuple: tuple(id, id),
+                    flags: TcpFlags::SYN,
+                }),
+                (protocol::ICMP, icmp::v4::ECHO_REPLY)
+                | (protocol::ICMPV6, icmp::v6::ECHO_REPLY) => Some(Key::Query {
+                    tuple: tuple(id, id),
+                    flags: TcpFlags(0),
+                }),
+                (
+                    protocol::ICMP,
+                    icmp::v4::DESTINATION_UNREACHABLE
+                    | icmp::v4::TIME_EXCEEDED
+                    | icmp::v4::PARAMETER_PROBLEM,
+                )
+                | (
+                    protocol::ICMPV6,
+                    icmp::v6::DESTINATION_UNREACHABLE
+                    | icmp::v6::PACKET_TOO_BIG
+                    | icmp::v6::TIME_EXCEEDED
+                    | icmp::v6::PARAMETER_PROBLEM,
+                ) => {
+                    let offset = offset + icmp::HEADER_LEN;
+                    let tuple = embedded_tuple(&packet[offset..])?;
+                    (tuple.src.is_ipv4() == src.is_ipv4()).then_some(Key::Error { tuple, offset })
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Returns the layout of a packet embedded in an ICMP error, which is usually truncated:
+/// the offsets of its addresses, their length and the offset of its transport header.
+pub(crate) fn embedded_layout(inner: &[u8]) -> Option<(usize, usize, usize, usize)> {
+    match inner.first()? >> 4 {
+        4 if inner.len() >= 20 && inner[0] & 0x0f >= 5 => {
+            Some((12, 16, 4, (inner[0] & 0x0f) as usize * 4))
+        }
+        6 if inner.len() >= 40 => Some((8, 24, 16, 40)),
+        _ => None,
+    }
+}
+
+pub(crate) fn embedded_tuple(inner: &[u8]) -> Option<Tuple> {
+    let (src, dst, len, header_len) = embedded_layout(inner)?;
+    let protocol = inner[if len == 4 { 9 } else { 6 }];
+    let segment = inner.get(header_len..)?;
+    let addr = |offset: usize| -> IpAddr {
+        let octets = &inner[offset..offset + len];
+        match len {
+            4 => Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]).into(),
+            _ => {
+                let mut addr = [0u8; 16];
+                addr.copy_from_slice(octets);
+                Ipv6Addr::from(addr).into()
+            }
+        }
+    };
+    let (src_port, dst_port) = match protocol {
+        protocol::TCP | protocol::UDP if segment.len() >= 4 => {
+            (read_u16(segment, 0), read_u16(segment, 2))
+        }
+        protocol::ICMP | protocol::ICMPV6 if segment.len() >= icmp::HEADER_LEN => {
+            (read_u16(segment, 4), read_u16(segment, 4))
+        }
+        _ => return None,
+    };
+    Some(Tuple {
+        protocol,
+        src: SocketAddr::new(addr(src), src_port),
+        dst: SocketAddr::new(addr(dst), dst_port),
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::packet::{checksum, ipv4, ipv6, write_u16, Ipv4Packet};
+
+    pub(crate) const PORT_UNREACHABLE: u8 = 3;
+
+    pub(crate) fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    /// Builds a packet of `protocol` from `src` to `dst`, using the source port as identifier
+    /// of ICMP messages.
+    pub(crate) fn packet(
+        protocol: u8,
+        src: SocketAddr,
+        dst: SocketAddr,
+        segment: &[u8],
+    ) -> Vec<u8> {
+        let header_len = match src {
+            SocketAddr::V4(_) => ipv4::HEADER_LEN,
+            SocketAddr::V6(_) => ipv6::HEADER_LEN,
+        };
+        let mut buf = vec![0u8; header_len + segment.len()];
+        match (src.ip(), dst.ip()) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                buf[0] = 0x45;
+                write_u16(&mut buf, 2, (header_len + segment.len()) as u16);
+                buf[8] = 64;
+                buf[9] = protocol;
+                buf[12..16].copy_from_slice(&src.octets());
+                buf[16..20].copy_from_slice(&dst.octets());
+                Ipv4Packet::new_unchecked(&mut buf[..]).fill_checksum();
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                buf[0] = 0x60;
+                write_u16(&mut buf, 4, segment.len() as u16);
+                buf[6] = protocol;
+                buf[7] = 64;
+                buf[8..24].copy_from_slice(&src.octets());
+                buf[24..40].copy_from_slice(&dst.octets());
+            }
+            _ => unreachable!(),
+        }
+        let transport = &mut buf[header_len..];
+        transport.copy_from_slice(segment);
+        match protocol {
+            protocol::TCP | protocol::UDP => {
+                write_u16(transport, 0, src.port());
+                write_u16(transport, 2, dst.port());
+            }
+            _ => write_u16(transport, 4, src.port()),
+        }
+        let checksum_offset = match protocol {
+            protocol::TCP => 16,
+            protocol::UDP => 6,
+            _ => 2,
+        };
+        write_u16(transport, checksum_offset, 0);
+        let checksum = match protocol {
+            protocol::ICMP => checksum::checksum(transport),
+            protocol => checksum::transport(src.ip(), dst.ip(), protocol, transport),
+        };
+        write_u16(transport, checksum_offset, checksum);
+        buf
+    }
+
+    pub(crate) fn tcp(src: &str, dst: &str, flags: TcpFlags) -> Vec<u8> {
+        let mut segment = [0u8; 20];
+        segment[12] = 0x50;
+        segment[13] = flags.0;
+        packet(protocol::TCP, addr(src), addr(dst), &segment)
+    }
+
+    pub(crate) fn udp(src: &str, dst: &str) -> Vec<u8> {
+        let mut segment = b"\0\0\0\0\0\x0d\0\0hello".to_vec();
+        segment[5] = segment.len() as u8;
+        packet(protocol::UDP, addr(src), addr(dst), &segment)
+    }
+
+    pub(crate) fn echo(msg_type: u8, src: &str, dst: &str) -> Vec<u8> {
+        let protocol = match addr(src) {
+            SocketAddr::V4(_) => protocol::ICMP,
+            SocketAddr::V6(_) => protocol::ICMPV6,
+        };
+        packet(
+            protocol,
+            addr(src),
+            addr(dst),
+            &[msg_type, 0, 0, 0, 0, 0, 0, 1],
+        )
+    }
+
+    pub(crate) fn error(msg_type: u8, src: IpAddr, dst: IpAddr, embedded: &[u8]) -> Vec<u8> {
+        let protocol = match src {
+            IpAddr::V4(_) => protocol::ICMP,
+            IpAddr::V6(_) => protocol::ICMPV6,
+        };
+        let mut message = vec![msg_type, PORT_UNREACHABLE, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(embedded);
+        // The unused field of the error, where echo messages hold their identifier, stays zero.
+        packet(protocol, (src, 0).into(), (dst, 0).into(), &message)
+    }
+
+    /// Returns the endpoints of a valid `packet`, checking all of its checksums.
+    pub(crate) fn endpoints(packet: &[u8]) -> (SocketAddr, SocketAddr) {
+        let ip = IpPacket::new_checked(packet).unwrap();
+        if let IpPacket::V4(packet) = &ip {
+            assert!(packet.verify_checksum());
+        }
+        let (src, dst) = (ip.src_addr(), ip.dst_addr());
+        let segment = ip.transport().unwrap();
+        let protocol = ip.protocol().unwrap();
+        let ports = match protocol {
+            protocol::TCP => {
+                let segment = TcpPacket::new_checked(segment).unwrap();
+                assert!(segment.verify_checksum(src, dst));
+                (segment.src_port(), segment.dst_port())
+            }
+            protocol::UDP => {
+                let datagram = UdpPacket::new_checked(segment).unwrap();
+                assert!(datagram.verify_checksum(src, dst));
+                (datagram.src_port(), datagram.dst_port())
+            }
+            _ => {
+                let sum = checksum::sum(segment);
+                let pseudo = match protocol {
+                    protocol::ICMP => 0,
+                    _ => checksum::pseudo_header(src, dst, protocol, segment.len() as u32),
+                };
+                assert_eq!(checksum::fold(sum + pseudo), 0xffff);
+                let id = read_u16(segment, 4);
+                (id, id)
+            }
+        };
+        (SocketAddr::new(src, ports.0), SocketAddr::new(dst, ports.1))
+    }
+}
@@ -0,0 +1,40 @@
+use std::future::{poll_fn, Future};
+use std::pin::pin;
+use std::task::Poll;
+
+/// Polls both futures and returns the output of the first one to complete.
+pub(crate) async fn race<T>(a: impl Future<Output = T>, b: impl Future<Output = T>) -> T {
+    let mut a = pin!(a);
+    let mut b = pin!(b);
+    poll_fn(|cx| match a.as_mut().poll(cx) {
+        Poll::Ready(output) => Poll::Ready(output),
+        Poll::Pending => b.as_mut().poll(cx),
+    })
+    .await
+}
+
+/// Polls both futures until they complete and returns their outputs.
+#[cfg(feature = "smoltcp")]
+pub(crate) async fn join<A, B>(a: impl Future<Output = A>, b: impl Future<Output = B>) -> (A, B) {
+    let mut a = pin!(a);
+    let mut b = pin!(b);
+    let (mut output_a, mut output_b) = (None, None);
+    poll_fn(|cx| {
+        if output_a.is_none() {
+            if let Poll::Ready(output) = a.as_mut().poll(cx) {
+                output_a = Some(output);
+            }
+        }
+        if output_b.is_none() {
+            if let Poll::Ready(output) = b.as_mut().poll(cx) {
+                output_b = Some(output);
+            }
+        }
+        if output_a.is_some() && output_b.is_some() {
+            Poll::Ready((output_a.take().unwrap(), output_b.take().unwrap()))
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
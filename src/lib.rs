@@ -2,14 +2,65 @@
 mod linux {
     pub mod address;
     pub mod interface;
+    pub mod netlink;
     pub mod params;
     pub mod request;
+    pub mod sysfs;
 }
 
 mod builder;
+mod config;
+mod device;
+mod flow;
+mod future;
+#[cfg(target_os = "linux")]
+mod macvtap;
+#[cfg(any(test, feature = "testing"))]
+mod mock;
+mod net;
+mod owner;
+#[cfg(feature = "smoltcp")]
+mod phy;
+mod responder;
+mod stats;
 mod tun;
 
+pub mod capture;
+pub mod firewall;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod nat;
+pub mod packet;
+pub mod qemu;
 pub mod result;
+#[cfg(feature = "smoltcp")]
+pub mod tun2socks;
+pub mod tunnel;
+#[cfg(target_os = "linux")]
+pub mod vhost;
+#[cfg(target_os = "linux")]
+pub mod vhost_user;
+
+/// Inspects existing network interfaces by name, without attaching to them.
+#[cfg(target_os = "linux")]
+pub mod interface {
+    pub use crate::linux::interface::features;
+    pub use crate::linux::sysfs::{group, ipv6_addresses, master, owner, read, tun_flags};
+}
 
 pub use self::builder::TunBuilder;
-pub use self::tun::Tun;
+pub use self::config::{ConfigError, TunConfig};
+pub use self::device::{DeviceKind, Framed, RecvFuture, SendFuture, TunDevice};
+#[cfg(target_os = "linux")]
+pub use self::linux::netlink::ensure_bridge;
+#[cfg(target_os = "linux")]
+pub use self::macvtap::{IpvtapMode, MacvtapBuilder, MacvtapMode};
+#[cfg(any(test, feature = "testing"))]
+pub use self::mock::{MockKernel, MockReader, MockTun, MockWriter};
+pub use self::net::{IpCidr, Ipv4Cidr, Ipv6Cidr, ParseCidrError, Route};
+pub use self::owner::{Group, Owner};
+#[cfg(feature = "smoltcp")]
+pub use self::phy::{TunRxToken, TunTxToken};
+pub use self::responder::Responder;
+pub use self::stats::{Counted, DeviceStats, QueueCounters, QueueStats};
+pub use self::tun::{Tun, TunIo};
@@ -85,3 +85,47 @@ impl TryFrom<sockaddr> for MacAddress {
         Ok(MacAddress::new(addr.try_into()?))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hton_ntoh_roundtrip() {
+        for octets in [[0, 0, 0, 0], [10, 0, 0, 1], [192, 168, 1, 254], [255; 4]] {
+            assert_eq!(ntoh(hton(octets)), octets);
+        }
+    }
+
+    #[test]
+    fn hton_is_network_order_in_memory() {
+        assert_eq!(hton([10, 0, 0, 1]).to_ne_bytes(), [10, 0, 0, 1]);
+    }
+
+    #[test]
+    fn ipv4_address_roundtrip() {
+        let addr = Ipv4Addr::new(172, 16, 5, 4);
+        let sock = addr.to_address();
+        assert_eq!(sock.sa_family, libc::AF_INET as u16);
+        assert_eq!(Ipv4Addr::from_address(sock), addr);
+    }
+
+    #[test]
+    fn mac_address_roundtrip() {
+        let mac = MacAddress::new([0x02, 0x00, 0x5e, 0x10, 0xfe, 0xff]);
+        let sock: sockaddr = mac.into();
+        assert_eq!(sock.sa_family, libc::ARPHRD_ETHER);
+        assert_eq!(MacAddress::try_from(sock).unwrap(), mac);
+    }
+
+    #[test]
+    fn mac_address_wrong_family() {
+        let sock = Ipv4Addr::LOCALHOST.to_address();
+        match MacAddress::try_from(sock) {
+            Err(MacAddressConversionError::WrongType(family)) => {
+                assert_eq!(family, libc::AF_INET as u16)
+            }
+            _ => panic!("conversion should fail"),
+        }
+    }
+}
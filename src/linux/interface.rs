@@ -1,13 +1,24 @@
-use super::request::ifreq;
+use super::netlink;
+use super::request::{ifreq, in6_ifreq, in6_rtmsg, rtentry};
+use crate::config::ConfigError;
 use crate::linux::address::Ipv4AddrExt;
+use crate::net::{IpCidr, Route};
 use crate::result::Result;
 use mac_address::MacAddress;
-use std::net::Ipv4Addr;
+use std::ffi::CString;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::AsRawFd;
 
 nix::ioctl_write_int!(tunsetiff, b'T', 202);
 nix::ioctl_write_int!(tunsetpersist, b'T', 203);
 nix::ioctl_write_int!(tunsetowner, b'T', 204);
+nix::ioctl_write_int!(tunsetlink, b'T', 205);
 nix::ioctl_write_int!(tunsetgroup, b'T', 206);
+nix::ioctl_read!(tungetfeatures, b'T', 207, u32);
+nix::ioctl_read!(tungetvnethdrsz, b'T', 215, i32);
+nix::ioctl_read!(tungetsndbuf, b'T', 211, i32);
+nix::ioctl_write_ptr!(tunsetsndbuf, b'T', 212, i32);
 
 nix::ioctl_write_ptr_bad!(siocsifmtu, libc::SIOCSIFMTU, ifreq);
 nix::ioctl_write_ptr_bad!(siocsifflags, libc::SIOCSIFFLAGS, ifreq);
@@ -16,6 +27,10 @@ nix::ioctl_write_ptr_bad!(siocsifhwaddr, libc::SIOCSIFHWADDR, ifreq);
 nix::ioctl_write_ptr_bad!(siocsifdstaddr, libc::SIOCSIFDSTADDR, ifreq);
 nix::ioctl_write_ptr_bad!(siocsifbrdaddr, libc::SIOCSIFBRDADDR, ifreq);
 nix::ioctl_write_ptr_bad!(siocsifnetmask, libc::SIOCSIFNETMASK, ifreq);
+nix::ioctl_write_ptr_bad!(siocsiftxqlen, libc::SIOCSIFTXQLEN, ifreq);
+nix::ioctl_write_ptr_bad!(siocsifaddr6, libc::SIOCSIFADDR, in6_ifreq);
+nix::ioctl_write_ptr_bad!(siocaddrt, libc::SIOCADDRT, rtentry);
+nix::ioctl_write_ptr_bad!(siocaddrt6, libc::SIOCADDRT, in6_rtmsg);
 
 nix::ioctl_read_bad!(siocgifmtu, libc::SIOCGIFMTU, ifreq);
 nix::ioctl_read_bad!(siocgifflags, libc::SIOCGIFFLAGS, ifreq);
@@ -24,131 +39,394 @@ nix::ioctl_read_bad!(siocgifhwaddr, libc::SIOCGIFHWADDR, ifreq);
 nix::ioctl_read_bad!(siocgifdstaddr, libc::SIOCGIFDSTADDR, ifreq);
 nix::ioctl_read_bad!(siocgifbrdaddr, libc::SIOCGIFBRDADDR, ifreq);
 nix::ioctl_read_bad!(siocgifnetmask, libc::SIOCGIFNETMASK, ifreq);
+nix::ioctl_read_bad!(siocgifindex, libc::SIOCGIFINDEX, ifreq);
+nix::ioctl_read_bad!(siocgiftxqlen, libc::SIOCGIFTXQLEN, ifreq);
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+fn check<T>(ioctl: &'static str, name: &str, result: nix::Result<T>) -> Result<T> {
+    #[cfg(feature = "tracing")]
+    match &result {
+        Ok(_) => tracing::debug!(ioctl, name, "ioctl succeeded"),
+        Err(error) => tracing::warn!(ioctl, name, %error, "ioctl failed"),
+    }
+    Ok(result?)
+}
+
+/// Returns the `IFF_*` flags supported by the kernel, as reported by `TUNGETFEATURES` without
+/// attaching to a device.
+pub fn features() -> Result<i32> {
+    let tun = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/net/tun")?;
+    let mut features = 0;
+    check("TUNGETFEATURES", "", unsafe {
+        tungetfeatures(tun.as_raw_fd(), &mut features)
+    })?;
+    Ok(features as _)
+}
+
+/// Maps the optional `IFF_*` flags to the names of the matching `TunBuilder` options.
+const OPTIONS: &[(i32, &str)] = &[
+    (libc::IFF_NAPI, "napi"),
+    (libc::IFF_NAPI_FRAGS, "napi_frags"),
+    (libc::IFF_NO_CARRIER, "no_carrier"),
+    (libc::IFF_VNET_HDR, "vnet_hdr"),
+    (libc::IFF_MULTI_QUEUE, "multi_queue"),
+];
 
-#[derive(Clone)]
 pub struct Interface {
     fds: Vec<i32>,
     socket: i32,
     name: String,
+    tun_flags: i16,
+    vnet_hdr_len: usize,
+    /// The index of the link deleted on drop, which the kernel does not delete with its queues.
+    link: Option<u32>,
 }
 
 impl Interface {
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(fds), fields(queues = fds.len())))]
     pub fn new(fds: Vec<i32>, name: &str, mut flags: i16) -> Result<Self> {
         let mut req = ifreq::new(name);
         if fds.len() > 1 {
             flags |= libc::IFF_MULTI_QUEUE as i16;
         }
         req.ifr_ifru.ifru_flags = flags;
+        let mut features = 0;
+        check("TUNGETFEATURES", name, unsafe {
+            tungetfeatures(fds[0], &mut features)
+        })?;
+        for (flag, option) in OPTIONS {
+            if flags as i32 & flag != 0 && features as i32 & flag == 0 {
+                return Err(ConfigError::new(*option, "not supported by the kernel").into());
+            }
+        }
         for fd in fds.iter() {
-            unsafe { tunsetiff(*fd, &req as *const _ as _) }?;
+            check("TUNSETIFF", name, unsafe {
+                tunsetiff(*fd, &req as *const _ as _)
+            })?;
+        }
+        let mut vnet_hdr_len = 0;
+        if flags as i32 & libc::IFF_VNET_HDR != 0 {
+            check("TUNGETVNETHDRSZ", name, unsafe {
+                tungetvnethdrsz(fds[0], &mut vnet_hdr_len)
+            })?;
         }
         Ok(Interface {
             fds,
             socket: unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) },
             name: req.name(),
+            tun_flags: flags,
+            vnet_hdr_len: vnet_hdr_len as _,
+            link: None,
         })
     }
 
+    /// Deletes the link `index` of device once it is dropped, e.g. of a macvtap device.
+    pub fn delete_on_drop(&mut self, index: u32) {
+        self.link = Some(index);
+    }
+
     pub fn name(&self) -> &str {
         self.name.as_str()
     }
 
+    pub fn tun_flags(&self) -> i16 {
+        self.tun_flags
+    }
+
+    pub fn vnet_hdr_len(&self) -> usize {
+        self.vnet_hdr_len
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self), fields(name = %self.name)))]
+    pub fn txqueuelen(&self, txqueuelen: Option<u32>) -> Result<u32> {
+        let mut req = ifreq::new(self.name());
+        if let Some(txqueuelen) = txqueuelen {
+            req.ifr_ifru.ifru_ivalue = txqueuelen.try_into()?;
+            check("SIOCSIFTXQLEN", self.name(), unsafe {
+                siocsiftxqlen(self.socket, &req)
+            })?;
+        } else {
+            check("SIOCGIFTXQLEN", self.name(), unsafe {
+                siocgiftxqlen(self.socket, &mut req)
+            })?;
+        }
+        Ok(unsafe { req.ifr_ifru.ifru_ivalue } as _)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self), fields(name = %self.name)))]
+    pub fn sndbuf(&self, fd: i32, sndbuf: Option<i32>) -> Result<i32> {
+        if let Some(sndbuf) = sndbuf {
+            check("TUNSETSNDBUF", self.name(), unsafe {
+                tunsetsndbuf(fd, &sndbuf)
+            })?;
+            return Ok(sndbuf);
+        }
+        let mut sndbuf = 0;
+        check("TUNGETSNDBUF", self.name(), unsafe {
+            tungetsndbuf(fd, &mut sndbuf)
+        })?;
+        Ok(sndbuf)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self), fields(name = %self.name)))]
+    pub fn set_link_type(&self, link_type: u16) -> Result<()> {
+        check("TUNSETLINK", self.name(), unsafe {
+            tunsetlink(self.fds[0], link_type as _)
+        })?;
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self), fields(name = %self.name)))]
     pub fn mtu(&self, mtu: Option<i32>) -> Result<i32> {
         let mut req = ifreq::new(self.name());
         if let Some(mtu) = mtu {
             req.ifr_ifru.ifru_mtu = mtu;
-            unsafe { siocsifmtu(self.socket, &req) }?;
+            check("SIOCSIFMTU", self.name(), unsafe {
+                siocsifmtu(self.socket, &req)
+            })?;
         } else {
-            unsafe { siocgifmtu(self.socket, &mut req) }?;
+            check("SIOCGIFMTU", self.name(), unsafe {
+                siocgifmtu(self.socket, &mut req)
+            })?;
         }
         Ok(unsafe { req.ifr_ifru.ifru_mtu })
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self), fields(name = %self.name)))]
     pub fn netmask(&self, netmask: Option<Ipv4Addr>) -> Result<Ipv4Addr> {
         let mut req = ifreq::new(self.name());
         if let Some(netmask) = netmask {
             req.ifr_ifru.ifru_netmask = netmask.to_address();
-            unsafe { siocsifnetmask(self.socket, &req) }?;
+            check("SIOCSIFNETMASK", self.name(), unsafe {
+                siocsifnetmask(self.socket, &req)
+            })?;
             return Ok(netmask);
         }
-        unsafe { siocgifnetmask(self.socket, &mut req) }?;
+        check("SIOCGIFNETMASK", self.name(), unsafe {
+            siocgifnetmask(self.socket, &mut req)
+        })?;
         Ok(unsafe { Ipv4Addr::from_address(req.ifr_ifru.ifru_netmask) })
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self), fields(name = %self.name)))]
     pub fn address(&self, address: Option<Ipv4Addr>) -> Result<Ipv4Addr> {
         let mut req = ifreq::new(self.name());
         if let Some(address) = address {
             req.ifr_ifru.ifru_addr = address.to_address();
-            unsafe { siocsifaddr(self.socket, &req) }?;
+            check("SIOCSIFADDR", self.name(), unsafe {
+                siocsifaddr(self.socket, &req)
+            })?;
             return Ok(address);
         }
-        unsafe { siocgifaddr(self.socket, &mut req) }?;
+        check("SIOCGIFADDR", self.name(), unsafe {
+            siocgifaddr(self.socket, &mut req)
+        })?;
         Ok(unsafe { Ipv4Addr::from_address(req.ifr_ifru.ifru_addr) })
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self), fields(name = %self.name)))]
     pub fn destination(&self, dst: Option<Ipv4Addr>) -> Result<Ipv4Addr> {
         let mut req = ifreq::new(self.name());
         if let Some(dst) = dst {
             req.ifr_ifru.ifru_dstaddr = dst.to_address();
-            unsafe { siocsifdstaddr(self.socket, &req) }?;
+            check("SIOCSIFDSTADDR", self.name(), unsafe {
+                siocsifdstaddr(self.socket, &req)
+            })?;
             return Ok(dst);
         }
-        unsafe { siocgifdstaddr(self.socket, &mut req) }?;
+        check("SIOCGIFDSTADDR", self.name(), unsafe {
+            siocgifdstaddr(self.socket, &mut req)
+        })?;
         Ok(unsafe { Ipv4Addr::from_address(req.ifr_ifru.ifru_dstaddr) })
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self), fields(name = %self.name)))]
     pub fn broadcast(&self, broadcast: Option<Ipv4Addr>) -> Result<Ipv4Addr> {
         let mut req = ifreq::new(self.name());
         if let Some(broadcast) = broadcast {
             req.ifr_ifru.ifru_broadaddr = broadcast.to_address();
-            unsafe { siocsifbrdaddr(self.socket, &req) }?;
+            check("SIOCSIFBRDADDR", self.name(), unsafe {
+                siocsifbrdaddr(self.socket, &req)
+            })?;
             return Ok(broadcast);
         }
-        unsafe { siocgifbrdaddr(self.socket, &mut req) }?;
+        check("SIOCGIFBRDADDR", self.name(), unsafe {
+            siocgifbrdaddr(self.socket, &mut req)
+        })?;
         Ok(unsafe { Ipv4Addr::from_address(req.ifr_ifru.ifru_broadaddr) })
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self), fields(name = %self.name)))]
     pub fn flags(&self, flags: Option<i16>) -> Result<i16> {
         let mut req = ifreq::new(self.name());
-        unsafe { siocgifflags(self.socket, &mut req) }?;
+        check("SIOCGIFFLAGS", self.name(), unsafe {
+            siocgifflags(self.socket, &mut req)
+        })?;
         if let Some(flags) = flags {
             unsafe { req.ifr_ifru.ifru_flags |= flags };
-            unsafe { siocsifflags(self.socket, &req) }?;
+            check("SIOCSIFFLAGS", self.name(), unsafe {
+                siocsifflags(self.socket, &req)
+            })?;
         }
         Ok(unsafe { req.ifr_ifru.ifru_flags })
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self), fields(name = %self.name)))]
     pub fn set_mac(&self, address: MacAddress) -> Result<()> {
         let mut req = ifreq::new(self.name());
         req.ifr_ifru.ifru_hwaddr = address.into();
-        unsafe { siocsifhwaddr(self.socket, &req) }?;
+        check("SIOCSIFHWADDR", self.name(), unsafe {
+            siocsifhwaddr(self.socket, &req)
+        })?;
         Ok(())
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self), fields(name = %self.name)))]
     pub fn owner(&self, owner: i32) -> Result<()> {
         for fd in self.fds.iter() {
-            unsafe { tunsetowner(*fd, owner as _) }?;
+            check("TUNSETOWNER", self.name(), unsafe {
+                tunsetowner(*fd, owner as _)
+            })?;
         }
         Ok(())
     }
 
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self), fields(name = %self.name)))]
     pub fn group(&self, group: i32) -> Result<()> {
         for fd in self.fds.iter() {
-            unsafe { tunsetgroup(*fd, group as _) }?;
+            check("TUNSETGROUP", self.name(), unsafe {
+                tunsetgroup(*fd, group as _)
+            })?;
         }
         Ok(())
     }
 
-    pub fn persist(&self) -> Result<()> {
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self), fields(name = %self.name)))]
+    pub fn persist(&self, persist: bool) -> Result<()> {
         for fd in self.fds.iter() {
-            unsafe { tunsetpersist(*fd, 1) }?;
+            check("TUNSETPERSIST", self.name(), unsafe {
+                tunsetpersist(*fd, persist as _)
+            })?;
         }
         Ok(())
     }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self), fields(name = %self.name)))]
+    pub fn features(&self) -> Result<i32> {
+        let mut features = 0;
+        check("TUNGETFEATURES", self.name(), unsafe {
+            tungetfeatures(self.fds[0], &mut features)
+        })?;
+        Ok(features as _)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self), fields(name = %self.name)))]
+    pub fn index(&self) -> Result<i32> {
+        let mut req = ifreq::new(self.name());
+        check("SIOCGIFINDEX", self.name(), unsafe {
+            siocgifindex(self.socket, &mut req)
+        })?;
+        Ok(unsafe { req.ifr_ifru.ifru_ivalue })
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self), fields(name = %self.name)))]
+    pub fn set_master(&self, master: Option<&str>) -> Result<()> {
+        let master = match master {
+            Some(master) => netlink::index(master)?,
+            None => 0,
+        };
+        netlink::set_master(self.index()? as _, master)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self), fields(name = %self.name)))]
+    pub fn add_address_v6(&self, address: Ipv6Addr, prefix: u8) -> Result<()> {
+        let req = in6_ifreq {
+            ifr6_addr: address.octets(),
+            ifr6_prefixlen: prefix as _,
+            ifr6_ifindex: self.index()?,
+        };
+        let socket = Socket::new(libc::AF_INET6)?;
+        check("SIOCSIFADDR", self.name(), unsafe {
+            siocsifaddr6(socket.0, &req)
+        })?;
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self), fields(name = %self.name)))]
+    pub fn add_route(&self, route: &Route) -> Result<()> {
+        let mut flags = libc::RTF_UP;
+        if route.gateway.is_some() {
+            flags |= libc::RTF_GATEWAY;
+        }
+        match route.destination {
+            IpCidr::V4(destination) => {
+                let dev = CString::new(self.name())?;
+                let mut req: rtentry = unsafe { mem::zeroed() };
+                req.rt_dst = destination.network().to_address();
+                req.rt_genmask = destination.netmask().to_address();
+                if let Some(IpAddr::V4(gateway)) = route.gateway {
+                    req.rt_gateway = gateway.to_address();
+                }
+                req.rt_flags = flags;
+                // The kernel stores `rt_metric - 1`, as route(8) does.
+                req.rt_metric = match route.metric {
+                    0 => 0,
+                    metric => metric.saturating_add(1).try_into()?,
+                };
+                req.rt_dev = dev.as_ptr() as _;
+                check("SIOCADDRT", self.name(), unsafe {
+                    siocaddrt(self.socket, &req)
+                })?;
+            }
+            IpCidr::V6(destination) => {
+                let mut req: in6_rtmsg = unsafe { mem::zeroed() };
+                req.rtmsg_dst = destination.network().octets();
+                req.rtmsg_dst_len = destination.prefix() as _;
+                if let Some(IpAddr::V6(gateway)) = route.gateway {
+                    req.rtmsg_gateway = gateway.octets();
+                }
+                req.rtmsg_flags = flags as _;
+                req.rtmsg_metric = route.metric;
+                req.rtmsg_ifindex = self.index()?;
+                let socket = Socket::new(libc::AF_INET6)?;
+                check("SIOCADDRT", self.name(), unsafe {
+                    siocaddrt6(socket.0, &req)
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Socket(i32);
+
+impl Socket {
+    fn new(domain: i32) -> Result<Self> {
+        match unsafe { libc::socket(domain, libc::SOCK_DGRAM, 0) } {
+            -1 => Err(std::io::Error::last_os_error().into()),
+            fd => Ok(Self(fd)),
+        }
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
 }
 
 impl Drop for Interface {
     fn drop(&mut self) {
         unsafe { libc::close(self.socket) };
+        if let Some(index) = self.link {
+            let _result = netlink::delete_link(index);
+            #[cfg(feature = "tracing")]
+            if let Err(error) = _result {
+                tracing::warn!(name = %self.name, %error, "failed to delete link");
+            }
+        }
     }
 }
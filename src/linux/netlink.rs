@@ -0,0 +1,256 @@
+//! A minimal rtnetlink client for the link operations which have no ioctl.
+
+use crate::result::Result;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+
+pub const RTM_NEWLINK: u16 = 16;
+pub const RTM_DELLINK: u16 = 17;
+pub const RTM_SETLINK: u16 = 19;
+
+pub const NLM_F_REQUEST: u16 = 0x01;
+pub const NLM_F_ACK: u16 = 0x04;
+pub const NLM_F_EXCL: u16 = 0x200;
+pub const NLM_F_CREATE: u16 = 0x400;
+
+pub const IFLA_ADDRESS: u16 = 1;
+pub const IFLA_IFNAME: u16 = 3;
+pub const IFLA_MTU: u16 = 4;
+pub const IFLA_LINK: u16 = 5;
+pub const IFLA_MASTER: u16 = 10;
+pub const IFLA_LINKINFO: u16 = 18;
+pub const IFLA_INFO_KIND: u16 = 1;
+pub const IFLA_INFO_DATA: u16 = 2;
+pub const IFLA_MACVLAN_MODE: u16 = 1;
+pub const IFLA_IPVLAN_MODE: u16 = 1;
+
+const NLMSG_ERROR: u16 = 2;
+const NLA_F_NESTED: u16 = 0x8000;
+const HEADER_LEN: usize = 16;
+const IFINFOMSG_LEN: usize = 16;
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Represents a link request, i.e. an `ifinfomsg` followed by attributes.
+pub struct LinkRequest {
+    buf: Vec<u8>,
+    nested: Vec<usize>,
+}
+
+impl LinkRequest {
+    pub fn new(msg_type: u16, flags: u16, index: u32) -> Self {
+        let mut buf = vec![0; HEADER_LEN + IFINFOMSG_LEN];
+        buf[4..6].copy_from_slice(&msg_type.to_ne_bytes());
+        buf[6..8].copy_from_slice(&(flags | NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes());
+        buf[HEADER_LEN] = libc::AF_UNSPEC as u8;
+        buf[HEADER_LEN + 4..HEADER_LEN + 8].copy_from_slice(&index.to_ne_bytes());
+        Self {
+            buf,
+            nested: Vec::new(),
+        }
+    }
+
+    /// Sets the interface flags in `change` to their value in `flags`.
+    pub fn flags(mut self, flags: u32, change: u32) -> Self {
+        let offset = HEADER_LEN + 8;
+        self.buf[offset..offset + 4].copy_from_slice(&flags.to_ne_bytes());
+        self.buf[offset + 4..offset + 8].copy_from_slice(&change.to_ne_bytes());
+        self
+    }
+
+    pub fn attr(mut self, kind: u16, data: &[u8]) -> Self {
+        let len = 4 + data.len();
+        self.buf.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align(self.buf.len()), 0);
+        self
+    }
+
+    pub fn attr_u16(self, kind: u16, value: u16) -> Self {
+        self.attr(kind, &value.to_ne_bytes())
+    }
+
+    pub fn attr_u32(self, kind: u16, value: u32) -> Self {
+        self.attr(kind, &value.to_ne_bytes())
+    }
+
+    pub fn attr_str(self, kind: u16, value: &str) -> Self {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.attr(kind, &data)
+    }
+
+    /// Starts a nested attribute, closed by [`end`](#method.end).
+    pub fn begin(mut self, kind: u16) -> Self {
+        self.nested.push(self.buf.len());
+        self.buf.extend_from_slice(&[0, 0]);
+        self.buf
+            .extend_from_slice(&(kind | NLA_F_NESTED).to_ne_bytes());
+        self
+    }
+
+    pub fn end(mut self) -> Self {
+        let start = self.nested.pop().expect("unbalanced nested attribute");
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+        self
+    }
+
+    fn finish(mut self, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// Represents a `NETLINK_ROUTE` socket.
+pub struct Netlink {
+    fd: i32,
+    seq: u32,
+}
+
+impl Netlink {
+    pub fn new() -> Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let netlink = Self { fd, seq: 0 };
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as _;
+        let len = mem::size_of::<libc::sockaddr_nl>() as _;
+        if unsafe { libc::bind(fd, &addr as *const _ as _, len) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(netlink)
+    }
+
+    /// Sends `request` and waits for its acknowledgement.
+    pub fn execute(&mut self, request: LinkRequest) -> Result<()> {
+        self.seq = self.seq.wrapping_add(1);
+        let msg = request.finish(self.seq);
+        if unsafe { libc::send(self.fd, msg.as_ptr() as _, msg.len(), 0) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let n = unsafe { libc::recv(self.fd, buf.as_mut_ptr() as _, buf.len(), 0) };
+            if n < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            let mut msgs = &buf[..n as usize];
+            while msgs.len() >= HEADER_LEN {
+                let len = u32::from_ne_bytes(msgs[0..4].try_into()?) as usize;
+                let msg_type = u16::from_ne_bytes(msgs[4..6].try_into()?);
+                let seq = u32::from_ne_bytes(msgs[8..12].try_into()?);
+                if len < HEADER_LEN || len > msgs.len() {
+                    return Err(io::Error::from(io::ErrorKind::InvalidData).into());
+                }
+                if msg_type == NLMSG_ERROR && seq == self.seq && len >= HEADER_LEN + 4 {
+                    let error = i32::from_ne_bytes(msgs[HEADER_LEN..HEADER_LEN + 4].try_into()?);
+                    return match error {
+                        0 => Ok(()),
+                        error => Err(io::Error::from_raw_os_error(-error).into()),
+                    };
+                }
+                msgs = &msgs[align(len).min(msgs.len())..];
+            }
+        }
+    }
+}
+
+impl Drop for Netlink {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Returns the index of the network interface `name`.
+pub fn index(name: &str) -> Result<u32> {
+    let cname = CString::new(name)?;
+    match unsafe { libc::if_nametoindex(cname.as_ptr()) } {
+        0 => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No such network interface: {}", name),
+        )
+        .into()),
+        index => Ok(index),
+    }
+}
+
+/// Enslaves the network interface `index` to the interface `master`, e.g. a bridge, bond or
+/// VRF, or releases it from its master if `master` is `0`.
+pub fn set_master(index: u32, master: u32) -> Result<()> {
+    let request = LinkRequest::new(RTM_SETLINK, 0, index).attr_u32(IFLA_MASTER, master);
+    Netlink::new()?.execute(request)
+}
+
+/// Deletes the network interface `index`.
+pub fn delete_link(index: u32) -> Result<()> {
+    Netlink::new()?.execute(LinkRequest::new(RTM_DELLINK, 0, index))
+}
+
+/// Creates the bridge `name` unless it already exists, and sets it up.
+pub fn ensure_bridge(name: &str) -> Result<()> {
+    let mut netlink = Netlink::new()?;
+    let request = LinkRequest::new(RTM_NEWLINK, NLM_F_CREATE | NLM_F_EXCL, 0)
+        .attr_str(IFLA_IFNAME, name)
+        .begin(IFLA_LINKINFO)
+        .attr_str(IFLA_INFO_KIND, "bridge")
+        .end();
+    if let Err(err) = netlink.execute(request) {
+        if err
+            .downcast_ref::<io::Error>()
+            .and_then(|e| e.raw_os_error())
+            != Some(libc::EEXIST)
+        {
+            return Err(err);
+        }
+        if !std::path::Path::new(&format!("/sys/class/net/{}/bridge", name)).exists() {
+            return Err(format!("{} exists and is not a bridge", name).into());
+        }
+    }
+    let flags = libc::IFF_UP as u32;
+    netlink.execute(LinkRequest::new(RTM_SETLINK, 0, index(name)?).flags(flags, flags))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        let msg = LinkRequest::new(RTM_NEWLINK, NLM_F_CREATE, 7)
+            .attr_str(IFLA_IFNAME, "br0")
+            .begin(IFLA_LINKINFO)
+            .attr_str(IFLA_INFO_KIND, "bridge")
+            .end()
+            .finish(42);
+        assert_eq!(msg.len(), 16 + 16 + 8 + 4 + 12);
+        assert_eq!(u32::from_ne_bytes(msg[0..4].try_into().unwrap()), 56);
+        assert_eq!(u16::from_ne_bytes(msg[4..6].try_into().unwrap()), 16);
+        assert_eq!(
+            u16::from_ne_bytes(msg[6..8].try_into().unwrap()),
+            NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE
+        );
+        assert_eq!(u32::from_ne_bytes(msg[8..12].try_into().unwrap()), 42);
+        assert_eq!(i32::from_ne_bytes(msg[20..24].try_into().unwrap()), 7);
+        // IFLA_IFNAME "br0\0"
+        assert_eq!(msg[32..40], [8, 0, 3, 0, b'b', b'r', b'0', 0]);
+        // IFLA_LINKINFO nesting IFLA_INFO_KIND "bridge\0" padded to 12 bytes
+        assert_eq!(msg[40..44], [16, 0, 18, 0x80]);
+        assert_eq!(msg[44..48], [11, 0, 1, 0]);
+        assert_eq!(&msg[48..55], b"bridge\0");
+    }
+}
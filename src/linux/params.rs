@@ -1,14 +1,20 @@
+use crate::net::Route;
 use mac_address::MacAddress;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
 /// Represents parameters for creating a new Tun/Tap device on Linux.
 #[cfg(target_os = "linux")]
+#[derive(Debug)]
 pub struct Params {
     pub name: Option<String>,
     pub flags: i16,
     pub persist: bool,
     pub up: bool,
     pub mtu: Option<i32>,
+    pub txqueuelen: Option<u32>,
+    pub link_type: Option<u16>,
+    pub sndbuf: Option<i32>,
+    pub backpressure: bool,
     pub owner: Option<i32>,
     pub group: Option<i32>,
     pub address: Option<Ipv4Addr>,
@@ -16,4 +22,7 @@ pub struct Params {
     pub broadcast: Option<Ipv4Addr>,
     pub netmask: Option<Ipv4Addr>,
     pub mac: Option<MacAddress>,
+    pub ipv6: Vec<(Ipv6Addr, u8)>,
+    pub routes: Vec<Route>,
+    pub master: Option<String>,
 }
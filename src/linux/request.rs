@@ -57,15 +57,96 @@ pub struct ifmap {
     pub port: ::std::os::raw::c_uchar,
 }
 
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct rtentry {
+    pub rt_pad1: ::std::os::raw::c_ulong,
+    pub rt_dst: sockaddr,
+    pub rt_gateway: sockaddr,
+    pub rt_genmask: sockaddr,
+    pub rt_flags: ::std::os::raw::c_ushort,
+    pub rt_pad2: ::std::os::raw::c_short,
+    pub rt_pad3: ::std::os::raw::c_ulong,
+    pub rt_pad4: *mut ::std::os::raw::c_void,
+    pub rt_metric: ::std::os::raw::c_short,
+    pub rt_dev: *mut ::std::os::raw::c_char,
+    pub rt_mtu: ::std::os::raw::c_ulong,
+    pub rt_window: ::std::os::raw::c_ulong,
+    pub rt_irtt: ::std::os::raw::c_ushort,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct in6_rtmsg {
+    pub rtmsg_dst: [u8; 16usize],
+    pub rtmsg_src: [u8; 16usize],
+    pub rtmsg_gateway: [u8; 16usize],
+    pub rtmsg_type: u32,
+    pub rtmsg_dst_len: u16,
+    pub rtmsg_src_len: u16,
+    pub rtmsg_metric: u32,
+    pub rtmsg_info: ::std::os::raw::c_ulong,
+    pub rtmsg_flags: u32,
+    pub rtmsg_ifindex: ::std::os::raw::c_int,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct in6_ifreq {
+    pub ifr6_addr: [u8; 16usize],
+    pub ifr6_prefixlen: u32,
+    pub ifr6_ifindex: ::std::os::raw::c_int,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct vhost_vring_state {
+    pub index: ::std::os::raw::c_uint,
+    pub num: ::std::os::raw::c_uint,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct vhost_vring_file {
+    pub index: ::std::os::raw::c_uint,
+    pub fd: ::std::os::raw::c_int,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct vhost_vring_addr {
+    pub index: ::std::os::raw::c_uint,
+    pub flags: ::std::os::raw::c_uint,
+    pub desc_user_addr: u64,
+    pub used_user_addr: u64,
+    pub avail_user_addr: u64,
+    pub log_guest_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct vhost_memory_region {
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    pub userspace_addr: u64,
+    pub flags_padding: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct vhost_memory {
+    pub nregions: u32,
+    pub padding: u32,
+    pub regions: [vhost_memory_region; 0usize],
+}
+
 impl ifreq {
     pub fn new(name: &str) -> Self {
         let mut req: ifreq = unsafe { mem::zeroed() };
         if !name.is_empty() {
             let mut ifname: [i8; IFNAMSIZ as _] = [0; IFNAMSIZ as _];
-            for (i, c) in name.as_bytes().iter().enumerate() {
-                if i > ifname.len() - 1 {
-                    break;
-                }
+            // The name is truncated to leave room for the terminating NUL.
+            for (i, c) in name.as_bytes().iter().take(ifname.len() - 1).enumerate() {
                 ifname[i] = *c as _;
             }
             req.ifr_ifrn.ifrn_name = ifname;
@@ -84,3 +165,50 @@ impl ifreq {
         name
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_name() {
+        let req = ifreq::new("");
+        assert_eq!(req.name(), "");
+        assert!(unsafe { req.ifr_ifrn.ifrn_name }.iter().all(|c| *c == 0));
+    }
+
+    #[test]
+    fn name_roundtrip() {
+        let req = ifreq::new("tun0");
+        assert_eq!(req.name(), "tun0");
+        let raw = unsafe { req.ifr_ifrn.ifrn_name };
+        assert_eq!(raw[..5], [b't' as _, b'u' as _, b'n' as _, b'0' as _, 0]);
+    }
+
+    #[test]
+    fn name_template() {
+        assert_eq!(ifreq::new("tun%d").name(), "tun%d");
+    }
+
+    #[test]
+    fn long_name_is_truncated() {
+        let req = ifreq::new("abcdefghijklmnopqrstuvwxyz");
+        assert_eq!(req.name(), "abcdefghijklmno");
+        assert_eq!(unsafe { req.ifr_ifrn.ifrn_name }[IFNAMSIZ as usize - 1], 0);
+    }
+
+    #[test]
+    fn union_sizes() {
+        assert_eq!(mem::size_of::<sockaddr>(), mem::size_of::<libc::sockaddr>());
+        assert_eq!(mem::size_of::<ifreq>(), 40);
+    }
+
+    #[test]
+    fn vhost_sizes() {
+        assert_eq!(mem::size_of::<vhost_vring_state>(), 8);
+        assert_eq!(mem::size_of::<vhost_vring_file>(), 8);
+        assert_eq!(mem::size_of::<vhost_vring_addr>(), 40);
+        assert_eq!(mem::size_of::<vhost_memory_region>(), 32);
+        assert_eq!(mem::size_of::<vhost_memory>(), 8);
+    }
+}
@@ -0,0 +1,65 @@
+use crate::net::Ipv6Cidr;
+use crate::result::Result;
+use nix::unistd::{Gid, Uid};
+use std::net::Ipv6Addr;
+use std::str::FromStr;
+
+/// Reads the attribute `attr` of the network interface `name` from `/sys/class/net`.
+pub fn read<T>(name: &str, attr: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let value = std::fs::read_to_string(format!("/sys/class/net/{}/{}", name, attr))?;
+    Ok(value.trim().parse()?)
+}
+
+/// Reads the `IFF_*` flags the tun/tap device `name` was created with.
+pub fn tun_flags(name: &str) -> Result<i32> {
+    let flags = read::<String>(name, "tun_flags")
+        .map_err(|_| format!("{} is not a tun/tap device", name))?;
+    Ok(i32::from_str_radix(flags.trim_start_matches("0x"), 16)?)
+}
+
+/// Returns the owner of the tun/tap device `name`, or `None` if any user may attach to it.
+pub fn owner(name: &str) -> Result<Option<Uid>> {
+    match read::<i64>(name, "owner")? {
+        -1 => Ok(None),
+        uid => Ok(Some(Uid::from_raw(uid as _))),
+    }
+}
+
+/// Returns the group of the tun/tap device `name`, or `None` if any group may attach to it.
+pub fn group(name: &str) -> Result<Option<Gid>> {
+    match read::<i64>(name, "group")? {
+        -1 => Ok(None),
+        gid => Ok(Some(Gid::from_raw(gid as _))),
+    }
+}
+
+/// Returns the name of the master of the network interface `name`, if any.
+pub fn master(name: &str) -> Result<Option<String>> {
+    match std::fs::read_link(format!("/sys/class/net/{}/master", name)) {
+        Ok(link) => Ok(link
+            .file_name()
+            .map(|master| master.to_string_lossy().into_owned())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Reads the IPv6 addresses and prefix lengths of the network interface `name` from `/proc/net/if_inet6`.
+pub fn ipv6_addresses(name: &str) -> Result<Vec<Ipv6Cidr>> {
+    let table = std::fs::read_to_string("/proc/net/if_inet6")?;
+    let mut addresses = Vec::new();
+    for line in table.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 || fields[5] != name {
+            continue;
+        }
+        let address = u128::from_str_radix(fields[0], 16)?;
+        let prefix = u8::from_str_radix(fields[2], 16)?;
+        addresses.push(Ipv6Cidr::new(Ipv6Addr::from_bits(address), prefix)?);
+    }
+    Ok(addresses)
+}
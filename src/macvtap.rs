@@ -0,0 +1,475 @@
+use crate::builder::{validate_name, MAX_MTU, MIN_MTU};
+use crate::config::ConfigError;
+use crate::linux::interface::Interface;
+use crate::linux::netlink::{
+    self, LinkRequest, Netlink, IFLA_ADDRESS, IFLA_IFNAME, IFLA_INFO_DATA, IFLA_INFO_KIND,
+    IFLA_IPVLAN_MODE, IFLA_LINK, IFLA_LINKINFO, IFLA_MACVLAN_MODE, IFLA_MTU, NLM_F_CREATE,
+    NLM_F_EXCL, RTM_NEWLINK,
+};
+use crate::result::Result;
+use crate::tun::Tun;
+use async_std::fs::OpenOptions;
+use async_std::os::unix::io::AsRawFd;
+use mac_address::MacAddress;
+use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use std::path::PathBuf;
+
+/// Represents how a macvtap device forwards frames to the other macvlan and macvtap devices of
+/// its parent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MacvtapMode {
+    /// Drops frames to the other devices of parent.
+    Private,
+    /// Sends frames to the other devices of parent through the external switch.
+    Vepa,
+    /// Forwards frames to the other devices of parent directly.
+    #[default]
+    Bridge,
+    /// Takes over parent, which then has a single device.
+    Passthru,
+}
+
+impl MacvtapMode {
+    fn raw(self) -> u32 {
+        match self {
+            MacvtapMode::Private => 1,
+            MacvtapMode::Vepa => 2,
+            MacvtapMode::Bridge => 4,
+            MacvtapMode::Passthru => 8,
+        }
+    }
+}
+
+/// Represents the layer at which an ipvtap device switches packets of its parent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IpvtapMode {
+    /// Switches frames by their destination address.
+    L2,
+    /// Routes packets by their destination address.
+    #[default]
+    L3,
+    /// Routes packets like `L3`, passing them through netfilter of the namespace of device.
+    L3s,
+}
+
+impl IpvtapMode {
+    fn raw(self) -> u16 {
+        match self {
+            IpvtapMode::L2 => 0,
+            IpvtapMode::L3 => 1,
+            IpvtapMode::L3s => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Macvtap(MacvtapMode),
+    Ipvtap(IpvtapMode),
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Macvtap(_) => "macvtap",
+            Kind::Ipvtap(_) => "ipvtap",
+        }
+    }
+}
+
+/// Represents a factory to build new instances of [`Tun`](struct.Tun.html) on a macvtap or
+/// ipvtap device, i.e. a link over a parent interface whose queues are `/dev/tapN`.
+///
+/// The queues are TAP queues without packet information. Unlike a TUN/TAP device, the link is
+/// not deleted by the kernel once its queues are closed, so it is deleted along with the last
+/// instance unless the device is [`persist`](#method.persist)ent. The send buffer is per queue
+/// and cannot be read back, so [`Tun::sndbuf`](struct.Tun.html#method.sndbuf) fails.
+#[derive(Debug, Clone)]
+pub struct MacvtapBuilder {
+    name: String,
+    parent: String,
+    kind: Kind,
+    vnet_hdr: bool,
+    persist: bool,
+    up: bool,
+    mtu: Option<i32>,
+    sndbuf: Option<u32>,
+    backpressure: bool,
+    mac: Option<MacAddress>,
+}
+
+impl Default for MacvtapBuilder {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            parent: String::new(),
+            kind: Kind::Macvtap(MacvtapMode::default()),
+            vnet_hdr: false,
+            persist: false,
+            up: false,
+            mtu: None,
+            sndbuf: None,
+            backpressure: false,
+            mac: None,
+        }
+    }
+}
+
+impl MacvtapBuilder {
+    /// Creates a new instance of [`MacvtapBuilder`](struct.MacvtapBuilder.html).
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the name of device (max length: 15 characters), which is required.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Sets the name of the parent interface of device, which is required.
+    pub fn parent(mut self, parent: impl Into<String>) -> Self {
+        self.parent = parent.into();
+        self
+    }
+
+    /// Creates a macvtap device in mode `mode`. Default value is a macvtap device in bridge mode.
+    pub fn mode(mut self, mode: MacvtapMode) -> Self {
+        self.kind = Kind::Macvtap(mode);
+        self
+    }
+
+    /// Creates an ipvtap device in mode `mode`, which shares the MAC address of parent.
+    pub fn ipvtap(mut self, mode: IpvtapMode) -> Self {
+        self.kind = Kind::Ipvtap(mode);
+        self
+    }
+
+    /// If `vnet_hdr` is true, then `IFF_VNET_HDR` flag is set and packets are prefixed with a `virtio_net_hdr`. Default value is `false`.
+    pub fn vnet_hdr(mut self, vnet_hdr: bool) -> Self {
+        self.vnet_hdr = vnet_hdr;
+        self
+    }
+
+    /// Sets the MTU of device.
+    pub fn mtu(mut self, mtu: i32) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Sets the size of the send buffer of each queue in bytes (`TUNSETSNDBUF`).
+    pub fn sndbuf(mut self, sndbuf: u32) -> Self {
+        self.sndbuf = Some(sndbuf);
+        self
+    }
+
+    /// If `backpressure` is true, then sends stay pending while the send buffer is full, see
+    /// [`TunBuilder::backpressure`](struct.TunBuilder.html#method.backpressure). Default value is `false`.
+    pub fn backpressure(mut self, backpressure: bool) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    /// Sets Ethernet MAC address of device (for macvtap).
+    pub fn mac(mut self, mac: MacAddress) -> Self {
+        self.mac = Some(mac);
+        self
+    }
+
+    /// Keeps the device once the last instance is dropped.
+    pub fn persist(mut self) -> Self {
+        self.persist = true;
+        self
+    }
+
+    /// Sets up the device.
+    pub fn up(mut self) -> Self {
+        self.up = true;
+        self
+    }
+
+    /// Checks the options and returns the first invalid one, which `try_build` would reject.
+    pub fn validate(&self) -> std::result::Result<(), ConfigError> {
+        for (field, name) in [("name", &self.name), ("parent", &self.parent)] {
+            if name.is_empty() {
+                return Err(ConfigError::new(field, "must not be empty"));
+            }
+            validate_name(field, name, false)?;
+        }
+        if self
+            .mtu
+            .is_some_and(|mtu| !(MIN_MTU..=MAX_MTU).contains(&mtu))
+        {
+            return Err(ConfigError::new(
+                "mtu",
+                format!("must be between {} and {}", MIN_MTU, MAX_MTU),
+            ));
+        }
+        if self
+            .sndbuf
+            .is_some_and(|sndbuf| sndbuf == 0 || sndbuf > i32::MAX as u32)
+        {
+            return Err(ConfigError::new(
+                "sndbuf",
+                format!("must be between 1 and {}", i32::MAX),
+            ));
+        }
+        if self.mac.is_some() && matches!(self.kind, Kind::Ipvtap(_)) {
+            return Err(ConfigError::new("mac", "requires macvtap"));
+        }
+        Ok(())
+    }
+
+    /// Builds a new instance of [`Tun`](struct.Tun.html).
+    pub async fn try_build(self) -> Result<Tun> {
+        Ok(self.try_build_mq(1).await?.remove(0))
+    }
+
+    /// Builds multiple instances of [`Tun`](struct.Tun.html), one for each queue of device.
+    pub async fn try_build_mq(self, queues: usize) -> Result<Vec<Tun>> {
+        self.validate()?;
+        if queues == 0 {
+            return Err(ConfigError::new("queues", "must be at least 1").into());
+        }
+        let mut request = LinkRequest::new(RTM_NEWLINK, NLM_F_CREATE | NLM_F_EXCL, 0)
+            .attr_str(IFLA_IFNAME, &self.name)
+            .attr_u32(IFLA_LINK, netlink::index(&self.parent)?);
+        if let Some(mac) = self.mac {
+            request = request.attr(IFLA_ADDRESS, &mac.bytes());
+        }
+        if let Some(mtu) = self.mtu {
+            request = request.attr_u32(IFLA_MTU, mtu as _);
+        }
+        request = request
+            .begin(IFLA_LINKINFO)
+            .attr_str(IFLA_INFO_KIND, self.kind.name())
+            .begin(IFLA_INFO_DATA);
+        request = match self.kind {
+            Kind::Macvtap(mode) => request.attr_u32(IFLA_MACVLAN_MODE, mode.raw()),
+            Kind::Ipvtap(mode) => request.attr_u16(IFLA_IPVLAN_MODE, mode.raw()),
+        };
+        Netlink::new()?.execute(request.end().end())?;
+        let index = netlink::index(&self.name)?;
+        let result = self.attach(index, queues).await;
+        if result.is_err() {
+            let _ = netlink::delete_link(index);
+        }
+        result
+    }
+
+    async fn attach(&self, index: u32, queues: usize) -> Result<Vec<Tun>> {
+        let node = self.node(index)?;
+        let mut files = Vec::with_capacity(queues);
+        for _ in 0..queues {
+            files.push(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&node.path)
+                    .await?,
+            );
+        }
+        drop(node);
+        let mut flags = libc::IFF_TAP as i16 | libc::IFF_NO_PI as i16;
+        if self.vnet_hdr {
+            flags |= libc::IFF_VNET_HDR as i16;
+        }
+        let mut iface = Interface::new(
+            files.iter().map(|file| file.as_raw_fd()).collect(),
+            &self.name,
+            flags,
+        )?;
+        if !self.persist {
+            iface.delete_on_drop(index);
+        }
+        if let Some(sndbuf) = self.sndbuf {
+            for file in files.iter() {
+                iface.sndbuf(file.as_raw_fd(), Some(sndbuf as _))?;
+            }
+        }
+        if self.up {
+            iface.flags(Some(libc::IFF_UP as i16 | libc::IFF_RUNNING as i16))?;
+        }
+        Tun::with_queues(files, iface, self.backpressure)
+    }
+
+    /// Returns the character device of the queues of device, i.e. `/dev/tapN` if udev created
+    /// it, or else a node of this process which is removed once the queues are open.
+    fn node(&self, index: u32) -> Result<Node> {
+        let path = PathBuf::from(format!("/dev/tap{}", index));
+        if path.exists() {
+            return Ok(Node {
+                path,
+                private: false,
+            });
+        }
+        let dev = std::fs::read_to_string(format!(
+            "/sys/class/net/{}/{}/tap{}/dev",
+            self.name,
+            self.kind.name(),
+            index
+        ))?;
+        let path = PathBuf::from(format!("/dev/.tap{}-{}", index, std::process::id()));
+        // A node left behind by a process which had the same id may have another number.
+        let _ = std::fs::remove_file(&path);
+        mknod(
+            &path,
+            SFlag::S_IFCHR,
+            Mode::S_IRUSR | Mode::S_IWUSR,
+            device_number(&dev)?,
+        )?;
+        Ok(Node {
+            path,
+            private: true,
+        })
+    }
+}
+
+/// Represents the character device of the queues of a device, removed on drop if it was
+/// created by this process.
+struct Node {
+    path: PathBuf,
+    private: bool,
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        if self.private {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Parses the `major:minor` device number read from sysfs.
+fn device_number(dev: &str) -> Result<u64> {
+    let (major, minor) = dev
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| format!("Invalid device number: {}", dev.trim()))?;
+    Ok(makedev(major.parse()?, minor.parse()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tun::tests::net_admin;
+    use async_std::task;
+    use std::path::Path;
+
+    fn field(builder: MacvtapBuilder) -> String {
+        builder.validate().unwrap_err().field().to_string()
+    }
+
+    #[test]
+    fn validate() {
+        let builder = MacvtapBuilder::new().name("mvtap0").parent("eth0");
+        assert!(builder.validate().is_ok());
+        assert_eq!(field(MacvtapBuilder::new().parent("eth0")), "name");
+        assert_eq!(field(MacvtapBuilder::new().name("mvtap0")), "parent");
+        assert_eq!(field(builder.clone().name("mvtap%d")), "name");
+        assert_eq!(field(builder.clone().mtu(65536)), "mtu");
+        assert_eq!(field(builder.clone().sndbuf(0)), "sndbuf");
+        let mac = MacAddress::new([2, 0, 0, 0, 0, 1]);
+        assert!(builder.clone().mac(mac).validate().is_ok());
+        assert_eq!(field(builder.ipvtap(IpvtapMode::L2).mac(mac)), "mac");
+    }
+
+    #[test]
+    fn modes() {
+        // MACVLAN_MODE_* and IPVLAN_MODE_* of the kernel.
+        let modes = [
+            MacvtapMode::Private,
+            MacvtapMode::Vepa,
+            MacvtapMode::Bridge,
+            MacvtapMode::Passthru,
+        ];
+        assert_eq!(modes.map(MacvtapMode::raw), [1, 2, 4, 8]);
+        let modes = [IpvtapMode::L2, IpvtapMode::L3, IpvtapMode::L3s];
+        assert_eq!(modes.map(IpvtapMode::raw), [0, 1, 2]);
+        assert_eq!(MacvtapMode::default(), MacvtapMode::Bridge);
+        assert_eq!(IpvtapMode::default(), IpvtapMode::L3);
+    }
+
+    #[test]
+    fn device_numbers() {
+        assert_eq!(device_number("241:3\n").unwrap(), makedev(241, 3));
+        assert!(device_number("241").is_err());
+        assert!(device_number("a:3").is_err());
+    }
+
+    #[test]
+    fn node() {
+        let path = std::env::temp_dir().join(format!("async-tun-node-{}", std::process::id()));
+        std::fs::write(&path, []).unwrap();
+        drop(Node {
+            path: path.clone(),
+            private: false,
+        });
+        assert!(path.exists());
+        drop(Node {
+            path: path.clone(),
+            private: true,
+        });
+        assert!(!path.exists());
+    }
+
+    /// Deletes the link of the given index once dropped.
+    struct Link(u32);
+
+    impl Drop for Link {
+        fn drop(&mut self) {
+            let _ = netlink::delete_link(self.0);
+        }
+    }
+
+    /// Returns `true` if `err` reports a kind of link the kernel was built without.
+    fn unsupported(err: &(dyn std::error::Error + 'static)) -> bool {
+        err.downcast_ref::<std::io::Error>()
+            .and_then(|err| err.raw_os_error())
+            == Some(libc::EOPNOTSUPP)
+    }
+
+    #[test]
+    fn dummy_parent() {
+        if !net_admin() {
+            return;
+        }
+        let request = LinkRequest::new(RTM_NEWLINK, NLM_F_CREATE | NLM_F_EXCL, 0)
+            .attr_str(IFLA_IFNAME, "mvdummy0")
+            .begin(IFLA_LINKINFO)
+            .attr_str(IFLA_INFO_KIND, "dummy")
+            .end();
+        if let Err(err) = Netlink::new().unwrap().execute(request) {
+            assert!(unsupported(&*err), "{}", err);
+            eprintln!("skipped: dummy links are not supported: {}", err);
+            return;
+        }
+        let _parent = Link(netlink::index("mvdummy0").unwrap());
+        let builders = [
+            MacvtapBuilder::new().name("mvtap0").parent("mvdummy0"),
+            MacvtapBuilder::new()
+                .name("ipvtap0")
+                .parent("mvdummy0")
+                .ipvtap(IpvtapMode::L2),
+        ];
+        for builder in builders {
+            let kind = builder.kind.name();
+            let tun = match task::block_on(builder.up().try_build()) {
+                Ok(tun) => tun,
+                Err(err) if unsupported(&*err) => {
+                    eprintln!("skipped: {} links are not supported: {}", kind, err);
+                    continue;
+                }
+                Err(err) => panic!("{}", err),
+            };
+            let index = netlink::index(tun.name()).unwrap();
+            assert!(Path::new(&format!("/sys/class/net/{}/{}", tun.name(), kind)).exists());
+            // The node opened by this process is gone once the queues are open.
+            let node = format!("/dev/.tap{}-{}", index, std::process::id());
+            assert!(!Path::new(&node).exists());
+            let name = tun.name().to_string();
+            drop(tun);
+            assert!(netlink::index(&name).is_err());
+        }
+    }
+}
@@ -0,0 +1,457 @@
+//! Export of device and queue counters through the [`metrics`](https://docs.rs/metrics) facade.
+//!
+//! Every metric is labelled with the `device` name and, for queue metrics, the `queue` index.
+
+use crate::device::{DeviceKind, Sending, TunDevice};
+use crate::result::Result;
+use crate::stats::{DeviceStats, QueueStats};
+use crate::tun::Tun;
+use ::metrics::{counter, histogram, Counter, Histogram};
+use async_std::sync::Arc;
+use async_std::task::{self, JoinHandle};
+use std::io;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Represents a [`TunDevice`](../trait.TunDevice.html) which reports the packets received from
+/// and sent to the inner device as metrics.
+///
+/// The latency of a send made through `send` is measured from the first poll of its future, that
+/// of a direct call to `poll_send` covers this call only.
+pub struct Metered<D> {
+    device: D,
+    packets_read: Counter,
+    packets_written: Counter,
+    bytes_read: Counter,
+    bytes_written: Counter,
+    read_errors: Counter,
+    write_errors: Counter,
+    read_size: Histogram,
+    write_latency: Histogram,
+}
+
+impl<D: TunDevice> Metered<D> {
+    /// Creates a new instance of [`Metered`](struct.Metered.html) reporting the traffic of `device` as queue `queue`.
+    pub fn new(device: D, queue: usize) -> Self {
+        let labels = [
+            ("device", device.name().to_string()),
+            ("queue", queue.to_string()),
+        ];
+        Self {
+            packets_read: counter!("tun_packets_read_total", &labels),
+            packets_written: counter!("tun_packets_written_total", &labels),
+            bytes_read: counter!("tun_bytes_read_total", &labels),
+            bytes_written: counter!("tun_bytes_written_total", &labels),
+            read_errors: counter!("tun_read_errors_total", &labels),
+            write_errors: counter!("tun_write_errors_total", &labels),
+            read_size: histogram!("tun_read_size_bytes", &labels),
+            write_latency: histogram!("tun_write_latency_seconds", &labels),
+            device,
+        }
+    }
+
+    /// Returns the underlying device.
+    pub fn get_ref(&self) -> &D {
+        &self.device
+    }
+
+    /// Returns the underlying device.
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+}
+
+impl<D: TunDevice> TunDevice for Metered<D> {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn mtu(&self) -> Result<i32> {
+        self.device.mtu()
+    }
+
+    fn kind(&self) -> DeviceKind {
+        self.device.kind()
+    }
+
+    fn packet_info(&self) -> bool {
+        self.device.packet_info()
+    }
+
+    fn header_len(&self) -> usize {
+        self.device.header_len()
+    }
+
+    fn poll_recv(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let result = self.device.poll_recv(cx, buf);
+        match &result {
+            Poll::Ready(Ok(n)) => {
+                self.packets_read.increment(1);
+                self.bytes_read.increment(*n as u64);
+                self.read_size.record(*n as f64);
+            }
+            Poll::Ready(Err(_)) => self.read_errors.increment(1),
+            Poll::Pending => {}
+        }
+        result
+    }
+
+    fn poll_send(&self, cx: &mut Context<'_>, packet: &[u8]) -> Poll<io::Result<usize>> {
+        let start = Sending::current().map_or_else(Instant::now, |sending| sending.started);
+        let result = self.device.poll_send(cx, packet);
+        match &result {
+            Poll::Ready(Ok(n)) => {
+                self.packets_written.increment(1);
+                self.bytes_written.increment(*n as u64);
+            }
+            Poll::Ready(Err(_)) => self.write_errors.increment(1),
+            Poll::Pending => return result,
+        }
+        self.write_latency.record(start.elapsed());
+        result
+    }
+}
+
+/// Reports the kernel interface counters of device `name`.
+pub fn record_device_stats(name: &str, stats: &DeviceStats) {
+    let labels = [("device", name.to_string())];
+    counter!("tun_kernel_rx_packets_total", &labels).absolute(stats.rx_packets);
+    counter!("tun_kernel_tx_packets_total", &labels).absolute(stats.tx_packets);
+    counter!("tun_kernel_rx_bytes_total", &labels).absolute(stats.rx_bytes);
+    counter!("tun_kernel_tx_bytes_total", &labels).absolute(stats.tx_bytes);
+    counter!("tun_kernel_rx_errors_total", &labels).absolute(stats.rx_errors);
+    counter!("tun_kernel_tx_errors_total", &labels).absolute(stats.tx_errors);
+    counter!("tun_kernel_rx_dropped_total", &labels).absolute(stats.rx_dropped);
+    counter!("tun_kernel_tx_dropped_total", &labels).absolute(stats.tx_dropped);
+}
+
+/// Reports the counters kept by this crate for queue `queue` of device `name`.
+pub fn record_queue_stats(name: &str, queue: usize, stats: &QueueStats) {
+    let labels = [("device", name.to_string()), ("queue", queue.to_string())];
+    counter!("tun_queue_packets_read_total", &labels).absolute(stats.packets_read);
+    counter!("tun_queue_packets_written_total", &labels).absolute(stats.packets_written);
+    counter!("tun_queue_bytes_read_total", &labels).absolute(stats.bytes_read);
+    counter!("tun_queue_bytes_written_total", &labels).absolute(stats.bytes_written);
+    counter!("tun_queue_short_writes_total", &labels).absolute(stats.short_writes);
+    counter!("tun_queue_would_block_total", &labels).absolute(stats.would_block);
+    counter!("tun_queue_oversize_total", &labels).absolute(stats.oversize);
+    counter!("tun_queue_errors_total", &labels).absolute(stats.errors);
+}
+
+impl Tun {
+    /// Reports the kernel counters of the device and the counters of the queue of this instance.
+    #[cfg(target_os = "linux")]
+    pub fn record_metrics(&self) -> Result<()> {
+        record_device_stats(self.name(), &self.stats()?);
+        record_queue_stats(self.name(), self.queue(), &self.queue_stats());
+        Ok(())
+    }
+}
+
+/// Spawns a task which reports the counters of `tuns` every `interval`, until the returned
+/// handle is cancelled. Devices which cannot be read, e.g. once deleted, are skipped.
+#[cfg(target_os = "linux")]
+pub fn spawn_exporter(tuns: Vec<Arc<Tun>>, interval: Duration) -> JoinHandle<()> {
+    task::spawn(async move {
+        loop {
+            for tun in tuns.iter() {
+                let _ = tun.record_metrics();
+            }
+            task::sleep(interval).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTun;
+    use ::metrics::{Gauge, HistogramFn, Key, KeyName, Metadata, Recorder, SharedString, Unit};
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::Mutex;
+    use std::task::Waker;
+    use std::thread;
+
+    /// Records counters and histogram samples by key.
+    #[derive(Default)]
+    struct Recorded {
+        counters: Mutex<HashMap<Key, Arc<AtomicU64>>>,
+        histograms: Mutex<HashMap<Key, Arc<Samples>>>,
+    }
+
+    #[derive(Default)]
+    struct Samples(Mutex<Vec<f64>>);
+
+    impl HistogramFn for Samples {
+        fn record(&self, value: f64) {
+            self.0.lock().unwrap().push(value);
+        }
+    }
+
+    impl Recorded {
+        fn counter(&self, name: &str, labels: &[(&str, &str)]) -> u64 {
+            let counters = self.counters.lock().unwrap();
+            let (_, value) = counters
+                .iter()
+                .find(|(key, _)| matches(key, name, labels))
+                .unwrap_or_else(|| panic!("{} is not registered", name));
+            value.load(Ordering::Relaxed)
+        }
+
+        fn samples(&self, name: &str, labels: &[(&str, &str)]) -> Vec<f64> {
+            let histograms = self.histograms.lock().unwrap();
+            let samples = histograms
+                .iter()
+                .find(|(key, _)| matches(key, name, labels))
+                .map(|(_, samples)| samples.clone())
+                .unwrap_or_else(|| panic!("{} is not registered", name));
+            let samples = samples.0.lock().unwrap();
+            samples.clone()
+        }
+    }
+
+    fn matches(key: &Key, name: &str, labels: &[(&str, &str)]) -> bool {
+        key.name() == name
+            && key
+                .labels()
+                .map(|label| (label.key(), label.value()))
+                .eq(labels.iter().copied())
+    }
+
+    impl Recorder for Recorded {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            let mut counters = self.counters.lock().unwrap();
+            Counter::from_arc(counters.entry(key.clone()).or_default().clone())
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+            let mut histograms = self.histograms.lock().unwrap();
+            Histogram::from_arc(histograms.entry(key.clone()).or_default().clone())
+        }
+    }
+
+    /// Fails every read, and every write after keeping the first one pending.
+    #[derive(Default)]
+    struct Failing {
+        pending: AtomicBool,
+    }
+
+    impl TunDevice for Failing {
+        fn name(&self) -> &str {
+            "fail0"
+        }
+
+        fn mtu(&self) -> Result<i32> {
+            Ok(1500)
+        }
+
+        fn kind(&self) -> DeviceKind {
+            DeviceKind::Tun
+        }
+
+        fn packet_info(&self) -> bool {
+            false
+        }
+
+        fn poll_recv(&self, _cx: &mut Context<'_>, _buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(Err(io::ErrorKind::Other.into()))
+        }
+
+        fn poll_send(&self, cx: &mut Context<'_>, _packet: &[u8]) -> Poll<io::Result<usize>> {
+            if !self.pending.swap(true, Ordering::Relaxed) {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+    }
+
+    /// Keeps every write pending until `open` is set.
+    #[derive(Default)]
+    struct Gated {
+        open: AtomicBool,
+    }
+
+    impl TunDevice for Gated {
+        fn name(&self) -> &str {
+            "gate0"
+        }
+
+        fn mtu(&self) -> Result<i32> {
+            Ok(1500)
+        }
+
+        fn kind(&self) -> DeviceKind {
+            DeviceKind::Tun
+        }
+
+        fn packet_info(&self) -> bool {
+            false
+        }
+
+        fn poll_recv(&self, _cx: &mut Context<'_>, _buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+
+        fn poll_send(&self, _cx: &mut Context<'_>, packet: &[u8]) -> Poll<io::Result<usize>> {
+            if self.open.load(Ordering::Relaxed) {
+                Poll::Ready(Ok(packet.len()))
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn metered() {
+        let recorder = Recorded::default();
+        let device = ::metrics::with_local_recorder(&recorder, || {
+            Metered::new(MockTun::new("mock0").with_packet_info(false), 1)
+        });
+        task::block_on(async {
+            let kernel = device.get_ref().kernel();
+            kernel.inject(&[0u8; 10]).await;
+            kernel.inject(&[0u8; 30]).await;
+            let mut buf = [0u8; 64];
+            assert_eq!(device.recv(&mut buf).await.unwrap(), 10);
+            assert_eq!(device.recv(&mut buf).await.unwrap(), 30);
+            device.send(&buf[..20]).await.unwrap();
+        });
+
+        let labels = [("device", "mock0"), ("queue", "1")];
+        assert_eq!(recorder.counter("tun_packets_read_total", &labels), 2);
+        assert_eq!(recorder.counter("tun_bytes_read_total", &labels), 40);
+        assert_eq!(recorder.counter("tun_packets_written_total", &labels), 1);
+        assert_eq!(recorder.counter("tun_bytes_written_total", &labels), 20);
+        assert_eq!(recorder.counter("tun_read_errors_total", &labels), 0);
+        assert_eq!(recorder.counter("tun_write_errors_total", &labels), 0);
+        assert_eq!(
+            recorder.samples("tun_read_size_bytes", &labels),
+            [10.0, 30.0]
+        );
+        assert_eq!(
+            recorder.samples("tun_write_latency_seconds", &labels).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn metered_errors() {
+        let recorder = Recorded::default();
+        let device =
+            ::metrics::with_local_recorder(&recorder, || Metered::new(Failing::default(), 0));
+        task::block_on(async {
+            let mut buf = [0u8; 64];
+            assert!(device.recv(&mut buf).await.is_err());
+            // The latency of a write covers the time it was pending.
+            assert!(device.send(&buf[..20]).await.is_err());
+        });
+
+        let labels = [("device", "fail0"), ("queue", "0")];
+        assert_eq!(recorder.counter("tun_read_errors_total", &labels), 1);
+        assert_eq!(recorder.counter("tun_write_errors_total", &labels), 1);
+        assert_eq!(recorder.counter("tun_packets_read_total", &labels), 0);
+        assert_eq!(recorder.counter("tun_packets_written_total", &labels), 0);
+        assert!(recorder.samples("tun_read_size_bytes", &labels).is_empty());
+        assert_eq!(
+            recorder.samples("tun_write_latency_seconds", &labels).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn concurrent_sends() {
+        let recorder = Recorded::default();
+        let device =
+            ::metrics::with_local_recorder(&recorder, || Metered::new(Gated::default(), 0));
+        let mut cx = Context::from_waker(Waker::noop());
+        let packet = [0u8; 20];
+        let mut first = device.send(&packet);
+        assert!(Pin::new(&mut first).poll(&mut cx).is_pending());
+        thread::sleep(Duration::from_millis(50));
+        let mut second = device.send(&packet);
+        assert!(Pin::new(&mut second).poll(&mut cx).is_pending());
+        device.get_ref().open.store(true, Ordering::Relaxed);
+        assert!(Pin::new(&mut second).poll(&mut cx).is_ready());
+        assert!(Pin::new(&mut first).poll(&mut cx).is_ready());
+
+        // Each send is timed from its own first poll.
+        let labels = [("device", "gate0"), ("queue", "0")];
+        let latencies = recorder.samples("tun_write_latency_seconds", &labels);
+        assert_eq!(latencies.len(), 2);
+        assert!(latencies[0] < 0.05);
+        assert!(latencies[1] >= 0.05);
+    }
+
+    #[test]
+    fn record_stats() {
+        let recorder = Recorded::default();
+        let device = DeviceStats {
+            rx_packets: 1,
+            tx_packets: 2,
+            rx_bytes: 3,
+            tx_bytes: 4,
+            rx_errors: 5,
+            tx_errors: 6,
+            rx_dropped: 7,
+            tx_dropped: 8,
+        };
+        let queue = QueueStats {
+            packets_read: 1,
+            packets_written: 2,
+            bytes_read: 3,
+            bytes_written: 4,
+            short_writes: 5,
+            would_block: 6,
+            oversize: 7,
+            errors: 8,
+        };
+        ::metrics::with_local_recorder(&recorder, || {
+            record_device_stats("tun0", &DeviceStats::default());
+            record_device_stats("tun0", &device);
+            record_queue_stats("tun0", 2, &queue);
+        });
+
+        let labels = [("device", "tun0")];
+        let names = [
+            "tun_kernel_rx_packets_total",
+            "tun_kernel_tx_packets_total",
+            "tun_kernel_rx_bytes_total",
+            "tun_kernel_tx_bytes_total",
+            "tun_kernel_rx_errors_total",
+            "tun_kernel_tx_errors_total",
+            "tun_kernel_rx_dropped_total",
+            "tun_kernel_tx_dropped_total",
+        ];
+        for (value, name) in (1..).zip(names) {
+            assert_eq!(recorder.counter(name, &labels), value, "{}", name);
+        }
+        let labels = [("device", "tun0"), ("queue", "2")];
+        let names = [
+            "tun_queue_packets_read_total",
+            "tun_queue_packets_written_total",
+            "tun_queue_bytes_read_total",
+            "tun_queue_bytes_written_total",
+            "tun_queue_short_writes_total",
+            "tun_queue_would_block_total",
+            "tun_queue_oversize_total",
+            "tun_queue_errors_total",
+        ];
+        for (value, name) in (1..).zip(names) {
+            assert_eq!(recorder.counter(name, &labels), value, "{}", name);
+        }
+    }
+}
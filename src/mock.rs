@@ -0,0 +1,373 @@
+use crate::device::{DeviceKind, TunDevice};
+use crate::result::Result;
+use async_std::channel::{self, Receiver, Sender};
+use async_std::io::{Read, Write};
+use async_std::stream::Stream;
+use mac_address::MacAddress;
+use std::io;
+use std::net::Ipv4Addr;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+
+/// Represents an in-memory Tun/Tap device for tests which cannot create real devices.
+///
+/// Packets injected through the [`MockKernel`](struct.MockKernel.html) handle are read by the
+/// application, and packets written by the application are received by the handle.
+pub struct MockTun {
+    name: String,
+    is_tap: bool,
+    packet_info: bool,
+    mtu: i32,
+    flags: i16,
+    address: Option<Ipv4Addr>,
+    destination: Option<Ipv4Addr>,
+    broadcast: Option<Ipv4Addr>,
+    netmask: Option<Ipv4Addr>,
+    mac: Option<MacAddress>,
+    inbound: (Sender<Vec<u8>>, Receiver<Vec<u8>>),
+    outbound: (Sender<Vec<u8>>, Receiver<Vec<u8>>),
+    device_reader: Mutex<MockReader>,
+}
+
+/// Represents the kernel side of a [`MockTun`](struct.MockTun.html).
+#[derive(Clone)]
+pub struct MockKernel {
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+}
+
+impl MockTun {
+    /// Creates a new instance of [`MockTun`](struct.MockTun.html) named `name`, with an MTU of 1500 and packet information enabled.
+    pub fn new(name: &str) -> Self {
+        let inbound = channel::unbounded();
+        let device_reader = Mutex::new(MockReader {
+            receiver: inbound.1.clone(),
+        });
+        Self {
+            name: name.into(),
+            is_tap: false,
+            packet_info: true,
+            mtu: 1500,
+            flags: (libc::IFF_UP | libc::IFF_RUNNING) as _,
+            address: None,
+            destination: None,
+            broadcast: None,
+            netmask: None,
+            mac: None,
+            inbound,
+            outbound: channel::unbounded(),
+            device_reader,
+        }
+    }
+
+    /// If `is_tap` is true, the device behaves as a TAP device. Default value is `false`.
+    pub fn with_tap(mut self, is_tap: bool) -> Self {
+        self.is_tap = is_tap;
+        self
+    }
+
+    /// Sets whether packets carry packet information. Default value is `true`.
+    pub fn with_packet_info(mut self, packet_info: bool) -> Self {
+        self.packet_info = packet_info;
+        self
+    }
+
+    /// Sets the MTU of device.
+    pub fn with_mtu(mut self, mtu: i32) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    /// Sets the flags of device.
+    pub fn with_flags(mut self, flags: i16) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Sets IPv4 address of device.
+    pub fn with_address(mut self, address: Ipv4Addr) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Sets IPv4 destination address of device.
+    pub fn with_destination(mut self, dst: Ipv4Addr) -> Self {
+        self.destination = Some(dst);
+        self
+    }
+
+    /// Sets IPv4 broadcast address of device.
+    pub fn with_broadcast(mut self, broadcast: Ipv4Addr) -> Self {
+        self.broadcast = Some(broadcast);
+        self
+    }
+
+    /// Sets IPv4 netmask address of device.
+    pub fn with_netmask(mut self, netmask: Ipv4Addr) -> Self {
+        self.netmask = Some(netmask);
+        self
+    }
+
+    /// Sets Ethernet MAC address of device.
+    pub fn with_mac(mut self, mac: MacAddress) -> Self {
+        self.mac = Some(mac);
+        self
+    }
+
+    /// Returns the kernel side of the device, to inject and receive packets.
+    pub fn kernel(&self) -> MockKernel {
+        MockKernel {
+            sender: self.inbound.0.clone(),
+            receiver: self.outbound.1.clone(),
+        }
+    }
+
+    /// Returns the name of Tun/Tap device.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns `true` if the device is a TAP device.
+    pub fn is_tap(&self) -> bool {
+        self.is_tap
+    }
+
+    /// Returns `true` if packets are prefixed with packet information.
+    pub fn packet_info(&self) -> bool {
+        self.packet_info
+    }
+
+    /// Returns the value of MTU.
+    pub fn mtu(&self) -> Result<i32> {
+        Ok(self.mtu)
+    }
+
+    /// Returns the IPv4 address of MTU.
+    pub fn address(&self) -> Result<Ipv4Addr> {
+        not_set(self.address)
+    }
+
+    /// Returns the IPv4 destination address of MTU.
+    pub fn destination(&self) -> Result<Ipv4Addr> {
+        not_set(self.destination)
+    }
+
+    /// Returns the IPv4 broadcast address of MTU.
+    pub fn broadcast(&self) -> Result<Ipv4Addr> {
+        not_set(self.broadcast)
+    }
+
+    /// Returns the IPv4 netmask address of MTU.
+    pub fn netmask(&self) -> Result<Ipv4Addr> {
+        not_set(self.netmask)
+    }
+
+    /// Returns to Ethernet MAC address.
+    pub fn mac(&self) -> Result<Option<MacAddress>> {
+        Ok(self.mac)
+    }
+
+    /// Returns the flags of MTU.
+    pub fn flags(&self) -> Result<i16> {
+        Ok(self.flags)
+    }
+
+    /// Splits self to reader and writer pairs.
+    pub fn split(&self) -> (MockReader, MockWriter) {
+        (self.reader(), self.writer())
+    }
+
+    /// Returns a reader to read from tun.
+    pub fn reader(&self) -> MockReader {
+        MockReader {
+            receiver: self.inbound.1.clone(),
+        }
+    }
+
+    /// Returns a writer to write to tun.
+    pub fn writer(&self) -> MockWriter {
+        MockWriter {
+            sender: self.outbound.0.clone(),
+        }
+    }
+}
+
+impl MockKernel {
+    /// Injects `packet` to be read by the application.
+    pub async fn inject(&self, packet: &[u8]) {
+        // The device holds the receiver, so sending never fails while it is alive.
+        let _ = self.sender.send(packet.to_vec()).await;
+    }
+
+    /// Waits for the next packet written by the application, returns `None` once the device
+    /// and all its writers are dropped.
+    pub async fn recv(&self) -> Option<Vec<u8>> {
+        self.receiver.recv().await.ok()
+    }
+
+    /// Returns the next packet written by the application, if any.
+    pub fn try_recv(&self) -> Option<Vec<u8>> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+/// Represents the reader of a [`MockTun`](struct.MockTun.html), returning one packet per read.
+pub struct MockReader {
+    receiver: Receiver<Vec<u8>>,
+}
+
+impl Read for MockReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.receiver).poll_next(cx) {
+            Poll::Ready(Some(packet)) => {
+                // Like the kernel, truncates packets which do not fit into the buffer.
+                let n = packet.len().min(buf.len());
+                buf[..n].copy_from_slice(&packet[..n]);
+                Poll::Ready(Ok(n))
+            }
+            Poll::Ready(None) => Poll::Ready(Ok(0)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Represents the writer of a [`MockTun`](struct.MockTun.html), sending one packet per write.
+pub struct MockWriter {
+    sender: Sender<Vec<u8>>,
+}
+
+impl Write for MockWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(send(&self.sender, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl TunDevice for MockTun {
+    fn name(&self) -> &str {
+        self.name()
+    }
+
+    fn mtu(&self) -> Result<i32> {
+        self.mtu()
+    }
+
+    fn kind(&self) -> DeviceKind {
+        if self.is_tap {
+            DeviceKind::Tap
+        } else {
+            DeviceKind::Tun
+        }
+    }
+
+    fn packet_info(&self) -> bool {
+        self.packet_info
+    }
+
+    fn poll_recv(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut reader = self.device_reader.lock().unwrap_or_else(|e| e.into_inner());
+        Pin::new(&mut *reader).poll_read(cx, buf)
+    }
+
+    fn poll_send(&self, _cx: &mut Context<'_>, packet: &[u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(send(&self.outbound.0, packet))
+    }
+}
+
+fn send(sender: &Sender<Vec<u8>>, packet: &[u8]) -> io::Result<usize> {
+    match sender.try_send(packet.to_vec()) {
+        Ok(()) => Ok(packet.len()),
+        Err(_) => Err(io::ErrorKind::BrokenPipe.into()),
+    }
+}
+
+fn not_set(address: Option<Ipv4Addr>) -> Result<Ipv4Addr> {
+    address.ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::prelude::*;
+    use async_std::task;
+
+    #[test]
+    fn getters() {
+        let tun = MockTun::new("mock0")
+            .with_tap(true)
+            .with_mtu(1400)
+            .with_address(Ipv4Addr::new(10, 0, 0, 1))
+            .with_netmask(Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(tun.name(), "mock0");
+        assert!(tun.is_tap());
+        assert!(tun.packet_info());
+        assert_eq!(tun.mtu().unwrap(), 1400);
+        assert_eq!(tun.address().unwrap(), Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(tun.netmask().unwrap(), Ipv4Addr::new(255, 255, 255, 0));
+        assert!(tun.destination().is_err());
+        assert_eq!(tun.mac().unwrap(), None);
+    }
+
+    #[test]
+    fn inject_and_read() {
+        task::block_on(async {
+            let tun = MockTun::new("mock0");
+            let kernel = tun.kernel();
+            kernel.inject(&[1, 2, 3]).await;
+            kernel.inject(&[4, 5, 6, 7, 8]).await;
+
+            let mut reader = tun.reader();
+            let mut buf = [0u8; 4];
+            assert_eq!(reader.read(&mut buf).await.unwrap(), 3);
+            assert_eq!(&buf[..3], &[1, 2, 3]);
+            assert_eq!(reader.read(&mut buf).await.unwrap(), 4);
+            assert_eq!(&buf, &[4, 5, 6, 7]);
+        })
+    }
+
+    #[test]
+    fn write_and_receive() {
+        task::block_on(async {
+            let tun = MockTun::new("mock0");
+            let kernel = tun.kernel();
+            let (_, mut writer) = tun.split();
+            writer.write_all(&[1, 2, 3]).await.unwrap();
+            writer.write_all(&[4]).await.unwrap();
+            assert_eq!(kernel.recv().await.unwrap(), vec![1, 2, 3]);
+            assert_eq!(kernel.try_recv().unwrap(), vec![4]);
+            assert!(kernel.try_recv().is_none());
+        })
+    }
+
+    #[test]
+    fn device() {
+        task::block_on(async {
+            let tun = MockTun::new("mock0").with_packet_info(false);
+            let kernel = tun.kernel();
+            assert_eq!(TunDevice::kind(&tun), DeviceKind::Tun);
+            assert_eq!(tun.header_len(), 0);
+
+            kernel.inject(&[1, 2, 3]).await;
+            let mut buf = [0u8; 16];
+            assert_eq!(tun.recv(&mut buf).await.unwrap(), 3);
+            assert_eq!(tun.send(&buf[..3]).await.unwrap(), 3);
+            assert_eq!(kernel.recv().await.unwrap(), vec![1, 2, 3]);
+        })
+    }
+}
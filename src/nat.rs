@@ -0,0 +1,919 @@
+//! A userspace NAT, which translates the addresses and ports of the packets forwarded between an
+//! inside and an outside network without netfilter.
+//!
+//! [`Nat`](struct.Nat.html) tracks the TCP, UDP and ICMP echo connections it translates, so
+//! replies and the ICMP errors about them are translated back. SNAT and masquerade rules apply to
+//! connections opened from the inside, DNAT rules to connections opened from the outside.
+//! [`NatRelay`](struct.NatRelay.html) couples two TUN devices through it:
+//!
+//! ```no_run
+//! # async fn run() -> async_tun::result::Result<()> {
+//! use async_std::sync::Arc;
+//! use async_tun::nat::{Nat, NatRelay};
+//! use async_tun::packet::protocol;
+//! use async_tun::TunBuilder;
+//!
+//! let inside = TunBuilder::new().name("inside0").packet_info(false).backpressure(true).up();
+//! let outside = TunBuilder::new().name("outside0").packet_info(false).backpressure(true).up();
+//! let nat = Nat::new()
+//!     .snat_ports("10.0.0.0/24".parse()?, "203.0.113.1".parse()?, 20000..=29999)
+//!     .dnat_port(protocol::TCP, "203.0.113.1:8080".parse()?, "10.0.0.2:80".parse()?);
+//! let relay = NatRelay::new(
+//!     Arc::new(inside.try_build().await?),
+//!     Arc::new(outside.try_build().await?),
+//!     nat,
+//! )?;
+//! relay.run().await
+//! # }
+//! ```
+
+use crate::device::{check_tun, TunDevice};
+use crate::flow::{embedded_layout, key, opens, Key, TcpState, Timeouts, Tuple};
+use crate::future::race;
+use crate::net::IpCidr;
+use crate::packet::{
+    checksum, ethertype, protocol, read_u16, write_u16, Icmpv4Packet, Icmpv6Packet, IpPacket,
+    PacketInfo, TcpFlags, TcpPacket, UdpPacket,
+};
+use crate::result::Result;
+use async_std::sync::Arc;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// The ports (or ICMP query identifiers) used when a source port is taken and the rule does not
+/// give a range.
+const DEFAULT_PORTS: RangeInclusive<u16> = 1024..=65535;
+/// The interval at which expired connections are dropped.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Represents the direction of a packet through a [`Nat`](struct.Nat.html).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the inside to the outside network.
+    Outbound,
+    /// From the outside to the inside network.
+    Inbound,
+}
+
+#[derive(Debug, Clone)]
+enum Rule {
+    /// Translates the source of outbound connections from `source`, to the masquerade address
+    /// if `to` is `None`.
+    Snat {
+        source: IpCidr,
+        to: Option<IpAddr>,
+        ports: Option<RangeInclusive<u16>>,
+    },
+    /// Translates the destination of inbound connections to `destination`.
+    Dnat {
+        protocol: Option<u8>,
+        destination: SocketAddr,
+        to: SocketAddr,
+        ports: bool,
+    },
+}
+
+/// Represents a tracked connection, keyed by its tuple on the inside.
+#[derive(Debug)]
+struct Conn {
+    /// The tuple of the outbound packets once translated.
+    outside: Tuple,
+    origin: Direction,
+    masquerade: bool,
+    state: TcpState,
+    last_seen: Instant,
+}
+
+/// Represents the translation rules and the connection tracking table of a userspace NAT.
+///
+/// Packets are IPv4 or IPv6 packets without packet information. Fragments are not reassembled:
+/// first fragments are translated like whole packets, while later fragments are only passed on
+/// if no rule applies to them.
+#[derive(Debug)]
+pub struct Nat {
+    rules: Vec<Rule>,
+    masquerade_v4: Option<Ipv4Addr>,
+    masquerade_v6: Option<Ipv6Addr>,
+    timeouts: Timeouts,
+    conns: HashMap<Tuple, Conn>,
+    /// Maps the outside tuples of the connections to their inside tuples.
+    outside: HashMap<Tuple, Tuple>,
+    next_port: u32,
+    last_sweep: Instant,
+}
+
+impl Default for Nat {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            masquerade_v4: None,
+            masquerade_v6: None,
+            timeouts: Default::default(),
+            conns: HashMap::new(),
+            outside: HashMap::new(),
+            next_port: 0,
+            last_sweep: Instant::now(),
+        }
+    }
+}
+
+impl Nat {
+    /// Creates a new instance of [`Nat`](struct.Nat.html) without rules, which passes every
+    /// packet unchanged.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Translates the source address of connections from `source` to `to`. Ports are kept
+    /// unless another connection already uses them, then one of 1024 to 65535 is used.
+    pub fn snat(mut self, source: IpCidr, to: IpAddr) -> Self {
+        self.rules.push(Rule::Snat {
+            source,
+            to: Some(to),
+            ports: None,
+        });
+        self
+    }
+
+    /// Translates the source of connections from `source` to `to` and a port of `ports`.
+    /// Source ports within `ports` are kept unless another connection already uses them.
+    pub fn snat_ports(mut self, source: IpCidr, to: IpAddr, ports: RangeInclusive<u16>) -> Self {
+        self.rules.push(Rule::Snat {
+            source,
+            to: Some(to),
+            ports: Some(ports),
+        });
+        self
+    }
+
+    /// Translates the source address of connections from `source` to the masquerade address of
+    /// their family, keeping ports like [`snat`](#method.snat). Packets are dropped until the
+    /// address is set with [`set_masquerade_addr`](#method.set_masquerade_addr).
+    pub fn masquerade(mut self, source: IpCidr) -> Self {
+        self.rules.push(Rule::Snat {
+            source,
+            to: None,
+            ports: None,
+        });
+        self
+    }
+
+    /// Translates the destination address of connections to `destination` to `to`, one to one.
+    pub fn dnat(mut self, destination: IpAddr, to: IpAddr) -> Self {
+        self.rules.push(Rule::Dnat {
+            protocol: None,
+            destination: SocketAddr::new(destination, 0),
+            to: SocketAddr::new(to, 0),
+            ports: false,
+        });
+        self
+    }
+
+    /// Forwards the TCP or UDP connections of `protocol` to `destination` to `to`.
+    pub fn dnat_port(mut self, protocol: u8, destination: SocketAddr, to: SocketAddr) -> Self {
+        self.rules.push(Rule::Dnat {
+            protocol: Some(protocol),
+            destination,
+            to,
+            ports: true,
+        });
+        self
+    }
+
+    /// Sets the time after which an idle established TCP connection is forgotten. Default value
+    /// is 2 hours.
+    pub fn tcp_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.tcp = timeout;
+        self
+    }
+
+    /// Sets the time after which an idle TCP connection is forgotten while it is being opened
+    /// or closed. Default value is 2 minutes.
+    pub fn tcp_transitory_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.tcp_transitory = timeout;
+        self
+    }
+
+    /// Sets the time after which an idle UDP connection is forgotten. Default value is 60
+    /// seconds.
+    pub fn udp_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.udp = timeout;
+        self
+    }
+
+    /// Sets the time after which an idle ICMP echo connection is forgotten. Default value is
+    /// 30 seconds.
+    pub fn icmp_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.icmp = timeout;
+        self
+    }
+
+    /// Sets the address of its family used by masquerade rules, e.g. the address of the outside
+    /// device. Connections masqueraded behind a previous address are forgotten.
+    pub fn set_masquerade_addr(&mut self, addr: IpAddr) {
+        let changed = match addr {
+            IpAddr::V4(addr) => self.masquerade_v4.replace(addr) != Some(addr),
+            IpAddr::V6(addr) => self.masquerade_v6.replace(addr) != Some(addr),
+        };
+        if changed {
+            let outside = &mut self.outside;
+            self.conns.retain(|_, conn| {
+                let stale = conn.masquerade && conn.outside.src.is_ipv4() == addr.is_ipv4();
+                if stale {
+                    outside.remove(&conn.outside);
+                }
+                !stale
+            });
+        }
+    }
+
+    /// Returns the number of tracked connections.
+    pub fn len(&self) -> usize {
+        self.conns.len()
+    }
+
+    /// Returns `true` if no connection is tracked.
+    pub fn is_empty(&self) -> bool {
+        self.conns.is_empty()
+    }
+
+    /// Translates `packet` travelling in `direction` in place, fixing its checksums. Returns
+    /// `false` if the packet must be dropped, e.g. as it is malformed or no port is left for it.
+    /// Packets no rule applies to are passed unchanged.
+    pub fn translate(&mut self, direction: Direction, packet: &mut [u8]) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.last_sweep) >= SWEEP_INTERVAL {
+            self.expire(now);
+        }
+        match key(packet) {
+            Some(Key::Query { tuple, flags }) => {
+                self.translate_query(direction, packet, tuple, flags, now)
+            }
+            Some(Key::Error { tuple, offset }) => {
+                self.translate_error(direction, packet, tuple, offset, now)
+            }
+            None => self.untracked(direction, packet),
+        }
+    }
+
+    fn translate_query(
+        &mut self,
+        direction: Direction,
+        packet: &mut [u8],
+        tuple: Tuple,
+        flags: TcpFlags,
+        now: Instant,
+    ) -> bool {
+        let (inside, to) = match direction {
+            Direction::Outbound => match self.lookup(tuple, now) {
+                Some(conn) => (tuple, conn.outside),
+                None => {
+                    if self.snat_rule(tuple.src.ip()).is_none() {
+                        return true;
+                    }
+                    if !opens(tuple.protocol, flags) {
+                        return false;
+                    }
+                    match self.open_outbound(tuple, now) {
+                        Some(outside) => (tuple, outside),
+                        None => return false,
+                    }
+                }
+            },
+            Direction::Inbound => match self.outside.get(&tuple.reverse()).copied() {
+                Some(inside) if self.lookup(inside, now).is_some() => (inside, inside.reverse()),
+                _ => {
+                    if !opens(tuple.protocol, flags) {
+                        return true;
+                    }
+                    match self.open_inbound(tuple, now) {
+                        Some(inside) => (inside, inside.reverse()),
+                        None => return true,
+                    }
+                }
+            },
+        };
+        let Some(conn) = self.conns.get_mut(&inside) else {
+            return false;
+        };
+        conn.last_seen = now;
+        if tuple.protocol == protocol::TCP {
+            conn.state = conn.state.next(flags, direction != conn.origin);
+        }
+        rewrite(packet, tuple, to)
+    }
+
+    fn translate_error(
+        &mut self,
+        direction: Direction,
+        packet: &mut [u8],
+        embedded: Tuple,
+        offset: usize,
+        now: Instant,
+    ) -> bool {
+        // The embedded packet travelled the opposite way of the error.
+        let (inside, to) = match direction {
+            Direction::Outbound => match self.lookup(embedded.reverse(), now) {
+                Some(conn) => (embedded.reverse(), conn.outside.reverse()),
+                None => return self.untracked(direction, packet),
+            },
+            Direction::Inbound => match self.outside.get(&embedded).copied() {
+                Some(inside) if self.lookup(inside, now).is_some() => (inside, inside),
+                _ => return self.untracked(direction, packet),
+            },
+        };
+        let Ok(mut ip) = IpPacket::new_checked(&mut *packet) else {
+            return false;
+        };
+        let translated = match direction {
+            Direction::Outbound => ip.set_src_addr(self.conns[&inside].outside.src.ip()),
+            Direction::Inbound => ip.set_dst_addr(inside.src.ip()),
+        };
+        if translated.is_err() || !rewrite_embedded(&mut packet[offset..], to) {
+            return false;
+        }
+        // The checksum of an error covers the embedded packet, whose own checksums changed with
+        // it, so it is computed again rather than updated.
+        match IpPacket::new_checked(&mut *packet) {
+            Ok(IpPacket::V4(mut ip)) => {
+                Icmpv4Packet::new_unchecked(ip.payload_mut()).fill_checksum()
+            }
+            Ok(IpPacket::V6(mut ip)) => {
+                let (src, dst) = (ip.src_addr(), ip.dst_addr());
+                match ip.upper_layer_payload_mut() {
+                    Ok(message) => Icmpv6Packet::new_unchecked(message).fill_checksum(src, dst),
+                    Err(_) => return false,
+                }
+            }
+            Err(_) => return false,
+        }
+        true
+    }
+
+    /// Decides on a packet which is not part of a connection: outbound packets from a source
+    /// subject to SNAT are dropped rather than leak its address, others are passed unchanged.
+    fn untracked(&self, direction: Direction, packet: &[u8]) -> bool {
+        match IpPacket::new_checked(packet) {
+            Ok(ip) => direction == Direction::Inbound || self.snat_rule(ip.src_addr()).is_none(),
+            Err(_) => false,
+        }
+    }
+
+    /// Returns the connection of `inside`, forgetting it if it expired.
+    fn lookup(&mut self, inside: Tuple, now: Instant) -> Option<&Conn> {
+        let conn = self.conns.get(&inside)?;
+        if now.duration_since(conn.last_seen) > self.timeouts.get(inside.protocol, conn.state) {
+            let conn = self.conns.remove(&inside)?;
+            self.outside.remove(&conn.outside);
+            return None;
+        }
+        self.conns.get(&inside)
+    }
+
+    fn snat_rule(&self, src: IpAddr) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .find(|rule| matches!(rule, Rule::Snat { source, .. } if source.contains(src)))
+    }
+
+    /// Tracks a new connection opened from the inside with `inside`, returning its outside tuple.
+    fn open_outbound(&mut self, inside: Tuple, now: Instant) -> Option<Tuple> {
+        let Some(Rule::Snat { to, ports, .. }) = self.snat_rule(inside.src.ip()).cloned() else {
+            return None;
+        };
+        let masquerade = to.is_none();
+        let to = match (to, inside.src.ip()) {
+            (Some(to), _) => to,
+            (None, IpAddr::V4(_)) => self.masquerade_v4?.into(),
+            (None, IpAddr::V6(_)) => self.masquerade_v6?.into(),
+        };
+        if to.is_ipv4() != inside.src.is_ipv4() {
+            return None;
+        }
+        let outside = self.allocate(inside, to, ports)?;
+        self.track(inside, outside, Direction::Outbound, masquerade, now);
+        Some(outside)
+    }
+
+    /// Tracks a new connection opened from the outside with `tuple`, returning its inside tuple.
+    fn open_inbound(&mut self, tuple: Tuple, now: Instant) -> Option<Tuple> {
+        let to = self.rules.iter().find_map(|rule| match rule {
+            Rule::Dnat {
+                protocol,
+                destination,
+                to,
+                ports,
+            } if destination.ip() == tuple.dst.ip()
+                && protocol.is_none_or(|protocol| protocol == tuple.protocol)
+                && (!ports || destination.port() == tuple.dst.port()) =>
+            {
+                let port = if *ports { to.port() } else { tuple.dst.port() };
+                Some(SocketAddr::new(to.ip(), port))
+            }
+            _ => None,
+        })?;
+        if to.is_ipv4() != tuple.src.is_ipv4() {
+            return None;
+        }
+        let inside = tuple.with_dst(to.ip(), to.port()).reverse();
+        if self.lookup(inside, now).is_some() {
+            return None;
+        }
+        self.track(inside, tuple.reverse(), Direction::Inbound, false, now);
+        Some(inside)
+    }
+
+    fn track(
+        &mut self,
+        inside: Tuple,
+        outside: Tuple,
+        origin: Direction,
+        masquerade: bool,
+        now: Instant,
+    ) {
+        self.outside.insert(outside, inside);
+        self.conns.insert(
+            inside,
+            Conn {
+                outside,
+                origin,
+                masquerade,
+                state: TcpState::Opening,
+                last_seen: now,
+            },
+        );
+    }
+
+    /// Returns the outside tuple of `inside` translated to `to` and a free port of `ports`,
+    /// keeping the source port if possible.
+    fn allocate(
+        &mut self,
+        inside: Tuple,
+        to: IpAddr,
+        ports: Option<RangeInclusive<u16>>,
+    ) -> Option<Tuple> {
+        let port = inside.src.port();
+        let candidate = inside.with_src(to, port);
+        let keep = ports.as_ref().is_none_or(|ports| ports.contains(&port));
+        if keep && !self.outside.contains_key(&candidate) {
+            return Some(candidate);
+        }
+        let ports = ports.unwrap_or(DEFAULT_PORTS);
+        let (start, end) = (*ports.start() as u32, *ports.end() as u32);
+        let len = end.checked_sub(start)? + 1;
+        for i in 0..len {
+            let port = (start + (self.next_port + i) % len) as u16;
+            let candidate = inside.with_src(to, port);
+            if !self.outside.contains_key(&candidate) {
+                self.next_port = self.next_port.wrapping_add(i + 1);
+                return Some(candidate);
+            }
+        }
+        None
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let (timeouts, outside) = (self.timeouts, &mut self.outside);
+        self.conns.retain(|inside, conn| {
+            let alive =
+                now.duration_since(conn.last_seen) <= timeouts.get(inside.protocol, conn.state);
+            if !alive {
+                outside.remove(&conn.outside);
+            }
+            alive
+        });
+        self.last_sweep = now;
+    }
+}
+
+/// Rewrites the endpoints of `packet` from `from` to `to`, updating its checksums incrementally.
+fn rewrite(packet: &mut [u8], from: Tuple, to: Tuple) -> bool {
+    let Ok(mut ip) = IpPacket::new_checked(packet) else {
+        return false;
+    };
+    if from.src.ip() != to.src.ip() && ip.set_src_addr(to.src.ip()).is_err() {
+        return false;
+    }
+    if from.dst.ip() != to.dst.ip() && ip.set_dst_addr(to.dst.ip()).is_err() {
+        return false;
+    }
+    let Ok(segment) = ip.transport_mut() else {
+        return false;
+    };
+    match to.protocol {
+        protocol::TCP => {
+            let mut segment = TcpPacket::new_unchecked(segment);
+            if from.src.port() != to.src.port() {
+                segment.set_src_port(to.src.port());
+            }
+            if from.dst.port() != to.dst.port() {
+                segment.set_dst_port(to.dst.port());
+            }
+        }
+        protocol::UDP => {
+            let mut datagram = UdpPacket::new_unchecked(segment);
+            if from.src.port() != to.src.port() {
+                datagram.set_src_port(to.src.port());
+            }
+            if from.dst.port() != to.dst.port() {
+                datagram.set_dst_port(to.dst.port());
+            }
+        }
+        protocol::ICMP if from.src.port() != to.src.port() => {
+            Icmpv4Packet::new_unchecked(segment).set_echo_ident(to.src.port())
+        }
+        protocol::ICMPV6 if from.src.port() != to.src.port() => {
+            Icmpv6Packet::new_unchecked(segment).set_echo_ident(to.src.port())
+        }
+        _ => {}
+    }
+    true
+}
+
+/// Rewrites the endpoints of a packet embedded in an ICMP error to `to`, updating
+/// the checksums of its headers as far as they are included.
+fn rewrite_embedded(inner: &mut [u8], to: Tuple) -> bool {
+    let Some((src, dst, len, header_len)) = embedded_layout(inner) else {
+        return false;
+    };
+    if inner.len() < header_len {
+        return false;
+    }
+    let mut old = Vec::new();
+    let mut new = Vec::new();
+    for (offset, addr) in [(src, to.src.ip()), (dst, to.dst.ip())] {
+        let octets = match addr {
+            IpAddr::V4(addr) => addr.octets().to_vec(),
+            IpAddr::V6(addr) => addr.octets().to_vec(),
+        };
+        if octets.len() != len {
+            return false;
+        }
+        old.extend_from_slice(&inner[offset..offset + len]);
+        new.extend_from_slice(&octets);
+        inner[offset..offset + len].copy_from_slice(&octets);
+    }
+    if len == 4 {
+        write_u16(inner, 10, checksum::update(read_u16(inner, 10), &old, &new));
+    }
+    let segment = &mut inner[header_len..];
+    let (ports, checksum_offset) = match to.protocol {
+        protocol::TCP => (vec![(0, to.src.port()), (2, to.dst.port())], 16),
+        protocol::UDP => (vec![(0, to.src.port()), (2, to.dst.port())], 6),
+        _ => (vec![(4, to.src.port())], 2),
+    };
+    // ICMPv4 has no pseudo header, so only the identifier counts.
+    if to.protocol == protocol::ICMP {
+        old.clear();
+        new.clear();
+    }
+    for (offset, port) in ports {
+        old.extend_from_slice(&read_u16(segment, offset).to_be_bytes());
+        new.extend_from_slice(&port.to_be_bytes());
+        write_u16(segment, offset, port);
+    }
+    if segment.len() >= checksum_offset + 2 {
+        let current = read_u16(segment, checksum_offset);
+        if to.protocol != protocol::UDP || current != 0 || len != 4 {
+            let updated = match checksum::update(current, &old, &new) {
+                0 if to.protocol == protocol::UDP => 0xffff,
+                updated => updated,
+            };
+            write_u16(segment, checksum_offset, updated);
+        }
+    }
+    true
+}
+
+/// Represents a relay forwarding packets between two TUN devices through a
+/// [`Nat`](struct.Nat.html).
+///
+/// Both devices must be TUN devices without vnet header, e.g. [`Tun`](../struct.Tun.html)s built
+/// with `tap(false)`; packet information is handled if present.
+pub struct NatRelay<I, O> {
+    inside: Arc<I>,
+    outside: Arc<O>,
+    nat: Mutex<Nat>,
+}
+
+impl<I, O> NatRelay<I, O>
+where
+    I: TunDevice + Send + Sync + 'static,
+    O: TunDevice + Send + Sync + 'static,
+{
+    /// Creates a new instance of [`NatRelay`](struct.NatRelay.html) translating the packets
+    /// between `inside` and `outside` with `nat`.
+    pub fn new(inside: Arc<I>, outside: Arc<O>, nat: Nat) -> Result<Self> {
+        check_tun(&*inside)?;
+        check_tun(&*outside)?;
+        Ok(Self {
+            inside,
+            outside,
+            nat: Mutex::new(nat),
+        })
+    }
+
+    /// Returns the NAT, e.g. to update its masquerade address once the outside device got a
+    /// new one.
+    pub fn nat(&self) -> MutexGuard<'_, Nat> {
+        self.nat.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Forwards packets in both directions until reading from either device fails.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(inside = self.inside.name(), outside = self.outside.name()), err))]
+    pub async fn run(&self) -> Result<()> {
+        race(
+            self.forward(&*self.inside, &*self.outside, Direction::Outbound),
+            self.forward(&*self.outside, &*self.inside, Direction::Inbound),
+        )
+        .await
+    }
+
+    async fn forward<A, B>(&self, from: &A, to: &B, direction: Direction) -> Result<()>
+    where
+        A: TunDevice,
+        B: TunDevice,
+    {
+        let mut buf = vec![0u8; from.header_len() + u16::MAX as usize];
+        let mut frame = Vec::with_capacity(to.header_len() + u16::MAX as usize);
+        loop {
+            let len = from.recv(&mut buf).await?;
+            if len == 0 {
+                return Ok(());
+            }
+            let Some(packet) = buf.get_mut(from.header_len()..len) else {
+                continue;
+            };
+            if !self.nat().translate(direction, packet) {
+                continue;
+            }
+            frame.clear();
+            if to.packet_info() {
+                frame.resize(PacketInfo::<&[u8]>::LEN, 0);
+                let protocol = match packet[0] >> 4 {
+                    6 => ethertype::IPV6,
+                    _ => ethertype::IPV4,
+                };
+                PacketInfo::new_unchecked(&mut frame[..]).set_protocol(protocol);
+            }
+            frame.extend_from_slice(packet);
+            // Packets which cannot be written are dropped, as a router does.
+            if let Err(_error) = to.send(&frame).await {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(name = to.name(), error = %_error, "dropped packet");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::tests::{addr, echo, endpoints, error, tcp, udp};
+    use crate::mock::MockTun;
+    use crate::packet::{icmp, Ipv4Packet};
+    use async_std::task;
+
+    fn translated(nat: &mut Nat, direction: Direction, mut packet: Vec<u8>) -> Option<Vec<u8>> {
+        nat.translate(direction, &mut packet).then_some(packet)
+    }
+
+    #[test]
+    fn snat_ports() {
+        let mut nat = Nat::new().snat_ports(
+            "10.0.0.0/24".parse().unwrap(),
+            "203.0.113.1".parse().unwrap(),
+            20000..=20001,
+        );
+        let outbound = |nat: &mut Nat, src| {
+            let packet = translated(nat, Direction::Outbound, udp(src, "192.0.2.1:53"));
+            packet.map(|packet| endpoints(&packet))
+        };
+        let server = addr("192.0.2.1:53");
+        assert_eq!(
+            outbound(&mut nat, "10.0.0.2:5000"),
+            Some((addr("203.0.113.1:20000"), server))
+        );
+        assert_eq!(
+            outbound(&mut nat, "10.0.0.2:5000"),
+            Some((addr("203.0.113.1:20000"), server))
+        );
+        assert_eq!(
+            outbound(&mut nat, "10.0.0.3:5000"),
+            Some((addr("203.0.113.1:20001"), server))
+        );
+        assert_eq!(outbound(&mut nat, "10.0.0.4:5000"), None);
+        assert_eq!(nat.len(), 2);
+
+        let reply = udp("192.0.2.1:53", "203.0.113.1:20001");
+        let reply = translated(&mut nat, Direction::Inbound, reply).unwrap();
+        assert_eq!(endpoints(&reply), (server, addr("10.0.0.3:5000")));
+        let unknown = udp("192.0.2.1:53", "203.0.113.1:30000");
+        assert_eq!(
+            translated(&mut nat, Direction::Inbound, unknown.clone()),
+            Some(unknown)
+        );
+
+        // Untracked packets from translated sources are dropped, others pass unchanged.
+        let reset = tcp("10.0.0.2:5000", "192.0.2.1:80", TcpFlags::RST);
+        assert_eq!(translated(&mut nat, Direction::Outbound, reset), None);
+        let other = udp("192.168.0.2:5000", "192.0.2.1:53");
+        assert_eq!(
+            translated(&mut nat, Direction::Outbound, other.clone()),
+            Some(other)
+        );
+    }
+
+    #[test]
+    fn snat() {
+        let mut nat = Nat::new().snat(
+            "10.0.0.0/24".parse().unwrap(),
+            "203.0.113.1".parse().unwrap(),
+        );
+        for (src, translated_src) in [
+            ("10.0.0.2:80", "203.0.113.1:80"),
+            ("10.0.0.3:80", "203.0.113.1:1024"),
+        ] {
+            let syn = tcp(src, "192.0.2.1:443", TcpFlags::SYN);
+            let syn = translated(&mut nat, Direction::Outbound, syn).unwrap();
+            assert_eq!(endpoints(&syn).0, addr(translated_src));
+        }
+    }
+
+    #[test]
+    fn dnat_port() {
+        let mut nat =
+            Nat::new().dnat_port(protocol::TCP, addr("203.0.113.1:8080"), addr("10.0.0.2:80"));
+        let client = addr("198.51.100.7:40000");
+        let syn = tcp("198.51.100.7:40000", "203.0.113.1:8080", TcpFlags::SYN);
+        let syn = translated(&mut nat, Direction::Inbound, syn).unwrap();
+        assert_eq!(endpoints(&syn), (client, addr("10.0.0.2:80")));
+
+        let syn_ack = tcp(
+            "10.0.0.2:80",
+            "198.51.100.7:40000",
+            TcpFlags::SYN | TcpFlags::ACK,
+        );
+        let syn_ack = translated(&mut nat, Direction::Outbound, syn_ack).unwrap();
+        assert_eq!(endpoints(&syn_ack), (addr("203.0.113.1:8080"), client));
+        assert_eq!(
+            nat.conns.values().next().unwrap().state,
+            TcpState::Established
+        );
+
+        let other = tcp("198.51.100.7:40000", "203.0.113.1:8081", TcpFlags::SYN);
+        assert_eq!(
+            translated(&mut nat, Direction::Inbound, other.clone()),
+            Some(other)
+        );
+    }
+
+    #[test]
+    fn masquerade() {
+        let mut nat = Nat::new()
+            .masquerade("10.0.0.0/24".parse().unwrap())
+            .masquerade("fd00::/64".parse().unwrap());
+        let request = echo(icmp::v4::ECHO_REQUEST, "10.0.0.2:7", "192.0.2.1:7");
+        assert_eq!(translated(&mut nat, Direction::Outbound, request), None);
+
+        nat.set_masquerade_addr("203.0.113.1".parse().unwrap());
+        nat.set_masquerade_addr("2001:db8::1".parse().unwrap());
+        for (src, translated_src) in [
+            ("10.0.0.2:7", "203.0.113.1:7"),
+            ("10.0.0.3:7", "203.0.113.1:1024"),
+        ] {
+            let request = echo(icmp::v4::ECHO_REQUEST, src, "192.0.2.1:7");
+            let request = translated(&mut nat, Direction::Outbound, request).unwrap();
+            assert_eq!(endpoints(&request).0, addr(translated_src));
+        }
+        let reply = echo(icmp::v4::ECHO_REPLY, "192.0.2.1:1024", "203.0.113.1:1024");
+        let reply = translated(&mut nat, Direction::Inbound, reply).unwrap();
+        assert_eq!(endpoints(&reply).1, addr("10.0.0.3:7"));
+
+        let request = echo(icmp::v6::ECHO_REQUEST, "[fd00::2]:9", "[2001:db8:1::1]:9");
+        let request = translated(&mut nat, Direction::Outbound, request).unwrap();
+        assert_eq!(endpoints(&request).0, addr("[2001:db8::1]:9"));
+        let reply = echo(icmp::v6::ECHO_REPLY, "[2001:db8:1::1]:9", "[2001:db8::1]:9");
+        let reply = translated(&mut nat, Direction::Inbound, reply).unwrap();
+        assert_eq!(endpoints(&reply).1, addr("[fd00::2]:9"));
+
+        assert_eq!(nat.len(), 3);
+        nat.set_masquerade_addr("203.0.113.2".parse().unwrap());
+        assert_eq!(nat.len(), 1);
+    }
+
+    #[test]
+    fn icmp_errors() {
+        let mut nat = Nat::new().snat_ports(
+            "10.0.0.0/24".parse().unwrap(),
+            "203.0.113.1".parse().unwrap(),
+            20000..=29999,
+        );
+        let request = udp("10.0.0.2:5000", "192.0.2.1:53");
+        let request = translated(&mut nat, Direction::Outbound, request).unwrap();
+        let server = "192.0.2.1".parse().unwrap();
+        let unreachable = error(
+            icmp::v4::DESTINATION_UNREACHABLE,
+            server,
+            "203.0.113.1".parse().unwrap(),
+            &request,
+        );
+        let unreachable = translated(&mut nat, Direction::Inbound, unreachable).unwrap();
+        let ip = Ipv4Packet::new_checked(&unreachable[..]).unwrap();
+        assert_eq!(ip.dst_addr(), Ipv4Addr::new(10, 0, 0, 2));
+        let message = Icmpv4Packet::new_checked(ip.payload()).unwrap();
+        assert!(message.verify_checksum());
+        assert_eq!(
+            endpoints(message.payload()),
+            (addr("10.0.0.2:5000"), addr("192.0.2.1:53"))
+        );
+
+        // Errors of the inside about connections forwarded to it are translated as well.
+        let mut nat = Nat::new().dnat_port(
+            protocol::UDP,
+            addr("[2001:db8::1]:53"),
+            addr("[fd00::2]:53"),
+        );
+        let query = udp("[2001:db8:1::7]:4000", "[2001:db8::1]:53");
+        let query = translated(&mut nat, Direction::Inbound, query).unwrap();
+        let client: IpAddr = "2001:db8:1::7".parse().unwrap();
+        let unreachable = error(
+            icmp::v6::DESTINATION_UNREACHABLE,
+            "fd00::2".parse().unwrap(),
+            client,
+            &query,
+        );
+        let unreachable = translated(&mut nat, Direction::Outbound, unreachable).unwrap();
+        let ip = IpPacket::new_checked(&unreachable[..]).unwrap();
+        let server: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(ip.src_addr(), server);
+        let message = Icmpv6Packet::new_checked(ip.transport().unwrap()).unwrap();
+        assert!(message.verify_checksum(
+            "2001:db8::1".parse().unwrap(),
+            "2001:db8:1::7".parse().unwrap()
+        ));
+        assert_eq!(
+            endpoints(message.payload()),
+            (addr("[2001:db8:1::7]:4000"), addr("[2001:db8::1]:53"))
+        );
+    }
+
+    #[test]
+    fn timeouts() {
+        let mut nat = Nat::new()
+            .snat_ports(
+                "10.0.0.0/24".parse().unwrap(),
+                "203.0.113.1".parse().unwrap(),
+                20000..=29999,
+            )
+            .udp_timeout(Duration::ZERO);
+        let request = udp("10.0.0.2:5000", "192.0.2.1:53");
+        assert!(translated(&mut nat, Direction::Outbound, request).is_some());
+        std::thread::sleep(Duration::from_millis(1));
+        let reply = udp("192.0.2.1:53", "203.0.113.1:20000");
+        assert_eq!(
+            translated(&mut nat, Direction::Inbound, reply.clone()),
+            Some(reply)
+        );
+        assert!(nat.is_empty());
+    }
+
+    #[test]
+    fn relay() {
+        task::block_on(async {
+            let inside = MockTun::new("inside0").with_packet_info(false);
+            let outside = MockTun::new("outside0");
+            let (inside_kernel, outside_kernel) = (inside.kernel(), outside.kernel());
+            let nat = Nat::new().snat_ports(
+                "10.0.0.0/24".parse().unwrap(),
+                "203.0.113.1".parse().unwrap(),
+                20000..=29999,
+            );
+            let relay = NatRelay::new(Arc::new(inside), Arc::new(outside), nat).unwrap();
+            task::spawn(async move { relay.run().await });
+
+            inside_kernel
+                .inject(&udp("10.0.0.2:5000", "192.0.2.1:53"))
+                .await;
+            let request = outside_kernel.recv().await.unwrap();
+            let info = PacketInfo::new_checked(&request[..]).unwrap();
+            assert_eq!(info.protocol(), ethertype::IPV4);
+            assert_eq!(
+                endpoints(info.payload()),
+                (addr("203.0.113.1:20000"), addr("192.0.2.1:53"))
+            );
+
+            let mut reply = vec![0, 0, 0x08, 0x00];
+            reply.extend_from_slice(&udp("192.0.2.1:53", "203.0.113.1:20000"));
+            outside_kernel.inject(&reply).await;
+            let reply = inside_kernel.recv().await.unwrap();
+            assert_eq!(
+                endpoints(&reply),
+                (addr("192.0.2.1:53"), addr("10.0.0.2:5000"))
+            );
+        });
+    }
+}
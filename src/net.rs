@@ -0,0 +1,244 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// Represents an error raised while parsing an address in CIDR notation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseCidrError {
+    /// The address part is not a valid IP address.
+    Address(String),
+    /// The prefix length is not a number or exceeds the length of the address.
+    Prefix(String),
+}
+
+impl fmt::Display for ParseCidrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Address(addr) => write!(f, "Invalid address: {}", addr),
+            Self::Prefix(prefix) => write!(f, "Invalid prefix length: {}", prefix),
+        }
+    }
+}
+
+impl std::error::Error for ParseCidrError {}
+
+macro_rules! cidr {
+    ($name:ident, $addr:ty, $bits:expr, $doc:literal) => {
+        #[doc = $doc]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub struct $name {
+            addr: $addr,
+            prefix: u8,
+        }
+
+        impl $name {
+            /// Creates a new instance from an address and a prefix length.
+            pub fn new(addr: $addr, prefix: u8) -> Result<Self, ParseCidrError> {
+                if prefix > $bits {
+                    return Err(ParseCidrError::Prefix(prefix.to_string()));
+                }
+                Ok(Self { addr, prefix })
+            }
+
+            /// Returns the address.
+            pub fn addr(&self) -> $addr {
+                self.addr
+            }
+
+            /// Returns the prefix length.
+            pub fn prefix(&self) -> u8 {
+                self.prefix
+            }
+
+            /// Returns the network mask of the prefix.
+            pub fn netmask(&self) -> $addr {
+                match self.prefix {
+                    0 => <$addr>::from_bits(0),
+                    prefix => <$addr>::from_bits(!0 << ($bits - prefix)),
+                }
+            }
+
+            /// Returns the network address, i.e. the address with host bits cleared.
+            pub fn network(&self) -> $addr {
+                <$addr>::from_bits(<$addr>::to_bits(self.addr) & <$addr>::to_bits(self.netmask()))
+            }
+
+            /// Returns `true` if `addr` belongs to the network.
+            pub fn contains(&self, addr: $addr) -> bool {
+                <$addr>::to_bits(addr) & <$addr>::to_bits(self.netmask())
+                    == <$addr>::to_bits(self.network())
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = ParseCidrError;
+
+            /// Parses `address/prefix`; a missing prefix denotes a single host.
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                let (addr, prefix) = match s.split_once('/') {
+                    Some((addr, prefix)) => (
+                        addr,
+                        prefix
+                            .parse()
+                            .map_err(|_| ParseCidrError::Prefix(prefix.into()))?,
+                    ),
+                    None => (s, $bits),
+                };
+                let addr = addr
+                    .parse()
+                    .map_err(|_| ParseCidrError::Address(addr.into()))?;
+                Self::new(addr, prefix)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}/{}", self.addr, self.prefix)
+            }
+        }
+    };
+}
+
+cidr!(
+    Ipv4Cidr,
+    Ipv4Addr,
+    32,
+    "Represents an IPv4 address with a prefix length, e.g. `10.0.0.1/24`."
+);
+cidr!(
+    Ipv6Cidr,
+    Ipv6Addr,
+    128,
+    "Represents an IPv6 address with a prefix length, e.g. `fd00::1/64`."
+);
+
+/// Represents an IPv4 or IPv6 address with a prefix length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IpCidr {
+    V4(Ipv4Cidr),
+    V6(Ipv6Cidr),
+}
+
+impl IpCidr {
+    /// Returns the address.
+    pub fn addr(&self) -> IpAddr {
+        match self {
+            Self::V4(cidr) => cidr.addr().into(),
+            Self::V6(cidr) => cidr.addr().into(),
+        }
+    }
+
+    /// Returns the prefix length.
+    pub fn prefix(&self) -> u8 {
+        match self {
+            Self::V4(cidr) => cidr.prefix(),
+            Self::V6(cidr) => cidr.prefix(),
+        }
+    }
+
+    /// Returns `true` if `addr` belongs to the network.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self, addr) {
+            (Self::V4(cidr), IpAddr::V4(addr)) => cidr.contains(addr),
+            (Self::V6(cidr), IpAddr::V6(addr)) => cidr.contains(addr),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = ParseCidrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains(':') {
+            s.parse().map(Self::V6)
+        } else {
+            s.parse().map(Self::V4)
+        }
+    }
+}
+
+impl fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::V4(cidr) => cidr.fmt(f),
+            Self::V6(cidr) => cidr.fmt(f),
+        }
+    }
+}
+
+impl From<Ipv4Cidr> for IpCidr {
+    fn from(cidr: Ipv4Cidr) -> Self {
+        Self::V4(cidr)
+    }
+}
+
+impl From<Ipv6Cidr> for IpCidr {
+    fn from(cidr: Ipv6Cidr) -> Self {
+        Self::V6(cidr)
+    }
+}
+
+#[cfg(feature = "serde")]
+macro_rules! serde_via_str {
+    ($($name:ident),*) => {
+        $(
+            impl serde::Serialize for $name {
+                fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    serializer.collect_str(self)
+                }
+            }
+
+            impl<'de> serde::Deserialize<'de> for $name {
+                fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                    let s = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+                    s.parse().map_err(serde::de::Error::custom)
+                }
+            }
+        )*
+    };
+}
+
+#[cfg(feature = "serde")]
+serde_via_str!(Ipv4Cidr, Ipv6Cidr, IpCidr);
+
+/// Represents a route through a device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
+pub struct Route {
+    /// The destination network, e.g. `0.0.0.0/0` for a default route.
+    pub destination: IpCidr,
+    /// The next hop, if the destination is not directly reachable through the device.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub gateway: Option<IpAddr>,
+    /// The metric of the route; zero selects the default of the kernel.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub metric: u32,
+}
+
+impl Route {
+    /// Creates a new instance of [`Route`](struct.Route.html) to `destination` without gateway.
+    pub fn new(destination: impl Into<IpCidr>) -> Self {
+        Self {
+            destination: destination.into(),
+            gateway: None,
+            metric: 0,
+        }
+    }
+
+    /// Sets the next hop of the route.
+    pub fn gateway(mut self, gateway: IpAddr) -> Self {
+        self.gateway = Some(gateway);
+        self
+    }
+
+    /// Sets the metric of the route.
+    pub fn metric(mut self, metric: u32) -> Self {
+        self.metric = metric;
+        self
+    }
+}
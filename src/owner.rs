@@ -0,0 +1,165 @@
+use crate::config::ConfigError;
+use nix::unistd::{Gid, Group as GroupEntry, Uid, User};
+use std::convert::Infallible;
+use std::fmt;
+use std::str::FromStr;
+
+macro_rules! principal {
+    ($name:ident, $id:ident, $entry:ident, $id_field:ident, $field:literal, $what:literal, $doc:literal) => {
+        #[doc = $doc]
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        pub enum $name {
+            /// A numeric id.
+            Id($id),
+            /// A name, resolved through the system database when the device is built.
+            Name(String),
+        }
+
+        impl $name {
+            /// Returns the numeric id, looking the name up if needed.
+            pub fn resolve(&self) -> Result<$id, ConfigError> {
+                match self {
+                    Self::Id(id) => Ok(*id),
+                    Self::Name(name) => match $entry::from_name(name) {
+                        Ok(Some(entry)) => Ok(entry.$id_field),
+                        Ok(None) => Err(ConfigError::new(
+                            $field,
+                            format!("unknown {} `{}`", $what, name),
+                        )),
+                        Err(err) => Err(ConfigError::new(
+                            $field,
+                            format!("cannot look up {} `{}`: {}", $what, name, err),
+                        )),
+                    },
+                }
+            }
+        }
+
+        impl From<$id> for $name {
+            fn from(id: $id) -> Self {
+                Self::Id(id)
+            }
+        }
+
+        impl From<i32> for $name {
+            fn from(id: i32) -> Self {
+                Self::Id($id::from_raw(id as _))
+            }
+        }
+
+        impl From<&str> for $name {
+            fn from(name: &str) -> Self {
+                Self::Name(name.into())
+            }
+        }
+
+        impl From<String> for $name {
+            fn from(name: String) -> Self {
+                Self::Name(name)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = Infallible;
+
+            /// Parses a numeric id, or takes `s` as a name otherwise.
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                Ok(match s.parse::<u32>() {
+                    Ok(id) => Self::Id($id::from_raw(id)),
+                    Err(_) => Self::Name(s.into()),
+                })
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    Self::Id(id) => id.fmt(f),
+                    Self::Name(name) => name.fmt(f),
+                }
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl serde::Serialize for $name {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                match self {
+                    Self::Id(id) => serializer.serialize_u32(id.as_raw()),
+                    Self::Name(name) => serializer.serialize_str(name),
+                }
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                struct Visitor;
+
+                impl<'de> serde::de::Visitor<'de> for Visitor {
+                    type Value = $name;
+
+                    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                        write!(f, "a numeric id or a {} name", $what)
+                    }
+
+                    fn visit_u64<E: serde::de::Error>(self, id: u64) -> Result<Self::Value, E> {
+                        u32::try_from(id)
+                            .map(|id| $name::Id($id::from_raw(id)))
+                            .map_err(|_| E::custom(format!("{} id out of range: {}", $what, id)))
+                    }
+
+                    fn visit_i64<E: serde::de::Error>(self, id: i64) -> Result<Self::Value, E> {
+                        u32::try_from(id)
+                            .map(|id| $name::Id($id::from_raw(id)))
+                            .map_err(|_| E::custom(format!("{} id out of range: {}", $what, id)))
+                    }
+
+                    fn visit_str<E: serde::de::Error>(self, name: &str) -> Result<Self::Value, E> {
+                        Ok($name::Name(name.into()))
+                    }
+                }
+
+                deserializer.deserialize_any(Visitor)
+            }
+        }
+    };
+}
+
+principal!(
+    Owner,
+    Uid,
+    User,
+    uid,
+    "owner",
+    "user",
+    "Represents the owner of a device, given as a numeric id or a user name."
+);
+principal!(
+    Group,
+    Gid,
+    GroupEntry,
+    gid,
+    "group",
+    "group",
+    "Represents the group of a device, given as a numeric id or a group name."
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!("1000".parse::<Owner>(), Ok(Owner::Id(Uid::from_raw(1000))));
+        assert_eq!("vpnsvc".parse::<Group>(), Ok(Group::Name("vpnsvc".into())));
+        assert_eq!(Owner::from(0), Owner::Id(Uid::from_raw(0)));
+    }
+
+    #[test]
+    fn resolve() {
+        assert_eq!(Owner::from("root").resolve(), Ok(Uid::from_raw(0)));
+        assert_eq!(Group::from("root").resolve(), Ok(Gid::from_raw(0)));
+        let err = Owner::from("no-such-user-here").resolve().unwrap_err();
+        assert_eq!(err.field(), "owner");
+    }
+}
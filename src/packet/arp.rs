@@ -0,0 +1,181 @@
+//! ARP packets (RFC 826) for IPv4 over Ethernet.
+
+use super::{ethertype, read_u16, write_u16, Error, Result};
+use mac_address::MacAddress;
+use std::net::Ipv4Addr;
+
+/// Length of an Ethernet/IPv4 ARP packet.
+pub const PACKET_LEN: usize = 28;
+
+/// Hardware type of Ethernet.
+pub const HTYPE_ETHERNET: u16 = 1;
+
+/// Operation of an ARP request.
+pub const REQUEST: u16 = 1;
+/// Operation of an ARP reply.
+pub const REPLY: u16 = 2;
+
+/// Represents a zero-copy view of an Ethernet/IPv4 ARP packet.
+#[derive(Debug, Clone)]
+pub struct ArpPacket<T> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> ArpPacket<T> {
+    /// Wraps `buffer` after checking its length and that it maps IPv4 to Ethernet addresses.
+    pub fn new_checked(buffer: T) -> Result<Self> {
+        let packet = Self { buffer };
+        if packet.buffer.as_ref().len() < PACKET_LEN {
+            return Err(Error::Truncated);
+        }
+        let buf = packet.buffer.as_ref();
+        if packet.hardware_type() != HTYPE_ETHERNET
+            || packet.protocol_type() != ethertype::IPV4
+            || buf[4] != 6
+            || buf[5] != 4
+        {
+            return Err(Error::Malformed);
+        }
+        Ok(packet)
+    }
+
+    /// Wraps `buffer` without any check.
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    /// Returns the wrapped buffer.
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+
+    /// Returns the hardware type.
+    pub fn hardware_type(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 0)
+    }
+
+    /// Returns the protocol type.
+    pub fn protocol_type(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 2)
+    }
+
+    /// Returns the operation (`REQUEST` or `REPLY`).
+    pub fn operation(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 6)
+    }
+
+    /// Returns the sender hardware address.
+    pub fn sender_hardware_addr(&self) -> MacAddress {
+        self.mac(8)
+    }
+
+    /// Returns the sender protocol address.
+    pub fn sender_protocol_addr(&self) -> Ipv4Addr {
+        self.ip(14)
+    }
+
+    /// Returns the target hardware address.
+    pub fn target_hardware_addr(&self) -> MacAddress {
+        self.mac(18)
+    }
+
+    /// Returns the target protocol address.
+    pub fn target_protocol_addr(&self) -> Ipv4Addr {
+        self.ip(24)
+    }
+
+    fn mac(&self, offset: usize) -> MacAddress {
+        let mut bytes = [0u8; 6];
+        bytes.copy_from_slice(&self.buffer.as_ref()[offset..offset + 6]);
+        MacAddress::new(bytes)
+    }
+
+    fn ip(&self, offset: usize) -> Ipv4Addr {
+        let buf = self.buffer.as_ref();
+        Ipv4Addr::new(
+            buf[offset],
+            buf[offset + 1],
+            buf[offset + 2],
+            buf[offset + 3],
+        )
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> ArpPacket<T> {
+    /// Writes the fixed fields of an Ethernet/IPv4 ARP packet with the given `operation`.
+    pub fn init(&mut self, operation: u16) {
+        let buf = self.buffer.as_mut();
+        write_u16(buf, 0, HTYPE_ETHERNET);
+        write_u16(buf, 2, ethertype::IPV4);
+        buf[4] = 6;
+        buf[5] = 4;
+        write_u16(buf, 6, operation);
+    }
+
+    /// Sets the sender hardware address.
+    pub fn set_sender_hardware_addr(&mut self, addr: MacAddress) {
+        self.buffer.as_mut()[8..14].copy_from_slice(&addr.bytes());
+    }
+
+    /// Sets the sender protocol address.
+    pub fn set_sender_protocol_addr(&mut self, addr: Ipv4Addr) {
+        self.buffer.as_mut()[14..18].copy_from_slice(&addr.octets());
+    }
+
+    /// Sets the target hardware address.
+    pub fn set_target_hardware_addr(&mut self, addr: MacAddress) {
+        self.buffer.as_mut()[18..24].copy_from_slice(&addr.bytes());
+    }
+
+    /// Sets the target protocol address.
+    pub fn set_target_protocol_addr(&mut self, addr: Ipv4Addr) {
+        self.buffer.as_mut()[24..28].copy_from_slice(&addr.octets());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_checked() {
+        let mut buf = [0u8; PACKET_LEN];
+        ArpPacket::new_unchecked(&mut buf[..]).init(REQUEST);
+        assert!(ArpPacket::new_checked(&buf[..]).is_ok());
+        assert_eq!(
+            ArpPacket::new_checked(&buf[..PACKET_LEN - 1]).unwrap_err(),
+            Error::Truncated
+        );
+        for (offset, value) in [(1, 6), (3, 0xdd), (4, 8), (5, 16)] {
+            let mut bad = buf;
+            bad[offset] = value;
+            assert_eq!(
+                ArpPacket::new_checked(&bad[..]).unwrap_err(),
+                Error::Malformed
+            );
+        }
+    }
+
+    #[test]
+    fn addresses() {
+        let mut buf = [0u8; PACKET_LEN];
+        let mut packet = ArpPacket::new_unchecked(&mut buf[..]);
+        packet.init(REPLY);
+        let (sha, tha) = (
+            MacAddress::new([2, 0, 0, 0, 0, 1]),
+            MacAddress::new([2, 0, 0, 0, 0, 2]),
+        );
+        packet.set_sender_hardware_addr(sha);
+        packet.set_sender_protocol_addr(Ipv4Addr::new(10, 0, 0, 1));
+        packet.set_target_hardware_addr(tha);
+        packet.set_target_protocol_addr(Ipv4Addr::new(10, 0, 0, 2));
+
+        let packet = ArpPacket::new_checked(&buf[..]).unwrap();
+        assert_eq!(packet.operation(), REPLY);
+        assert_eq!(packet.sender_hardware_addr(), sha);
+        assert_eq!(packet.sender_protocol_addr(), Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(packet.target_hardware_addr(), tha);
+        assert_eq!(packet.target_protocol_addr(), Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(buf[..8], [0, 1, 8, 0, 6, 4, 0, 2]);
+    }
+}
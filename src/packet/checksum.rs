@@ -0,0 +1,126 @@
+//! Internet checksum (RFC 1071) computation and incremental update (RFC 1624).
+
+use std::net::IpAddr;
+
+/// Returns the unfolded one's complement sum of `data`, padding an odd trailing byte with zero.
+pub fn sum(data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    let mut acc = chunks.by_ref().fold(0u32, |acc, word| {
+        acc + u16::from_be_bytes([word[0], word[1]]) as u32
+    });
+    if let [byte] = chunks.remainder() {
+        acc += (*byte as u32) << 8;
+    }
+    acc
+}
+
+/// Folds a 32-bit one's complement sum into 16 bits.
+pub fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Returns the Internet checksum of `data`.
+pub fn checksum(data: &[u8]) -> u16 {
+    !fold(sum(data))
+}
+
+/// Returns the unfolded sum of the pseudo header used by TCP, UDP and ICMPv6 checksums.
+pub fn pseudo_header(src: IpAddr, dst: IpAddr, protocol: u8, length: u32) -> u32 {
+    let addrs = match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => sum(&src.octets()) + sum(&dst.octets()),
+        (src, dst) => sum(&to_v6(src).octets()) + sum(&to_v6(dst).octets()),
+    };
+    addrs + (length >> 16) + (length & 0xffff) + protocol as u32
+}
+
+/// Returns the checksum of a transport `segment` including its pseudo header. The checksum
+/// field of `segment` is expected to be zero, or the result is zero for a valid segment.
+pub fn transport(src: IpAddr, dst: IpAddr, protocol: u8, segment: &[u8]) -> u16 {
+    !fold(pseudo_header(src, dst, protocol, segment.len() as u32) + sum(segment))
+}
+
+/// Returns `checksum` updated for a change of the covered bytes from `old` to `new`.
+/// Both slices must have the same, even, length.
+pub fn update(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    debug_assert_eq!(old.len(), new.len());
+    debug_assert_eq!(old.len() % 2, 0);
+    let mut acc = !checksum as u32;
+    for (old, new) in old.chunks_exact(2).zip(new.chunks_exact(2)) {
+        acc += !u16::from_be_bytes([old[0], old[1]]) as u32;
+        acc += u16::from_be_bytes([new[0], new[1]]) as u32;
+    }
+    !fold(acc)
+}
+
+fn to_v6(addr: IpAddr) -> std::net::Ipv6Addr {
+    match addr {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped(),
+        IpAddr::V6(addr) => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    /// Returns `len` pseudo-random bytes derived from `seed`.
+    fn bytes(seed: u32, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rfc1071() {
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(sum(&data), 0x2ddf0);
+        assert_eq!(fold(0x2ddf0), 0xddf2);
+        assert_eq!(checksum(&data), 0x220d);
+        // An odd trailing byte is padded with zero.
+        assert_eq!(sum(&[0x12, 0x34, 0x56]), 0x1234 + 0x5600);
+        assert_eq!(checksum(&[]), 0xffff);
+    }
+
+    #[test]
+    fn update_matches_recompute() {
+        for seed in 0..200 {
+            let mut data = bytes(seed, 40);
+            data[0] |= 1;
+            let before = checksum(&data);
+            let offset = (seed as usize % 9) * 4;
+            let len = if seed % 2 == 0 { 2 } else { 4 };
+            let old = data[offset..offset + len].to_vec();
+            let new = bytes(seed + 1000, len);
+            data[offset..offset + len].copy_from_slice(&new);
+            assert_eq!(update(before, &old, &new), checksum(&data), "seed {}", seed);
+        }
+        // Unchanged bytes leave the checksum as is.
+        assert_eq!(update(0x1234, &[1, 2], &[1, 2]), 0x1234);
+    }
+
+    #[test]
+    fn pseudo_headers() {
+        let (src, dst) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+        let expected = 0x0a00 + 0x0001 + 0x0a00 + 0x0002 + 17 + 8;
+        assert_eq!(pseudo_header(src.into(), dst.into(), 17, 8), expected);
+        // Lengths beyond 16 bits are split, as in the IPv6 pseudo header.
+        let (src, dst) = (Ipv6Addr::LOCALHOST, Ipv6Addr::LOCALHOST);
+        assert_eq!(
+            pseudo_header(src.into(), dst.into(), 6, 0x1_0002),
+            2 + 1 + 2 + 6
+        );
+
+        let mut segment = vec![0x03, 0xe8, 0, 53, 0, 12, 0, 0, 1, 2, 3, 4];
+        let checksum = transport(src.into(), dst.into(), 17, &segment);
+        segment[6..8].copy_from_slice(&checksum.to_be_bytes());
+        assert_eq!(transport(src.into(), dst.into(), 17, &segment), 0);
+    }
+}
@@ -0,0 +1,213 @@
+//! Ethernet II frames with optional 802.1Q/802.1ad VLAN tags, as read from TAP devices.
+
+use super::{ethertype, read_u16, write_u16, Error, Result};
+use mac_address::MacAddress;
+
+/// Length of an untagged Ethernet header.
+pub const HEADER_LEN: usize = 14;
+
+/// Length of a VLAN tag, including its EtherType.
+pub const VLAN_TAG_LEN: usize = 4;
+
+/// Represents an 802.1Q VLAN tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VlanTag {
+    /// The EtherType which introduced this tag (`VLAN` or `QINQ`).
+    pub tpid: u16,
+    /// The priority code point.
+    pub pcp: u8,
+    /// The drop eligible indicator.
+    pub dei: bool,
+    /// The VLAN identifier.
+    pub vid: u16,
+}
+
+/// Represents a zero-copy view of an Ethernet frame.
+#[derive(Debug, Clone)]
+pub struct EthernetFrame<T> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> EthernetFrame<T> {
+    /// Wraps `buffer` after checking that the header, including VLAN tags, fits into it.
+    pub fn new_checked(buffer: T) -> Result<Self> {
+        let frame = Self { buffer };
+        if frame.buffer.as_ref().len() < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+        frame.header_len()?;
+        Ok(frame)
+    }
+
+    /// Wraps `buffer` without any check.
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    /// Returns the wrapped buffer.
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+
+    /// Returns the destination MAC address.
+    pub fn dst_addr(&self) -> MacAddress {
+        self.mac(0)
+    }
+
+    /// Returns the source MAC address.
+    pub fn src_addr(&self) -> MacAddress {
+        self.mac(6)
+    }
+
+    /// Returns the EtherType following the addresses, which is `VLAN` for tagged frames.
+    pub fn ethertype(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 12)
+    }
+
+    /// Returns the VLAN tags of the frame, outermost first.
+    pub fn vlan_tags(&self) -> impl Iterator<Item = VlanTag> + '_ {
+        let buf = self.buffer.as_ref();
+        let mut offset = 12;
+        std::iter::from_fn(move || {
+            let tpid = read_u16(buf, offset);
+            if !is_vlan(tpid) || buf.len() < offset + VLAN_TAG_LEN + 2 {
+                return None;
+            }
+            let tci = read_u16(buf, offset + 2);
+            offset += VLAN_TAG_LEN;
+            Some(VlanTag {
+                tpid,
+                pcp: (tci >> 13) as u8,
+                dei: tci & 0x1000 != 0,
+                vid: tci & 0x0fff,
+            })
+        })
+    }
+
+    /// Returns the EtherType of the payload, following VLAN tags.
+    pub fn payload_ethertype(&self) -> u16 {
+        read_u16(
+            self.buffer.as_ref(),
+            self.header_len().unwrap_or(HEADER_LEN) - 2,
+        )
+    }
+
+    /// Returns the length of the header including VLAN tags, in bytes.
+    pub fn header_len(&self) -> Result<usize> {
+        let buf = self.buffer.as_ref();
+        let mut offset = 12;
+        while is_vlan(read_u16(buf, offset)) {
+            offset += VLAN_TAG_LEN;
+            if buf.len() < offset + 2 {
+                return Err(Error::Truncated);
+            }
+        }
+        Ok(offset + 2)
+    }
+
+    /// Returns the payload following the header and VLAN tags.
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer.as_ref()[self.header_len().unwrap_or(HEADER_LEN)..]
+    }
+
+    fn mac(&self, offset: usize) -> MacAddress {
+        let mut bytes = [0u8; 6];
+        bytes.copy_from_slice(&self.buffer.as_ref()[offset..offset + 6]);
+        MacAddress::new(bytes)
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> EthernetFrame<T> {
+    /// Sets the destination MAC address.
+    pub fn set_dst_addr(&mut self, addr: MacAddress) {
+        self.buffer.as_mut()[0..6].copy_from_slice(&addr.bytes());
+    }
+
+    /// Sets the source MAC address.
+    pub fn set_src_addr(&mut self, addr: MacAddress) {
+        self.buffer.as_mut()[6..12].copy_from_slice(&addr.bytes());
+    }
+
+    /// Sets the EtherType following the addresses.
+    pub fn set_ethertype(&mut self, ethertype: u16) {
+        write_u16(self.buffer.as_mut(), 12, ethertype)
+    }
+
+    /// Returns the mutable payload following the header and VLAN tags.
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let start = self.header_len().unwrap_or(HEADER_LEN);
+        &mut self.buffer.as_mut()[start..]
+    }
+}
+
+fn is_vlan(ethertype: u16) -> bool {
+    ethertype == ethertype::VLAN || ethertype == ethertype::QINQ
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a frame with the given `tags` in front of an IPv4 `payload`.
+    fn frame(tags: &[(u16, u16)], payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0xff; 6];
+        buf.extend_from_slice(&[2, 0, 0, 0, 0, 1]);
+        for (tpid, tci) in tags {
+            buf.extend_from_slice(&tpid.to_be_bytes());
+            buf.extend_from_slice(&tci.to_be_bytes());
+        }
+        buf.extend_from_slice(&ethertype::IPV4.to_be_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    #[test]
+    fn untagged() {
+        let buf = frame(&[], &[0x45]);
+        assert_eq!(
+            EthernetFrame::new_checked(&buf[..13]).unwrap_err(),
+            Error::Truncated
+        );
+        let frame = EthernetFrame::new_checked(&buf[..]).unwrap();
+        assert_eq!(frame.dst_addr(), MacAddress::new([0xff; 6]));
+        assert_eq!(frame.src_addr(), MacAddress::new([2, 0, 0, 0, 0, 1]));
+        assert_eq!(frame.ethertype(), ethertype::IPV4);
+        assert_eq!(frame.payload_ethertype(), ethertype::IPV4);
+        assert_eq!(frame.header_len(), Ok(HEADER_LEN));
+        assert_eq!(frame.vlan_tags().count(), 0);
+        assert_eq!(frame.payload(), [0x45]);
+    }
+
+    #[test]
+    fn tagged() {
+        let buf = frame(&[(ethertype::VLAN, 0xb00a)], &[0x45]);
+        let frame = EthernetFrame::new_checked(&buf[..]).unwrap();
+        assert_eq!(frame.ethertype(), ethertype::VLAN);
+        assert_eq!(frame.payload_ethertype(), ethertype::IPV4);
+        assert_eq!(frame.header_len(), Ok(18));
+        let tag = VlanTag {
+            tpid: ethertype::VLAN,
+            pcp: 5,
+            dei: true,
+            vid: 10,
+        };
+        assert_eq!(frame.vlan_tags().collect::<Vec<_>>(), [tag]);
+        assert_eq!(frame.payload(), [0x45]);
+    }
+
+    #[test]
+    fn qinq() {
+        let buf = frame(&[(ethertype::QINQ, 100), (ethertype::VLAN, 200)], &[0x45]);
+        let frame = EthernetFrame::new_checked(&buf[..]).unwrap();
+        let vids: Vec<_> = frame.vlan_tags().map(|tag| (tag.tpid, tag.vid)).collect();
+        assert_eq!(vids, [(ethertype::QINQ, 100), (ethertype::VLAN, 200)]);
+        assert_eq!(frame.header_len(), Ok(22));
+        assert_eq!(frame.payload(), [0x45]);
+
+        // A tag without the EtherType following it is truncated.
+        assert_eq!(
+            EthernetFrame::new_checked(&buf[..20]).unwrap_err(),
+            Error::Truncated
+        );
+    }
+}
@@ -0,0 +1,257 @@
+//! ICMPv4 (RFC 792) and ICMPv6 (RFC 4443) messages.
+
+use super::{checksum, read_u16, write_u16, Error, Result};
+use std::net::Ipv6Addr;
+
+/// Length of an ICMP header, including the message-specific rest of header.
+pub const HEADER_LEN: usize = 8;
+
+pub(crate) const CHECKSUM: usize = 2;
+
+/// Well-known ICMPv4 message types.
+pub mod v4 {
+    pub const ECHO_REPLY: u8 = 0;
+    pub const DESTINATION_UNREACHABLE: u8 = 3;
+    pub const REDIRECT: u8 = 5;
+    pub const ECHO_REQUEST: u8 = 8;
+    pub const TIME_EXCEEDED: u8 = 11;
+    pub const PARAMETER_PROBLEM: u8 = 12;
+    pub const TIMESTAMP: u8 = 13;
+    pub const TIMESTAMP_REPLY: u8 = 14;
+}
+
+/// Well-known ICMPv6 message types.
+pub mod v6 {
+    pub const DESTINATION_UNREACHABLE: u8 = 1;
+    pub const PACKET_TOO_BIG: u8 = 2;
+    pub const TIME_EXCEEDED: u8 = 3;
+    pub const PARAMETER_PROBLEM: u8 = 4;
+    pub const ECHO_REQUEST: u8 = 128;
+    pub const ECHO_REPLY: u8 = 129;
+    pub const ROUTER_SOLICITATION: u8 = 133;
+    pub const ROUTER_ADVERTISEMENT: u8 = 134;
+    pub const NEIGHBOR_SOLICITATION: u8 = 135;
+    pub const NEIGHBOR_ADVERTISEMENT: u8 = 136;
+}
+
+macro_rules! icmp_packet {
+    ($name:ident, $doc:literal) => {
+        #[doc = $doc]
+        #[derive(Debug, Clone)]
+        pub struct $name<T> {
+            buffer: T,
+        }
+
+        impl<T: AsRef<[u8]>> $name<T> {
+            /// Wraps `buffer` after checking that it holds a complete header.
+            pub fn new_checked(buffer: T) -> Result<Self> {
+                if buffer.as_ref().len() < HEADER_LEN {
+                    return Err(Error::Truncated);
+                }
+                Ok(Self { buffer })
+            }
+
+            /// Wraps `buffer` without any check.
+            pub fn new_unchecked(buffer: T) -> Self {
+                Self { buffer }
+            }
+
+            /// Returns the wrapped buffer.
+            pub fn into_inner(self) -> T {
+                self.buffer
+            }
+
+            /// Returns the message type.
+            pub fn msg_type(&self) -> u8 {
+                self.buffer.as_ref()[0]
+            }
+
+            /// Returns the message code.
+            pub fn msg_code(&self) -> u8 {
+                self.buffer.as_ref()[1]
+            }
+
+            /// Returns the checksum.
+            pub fn checksum(&self) -> u16 {
+                read_u16(self.buffer.as_ref(), CHECKSUM)
+            }
+
+            /// Returns the identifier of an echo request or reply.
+            pub fn echo_ident(&self) -> u16 {
+                read_u16(self.buffer.as_ref(), 4)
+            }
+
+            /// Returns the sequence number of an echo request or reply.
+            pub fn echo_seq_no(&self) -> u16 {
+                read_u16(self.buffer.as_ref(), 6)
+            }
+
+            /// Returns the message-specific four bytes following the checksum.
+            pub fn rest_of_header(&self) -> &[u8] {
+                &self.buffer.as_ref()[4..HEADER_LEN]
+            }
+
+            /// Returns the message body following the header, e.g. the echo data or the
+            /// invoking packet of an error message.
+            pub fn payload(&self) -> &[u8] {
+                &self.buffer.as_ref()[HEADER_LEN..]
+            }
+        }
+
+        impl<T: AsRef<[u8]> + AsMut<[u8]>> $name<T> {
+            /// Sets the message type. The checksum has to be filled afterwards.
+            pub fn set_msg_type(&mut self, msg_type: u8) {
+                self.buffer.as_mut()[0] = msg_type;
+            }
+
+            /// Sets the message code. The checksum has to be filled afterwards.
+            pub fn set_msg_code(&mut self, msg_code: u8) {
+                self.buffer.as_mut()[1] = msg_code;
+            }
+
+            /// Sets the checksum.
+            pub fn set_checksum(&mut self, checksum: u16) {
+                write_u16(self.buffer.as_mut(), CHECKSUM, checksum)
+            }
+
+            /// Sets the identifier of an echo request or reply, fixing the checksum
+            /// incrementally.
+            pub fn set_echo_ident(&mut self, ident: u16) {
+                let old = self.echo_ident();
+                write_u16(self.buffer.as_mut(), 4, ident);
+                let checksum =
+                    checksum::update(self.checksum(), &old.to_be_bytes(), &ident.to_be_bytes());
+                self.set_checksum(checksum);
+            }
+
+            /// Sets the sequence number of an echo request or reply, fixing the checksum
+            /// incrementally.
+            pub fn set_echo_seq_no(&mut self, seq_no: u16) {
+                let old = self.echo_seq_no();
+                write_u16(self.buffer.as_mut(), 6, seq_no);
+                let checksum =
+                    checksum::update(self.checksum(), &old.to_be_bytes(), &seq_no.to_be_bytes());
+                self.set_checksum(checksum);
+            }
+
+            /// Returns the mutable message-specific four bytes following the checksum.
+            pub fn rest_of_header_mut(&mut self) -> &mut [u8] {
+                &mut self.buffer.as_mut()[4..HEADER_LEN]
+            }
+
+            /// Returns the mutable message body following the header.
+            pub fn payload_mut(&mut self) -> &mut [u8] {
+                &mut self.buffer.as_mut()[HEADER_LEN..]
+            }
+        }
+    };
+}
+
+icmp_packet!(
+    Icmpv4Packet,
+    "Represents a zero-copy view of an ICMPv4 message."
+);
+icmp_packet!(
+    Icmpv6Packet,
+    "Represents a zero-copy view of an ICMPv6 message."
+);
+
+impl<T: AsRef<[u8]>> Icmpv4Packet<T> {
+    /// Returns `true` if the checksum is valid.
+    pub fn verify_checksum(&self) -> bool {
+        checksum::checksum(self.buffer.as_ref()) == 0
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Icmpv4Packet<T> {
+    /// Computes the checksum from scratch and stores it.
+    pub fn fill_checksum(&mut self) {
+        self.set_checksum(0);
+        let checksum = checksum::checksum(self.buffer.as_ref());
+        self.set_checksum(checksum);
+    }
+}
+
+impl<T: AsRef<[u8]>> Icmpv6Packet<T> {
+    /// Returns `true` if the checksum is valid for a message sent from `src` to `dst`.
+    pub fn verify_checksum(&self, src: Ipv6Addr, dst: Ipv6Addr) -> bool {
+        let protocol = super::protocol::ICMPV6;
+        checksum::transport(src.into(), dst.into(), protocol, self.buffer.as_ref()) == 0
+    }
+
+    /// Returns the target address of a neighbor solicitation or advertisement.
+    pub fn target_addr(&self) -> Result<Ipv6Addr> {
+        let buf = self.buffer.as_ref();
+        if buf.len() < HEADER_LEN + 16 {
+            return Err(Error::Truncated);
+        }
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(&buf[HEADER_LEN..HEADER_LEN + 16]);
+        Ok(octets.into())
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Icmpv6Packet<T> {
+    /// Computes the checksum from scratch for a message sent from `src` to `dst` and stores it.
+    pub fn fill_checksum(&mut self, src: Ipv6Addr, dst: Ipv6Addr) {
+        self.set_checksum(0);
+        let protocol = super::protocol::ICMPV6;
+        let checksum = checksum::transport(src.into(), dst.into(), protocol, self.buffer.as_ref());
+        self.set_checksum(checksum);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::tests::{DST6, SRC6};
+
+    #[test]
+    fn icmpv4() {
+        assert_eq!(
+            Icmpv4Packet::new_checked(&[v4::ECHO_REQUEST, 0, 0, 0][..]).unwrap_err(),
+            Error::Truncated
+        );
+        let mut buf = [
+            v4::ECHO_REQUEST,
+            0,
+            0,
+            0,
+            0x12,
+            0x34,
+            0,
+            1,
+            b'p',
+            b'i',
+            b'n',
+            b'g',
+            b'!',
+        ];
+        let mut packet = Icmpv4Packet::new_checked(&mut buf[..]).unwrap();
+        packet.fill_checksum();
+        assert!(packet.verify_checksum());
+        assert_eq!((packet.echo_ident(), packet.echo_seq_no()), (0x1234, 1));
+        assert_eq!(packet.payload(), b"ping!");
+
+        packet.set_echo_ident(0xfedc);
+        packet.set_echo_seq_no(0xffff);
+        assert!(packet.verify_checksum());
+        let checksum = packet.checksum();
+        packet.fill_checksum();
+        assert_eq!(packet.checksum(), checksum);
+    }
+
+    #[test]
+    fn icmpv6() {
+        let mut buf = [0u8; 24];
+        buf[0] = v6::NEIGHBOR_SOLICITATION;
+        buf[8..].copy_from_slice(&DST6.octets());
+        let mut packet = Icmpv6Packet::new_checked(&mut buf[..]).unwrap();
+        assert_eq!(packet.target_addr(), Ok(DST6));
+        packet.fill_checksum(SRC6, DST6);
+        assert!(packet.verify_checksum(SRC6, DST6));
+        assert!(!packet.verify_checksum(SRC6, Ipv6Addr::LOCALHOST));
+        let packet = Icmpv6Packet::new_checked(&buf[..23]).unwrap();
+        assert_eq!(packet.target_addr(), Err(Error::Truncated));
+    }
+}
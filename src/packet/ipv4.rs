@@ -0,0 +1,345 @@
+//! IPv4 packets (RFC 791).
+
+use super::{checksum, read_u16, update_pseudo_checksum, write_u16, Error, Result};
+use std::net::Ipv4Addr;
+
+/// Length of an IPv4 header without options.
+pub const HEADER_LEN: usize = 20;
+
+const CHECKSUM: usize = 10;
+const SRC_ADDR: usize = 12;
+const DST_ADDR: usize = 16;
+
+/// Flag which forbids fragmentation of the packet.
+pub const DONT_FRAGMENT: u16 = 0x4000;
+/// Flag which marks every fragment but the last one.
+pub const MORE_FRAGMENTS: u16 = 0x2000;
+
+/// Represents a zero-copy view of an IPv4 packet.
+#[derive(Debug, Clone)]
+pub struct Ipv4Packet<T> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> Ipv4Packet<T> {
+    /// Wraps `buffer` after checking the version and that the header and the total length
+    /// declared by it fit into the buffer.
+    pub fn new_checked(buffer: T) -> Result<Self> {
+        let packet = Self { buffer };
+        let len = packet.buffer.as_ref().len();
+        if len < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+        if packet.version() != 4 || packet.header_len() < HEADER_LEN {
+            return Err(Error::Malformed);
+        }
+        let total_len = packet.total_len() as usize;
+        if total_len < packet.header_len() {
+            return Err(Error::Malformed);
+        }
+        if len < total_len {
+            return Err(Error::Truncated);
+        }
+        Ok(packet)
+    }
+
+    /// Wraps `buffer` without any check.
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    /// Returns the wrapped buffer.
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+
+    /// Returns the version field.
+    pub fn version(&self) -> u8 {
+        self.buffer.as_ref()[0] >> 4
+    }
+
+    /// Returns the length of the header including options, in bytes.
+    pub fn header_len(&self) -> usize {
+        (self.buffer.as_ref()[0] & 0x0f) as usize * 4
+    }
+
+    /// Returns the differentiated services code point.
+    pub fn dscp(&self) -> u8 {
+        self.buffer.as_ref()[1] >> 2
+    }
+
+    /// Returns the explicit congestion notification bits.
+    pub fn ecn(&self) -> u8 {
+        self.buffer.as_ref()[1] & 0x03
+    }
+
+    /// Returns the total length of the packet, in bytes.
+    pub fn total_len(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 2)
+    }
+
+    /// Returns the identification field.
+    pub fn ident(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 4)
+    }
+
+    /// Returns the flags (`DONT_FRAGMENT`, `MORE_FRAGMENTS`) of the packet.
+    pub fn flags(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 6) & 0xe000
+    }
+
+    /// Returns the fragment offset, in bytes.
+    pub fn fragment_offset(&self) -> u16 {
+        (read_u16(self.buffer.as_ref(), 6) & 0x1fff) * 8
+    }
+
+    /// Returns the time to live.
+    pub fn ttl(&self) -> u8 {
+        self.buffer.as_ref()[8]
+    }
+
+    /// Returns the protocol of the payload.
+    pub fn protocol(&self) -> u8 {
+        self.buffer.as_ref()[9]
+    }
+
+    /// Returns the header checksum.
+    pub fn checksum(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), CHECKSUM)
+    }
+
+    /// Returns the source address.
+    pub fn src_addr(&self) -> Ipv4Addr {
+        let buf = self.buffer.as_ref();
+        Ipv4Addr::new(buf[12], buf[13], buf[14], buf[15])
+    }
+
+    /// Returns the destination address.
+    pub fn dst_addr(&self) -> Ipv4Addr {
+        let buf = self.buffer.as_ref();
+        Ipv4Addr::new(buf[16], buf[17], buf[18], buf[19])
+    }
+
+    /// Returns the raw options of the header.
+    pub fn options(&self) -> &[u8] {
+        &self.buffer.as_ref()[HEADER_LEN..self.header_len()]
+    }
+
+    /// Returns an iterator over the options of the header.
+    pub fn option_iter(&self) -> Options<'_> {
+        Options {
+            data: self.options(),
+        }
+    }
+
+    /// Returns the header including options.
+    pub fn header(&self) -> &[u8] {
+        &self.buffer.as_ref()[..self.header_len()]
+    }
+
+    /// Returns the payload, bounded by the total length of the packet.
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer.as_ref()[self.header_len()..self.total_len() as usize]
+    }
+
+    /// Returns `true` if the header checksum is valid.
+    pub fn verify_checksum(&self) -> bool {
+        checksum::checksum(self.header()) == 0
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Ipv4Packet<T> {
+    /// Sets the time to live, fixing the header checksum incrementally.
+    pub fn set_ttl(&mut self, ttl: u8) {
+        let old = [self.ttl(), self.protocol()];
+        self.buffer.as_mut()[8] = ttl;
+        self.update_checksum(&old, &[ttl, old[1]]);
+    }
+
+    /// Sets the source address, fixing the header checksum and the checksum of a TCP or UDP
+    /// payload incrementally.
+    pub fn set_src_addr(&mut self, addr: Ipv4Addr) {
+        self.set_addr(SRC_ADDR, addr)
+    }
+
+    /// Sets the destination address, fixing the header checksum and the checksum of a TCP or
+    /// UDP payload incrementally.
+    pub fn set_dst_addr(&mut self, addr: Ipv4Addr) {
+        self.set_addr(DST_ADDR, addr)
+    }
+
+    /// Sets the header checksum.
+    pub fn set_checksum(&mut self, checksum: u16) {
+        write_u16(self.buffer.as_mut(), CHECKSUM, checksum)
+    }
+
+    /// Computes the header checksum from scratch and stores it.
+    pub fn fill_checksum(&mut self) {
+        self.set_checksum(0);
+        let checksum = checksum::checksum(self.header());
+        self.set_checksum(checksum);
+    }
+
+    /// Returns the mutable payload, bounded by the total length of the packet.
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let (start, end) = (self.header_len(), self.total_len() as usize);
+        &mut self.buffer.as_mut()[start..end]
+    }
+
+    fn set_addr(&mut self, offset: usize, addr: Ipv4Addr) {
+        let mut old = [0u8; 4];
+        old.copy_from_slice(&self.buffer.as_ref()[offset..offset + 4]);
+        let new = addr.octets();
+        self.buffer.as_mut()[offset..offset + 4].copy_from_slice(&new);
+        self.update_checksum(&old, &new);
+        if self.fragment_offset() == 0 {
+            let protocol = self.protocol();
+            update_pseudo_checksum(self.payload_mut(), protocol, false, &old, &new);
+        }
+    }
+
+    fn update_checksum(&mut self, old: &[u8], new: &[u8]) {
+        let checksum = checksum::update(self.checksum(), old, new);
+        self.set_checksum(checksum);
+    }
+}
+
+/// Represents an option of an IPv4 header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Option<'a> {
+    /// The option type, including the copied flag and class.
+    pub kind: u8,
+    /// The option data, excluding the type and length octets.
+    pub data: &'a [u8],
+}
+
+/// Iterates over the options of an IPv4 header, stopping at the end of option list or at the
+/// first malformed option.
+#[derive(Debug, Clone)]
+pub struct Options<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = Result<Ipv4Option<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (&kind, rest) = self.data.split_first()?;
+            match kind {
+                // End of option list.
+                0 => {
+                    self.data = &[];
+                    return None;
+                }
+                // No operation.
+                1 => self.data = rest,
+                _ => {
+                    let len = match rest.first() {
+                        Some(&len) if len >= 2 && len as usize <= self.data.len() => len as usize,
+                        _ => {
+                            self.data = &[];
+                            return Some(Err(Error::Malformed));
+                        }
+                    };
+                    let data = &self.data[2..len];
+                    self.data = &self.data[len..];
+                    return Some(Ok(Ipv4Option { kind, data }));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::tests::{ipv4, udp, DST4, SRC4};
+    use crate::packet::{protocol, UdpPacket};
+
+    #[test]
+    fn new_checked() {
+        let packet = ipv4(protocol::UDP, &[0; 8]);
+        assert_eq!(
+            Ipv4Packet::new_checked(&packet[..19]).unwrap_err(),
+            Error::Truncated
+        );
+        assert_eq!(
+            Ipv4Packet::new_checked(&packet[..27]).unwrap_err(),
+            Error::Truncated
+        );
+        let mut bad = packet.clone();
+        bad[0] = 0x65;
+        assert_eq!(
+            Ipv4Packet::new_checked(&bad[..]).unwrap_err(),
+            Error::Malformed
+        );
+        bad[0] = 0x44;
+        assert_eq!(
+            Ipv4Packet::new_checked(&bad[..]).unwrap_err(),
+            Error::Malformed
+        );
+        // Options past the declared total length.
+        bad[0] = 0x48;
+        assert_eq!(
+            Ipv4Packet::new_checked(&bad[..]).unwrap_err(),
+            Error::Malformed
+        );
+
+        // Trailing padding is not part of the payload.
+        let mut padded = packet.clone();
+        padded.extend_from_slice(&[0xff; 4]);
+        let packet = Ipv4Packet::new_checked(&padded[..]).unwrap();
+        assert_eq!(packet.total_len(), 28);
+        assert_eq!(packet.payload(), [0; 8]);
+        assert_eq!((packet.src_addr(), packet.dst_addr()), (SRC4, DST4));
+        assert_eq!((packet.ident(), packet.ttl()), (0x1234, 64));
+        assert!(packet.verify_checksum());
+    }
+
+    #[test]
+    fn options() {
+        // NOP, record route with one address, NOP, end of list and padding.
+        let options = [1, 7, 7, 4, 192, 168, 0, 1, 1, 0, 0xff, 0xff];
+        let mut buf = ipv4(protocol::UDP, &options);
+        buf[0] = 0x48;
+        let packet = Ipv4Packet::new_checked(&buf[..]).unwrap();
+        assert_eq!(packet.header_len(), 32);
+        assert!(packet.payload().is_empty());
+        let options: Vec<_> = packet.option_iter().collect();
+        assert_eq!(
+            options,
+            [Ok(Ipv4Option {
+                kind: 7,
+                data: &[4, 192, 168, 0, 1]
+            })]
+        );
+
+        // A length shorter than the option itself, or longer than the options, is malformed and
+        // ends the iteration.
+        for options in [[1, 0x44, 1, 0], [0x44, 8, 0, 0]] {
+            let mut buf = ipv4(protocol::UDP, &options);
+            buf[0] = 0x46;
+            let packet = Ipv4Packet::new_checked(&buf[..]).unwrap();
+            let options: Vec<_> = packet.option_iter().collect();
+            assert_eq!(options, [Err(Error::Malformed)]);
+        }
+    }
+
+    #[test]
+    fn setters() {
+        let datagram = udp(SRC4.into(), DST4.into(), b"data");
+        let mut buf = ipv4(protocol::UDP, &datagram);
+        let mut packet = Ipv4Packet::new_checked(&mut buf[..]).unwrap();
+        packet.set_ttl(1);
+        let src = Ipv4Addr::new(192, 168, 1, 1);
+        packet.set_src_addr(src);
+        packet.set_dst_addr(Ipv4Addr::BROADCAST);
+        assert!(packet.verify_checksum());
+        let checksum = packet.checksum();
+        packet.fill_checksum();
+        assert_eq!(packet.checksum(), checksum);
+        let datagram = UdpPacket::new_checked(packet.payload()).unwrap();
+        assert!(datagram.verify_checksum(src.into(), Ipv4Addr::BROADCAST.into()));
+    }
+}
@@ -0,0 +1,342 @@
+//! IPv6 packets (RFC 8200) and their extension headers.
+
+use super::{protocol, read_u16, update_pseudo_checksum, write_u16, Error, Result};
+use std::net::Ipv6Addr;
+
+/// Length of the fixed IPv6 header.
+pub const HEADER_LEN: usize = 40;
+
+const SRC_ADDR: usize = 8;
+const DST_ADDR: usize = 24;
+
+/// Represents a zero-copy view of an IPv6 packet.
+#[derive(Debug, Clone)]
+pub struct Ipv6Packet<T> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> Ipv6Packet<T> {
+    /// Wraps `buffer` after checking the version and that the payload length declared by the
+    /// header fits into the buffer.
+    pub fn new_checked(buffer: T) -> Result<Self> {
+        let packet = Self { buffer };
+        let len = packet.buffer.as_ref().len();
+        if len < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+        if packet.version() != 6 {
+            return Err(Error::Malformed);
+        }
+        if len < HEADER_LEN + packet.payload_len() as usize {
+            return Err(Error::Truncated);
+        }
+        Ok(packet)
+    }
+
+    /// Wraps `buffer` without any check.
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    /// Returns the wrapped buffer.
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+
+    /// Returns the version field.
+    pub fn version(&self) -> u8 {
+        self.buffer.as_ref()[0] >> 4
+    }
+
+    /// Returns the traffic class.
+    pub fn traffic_class(&self) -> u8 {
+        (read_u16(self.buffer.as_ref(), 0) >> 4) as u8
+    }
+
+    /// Returns the flow label.
+    pub fn flow_label(&self) -> u32 {
+        let buf = self.buffer.as_ref();
+        ((buf[1] as u32 & 0x0f) << 16) | (buf[2] as u32) << 8 | buf[3] as u32
+    }
+
+    /// Returns the length of the payload including extension headers, in bytes.
+    pub fn payload_len(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 4)
+    }
+
+    /// Returns the type of the header following the fixed header.
+    pub fn next_header(&self) -> u8 {
+        self.buffer.as_ref()[6]
+    }
+
+    /// Returns the hop limit.
+    pub fn hop_limit(&self) -> u8 {
+        self.buffer.as_ref()[7]
+    }
+
+    /// Returns the source address.
+    pub fn src_addr(&self) -> Ipv6Addr {
+        self.addr(SRC_ADDR)
+    }
+
+    /// Returns the destination address.
+    pub fn dst_addr(&self) -> Ipv6Addr {
+        self.addr(DST_ADDR)
+    }
+
+    /// Returns the payload including extension headers, bounded by the payload length.
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer.as_ref()[HEADER_LEN..HEADER_LEN + self.payload_len() as usize]
+    }
+
+    /// Returns an iterator over the extension headers of the packet.
+    pub fn extension_headers(&self) -> ExtensionHeaders<'_> {
+        ExtensionHeaders {
+            next_header: self.next_header(),
+            data: self.payload(),
+            offset: HEADER_LEN,
+        }
+    }
+
+    /// Walks the extension headers and returns the upper-layer protocol together with the
+    /// offset of its header within the packet.
+    pub fn upper_layer(&self) -> Result<(u8, usize)> {
+        let mut headers = self.extension_headers();
+        for header in headers.by_ref() {
+            header?;
+        }
+        Ok((headers.next_header, headers.offset))
+    }
+
+    /// Returns the upper-layer segment following the extension headers. Non-first fragments
+    /// have no upper-layer header and return an empty slice.
+    pub fn upper_layer_payload(&self) -> Result<&[u8]> {
+        let (start, end) = self.upper_layer_bounds()?;
+        Ok(&self.buffer.as_ref()[start..end])
+    }
+
+    fn upper_layer_bounds(&self) -> Result<(usize, usize)> {
+        let end = HEADER_LEN + self.payload_len() as usize;
+        let mut headers = self.extension_headers();
+        for header in headers.by_ref() {
+            let header = header?;
+            if header.kind == protocol::FRAGMENT && read_u16(header.data, 2) & 0xfff8 != 0 {
+                return Ok((end, end));
+            }
+        }
+        Ok((headers.offset, end))
+    }
+
+    fn addr(&self, offset: usize) -> Ipv6Addr {
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(&self.buffer.as_ref()[offset..offset + 16]);
+        octets.into()
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Ipv6Packet<T> {
+    /// Sets the hop limit.
+    pub fn set_hop_limit(&mut self, hop_limit: u8) {
+        self.buffer.as_mut()[7] = hop_limit;
+    }
+
+    /// Sets the source address, fixing the checksum of a TCP, UDP or ICMPv6 payload
+    /// incrementally.
+    pub fn set_src_addr(&mut self, addr: Ipv6Addr) -> Result<()> {
+        self.set_addr(SRC_ADDR, addr)
+    }
+
+    /// Sets the destination address, fixing the checksum of a TCP, UDP or ICMPv6 payload
+    /// incrementally.
+    pub fn set_dst_addr(&mut self, addr: Ipv6Addr) -> Result<()> {
+        self.set_addr(DST_ADDR, addr)
+    }
+
+    /// Returns the mutable upper-layer segment, see
+    /// [`upper_layer_payload`](#method.upper_layer_payload).
+    pub fn upper_layer_payload_mut(&mut self) -> Result<&mut [u8]> {
+        let (start, end) = self.upper_layer_bounds()?;
+        Ok(&mut self.buffer.as_mut()[start..end])
+    }
+
+    /// Sets the payload length.
+    pub fn set_payload_len(&mut self, len: u16) {
+        write_u16(self.buffer.as_mut(), 4, len)
+    }
+
+    fn set_addr(&mut self, offset: usize, addr: Ipv6Addr) -> Result<()> {
+        let (protocol, _) = self.upper_layer()?;
+        let mut old = [0u8; 16];
+        old.copy_from_slice(&self.buffer.as_ref()[offset..offset + 16]);
+        let new = addr.octets();
+        self.buffer.as_mut()[offset..offset + 16].copy_from_slice(&new);
+        update_pseudo_checksum(self.upper_layer_payload_mut()?, protocol, true, &old, &new);
+        Ok(())
+    }
+}
+
+/// Represents an IPv6 extension header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtensionHeader<'a> {
+    /// The type of this header, i.e. the next header value of the preceding header.
+    pub kind: u8,
+    /// The type of the header following this one.
+    pub next_header: u8,
+    /// The whole header, including the next header and length octets.
+    pub data: &'a [u8],
+}
+
+/// Iterates over the extension headers of an IPv6 packet. Iteration stops at the first
+/// header which is not an extension header, and at ESP whose contents are encrypted.
+#[derive(Debug, Clone)]
+pub struct ExtensionHeaders<'a> {
+    next_header: u8,
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for ExtensionHeaders<'a> {
+    type Item = Result<ExtensionHeader<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let kind = self.next_header;
+        let len = match kind {
+            protocol::HOP_BY_HOP | protocol::ROUTING | protocol::DESTINATION_OPTIONS => {
+                self.data.get(1).map(|len| (*len as usize + 1) * 8)
+            }
+            protocol::FRAGMENT => Some(8),
+            protocol::AH => self.data.get(1).map(|len| (*len as usize + 2) * 4),
+            _ => return None,
+        };
+        let len = match len {
+            Some(len) if len <= self.data.len() => len,
+            _ => {
+                // Stops the iteration after reporting the error.
+                self.next_header = protocol::NO_NEXT_HEADER;
+                return Some(Err(Error::Truncated));
+            }
+        };
+        let (data, rest) = self.data.split_at(len);
+        self.next_header = data[0];
+        self.data = rest;
+        self.offset += len;
+        Some(Ok(ExtensionHeader {
+            kind,
+            next_header: data[0],
+            data,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::tests::{ipv6, udp, DST6, SRC6};
+    use crate::packet::UdpPacket;
+
+    /// Returns a hop-by-hop header, a fragment header at `offset` and an authentication header
+    /// in front of a UDP datagram.
+    fn extended(offset: u16) -> Vec<u8> {
+        let mut payload = vec![protocol::FRAGMENT, 0, 1, 4, 0, 0, 0, 0];
+        payload.extend_from_slice(&[protocol::AH, 0]);
+        payload.extend_from_slice(&(offset << 3).to_be_bytes());
+        payload.extend_from_slice(&[0, 0, 0, 1]);
+        payload.extend_from_slice(&[protocol::UDP, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1]);
+        payload.extend_from_slice(&udp(SRC6.into(), DST6.into(), b"data"));
+        ipv6(protocol::HOP_BY_HOP, &payload)
+    }
+
+    #[test]
+    fn new_checked() {
+        let buf = ipv6(protocol::UDP, &[0; 8]);
+        assert_eq!(
+            Ipv6Packet::new_checked(&buf[..39]).unwrap_err(),
+            Error::Truncated
+        );
+        assert_eq!(
+            Ipv6Packet::new_checked(&buf[..47]).unwrap_err(),
+            Error::Truncated
+        );
+        let mut bad = buf.clone();
+        bad[0] = 0x40;
+        assert_eq!(
+            Ipv6Packet::new_checked(&bad[..]).unwrap_err(),
+            Error::Malformed
+        );
+
+        let mut buf = buf;
+        buf[..4].copy_from_slice(&[0x6b, 0x81, 0x23, 0x45]);
+        buf.extend_from_slice(&[0xff; 4]);
+        let packet = Ipv6Packet::new_checked(&buf[..]).unwrap();
+        assert_eq!(
+            (packet.traffic_class(), packet.flow_label()),
+            (0xb8, 0x12345)
+        );
+        assert_eq!((packet.src_addr(), packet.dst_addr()), (SRC6, DST6));
+        assert_eq!(packet.payload(), [0; 8]);
+    }
+
+    #[test]
+    fn extension_headers() {
+        let buf = extended(0);
+        let packet = Ipv6Packet::new_checked(&buf[..]).unwrap();
+        let headers: Vec<_> = packet
+            .extension_headers()
+            .map(|header| header.map(|header| (header.kind, header.data.len())))
+            .collect();
+        assert_eq!(
+            headers,
+            [
+                Ok((protocol::HOP_BY_HOP, 8)),
+                Ok((protocol::FRAGMENT, 8)),
+                Ok((protocol::AH, 12)),
+            ]
+        );
+        assert_eq!(packet.upper_layer(), Ok((protocol::UDP, 68)));
+        assert_eq!(packet.upper_layer_payload().unwrap(), &buf[68..]);
+
+        // A non-first fragment has no upper-layer header.
+        let buf = extended(1);
+        let packet = Ipv6Packet::new_checked(&buf[..]).unwrap();
+        assert_eq!(packet.upper_layer(), Ok((protocol::UDP, 68)));
+        assert!(packet.upper_layer_payload().unwrap().is_empty());
+
+        // ESP contents are encrypted, so the walk stops there.
+        let buf = ipv6(protocol::ESP, &[0; 16]);
+        let packet = Ipv6Packet::new_checked(&buf[..]).unwrap();
+        assert_eq!(packet.upper_layer(), Ok((protocol::ESP, 40)));
+
+        // A header longer than the payload ends the walk with an error.
+        let buf = ipv6(
+            protocol::DESTINATION_OPTIONS,
+            &[protocol::UDP, 1, 0, 0, 0, 0, 0, 0],
+        );
+        let packet = Ipv6Packet::new_checked(&buf[..]).unwrap();
+        let headers: Vec<_> = packet.extension_headers().collect();
+        assert_eq!(headers, [Err(Error::Truncated)]);
+        assert_eq!(packet.upper_layer(), Err(Error::Truncated));
+        let buf = ipv6(protocol::ROUTING, &[]);
+        let packet = Ipv6Packet::new_checked(&buf[..]).unwrap();
+        assert_eq!(packet.upper_layer_payload(), Err(Error::Truncated));
+    }
+
+    #[test]
+    fn set_addr() {
+        let mut buf = extended(0);
+        let mut packet = Ipv6Packet::new_checked(&mut buf[..]).unwrap();
+        packet.set_src_addr(Ipv6Addr::LOCALHOST).unwrap();
+        packet.set_dst_addr(Ipv6Addr::LOCALHOST).unwrap();
+        packet.set_hop_limit(1);
+        assert_eq!(packet.hop_limit(), 1);
+        let (src, dst) = (packet.src_addr().into(), packet.dst_addr().into());
+        let datagram = UdpPacket::new_checked(packet.upper_layer_payload().unwrap()).unwrap();
+        assert!(datagram.verify_checksum(src, dst));
+
+        let mut buf = ipv6(protocol::ROUTING, &[]);
+        let mut packet = Ipv6Packet::new_checked(&mut buf[..]).unwrap();
+        assert_eq!(
+            packet.set_src_addr(Ipv6Addr::LOCALHOST),
+            Err(Error::Truncated)
+        );
+    }
+}
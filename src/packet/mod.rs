@@ -0,0 +1,433 @@
+//! Zero-copy views over the packets read from and written to a [`Tun`](../struct.Tun.html).
+//!
+//! Every view wraps a buffer `T: AsRef<[u8]>` and reads fields in place; setters are available
+//! when `T: AsMut<[u8]>` as well. Address and port setters fix the affected checksums
+//! incrementally, so packets can be rewritten without recomputing them from scratch.
+
+pub mod arp;
+pub mod checksum;
+pub mod ethernet;
+pub mod icmp;
+pub mod ipv4;
+pub mod ipv6;
+pub mod tcp;
+pub mod udp;
+
+pub use self::arp::ArpPacket;
+pub use self::ethernet::EthernetFrame;
+pub use self::icmp::{Icmpv4Packet, Icmpv6Packet};
+pub use self::ipv4::Ipv4Packet;
+pub use self::ipv6::Ipv6Packet;
+pub use self::tcp::{TcpFlags, TcpPacket};
+pub use self::udp::UdpPacket;
+
+use std::net::IpAddr;
+
+/// Well-known EtherType values, as carried by Ethernet frames and the packet information header.
+pub mod ethertype {
+    pub const IPV4: u16 = 0x0800;
+    pub const ARP: u16 = 0x0806;
+    pub const VLAN: u16 = 0x8100;
+    pub const QINQ: u16 = 0x88a8;
+    pub const IPV6: u16 = 0x86dd;
+}
+
+/// Well-known IP protocol numbers (IPv4 protocol and IPv6 next header values).
+pub mod protocol {
+    pub const HOP_BY_HOP: u8 = 0;
+    pub const ICMP: u8 = 1;
+    pub const TCP: u8 = 6;
+    pub const UDP: u8 = 17;
+    pub const ROUTING: u8 = 43;
+    pub const FRAGMENT: u8 = 44;
+    pub const ESP: u8 = 50;
+    pub const AH: u8 = 51;
+    pub const ICMPV6: u8 = 58;
+    pub const NO_NEXT_HEADER: u8 = 59;
+    pub const DESTINATION_OPTIONS: u8 = 60;
+}
+
+/// Represents an error raised while parsing a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The buffer is shorter than the header or the length it declares.
+    Truncated,
+    /// A header field holds a value which is not valid for the protocol.
+    Malformed,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "Packet is truncated"),
+            Self::Malformed => write!(f, "Packet is malformed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Represents an alias for the result of packet parsing.
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub(crate) fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+pub(crate) fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+pub(crate) fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+pub(crate) fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Represents the packet information header (`struct tun_pi`) which prefixes every packet
+/// unless the device is built with `packet_info(false)`.
+#[derive(Debug, Clone)]
+pub struct PacketInfo<T> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> PacketInfo<T> {
+    /// Length of the packet information header.
+    pub const LEN: usize = 4;
+
+    /// Flag set by the kernel when the packet was truncated to fit the read buffer.
+    pub const TUN_PKT_STRIP: u16 = 0x0001;
+
+    /// Wraps `buffer` after checking that it holds a complete header.
+    pub fn new_checked(buffer: T) -> Result<Self> {
+        if buffer.as_ref().len() < Self::LEN {
+            return Err(Error::Truncated);
+        }
+        Ok(Self { buffer })
+    }
+
+    /// Wraps `buffer` without checking its length.
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    /// Returns the wrapped buffer.
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+
+    /// Returns the flags of the header.
+    pub fn flags(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 0)
+    }
+
+    /// Returns the EtherType of the payload.
+    pub fn protocol(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 2)
+    }
+
+    /// Returns the packet following the header.
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer.as_ref()[Self::LEN..]
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> PacketInfo<T> {
+    /// Sets the flags of the header.
+    pub fn set_flags(&mut self, flags: u16) {
+        write_u16(self.buffer.as_mut(), 0, flags)
+    }
+
+    /// Sets the EtherType of the payload.
+    pub fn set_protocol(&mut self, protocol: u16) {
+        write_u16(self.buffer.as_mut(), 2, protocol)
+    }
+
+    /// Returns the mutable packet following the header.
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer.as_mut()[Self::LEN..]
+    }
+}
+
+/// Represents either an IPv4 or an IPv6 packet.
+#[derive(Debug, Clone)]
+pub enum IpPacket<T> {
+    V4(Ipv4Packet<T>),
+    V6(Ipv6Packet<T>),
+}
+
+impl<T: AsRef<[u8]>> IpPacket<T> {
+    /// Wraps `buffer` as an IPv4 or IPv6 packet according to its version field.
+    pub fn new_checked(buffer: T) -> Result<Self> {
+        match buffer.as_ref().first().map(|b| b >> 4) {
+            Some(4) => Ok(Self::V4(Ipv4Packet::new_checked(buffer)?)),
+            Some(6) => Ok(Self::V6(Ipv6Packet::new_checked(buffer)?)),
+            Some(_) => Err(Error::Malformed),
+            None => Err(Error::Truncated),
+        }
+    }
+
+    /// Wraps `buffer` according to `ethertype`, e.g. the protocol of a [`PacketInfo`] header
+    /// or an Ethernet frame. Returns `None` if `ethertype` is neither IPv4 nor IPv6.
+    pub fn with_ethertype(ethertype: u16, buffer: T) -> Option<Result<Self>> {
+        match ethertype {
+            ethertype::IPV4 => Some(Ipv4Packet::new_checked(buffer).map(Self::V4)),
+            ethertype::IPV6 => Some(Ipv6Packet::new_checked(buffer).map(Self::V6)),
+            _ => None,
+        }
+    }
+
+    /// Returns the wrapped buffer.
+    pub fn into_inner(self) -> T {
+        match self {
+            Self::V4(packet) => packet.into_inner(),
+            Self::V6(packet) => packet.into_inner(),
+        }
+    }
+
+    /// Returns the source address.
+    pub fn src_addr(&self) -> IpAddr {
+        match self {
+            Self::V4(packet) => packet.src_addr().into(),
+            Self::V6(packet) => packet.src_addr().into(),
+        }
+    }
+
+    /// Returns the destination address.
+    pub fn dst_addr(&self) -> IpAddr {
+        match self {
+            Self::V4(packet) => packet.dst_addr().into(),
+            Self::V6(packet) => packet.dst_addr().into(),
+        }
+    }
+
+    /// Returns the transport protocol, skipping IPv6 extension headers.
+    pub fn protocol(&self) -> Result<u8> {
+        match self {
+            Self::V4(packet) => Ok(packet.protocol()),
+            Self::V6(packet) => packet.upper_layer().map(|(protocol, _)| protocol),
+        }
+    }
+
+    /// Returns the transport segment, skipping IPv4 options and IPv6 extension headers.
+    /// Non-first IPv4 fragments have no transport header and return an empty slice.
+    pub fn transport(&self) -> Result<&[u8]> {
+        match self {
+            Self::V4(packet) if packet.fragment_offset() != 0 => Ok(&[]),
+            Self::V4(packet) => Ok(packet.payload()),
+            Self::V6(packet) => packet.upper_layer_payload(),
+        }
+    }
+
+    /// Returns the length of the whole packet, as declared by its header.
+    pub fn total_len(&self) -> usize {
+        match self {
+            Self::V4(packet) => packet.total_len() as usize,
+            Self::V6(packet) => ipv6::HEADER_LEN + packet.payload_len() as usize,
+        }
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> IpPacket<T> {
+    /// Sets the source address, fixing the IP and transport checksums incrementally.
+    /// Returns `Error::Malformed` if the address family does not match the packet.
+    pub fn set_src_addr(&mut self, addr: IpAddr) -> Result<()> {
+        match (self, addr) {
+            (Self::V4(packet), IpAddr::V4(addr)) => packet.set_src_addr(addr),
+            (Self::V6(packet), IpAddr::V6(addr)) => packet.set_src_addr(addr)?,
+            _ => return Err(Error::Malformed),
+        }
+        Ok(())
+    }
+
+    /// Sets the destination address, fixing the IP and transport checksums incrementally.
+    /// Returns `Error::Malformed` if the address family does not match the packet.
+    pub fn set_dst_addr(&mut self, addr: IpAddr) -> Result<()> {
+        match (self, addr) {
+            (Self::V4(packet), IpAddr::V4(addr)) => packet.set_dst_addr(addr),
+            (Self::V6(packet), IpAddr::V6(addr)) => packet.set_dst_addr(addr)?,
+            _ => return Err(Error::Malformed),
+        }
+        Ok(())
+    }
+
+    /// Returns the mutable transport segment, see [`transport`](#method.transport).
+    pub fn transport_mut(&mut self) -> Result<&mut [u8]> {
+        match self {
+            Self::V4(packet) if packet.fragment_offset() != 0 => Ok(&mut []),
+            Self::V4(packet) => Ok(packet.payload_mut()),
+            Self::V6(packet) => packet.upper_layer_payload_mut(),
+        }
+    }
+}
+
+/// Fixes the checksum of a transport `segment` of `protocol` after a field covered by its
+/// pseudo header changed from `old` to `new`. Segments without such a checksum are left as is.
+pub(crate) fn update_pseudo_checksum(
+    segment: &mut [u8],
+    protocol: u8,
+    ipv6: bool,
+    old: &[u8],
+    new: &[u8],
+) {
+    let offset = match protocol {
+        self::protocol::TCP => tcp::CHECKSUM,
+        self::protocol::UDP => udp::CHECKSUM,
+        self::protocol::ICMPV6 if ipv6 => icmp::CHECKSUM,
+        _ => return,
+    };
+    if segment.len() < offset + 2 {
+        return;
+    }
+    let current = read_u16(segment, offset);
+    // A zero UDP checksum over IPv4 means that the checksum is not used.
+    if protocol == self::protocol::UDP && !ipv6 && current == 0 {
+        return;
+    }
+    let mut updated = checksum::update(current, old, new);
+    if protocol == self::protocol::UDP && updated == 0 {
+        updated = 0xffff;
+    }
+    write_u16(segment, offset, updated);
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    pub(crate) const SRC4: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    pub(crate) const DST4: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    pub(crate) const SRC6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+    pub(crate) const DST6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+
+    /// Returns an IPv4 packet from `SRC4` to `DST4` carrying `payload`.
+    pub(crate) fn ipv4(protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0x45, 0, 0, 0, 0x12, 0x34, 0, 0, 64, protocol, 0, 0];
+        buf.extend_from_slice(&SRC4.octets());
+        buf.extend_from_slice(&DST4.octets());
+        buf.extend_from_slice(payload);
+        let len = buf.len() as u16;
+        write_u16(&mut buf, 2, len);
+        Ipv4Packet::new_unchecked(&mut buf[..]).fill_checksum();
+        buf
+    }
+
+    /// Returns an IPv6 packet from `SRC6` to `DST6` carrying `payload`.
+    pub(crate) fn ipv6(next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0x60, 0, 0, 0, 0, 0, next_header, 64];
+        write_u16(&mut buf, 4, payload.len() as u16);
+        buf.extend_from_slice(&SRC6.octets());
+        buf.extend_from_slice(&DST6.octets());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    /// Returns a UDP datagram from port 1000 to port 53, checksummed for `src` to `dst`.
+    pub(crate) fn udp(src: IpAddr, dst: IpAddr, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0x03, 0xe8, 0, 53, 0, 0, 0, 0];
+        buf.extend_from_slice(payload);
+        let len = buf.len() as u16;
+        write_u16(&mut buf, 4, len);
+        UdpPacket::new_unchecked(&mut buf[..]).fill_checksum(src, dst);
+        buf
+    }
+
+    #[test]
+    fn packet_info() {
+        assert_eq!(
+            PacketInfo::new_checked(&[0u8, 0, 8][..]).unwrap_err(),
+            Error::Truncated
+        );
+        let mut buf = [0u8, 0, 0x86, 0xdd, 0x60];
+        let mut info = PacketInfo::new_checked(&mut buf[..]).unwrap();
+        assert_eq!(info.protocol(), ethertype::IPV6);
+        assert_eq!(info.payload(), [0x60]);
+        info.set_flags(PacketInfo::<&[u8]>::TUN_PKT_STRIP);
+        info.set_protocol(ethertype::IPV4);
+        assert_eq!(buf, [0, 1, 8, 0, 0x60]);
+    }
+
+    #[test]
+    fn ip_packet() {
+        assert_eq!(
+            IpPacket::new_checked(&[][..]).unwrap_err(),
+            Error::Truncated
+        );
+        assert_eq!(
+            IpPacket::new_checked(&[0x50; 40][..]).unwrap_err(),
+            Error::Malformed
+        );
+        assert!(IpPacket::with_ethertype(ethertype::ARP, &[0x45][..]).is_none());
+        let packet = ipv4(protocol::UDP, &[0; 8]);
+        let mismatched = IpPacket::with_ethertype(ethertype::IPV6, &packet[..]).unwrap();
+        assert_eq!(mismatched.unwrap_err(), Error::Truncated);
+
+        let (src, dst) = (SRC4.into(), DST4.into());
+        let mut buf = ipv4(protocol::UDP, &udp(src, dst, b"data"));
+        let mut packet = IpPacket::new_checked(&mut buf[..]).unwrap();
+        assert_eq!((packet.src_addr(), packet.dst_addr()), (src, dst));
+        assert_eq!(packet.protocol(), Ok(protocol::UDP));
+        assert_eq!(packet.transport().unwrap().len(), 12);
+        assert_eq!(packet.total_len(), 32);
+        assert_eq!(
+            packet.set_src_addr(SRC6.into()).unwrap_err(),
+            Error::Malformed
+        );
+
+        let (src, dst) = (SRC6.into(), DST6.into());
+        let buf = ipv6(protocol::UDP, &udp(src, dst, b"data"));
+        let packet = IpPacket::new_checked(&buf[..]).unwrap();
+        assert_eq!((packet.src_addr(), packet.dst_addr()), (src, dst));
+        assert_eq!(packet.total_len(), 52);
+        assert_eq!(packet.transport().unwrap(), &buf[40..]);
+    }
+
+    #[test]
+    fn fragments() {
+        let (src, dst) = (SRC4.into(), DST4.into());
+        let mut buf = ipv4(protocol::UDP, &udp(src, dst, b"data"));
+        // A non-first fragment carries no transport header to fix.
+        write_u16(&mut buf, 6, 1);
+        Ipv4Packet::new_unchecked(&mut buf[..]).fill_checksum();
+        let segment = buf[20..].to_vec();
+        let mut packet = IpPacket::new_checked(&mut buf[..]).unwrap();
+        assert!(packet.transport().unwrap().is_empty());
+        assert!(packet.transport_mut().unwrap().is_empty());
+        packet
+            .set_dst_addr(Ipv4Addr::new(10, 0, 0, 3).into())
+            .unwrap();
+        assert_eq!(buf[20..], segment);
+        assert!(Ipv4Packet::new_checked(&buf[..]).unwrap().verify_checksum());
+    }
+
+    #[test]
+    fn pseudo_checksum() {
+        let (old, new) = (SRC4.octets(), [192, 168, 0, 1]);
+        // A zero UDP checksum over IPv4 is not used, and stays zero.
+        let mut segment = udp(SRC4.into(), DST4.into(), b"data");
+        write_u16(&mut segment, udp::CHECKSUM, 0);
+        update_pseudo_checksum(&mut segment, protocol::UDP, false, &old, &new);
+        assert_eq!(read_u16(&segment, udp::CHECKSUM), 0);
+
+        let mut segment = udp(SRC4.into(), DST4.into(), b"data");
+        update_pseudo_checksum(&mut segment, protocol::UDP, false, &old, &new);
+        let src = Ipv4Addr::from(new).into();
+        assert!(UdpPacket::new_checked(&segment[..])
+            .unwrap()
+            .verify_checksum(src, DST4.into()));
+
+        // Protocols without pseudo header and truncated segments are left as is.
+        let mut segment = [8, 0, 0xab, 0xcd, 0, 0, 0, 0];
+        update_pseudo_checksum(&mut segment, protocol::ICMP, false, &old, &new);
+        update_pseudo_checksum(&mut segment[..4], protocol::TCP, false, &old, &new);
+        assert_eq!(segment, [8, 0, 0xab, 0xcd, 0, 0, 0, 0]);
+    }
+}
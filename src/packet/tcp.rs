@@ -0,0 +1,272 @@
+//! TCP segments (RFC 9293).
+
+use super::{checksum, read_u16, read_u32, write_u16, write_u32, Error, Result};
+use std::net::IpAddr;
+
+/// Length of a TCP header without options.
+pub const HEADER_LEN: usize = 20;
+
+pub(crate) const CHECKSUM: usize = 16;
+
+/// Represents the control bits of a TCP header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct TcpFlags(pub u8);
+
+impl TcpFlags {
+    pub const FIN: Self = Self(0x01);
+    pub const SYN: Self = Self(0x02);
+    pub const RST: Self = Self(0x04);
+    pub const PSH: Self = Self(0x08);
+    pub const ACK: Self = Self(0x10);
+    pub const URG: Self = Self(0x20);
+    pub const ECE: Self = Self(0x40);
+    pub const CWR: Self = Self(0x80);
+
+    /// Returns `true` if all bits of `other` are set.
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns `true` if any bit of `other` is set.
+    pub fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl std::ops::BitOr for TcpFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Represents a zero-copy view of a TCP segment.
+#[derive(Debug, Clone)]
+pub struct TcpPacket<T> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> TcpPacket<T> {
+    /// Wraps `buffer` after checking that the header, including options, fits into it.
+    pub fn new_checked(buffer: T) -> Result<Self> {
+        let packet = Self { buffer };
+        let len = packet.buffer.as_ref().len();
+        if len < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+        if packet.header_len() < HEADER_LEN {
+            return Err(Error::Malformed);
+        }
+        if len < packet.header_len() {
+            return Err(Error::Truncated);
+        }
+        Ok(packet)
+    }
+
+    /// Wraps `buffer` without any check.
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    /// Returns the wrapped buffer.
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+
+    /// Returns the source port.
+    pub fn src_port(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 0)
+    }
+
+    /// Returns the destination port.
+    pub fn dst_port(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 2)
+    }
+
+    /// Returns the sequence number.
+    pub fn seq_number(&self) -> u32 {
+        read_u32(self.buffer.as_ref(), 4)
+    }
+
+    /// Returns the acknowledgment number.
+    pub fn ack_number(&self) -> u32 {
+        read_u32(self.buffer.as_ref(), 8)
+    }
+
+    /// Returns the length of the header including options, in bytes.
+    pub fn header_len(&self) -> usize {
+        (self.buffer.as_ref()[12] >> 4) as usize * 4
+    }
+
+    /// Returns the control bits.
+    pub fn flags(&self) -> TcpFlags {
+        TcpFlags(self.buffer.as_ref()[13])
+    }
+
+    /// Returns the window size.
+    pub fn window(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 14)
+    }
+
+    /// Returns the checksum.
+    pub fn checksum(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), CHECKSUM)
+    }
+
+    /// Returns the urgent pointer.
+    pub fn urgent_pointer(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 18)
+    }
+
+    /// Returns the raw options of the header.
+    pub fn options(&self) -> &[u8] {
+        &self.buffer.as_ref()[HEADER_LEN..self.header_len()]
+    }
+
+    /// Returns the payload.
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer.as_ref()[self.header_len()..]
+    }
+
+    /// Returns `true` if the checksum is valid for a segment sent from `src` to `dst`.
+    pub fn verify_checksum(&self, src: IpAddr, dst: IpAddr) -> bool {
+        checksum::transport(src, dst, super::protocol::TCP, self.buffer.as_ref()) == 0
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> TcpPacket<T> {
+    /// Sets the source port, fixing the checksum incrementally.
+    pub fn set_src_port(&mut self, port: u16) {
+        self.set_port(0, port)
+    }
+
+    /// Sets the destination port, fixing the checksum incrementally.
+    pub fn set_dst_port(&mut self, port: u16) {
+        self.set_port(2, port)
+    }
+
+    /// Sets the sequence number. The checksum has to be filled afterwards.
+    pub fn set_seq_number(&mut self, seq: u32) {
+        write_u32(self.buffer.as_mut(), 4, seq)
+    }
+
+    /// Sets the acknowledgment number. The checksum has to be filled afterwards.
+    pub fn set_ack_number(&mut self, ack: u32) {
+        write_u32(self.buffer.as_mut(), 8, ack)
+    }
+
+    /// Sets the length of the header in bytes, which must be a multiple of 4.
+    pub fn set_header_len(&mut self, len: usize) {
+        let buf = self.buffer.as_mut();
+        buf[12] = (buf[12] & 0x0f) | ((len / 4) as u8) << 4;
+    }
+
+    /// Sets the control bits. The checksum has to be filled afterwards.
+    pub fn set_flags(&mut self, flags: TcpFlags) {
+        self.buffer.as_mut()[13] = flags.0;
+    }
+
+    /// Sets the window size. The checksum has to be filled afterwards.
+    pub fn set_window(&mut self, window: u16) {
+        write_u16(self.buffer.as_mut(), 14, window)
+    }
+
+    /// Sets the checksum.
+    pub fn set_checksum(&mut self, checksum: u16) {
+        write_u16(self.buffer.as_mut(), CHECKSUM, checksum)
+    }
+
+    /// Computes the checksum from scratch for a segment sent from `src` to `dst` and stores it.
+    pub fn fill_checksum(&mut self, src: IpAddr, dst: IpAddr) {
+        self.set_checksum(0);
+        let checksum = checksum::transport(src, dst, super::protocol::TCP, self.buffer.as_ref());
+        self.set_checksum(checksum);
+    }
+
+    /// Returns the mutable payload.
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let start = self.header_len();
+        &mut self.buffer.as_mut()[start..]
+    }
+
+    fn set_port(&mut self, offset: usize, port: u16) {
+        let old = read_u16(self.buffer.as_ref(), offset);
+        write_u16(self.buffer.as_mut(), offset, port);
+        let checksum = checksum::update(self.checksum(), &old.to_be_bytes(), &port.to_be_bytes());
+        self.set_checksum(checksum);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    /// Returns a SYN segment with an MSS option and `payload`, checksummed from `src` to `dst`.
+    fn segment(src: IpAddr, dst: IpAddr, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0u8; 24];
+        let mut segment = TcpPacket::new_unchecked(&mut buf[..]);
+        segment.set_src_port(1000);
+        segment.set_dst_port(80);
+        segment.set_seq_number(0x0102_0304);
+        segment.set_header_len(24);
+        segment.set_flags(TcpFlags::SYN | TcpFlags::ECE);
+        segment.set_window(0xffff);
+        buf[20..].copy_from_slice(&[2, 4, 0x05, 0xb4]);
+        buf.extend_from_slice(payload);
+        TcpPacket::new_unchecked(&mut buf[..]).fill_checksum(src, dst);
+        buf
+    }
+
+    #[test]
+    fn new_checked() {
+        let (src, dst) = (Ipv4Addr::LOCALHOST.into(), Ipv4Addr::LOCALHOST.into());
+        let buf = segment(src, dst, b"data");
+        assert_eq!(
+            TcpPacket::new_checked(&buf[..19]).unwrap_err(),
+            Error::Truncated
+        );
+        assert_eq!(
+            TcpPacket::new_checked(&buf[..23]).unwrap_err(),
+            Error::Truncated
+        );
+        let mut bad = buf.clone();
+        bad[12] = 0x40;
+        assert_eq!(
+            TcpPacket::new_checked(&bad[..]).unwrap_err(),
+            Error::Malformed
+        );
+
+        let segment = TcpPacket::new_checked(&buf[..]).unwrap();
+        assert_eq!((segment.src_port(), segment.dst_port()), (1000, 80));
+        assert_eq!(segment.seq_number(), 0x0102_0304);
+        assert_eq!(segment.header_len(), 24);
+        assert_eq!(segment.options(), [2, 4, 0x05, 0xb4]);
+        assert_eq!(segment.payload(), b"data");
+        assert!(segment.verify_checksum(src, dst));
+        assert!(!segment.verify_checksum(src, Ipv4Addr::UNSPECIFIED.into()));
+    }
+
+    #[test]
+    fn flags() {
+        let flags = TcpFlags::SYN | TcpFlags::ACK;
+        assert!(flags.contains(TcpFlags::SYN));
+        assert!(!flags.contains(TcpFlags::SYN | TcpFlags::FIN));
+        assert!(flags.intersects(TcpFlags::FIN | TcpFlags::ACK));
+        assert!(!flags.intersects(TcpFlags::RST));
+    }
+
+    #[test]
+    fn set_port() {
+        let (src, dst) = (Ipv4Addr::LOCALHOST.into(), Ipv4Addr::BROADCAST.into());
+        let mut buf = segment(src, dst, b"odd");
+        let mut segment = TcpPacket::new_checked(&mut buf[..]).unwrap();
+        segment.set_src_port(0xfffe);
+        segment.set_dst_port(1);
+        let checksum = segment.checksum();
+        segment.fill_checksum(src, dst);
+        assert_eq!(segment.checksum(), checksum);
+        assert!(segment.verify_checksum(src, dst));
+    }
+}
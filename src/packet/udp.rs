@@ -0,0 +1,185 @@
+//! UDP datagrams (RFC 768).
+
+use super::{checksum, read_u16, write_u16, Error, Result};
+use std::net::IpAddr;
+
+/// Length of a UDP header.
+pub const HEADER_LEN: usize = 8;
+
+pub(crate) const CHECKSUM: usize = 6;
+
+/// Represents a zero-copy view of a UDP datagram.
+#[derive(Debug, Clone)]
+pub struct UdpPacket<T> {
+    buffer: T,
+}
+
+impl<T: AsRef<[u8]>> UdpPacket<T> {
+    /// Wraps `buffer` after checking that the header and the length declared by it fit into it.
+    pub fn new_checked(buffer: T) -> Result<Self> {
+        let packet = Self { buffer };
+        let len = packet.buffer.as_ref().len();
+        if len < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+        if (packet.len() as usize) < HEADER_LEN {
+            return Err(Error::Malformed);
+        }
+        if len < packet.len() as usize {
+            return Err(Error::Truncated);
+        }
+        Ok(packet)
+    }
+
+    /// Wraps `buffer` without any check.
+    pub fn new_unchecked(buffer: T) -> Self {
+        Self { buffer }
+    }
+
+    /// Returns the wrapped buffer.
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+
+    /// Returns the source port.
+    pub fn src_port(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 0)
+    }
+
+    /// Returns the destination port.
+    pub fn dst_port(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 2)
+    }
+
+    /// Returns the length of the datagram including the header, in bytes.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), 4)
+    }
+
+    /// Returns the checksum, zero if the checksum is not used.
+    pub fn checksum(&self) -> u16 {
+        read_u16(self.buffer.as_ref(), CHECKSUM)
+    }
+
+    /// Returns the payload, bounded by the length of the datagram.
+    pub fn payload(&self) -> &[u8] {
+        &self.buffer.as_ref()[HEADER_LEN..self.len() as usize]
+    }
+
+    /// Returns `true` if the checksum is valid for a datagram sent from `src` to `dst`, or if
+    /// it is not used by an IPv4 datagram.
+    pub fn verify_checksum(&self, src: IpAddr, dst: IpAddr) -> bool {
+        if src.is_ipv4() && self.checksum() == 0 {
+            return true;
+        }
+        let datagram = &self.buffer.as_ref()[..self.len() as usize];
+        checksum::transport(src, dst, super::protocol::UDP, datagram) == 0
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> UdpPacket<T> {
+    /// Sets the source port, fixing the checksum incrementally.
+    pub fn set_src_port(&mut self, port: u16) {
+        self.set_port(0, port)
+    }
+
+    /// Sets the destination port, fixing the checksum incrementally.
+    pub fn set_dst_port(&mut self, port: u16) {
+        self.set_port(2, port)
+    }
+
+    /// Sets the length of the datagram. The checksum has to be filled afterwards.
+    pub fn set_len(&mut self, len: u16) {
+        write_u16(self.buffer.as_mut(), 4, len)
+    }
+
+    /// Sets the checksum.
+    pub fn set_checksum(&mut self, checksum: u16) {
+        write_u16(self.buffer.as_mut(), CHECKSUM, checksum)
+    }
+
+    /// Computes the checksum from scratch for a datagram sent from `src` to `dst` and stores it.
+    pub fn fill_checksum(&mut self, src: IpAddr, dst: IpAddr) {
+        self.set_checksum(0);
+        let datagram = &self.buffer.as_ref()[..self.len() as usize];
+        match checksum::transport(src, dst, super::protocol::UDP, datagram) {
+            0 => self.set_checksum(0xffff),
+            checksum => self.set_checksum(checksum),
+        }
+    }
+
+    /// Returns the mutable payload, bounded by the length of the datagram.
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        let end = self.len() as usize;
+        &mut self.buffer.as_mut()[HEADER_LEN..end]
+    }
+
+    fn set_port(&mut self, offset: usize, port: u16) {
+        let old = read_u16(self.buffer.as_ref(), offset);
+        write_u16(self.buffer.as_mut(), offset, port);
+        if self.checksum() == 0 {
+            return;
+        }
+        match checksum::update(self.checksum(), &old.to_be_bytes(), &port.to_be_bytes()) {
+            0 => self.set_checksum(0xffff),
+            checksum => self.set_checksum(checksum),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::tests::{udp, DST4, DST6, SRC4, SRC6};
+
+    #[test]
+    fn new_checked() {
+        let (src, dst) = (SRC4.into(), DST4.into());
+        let mut buf = udp(src, dst, b"data");
+        assert_eq!(
+            UdpPacket::new_checked(&buf[..7]).unwrap_err(),
+            Error::Truncated
+        );
+        assert_eq!(
+            UdpPacket::new_checked(&buf[..11]).unwrap_err(),
+            Error::Truncated
+        );
+        let mut bad = buf.clone();
+        bad[5] = 7;
+        assert_eq!(
+            UdpPacket::new_checked(&bad[..]).unwrap_err(),
+            Error::Malformed
+        );
+
+        // Trailing bytes are not part of the datagram.
+        buf.push(0xff);
+        let datagram = UdpPacket::new_checked(&buf[..]).unwrap();
+        assert_eq!((datagram.src_port(), datagram.dst_port()), (1000, 53));
+        assert_eq!(datagram.len(), 12);
+        assert_eq!(datagram.payload(), b"data");
+        assert!(datagram.verify_checksum(src, dst));
+    }
+
+    #[test]
+    fn checksum() {
+        // A zero checksum is not used over IPv4, but is invalid over IPv6.
+        let mut buf = udp(SRC4.into(), DST4.into(), b"data");
+        let mut datagram = UdpPacket::new_checked(&mut buf[..]).unwrap();
+        datagram.set_checksum(0);
+        assert!(datagram.verify_checksum(SRC4.into(), DST4.into()));
+        datagram.set_src_port(1);
+        assert_eq!(datagram.checksum(), 0);
+        assert!(!datagram.verify_checksum(SRC6.into(), DST6.into()));
+
+        let (src, dst) = (SRC6.into(), DST6.into());
+        let mut buf = udp(src, dst, b"odd");
+        let mut datagram = UdpPacket::new_checked(&mut buf[..]).unwrap();
+        datagram.set_src_port(0xffff);
+        datagram.set_dst_port(0);
+        let checksum = datagram.checksum();
+        datagram.fill_checksum(src, dst);
+        assert_eq!(datagram.checksum(), checksum);
+        assert!(datagram.verify_checksum(src, dst));
+    }
+}
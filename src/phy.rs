@@ -0,0 +1,165 @@
+use crate::device::TunDevice;
+use crate::future::race;
+use crate::packet::{ethernet, ethertype, PacketInfo};
+use crate::result::Result;
+use crate::tun::Tun;
+use async_io::Timer;
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+/// The MTU assumed while the MTU of device cannot be read.
+const DEFAULT_MTU: usize = 1500;
+
+/// Represents a packet received from a [`Tun`](struct.Tun.html) by smoltcp.
+pub struct TunRxToken {
+    buf: Vec<u8>,
+    header_len: usize,
+}
+
+impl phy::RxToken for TunRxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.buf[self.header_len..])
+    }
+}
+
+/// Represents a packet sent to a [`Tun`](struct.Tun.html) by smoltcp.
+pub struct TunTxToken<'a> {
+    tun: &'a Tun,
+}
+
+impl phy::TxToken for TunTxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let header_len = self.tun.header_len();
+        let mut buf = vec![0; header_len + len];
+        let result = f(&mut buf[header_len..]);
+        if self.tun.packet_info() {
+            let protocol = protocol(self.tun.is_tap(), &buf[header_len..]);
+            PacketInfo::new_unchecked(&mut buf[..]).set_protocol(protocol);
+        }
+        // The zeroed vnet header requests neither checksum offload nor segmentation. Packets
+        // are dropped while the send buffer is full, as smoltcp retransmits them.
+        let _ = self
+            .tun
+            .poll_send(&mut Context::from_waker(Waker::noop()), &buf);
+        result
+    }
+}
+
+/// Returns the EtherType of the packet information of `packet`, which is a frame on TAP devices.
+pub(crate) fn protocol(is_tap: bool, packet: &[u8]) -> u16 {
+    if is_tap {
+        return packet
+            .get(12..ethernet::HEADER_LEN)
+            .map_or(0, |bytes| u16::from_be_bytes([bytes[0], bytes[1]]));
+    }
+    match packet.first().map(|b| b >> 4) {
+        Some(6) => ethertype::IPV6,
+        _ => ethertype::IPV4,
+    }
+}
+
+/// Serves a smoltcp [`Interface`](https://docs.rs/smoltcp/latest/smoltcp/iface/struct.Interface.html)
+/// with `Medium::Ethernet` on TAP devices and `Medium::Ip` on TUN devices. Packet information
+/// and vnet headers are stripped from received packets and prepended to sent ones.
+///
+/// Receiving never blocks; use [`wait`](#method.wait) to sleep until the next packet or the
+/// delay of the next poll, and avoid reading the same queue through other methods meanwhile.
+/// See `examples/smoltcp-http.rs`.
+impl Device for Tun {
+    type RxToken<'a> = TunRxToken;
+    type TxToken<'a> = TunTxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let buf = match self.pending.take() {
+            Some(buf) => buf,
+            None => self.try_recv()?,
+        };
+        let rx = TunRxToken {
+            buf,
+            header_len: self.header_len(),
+        };
+        Some((rx, TunTxToken { tun: self }))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(TunTxToken { tun: self })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mtu = self.mtu().map_or(DEFAULT_MTU, |mtu| mtu as usize);
+        let mut caps = DeviceCapabilities::default();
+        if self.is_tap() {
+            caps.medium = Medium::Ethernet;
+            caps.max_transmission_unit = mtu + ethernet::HEADER_LEN;
+        } else {
+            caps.medium = Medium::Ip;
+            caps.max_transmission_unit = mtu;
+        }
+        caps
+    }
+}
+
+impl Tun {
+    /// Waits until a packet is ready to be received by smoltcp or `timeout` elapsed, e.g. the
+    /// value of `Interface::poll_delay`. Without `timeout`, waits for the next packet only.
+    pub async fn wait(&mut self, timeout: Option<Duration>) -> Result<()> {
+        if self.pending.is_some() {
+            return Ok(());
+        }
+        let mut buf = self.rx_buffer();
+        let tun = &*self;
+        let recv = async { tun.recv(&mut buf).await.map(Some) };
+        let timer = async {
+            match timeout {
+                Some(timeout) => Timer::after(timeout).await,
+                None => Timer::never().await,
+            };
+            Ok(None)
+        };
+        let len = race(recv, timer).await?;
+        if let Some(len) = len {
+            buf.truncate(len);
+            self.pending = Some(buf);
+        }
+        Ok(())
+    }
+
+    fn try_recv(&self) -> Option<Vec<u8>> {
+        let mut buf = self.rx_buffer();
+        match self.poll_recv(&mut Context::from_waker(Waker::noop()), &mut buf) {
+            Poll::Ready(Ok(len)) if len > 0 => {
+                buf.truncate(len);
+                Some(buf)
+            }
+            _ => None,
+        }
+    }
+
+    fn rx_buffer(&self) -> Vec<u8> {
+        let mtu = self.mtu().map_or(DEFAULT_MTU, |mtu| mtu as usize);
+        vec![0; self.header_len() + ethernet::HEADER_LEN + mtu]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet_info_protocol() {
+        let mut frame = vec![0; ethernet::HEADER_LEN];
+        frame[12..].copy_from_slice(&ethertype::ARP.to_be_bytes());
+        assert_eq!(protocol(true, &frame), ethertype::ARP);
+        assert_eq!(protocol(true, &frame[..12]), 0);
+        assert_eq!(protocol(false, &[0x45, 0]), ethertype::IPV4);
+        assert_eq!(protocol(false, &[0x60, 0]), ethertype::IPV6);
+    }
+}
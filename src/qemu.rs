@@ -0,0 +1,354 @@
+//! A bridge between a TAP device and a QEMU socket netdev, which wires guests into the segment
+//! of device without a bridge on the host.
+//!
+//! Stream netdevs (`-netdev stream` and `-netdev socket,listen=`/`connect=`) prefix every frame
+//! with its length as a 32-bit big-endian integer, while datagram netdevs (`-netdev dgram` and
+//! `-netdev socket,udp=`) send every frame as one datagram. The bridge takes either role of a
+//! stream netdev, e.g. it serves `-netdev stream,id=net0,server=off,addr.type=unix,addr.path=/run/tap0.sock`:
+//!
+//! ```no_run
+//! # async fn run() -> async_tun::result::Result<()> {
+//! use async_std::sync::Arc;
+//! use async_tun::qemu::{NetdevAddr, NetdevBridge};
+//! use async_tun::TunBuilder;
+//!
+//! let tun = TunBuilder::new()
+//!     .tap(true)
+//!     .packet_info(false)
+//!     .backpressure(true)
+//!     .up()
+//!     .try_build()
+//!     .await?;
+//! NetdevBridge::new(Arc::new(tun))?
+//!     .serve(&NetdevAddr::Unix("/run/tap0.sock".into()))
+//!     .await
+//! # }
+//! ```
+
+use crate::device::{DeviceKind, TunDevice};
+use crate::future::race;
+use crate::result::Result;
+use async_std::io::{Read, ReadExt, Write, WriteExt};
+use async_std::net::{TcpListener, TcpStream, UdpSocket};
+use async_std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+use async_std::sync::Arc;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// The largest frame QEMU sends or accepts (`NET_BUFSIZE`).
+const MAX_FRAME_LEN: usize = 4096 + 65536;
+const LEN_PREFIX_LEN: usize = 4;
+
+/// Represents the socket of a QEMU socket netdev.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetdevAddr {
+    /// A stream over TCP, i.e. `addr.type=inet` or `-netdev socket,listen=`/`connect=`.
+    Tcp(SocketAddr),
+    /// A stream over a Unix socket, i.e. `addr.type=unix`.
+    Unix(PathBuf),
+    /// Datagrams over UDP received on `local` and sent to `remote`, i.e. the `remote` and
+    /// `local` addresses of QEMU swapped.
+    Udp {
+        local: SocketAddr,
+        remote: SocketAddr,
+    },
+    /// Datagrams over Unix sockets received on `local` and sent to `remote`.
+    UnixDgram { local: PathBuf, remote: PathBuf },
+}
+
+/// Represents a bridge forwarding frames between a TAP device and a QEMU socket netdev.
+///
+/// The device must be a TAP device without packet information and without a vnet header, e.g. a
+/// [`Tun`](../struct.Tun.html) built with `tap(true)` and `packet_info(false)`.
+pub struct NetdevBridge<D> {
+    device: Arc<D>,
+}
+
+impl<D: TunDevice + Send + Sync + 'static> NetdevBridge<D> {
+    /// Creates a new instance of [`NetdevBridge`](struct.NetdevBridge.html) forwarding the frames of `device`.
+    pub fn new(device: Arc<D>) -> Result<Self> {
+        if device.kind() != DeviceKind::Tap || device.header_len() != 0 {
+            return Err(format!(
+                "{} is not a TAP device without packet information and vnet header",
+                device.name()
+            )
+            .into());
+        }
+        Ok(Self { device })
+    }
+
+    /// Takes the client role: connects to the stream netdev listening on `addr` and forwards
+    /// frames until it disconnects. Datagram netdevs are forwarded as with [`serve`](#method.serve).
+    pub async fn connect(&self, addr: &NetdevAddr) -> Result<()> {
+        match addr {
+            NetdevAddr::Tcp(addr) => self.handle(TcpStream::connect(addr).await?).await,
+            NetdevAddr::Unix(path) => self.handle(UnixStream::connect(path).await?).await,
+            _ => self.serve(addr).await,
+        }
+    }
+
+    /// Takes the server role: listens on `addr` and forwards the frames of the stream netdevs
+    /// connecting to it, one at a time, until accepting fails; a connection which fails is
+    /// closed. Datagram netdevs are forwarded until an error occurs; frames which cannot be sent
+    /// to `remote`, e.g. before QEMU is started, are dropped, as well as the frames received
+    /// from another address than `remote`.
+    pub async fn serve(&self, addr: &NetdevAddr) -> Result<()> {
+        match addr {
+            NetdevAddr::Tcp(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    if let Err(_error) = self.handle(stream).await {
+                        #[cfg(feature = "tracing")]
+                        tracing::warn!(name = self.device.name(), error = %_error, "connection failed");
+                    }
+                }
+            }
+            NetdevAddr::Unix(path) => {
+                let listener = UnixListener::bind(path).await?;
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    if let Err(_error) = self.handle(stream).await {
+                        #[cfg(feature = "tracing")]
+                        tracing::warn!(name = self.device.name(), error = %_error, "connection failed");
+                    }
+                }
+            }
+            NetdevAddr::Udp { local, remote } => {
+                let socket = Datagram::Udp(UdpSocket::bind(local).await?, *remote);
+                self.forward_datagrams(socket).await
+            }
+            NetdevAddr::UnixDgram { local, remote } => {
+                let socket = Datagram::Unix(UnixDatagram::bind(local).await?, remote.clone());
+                self.forward_datagrams(socket).await
+            }
+        }
+    }
+
+    /// Forwards frames between device and the stream netdev connected to `stream` until it
+    /// disconnects.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(name = self.device.name()), err))]
+    pub async fn handle<S>(&self, stream: S) -> Result<()>
+    where
+        S: Read + Write + Clone + Unpin,
+    {
+        let mut reader = stream.clone();
+        let mut writer = stream;
+        let device = &self.device;
+        let inbound = async {
+            let mut buf = vec![0; MAX_FRAME_LEN];
+            loop {
+                let mut prefix = [0; LEN_PREFIX_LEN];
+                match reader.read_exact(&mut prefix).await {
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                    result => result?,
+                }
+                let len = u32::from_be_bytes(prefix) as usize;
+                if len > MAX_FRAME_LEN {
+                    return Err(format!("Invalid frame length: {}", len).into());
+                }
+                reader.read_exact(&mut buf[..len]).await?;
+                // Frames are dropped like on a link without carrier, e.g. while device is down.
+                let _ = device.send(&buf[..len]).await;
+            }
+        };
+        let outbound = async {
+            let mut buf = vec![0; LEN_PREFIX_LEN + MAX_FRAME_LEN];
+            loop {
+                let len = device.recv(&mut buf[LEN_PREFIX_LEN..]).await?;
+                if len == 0 {
+                    return Ok(());
+                }
+                buf[..LEN_PREFIX_LEN].copy_from_slice(&(len as u32).to_be_bytes());
+                writer.write_all(&buf[..LEN_PREFIX_LEN + len]).await?;
+            }
+        };
+        race(inbound, outbound).await
+    }
+
+    async fn forward_datagrams(&self, socket: Datagram) -> Result<()> {
+        let device = &self.device;
+        let inbound = async {
+            let mut buf = vec![0; MAX_FRAME_LEN];
+            loop {
+                let len = socket.recv(&mut buf).await?;
+                let _ = device.send(&buf[..len]).await;
+            }
+        };
+        let outbound = async {
+            let mut buf = vec![0; MAX_FRAME_LEN];
+            loop {
+                let len = device.recv(&mut buf).await?;
+                if len == 0 {
+                    return Ok(());
+                }
+                let _ = socket.send(&buf[..len]).await;
+            }
+        };
+        race(inbound, outbound).await
+    }
+}
+
+/// Represents the socket of a datagram netdev and the address of its peer.
+enum Datagram {
+    Udp(UdpSocket, SocketAddr),
+    Unix(UnixDatagram, PathBuf),
+}
+
+impl Datagram {
+    /// Receives the next datagram sent by the peer, dropping the ones sent from elsewhere.
+    async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let (len, from_peer) = match self {
+                Datagram::Udp(socket, remote) => {
+                    let (len, from) = socket.recv_from(buf).await?;
+                    (len, from == *remote)
+                }
+                Datagram::Unix(socket, remote) => {
+                    let (len, from) = socket.recv_from(buf).await?;
+                    (len, from.as_pathname() == Some(remote.as_path()))
+                }
+            };
+            if from_peer {
+                return Ok(len);
+            }
+        }
+    }
+
+    async fn send(&self, frame: &[u8]) -> Result<usize> {
+        Ok(match self {
+            Datagram::Udp(socket, remote) => socket.send_to(frame, remote).await?,
+            Datagram::Unix(socket, remote) => socket.send_to(frame, remote).await?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTun;
+    use async_std::task;
+
+    fn frame(ethertype: u16) -> Vec<u8> {
+        let mut frame = vec![0xff; 6];
+        frame.extend_from_slice(&[2, 0, 0, 0, 0, 1]);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.resize(60, 0xab);
+        frame
+    }
+
+    fn tap(name: &str) -> MockTun {
+        MockTun::new(name).with_tap(true).with_packet_info(false)
+    }
+
+    #[test]
+    fn stream() {
+        task::block_on(async {
+            let tun = tap("tap0");
+            let kernel = tun.kernel();
+            let bridge = NetdevBridge::new(Arc::new(tun)).unwrap();
+            let (mut qemu, stream) = UnixStream::pair().unwrap();
+            let session = task::spawn(async move { bridge.handle(stream).await });
+
+            let sent = frame(0x0800);
+            kernel.inject(&sent).await;
+            let mut prefix = [0; LEN_PREFIX_LEN];
+            qemu.read_exact(&mut prefix).await.unwrap();
+            assert_eq!(u32::from_be_bytes(prefix) as usize, sent.len());
+            let mut received = vec![0; sent.len()];
+            qemu.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, sent);
+
+            let sent = frame(0x86dd);
+            qemu.write_all(&(sent.len() as u32).to_be_bytes())
+                .await
+                .unwrap();
+            qemu.write_all(&sent).await.unwrap();
+            assert_eq!(kernel.recv().await.unwrap(), sent);
+
+            drop(qemu);
+            session.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn stream_invalid_length() {
+        task::block_on(async {
+            let bridge = NetdevBridge::new(Arc::new(tap("tap0"))).unwrap();
+            let (mut qemu, stream) = UnixStream::pair().unwrap();
+            let session = task::spawn(async move { bridge.handle(stream).await });
+            qemu.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+            assert!(session.await.is_err());
+        });
+    }
+
+    #[test]
+    fn serve() {
+        task::block_on(async {
+            let path =
+                std::env::temp_dir().join(format!("async-tun-{}-serve.sock", std::process::id()));
+            let tun = tap("tap0");
+            let kernel = tun.kernel();
+            let bridge = NetdevBridge::new(Arc::new(tun)).unwrap();
+            let addr = NetdevAddr::Unix(path.clone());
+            let session = task::spawn(async move { bridge.serve(&addr).await });
+            while !path.exists() {
+                task::yield_now().await;
+            }
+
+            // A failed connection does not stop the next ones from being served.
+            let mut qemu = UnixStream::connect(&path).await.unwrap();
+            qemu.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+            let mut qemu = UnixStream::connect(&path).await.unwrap();
+            let sent = frame(0x0800);
+            qemu.write_all(&(sent.len() as u32).to_be_bytes())
+                .await
+                .unwrap();
+            qemu.write_all(&sent).await.unwrap();
+            assert_eq!(kernel.recv().await.unwrap(), sent);
+
+            session.cancel().await;
+            let _ = std::fs::remove_file(path);
+        });
+    }
+
+    #[test]
+    fn datagram() {
+        task::block_on(async {
+            let dir = std::env::temp_dir();
+            let id = std::process::id();
+            let local = dir.join(format!("async-tun-{}-bridge.sock", id));
+            let remote = dir.join(format!("async-tun-{}-qemu.sock", id));
+            let qemu = UnixDatagram::bind(&remote).await.unwrap();
+            let tun = tap("tap0");
+            let kernel = tun.kernel();
+            let bridge = NetdevBridge::new(Arc::new(tun)).unwrap();
+            let addr = NetdevAddr::UnixDgram {
+                local: local.clone(),
+                remote: remote.clone(),
+            };
+            let session = task::spawn(async move { bridge.serve(&addr).await });
+
+            let sent = frame(0x0800);
+            kernel.inject(&sent).await;
+            let mut buf = vec![0; MAX_FRAME_LEN];
+            let len = qemu.recv(&mut buf).await.unwrap();
+            assert_eq!(buf[..len], sent);
+
+            // Frames from another socket than QEMU are dropped.
+            let other = dir.join(format!("async-tun-{}-other.sock", id));
+            let socket = UnixDatagram::bind(&other).await.unwrap();
+            socket.send_to(&frame(0x86dd), &local).await.unwrap();
+            let sent = frame(0x0806);
+            qemu.send_to(&sent, &local).await.unwrap();
+            assert_eq!(kernel.recv().await.unwrap(), sent);
+            assert!(kernel.try_recv().is_none());
+
+            session.cancel().await;
+            for path in [local, remote, other] {
+                let _ = std::fs::remove_file(path);
+            }
+        });
+    }
+}
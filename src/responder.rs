@@ -0,0 +1,556 @@
+use crate::device::{DeviceKind, TunDevice};
+use crate::packet::{
+    arp, ethernet, ethertype, icmp, ipv4, ipv6, protocol, ArpPacket, EthernetFrame, Icmpv4Packet,
+    Icmpv6Packet, IpPacket, Ipv4Packet, Ipv6Packet, PacketInfo,
+};
+use crate::result::Result;
+use async_std::channel::{self, Receiver};
+use async_std::sync::Arc;
+use async_std::task;
+use mac_address::MacAddress;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const HOP_LIMIT: u8 = 64;
+const NDP_HOP_LIMIT: u8 = 255;
+const NDP_OPTION_TARGET_LINK_ADDR: u8 = 2;
+const NA_FLAG_SOLICITED: u8 = 0x40;
+const NA_FLAG_OVERRIDE: u8 = 0x20;
+
+/// Answers ICMP/ICMPv6 echo requests and, on TAP devices, ARP requests and IPv6 neighbor
+/// solicitations for a set of configured addresses, without a network stack on the host side.
+///
+/// Use [`spawn`](#method.spawn) to run it as a task on a [`Tun`](struct.Tun.html), or
+/// [`reply`](#method.reply) to drive it from an existing read loop.
+#[derive(Debug, Clone, Default)]
+pub struct Responder {
+    tap: bool,
+    packet_info: bool,
+    echo: Vec<IpAddr>,
+    neighbors: HashMap<IpAddr, MacAddress>,
+}
+
+impl Responder {
+    /// Creates a new instance of [`Responder`](struct.Responder.html) for TUN packets without
+    /// packet information.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Creates a new instance of [`Responder`](struct.Responder.html) matching the kind and the
+    /// packet information setting of `tun`.
+    pub fn for_tun<D: TunDevice + ?Sized>(tun: &D) -> Self {
+        Self::new()
+            .tap(tun.kind() == DeviceKind::Tap)
+            .packet_info(tun.packet_info())
+    }
+
+    /// If `is_tap` is true, packets are parsed as Ethernet frames. Default value is `false`.
+    pub fn tap(mut self, is_tap: bool) -> Self {
+        self.tap = is_tap;
+        self
+    }
+
+    /// If `packet_info` is true, packets are prefixed with packet information. Default value is `false`.
+    pub fn packet_info(mut self, packet_info: bool) -> Self {
+        self.packet_info = packet_info;
+        self
+    }
+
+    /// Answers echo requests sent to `addr`, e.g. the destination address of the device.
+    pub fn echo(mut self, addr: IpAddr) -> Self {
+        self.echo.push(addr);
+        self
+    }
+
+    /// Answers ARP requests (IPv4) or neighbor solicitations (IPv6) for `addr` with `mac` (for tap mode).
+    pub fn neighbor(mut self, addr: IpAddr, mac: MacAddress) -> Self {
+        self.neighbors.insert(addr, mac);
+        self
+    }
+
+    /// Returns the reply to `packet`, or `None` if the packet is not answered by this responder.
+    pub fn reply(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let (info, packet) = if self.packet_info {
+            let info = PacketInfo::new_checked(packet).ok()?;
+            (Some(info.protocol()), &packet[PacketInfo::<&[u8]>::LEN..])
+        } else {
+            (None, packet)
+        };
+        let reply = if self.tap {
+            self.reply_frame(packet)?
+        } else {
+            self.reply_ip(packet)?
+        };
+        Some(match info {
+            Some(protocol) => {
+                let mut buf = vec![0u8; PacketInfo::<&[u8]>::LEN];
+                PacketInfo::new_unchecked(&mut buf[..]).set_protocol(protocol);
+                buf.extend_from_slice(&reply);
+                buf
+            }
+            None => reply,
+        })
+    }
+
+    /// Spawns a task which reads packets from `tun`, writes the replies back and passes every
+    /// other packet to the returned channel. The task stops on read errors or once the
+    /// receiver is dropped.
+    pub fn spawn<D>(self, tun: Arc<D>, capacity: usize) -> Receiver<Result<Vec<u8>>>
+    where
+        D: TunDevice + Send + Sync + 'static,
+    {
+        let (sender, receiver) = channel::bounded(capacity);
+        task::spawn(async move {
+            let mut buf = vec![0u8; u16::MAX as usize];
+            loop {
+                let n = match tun.recv(&mut buf).await {
+                    Ok(n) => n,
+                    Err(e) => {
+                        let _ = sender.send(Err(e.into())).await;
+                        return;
+                    }
+                };
+                let packet = &buf[..n];
+                match self.reply(packet) {
+                    Some(reply) => {
+                        if let Err(e) = tun.send(&reply).await {
+                            if sender.send(Err(e.into())).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    None => {
+                        if sender.send(Ok(packet.to_vec())).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        receiver
+    }
+
+    fn reply_ip(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        match IpPacket::new_checked(packet).ok()? {
+            IpPacket::V4(packet) => self.reply_echo_v4(&packet),
+            IpPacket::V6(packet) => self.reply_echo_v6(&packet),
+        }
+    }
+
+    fn reply_frame(&self, frame: &[u8]) -> Option<Vec<u8>> {
+        let frame = EthernetFrame::new_checked(frame).ok()?;
+        let payload = frame.payload();
+        let (src_mac, reply) = match frame.payload_ethertype() {
+            ethertype::ARP => return self.reply_arp(&frame),
+            ethertype::IPV4 => {
+                let packet = Ipv4Packet::new_checked(payload).ok()?;
+                let dst = IpAddr::V4(packet.dst_addr());
+                (self.src_mac(&frame, dst), self.reply_echo_v4(&packet)?)
+            }
+            ethertype::IPV6 => {
+                let packet = Ipv6Packet::new_checked(payload).ok()?;
+                if let Some(reply) = self.reply_neighbor_solicitation(&frame, &packet) {
+                    return Some(reply);
+                }
+                let dst = IpAddr::V6(packet.dst_addr());
+                (self.src_mac(&frame, dst), self.reply_echo_v6(&packet)?)
+            }
+            _ => return None,
+        };
+        Some(ethernet_frame(
+            frame.src_addr(),
+            src_mac,
+            frame.payload_ethertype(),
+            &reply,
+        ))
+    }
+
+    fn reply_echo_v4(&self, packet: &Ipv4Packet<&[u8]>) -> Option<Vec<u8>> {
+        if packet.protocol() != protocol::ICMP
+            || packet.fragment_offset() != 0
+            || packet.flags() & ipv4::MORE_FRAGMENTS != 0
+            || !self.echo.contains(&IpAddr::V4(packet.dst_addr()))
+        {
+            return None;
+        }
+        let request = Icmpv4Packet::new_checked(packet.payload()).ok()?;
+        if request.msg_type() != icmp::v4::ECHO_REQUEST || !request.verify_checksum() {
+            return None;
+        }
+        let mut reply = vec![0u8; ipv4::HEADER_LEN + packet.payload().len()];
+        reply[ipv4::HEADER_LEN..].copy_from_slice(packet.payload());
+        let mut message = Icmpv4Packet::new_unchecked(&mut reply[ipv4::HEADER_LEN..]);
+        message.set_msg_type(icmp::v4::ECHO_REPLY);
+        message.fill_checksum();
+        ipv4_header(
+            &mut reply,
+            packet.dst_addr(),
+            packet.src_addr(),
+            protocol::ICMP,
+        );
+        Some(reply)
+    }
+
+    fn reply_echo_v6(&self, packet: &Ipv6Packet<&[u8]>) -> Option<Vec<u8>> {
+        if !self.echo.contains(&IpAddr::V6(packet.dst_addr())) {
+            return None;
+        }
+        let (src, dst) = (packet.src_addr(), packet.dst_addr());
+        let request = icmpv6_message(packet)?;
+        if request.msg_type() != icmp::v6::ECHO_REQUEST || !request.verify_checksum(src, dst) {
+            return None;
+        }
+        Some(ipv6_packet(
+            dst,
+            src,
+            HOP_LIMIT,
+            request.into_inner(),
+            |message| message.set_msg_type(icmp::v6::ECHO_REPLY),
+        ))
+    }
+
+    fn reply_arp(&self, frame: &EthernetFrame<&[u8]>) -> Option<Vec<u8>> {
+        let request = ArpPacket::new_checked(frame.payload()).ok()?;
+        let target = request.target_protocol_addr();
+        let mac = *self.neighbors.get(&IpAddr::V4(target))?;
+        if request.operation() != arp::REQUEST {
+            return None;
+        }
+        let mut reply = [0u8; arp::PACKET_LEN];
+        let mut packet = ArpPacket::new_unchecked(&mut reply[..]);
+        packet.init(arp::REPLY);
+        packet.set_sender_hardware_addr(mac);
+        packet.set_sender_protocol_addr(target);
+        packet.set_target_hardware_addr(request.sender_hardware_addr());
+        packet.set_target_protocol_addr(request.sender_protocol_addr());
+        Some(ethernet_frame(
+            request.sender_hardware_addr(),
+            mac,
+            ethertype::ARP,
+            &reply,
+        ))
+    }
+
+    fn reply_neighbor_solicitation(
+        &self,
+        frame: &EthernetFrame<&[u8]>,
+        packet: &Ipv6Packet<&[u8]>,
+    ) -> Option<Vec<u8>> {
+        let (src, dst) = (packet.src_addr(), packet.dst_addr());
+        let request = icmpv6_message(packet)?;
+        if request.msg_type() != icmp::v6::NEIGHBOR_SOLICITATION
+            || packet.hop_limit() != NDP_HOP_LIMIT
+            || !request.verify_checksum(src, dst)
+        {
+            return None;
+        }
+        let target = request.target_addr().ok()?;
+        let mac = *self.neighbors.get(&IpAddr::V6(target))?;
+        // Solicitations for duplicate address detection are answered to all nodes.
+        let (reply_dst, flags) = if src.is_unspecified() {
+            (Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1), NA_FLAG_OVERRIDE)
+        } else {
+            (src, NA_FLAG_SOLICITED | NA_FLAG_OVERRIDE)
+        };
+        let mut message = [0u8; icmp::HEADER_LEN + 16 + 8];
+        message[0] = icmp::v6::NEIGHBOR_ADVERTISEMENT;
+        message[4] = flags;
+        message[icmp::HEADER_LEN..icmp::HEADER_LEN + 16].copy_from_slice(&target.octets());
+        message[icmp::HEADER_LEN + 16] = NDP_OPTION_TARGET_LINK_ADDR;
+        message[icmp::HEADER_LEN + 17] = 1;
+        message[icmp::HEADER_LEN + 18..].copy_from_slice(&mac.bytes());
+        let reply = ipv6_packet(target, reply_dst, NDP_HOP_LIMIT, &message, |_| {});
+        let dst_mac = if src.is_unspecified() {
+            MacAddress::new([0x33, 0x33, 0, 0, 0, 1])
+        } else {
+            frame.src_addr()
+        };
+        Some(ethernet_frame(dst_mac, mac, ethertype::IPV6, &reply))
+    }
+
+    fn src_mac(&self, frame: &EthernetFrame<&[u8]>, dst: IpAddr) -> MacAddress {
+        let mac = frame.dst_addr();
+        // Replies never originate from a group address.
+        if mac.bytes()[0] & 0x01 != 0 {
+            if let Some(mac) = self.neighbors.get(&dst) {
+                return *mac;
+            }
+        }
+        mac
+    }
+}
+
+fn icmpv6_message<'a>(packet: &Ipv6Packet<&'a [u8]>) -> Option<Icmpv6Packet<&'a [u8]>> {
+    let (next_header, offset) = packet.upper_layer().ok()?;
+    if next_header != protocol::ICMPV6 {
+        return None;
+    }
+    let buf: &'a [u8] = packet.clone().into_inner();
+    let end = ipv6::HEADER_LEN + packet.payload_len() as usize;
+    Icmpv6Packet::new_checked(&buf[offset..end]).ok()
+}
+
+fn ipv4_header(buf: &mut [u8], src: Ipv4Addr, dst: Ipv4Addr, protocol: u8) {
+    let total_len = buf.len() as u16;
+    buf[0] = 0x45;
+    buf[2..4].copy_from_slice(&total_len.to_be_bytes());
+    buf[8] = HOP_LIMIT;
+    buf[9] = protocol;
+    buf[12..16].copy_from_slice(&src.octets());
+    buf[16..20].copy_from_slice(&dst.octets());
+    Ipv4Packet::new_unchecked(buf).fill_checksum();
+}
+
+fn ipv6_packet(
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+    hop_limit: u8,
+    message: &[u8],
+    edit: impl FnOnce(&mut Icmpv6Packet<&mut [u8]>),
+) -> Vec<u8> {
+    let mut buf = vec![0u8; ipv6::HEADER_LEN + message.len()];
+    buf[0] = 0x60;
+    buf[4..6].copy_from_slice(&(message.len() as u16).to_be_bytes());
+    buf[6] = protocol::ICMPV6;
+    buf[7] = hop_limit;
+    buf[8..24].copy_from_slice(&src.octets());
+    buf[24..40].copy_from_slice(&dst.octets());
+    buf[ipv6::HEADER_LEN..].copy_from_slice(message);
+    let mut message = Icmpv6Packet::new_unchecked(&mut buf[ipv6::HEADER_LEN..]);
+    edit(&mut message);
+    message.fill_checksum(src, dst);
+    buf
+}
+
+fn ethernet_frame(dst: MacAddress, src: MacAddress, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0u8; ethernet::HEADER_LEN + payload.len()];
+    let mut frame = EthernetFrame::new_unchecked(&mut buf[..]);
+    frame.set_dst_addr(dst);
+    frame.set_src_addr(src);
+    frame.set_ethertype(ethertype);
+    frame.payload_mut().copy_from_slice(payload);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTun;
+    use crate::packet::tests::{ipv4, ipv6, DST4, DST6, SRC4, SRC6};
+
+    const HOST: [u8; 6] = [2, 0, 0, 0, 0, 1];
+    const MAC: [u8; 6] = [2, 0, 0, 0, 0, 2];
+
+    /// Returns an echo request from `SRC4` to `DST4`.
+    fn echo_request_v4() -> Vec<u8> {
+        let mut message = vec![icmp::v4::ECHO_REQUEST, 0, 0, 0, 0x12, 0x34, 0, 1];
+        message.extend_from_slice(b"ping");
+        Icmpv4Packet::new_unchecked(&mut message[..]).fill_checksum();
+        ipv4(protocol::ICMP, &message)
+    }
+
+    /// Returns the reply to [`echo_request_v4`], built field by field.
+    fn echo_reply_v4() -> Vec<u8> {
+        let mut reply = vec![0x45, 0, 0, 32, 0, 0, 0, 0, 64, protocol::ICMP, 0, 0];
+        reply.extend_from_slice(&DST4.octets());
+        reply.extend_from_slice(&SRC4.octets());
+        reply.extend_from_slice(&[icmp::v4::ECHO_REPLY, 0, 0, 0, 0x12, 0x34, 0, 1]);
+        reply.extend_from_slice(b"ping");
+        Icmpv4Packet::new_unchecked(&mut reply[20..]).fill_checksum();
+        Ipv4Packet::new_unchecked(&mut reply[..]).fill_checksum();
+        reply
+    }
+
+    /// Returns a frame from `src` to `dst` with the given VLAN `tags`.
+    fn frame(dst: [u8; 6], src: [u8; 6], tags: &[u16], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut buf = [dst, src].concat();
+        for tci in tags {
+            buf.extend_from_slice(&ethertype::VLAN.to_be_bytes());
+            buf.extend_from_slice(&tci.to_be_bytes());
+        }
+        buf.extend_from_slice(&ethertype.to_be_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    /// Returns a neighbor solicitation for `DST6` sent from `src`.
+    fn neighbor_solicitation(src: Ipv6Addr) -> Vec<u8> {
+        let mut message = vec![icmp::v6::NEIGHBOR_SOLICITATION, 0, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(&DST6.octets());
+        message.extend_from_slice(&[1, 1]);
+        message.extend_from_slice(&HOST);
+        let mut packet = ipv6(protocol::ICMPV6, &message);
+        packet[7] = NDP_HOP_LIMIT;
+        packet[8..24].copy_from_slice(&src.octets());
+        Icmpv6Packet::new_unchecked(&mut packet[40..]).fill_checksum(src, DST6);
+        packet
+    }
+
+    /// Returns the neighbor advertisement for `DST6` sent to `dst` with `flags`.
+    fn neighbor_advertisement(dst: Ipv6Addr, flags: u8) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0, 0, 32, protocol::ICMPV6, NDP_HOP_LIMIT];
+        packet.extend_from_slice(&DST6.octets());
+        packet.extend_from_slice(&dst.octets());
+        packet.extend_from_slice(&[icmp::v6::NEIGHBOR_ADVERTISEMENT, 0, 0, 0, flags, 0, 0, 0]);
+        packet.extend_from_slice(&DST6.octets());
+        packet.extend_from_slice(&[2, 1]);
+        packet.extend_from_slice(&MAC);
+        Icmpv6Packet::new_unchecked(&mut packet[40..]).fill_checksum(DST6, dst);
+        packet
+    }
+
+    #[test]
+    fn echo_v4() {
+        let responder = Responder::new().echo(DST4.into());
+        assert_eq!(
+            responder.reply(&echo_request_v4()).unwrap(),
+            echo_reply_v4()
+        );
+
+        // Requests to other addresses, with a bad checksum or fragmented are not answered.
+        assert!(Responder::new().reply(&echo_request_v4()).is_none());
+        let mut request = echo_request_v4();
+        request[27] ^= 1;
+        assert!(responder.reply(&request).is_none());
+        let mut request = echo_request_v4();
+        request[6] = (ipv4::MORE_FRAGMENTS >> 8) as u8;
+        Ipv4Packet::new_unchecked(&mut request[..]).fill_checksum();
+        assert!(responder.reply(&request).is_none());
+        assert!(responder.reply(&echo_reply_v4()).is_none());
+    }
+
+    #[test]
+    fn echo_v6() {
+        let mut message = vec![icmp::v6::ECHO_REQUEST, 0, 0, 0, 0x12, 0x34, 0, 1];
+        message.extend_from_slice(b"ping");
+        let mut request = ipv6(protocol::ICMPV6, &message);
+        Icmpv6Packet::new_unchecked(&mut request[40..]).fill_checksum(SRC6, DST6);
+
+        let mut expected = vec![0x60, 0, 0, 0, 0, 12, protocol::ICMPV6, HOP_LIMIT];
+        expected.extend_from_slice(&DST6.octets());
+        expected.extend_from_slice(&SRC6.octets());
+        expected.extend_from_slice(&[icmp::v6::ECHO_REPLY, 0, 0, 0, 0x12, 0x34, 0, 1]);
+        expected.extend_from_slice(b"ping");
+        Icmpv6Packet::new_unchecked(&mut expected[40..]).fill_checksum(DST6, SRC6);
+
+        let responder = Responder::new().echo(DST6.into());
+        assert_eq!(responder.reply(&request).unwrap(), expected);
+        assert!(Responder::new().echo(SRC6.into()).reply(&request).is_none());
+    }
+
+    #[test]
+    fn arp() {
+        let mut request = vec![0, 1, 8, 0, 6, 4, 0, 1];
+        request.extend_from_slice(&HOST);
+        request.extend_from_slice(&SRC4.octets());
+        request.extend_from_slice(&[0; 6]);
+        request.extend_from_slice(&DST4.octets());
+        let mut reply = vec![0, 1, 8, 0, 6, 4, 0, 2];
+        reply.extend_from_slice(&MAC);
+        reply.extend_from_slice(&DST4.octets());
+        reply.extend_from_slice(&HOST);
+        reply.extend_from_slice(&SRC4.octets());
+
+        let responder = Responder::new()
+            .tap(true)
+            .neighbor(DST4.into(), MacAddress::new(MAC));
+        let request = frame([0xff; 6], HOST, &[], ethertype::ARP, &request);
+        assert_eq!(
+            responder.reply(&request).unwrap(),
+            frame(HOST, MAC, &[], ethertype::ARP, &reply)
+        );
+        assert!(Responder::new().tap(true).reply(&request).is_none());
+    }
+
+    #[test]
+    fn ndp() {
+        let responder = Responder::new()
+            .tap(true)
+            .neighbor(DST6.into(), MacAddress::new(MAC));
+        let request = neighbor_solicitation(SRC6);
+        let request = frame(
+            [0x33, 0x33, 0xff, 0, 0, 2],
+            HOST,
+            &[],
+            ethertype::IPV6,
+            &request,
+        );
+        let reply = neighbor_advertisement(SRC6, NA_FLAG_SOLICITED | NA_FLAG_OVERRIDE);
+        assert_eq!(
+            responder.reply(&request).unwrap(),
+            frame(HOST, MAC, &[], ethertype::IPV6, &reply)
+        );
+
+        // Duplicate address detection is answered to all nodes.
+        let request = neighbor_solicitation(Ipv6Addr::UNSPECIFIED);
+        let request = frame(
+            [0x33, 0x33, 0xff, 0, 0, 2],
+            HOST,
+            &[],
+            ethertype::IPV6,
+            &request,
+        );
+        let all_nodes = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+        let reply = neighbor_advertisement(all_nodes, NA_FLAG_OVERRIDE);
+        let dst = [0x33, 0x33, 0, 0, 0, 1];
+        assert_eq!(
+            responder.reply(&request).unwrap(),
+            frame(dst, MAC, &[], ethertype::IPV6, &reply)
+        );
+
+        // Solicitations which may have been forwarded are not answered.
+        let mut request = neighbor_solicitation(SRC6);
+        request[7] = HOP_LIMIT;
+        let request = frame(MAC, HOST, &[], ethertype::IPV6, &request);
+        assert!(responder.reply(&request).is_none());
+    }
+
+    #[test]
+    fn vlan() {
+        let responder = Responder::new()
+            .tap(true)
+            .echo(DST4.into())
+            .neighbor(DST4.into(), MacAddress::new(MAC));
+        // A tagged request to the broadcast address gets an untagged reply from the neighbor.
+        let request = frame(
+            [0xff; 6],
+            HOST,
+            &[0x2005],
+            ethertype::IPV4,
+            &echo_request_v4(),
+        );
+        assert_eq!(
+            responder.reply(&request).unwrap(),
+            frame(HOST, MAC, &[], ethertype::IPV4, &echo_reply_v4())
+        );
+        let request = frame(MAC, HOST, &[5, 6], ethertype::IPV4, &echo_request_v4());
+        assert_eq!(
+            responder.reply(&request).unwrap(),
+            frame(HOST, MAC, &[], ethertype::IPV4, &echo_reply_v4())
+        );
+    }
+
+    #[test]
+    fn spawn() {
+        task::block_on(async {
+            let tun = Arc::new(MockTun::new("tun0"));
+            let kernel = tun.kernel();
+            let responder = Responder::for_tun(&*tun).echo(DST4.into());
+            let packets = responder.spawn(tun, 4);
+
+            let info = [0, 0, 8, 0];
+            kernel
+                .inject(&[&info[..], &echo_request_v4()].concat())
+                .await;
+            let reply = kernel.recv().await.unwrap();
+            assert_eq!(reply, [&info[..], &echo_reply_v4()].concat());
+
+            // Other packets are passed on.
+            let packet = [&info[..], &echo_reply_v4()].concat();
+            kernel.inject(&packet).await;
+            assert_eq!(packets.recv().await.unwrap().unwrap(), packet);
+            assert!(kernel.try_recv().is_none());
+        })
+    }
+}
@@ -0,0 +1,260 @@
+use crate::device::{DeviceKind, TunDevice};
+use crate::result::Result;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Represents the interface counters kept by the kernel for a device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceStats {
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_dropped: u64,
+}
+
+impl DeviceStats {
+    /// Reads the counters of the interface `name` from `/sys/class/net/<name>/statistics`.
+    #[cfg(target_os = "linux")]
+    pub fn read(name: &str) -> Result<Self> {
+        use crate::linux::sysfs;
+        let read = |counter: &str| sysfs::read::<u64>(name, &format!("statistics/{}", counter));
+        Ok(Self {
+            rx_packets: read("rx_packets")?,
+            tx_packets: read("tx_packets")?,
+            rx_bytes: read("rx_bytes")?,
+            tx_bytes: read("tx_bytes")?,
+            rx_errors: read("rx_errors")?,
+            tx_errors: read("tx_errors")?,
+            rx_dropped: read("rx_dropped")?,
+            tx_dropped: read("tx_dropped")?,
+        })
+    }
+}
+
+/// Represents a snapshot of the counters kept by this crate for one queue of a device.
+///
+/// Counters are updated by packets exchanged through [`TunDevice`](trait.TunDevice.html) and
+/// [`Tun::io`](struct.Tun.html#method.io), i.e. also by `io_reader`, `io_writer`, `io_split` and
+/// `with_capture`, but not by `reader`, `writer` and `split`, which go through the cache of `File`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Number of packets read from the device.
+    pub packets_read: u64,
+    /// Number of packets written to the device.
+    pub packets_written: u64,
+    /// Number of bytes read from the device.
+    pub bytes_read: u64,
+    /// Number of bytes written to the device.
+    pub bytes_written: u64,
+    /// Number of writes which did not write the whole packet.
+    pub short_writes: u64,
+    /// Number of times a write had to wait for the kernel to consume packets, as a device built
+    /// with backpressure does, or a read or write failed with `WouldBlock`. Reads waiting for a
+    /// packet are not counted.
+    pub would_block: u64,
+    /// Number of packets which filled the whole read buffer and were possibly truncated.
+    pub oversize: u64,
+    /// Number of failed reads and writes.
+    pub errors: u64,
+}
+
+/// Represents the live counters of one queue, shared between a device and its observers.
+#[derive(Debug, Default)]
+pub struct QueueCounters {
+    packets_read: AtomicU64,
+    packets_written: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    short_writes: AtomicU64,
+    would_block: AtomicU64,
+    oversize: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl QueueCounters {
+    /// Returns a snapshot of the counters.
+    pub fn snapshot(&self) -> QueueStats {
+        QueueStats {
+            packets_read: self.packets_read.load(Ordering::Relaxed),
+            packets_written: self.packets_written.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            short_writes: self.short_writes.load(Ordering::Relaxed),
+            would_block: self.would_block.load(Ordering::Relaxed),
+            oversize: self.oversize.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Updates the counters with the result of receiving into a buffer of `len` bytes.
+    pub(crate) fn on_recv(&self, result: &Poll<io::Result<usize>>, len: usize) {
+        match result {
+            Poll::Ready(Ok(n)) => {
+                self.packets_read.fetch_add(1, Ordering::Relaxed);
+                self.bytes_read.fetch_add(*n as u64, Ordering::Relaxed);
+                if *n == len {
+                    self.oversize.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Poll::Ready(Err(e)) => self.on_error(e),
+            Poll::Pending => {}
+        }
+    }
+
+    /// Updates the counters with the result of sending a packet of `len` bytes. A pending send
+    /// is counted as blocked only if `backpressure` tells it waits for the kernel to consume
+    /// packets, rather than e.g. for another task.
+    pub(crate) fn on_send(&self, result: &Poll<io::Result<usize>>, len: usize, backpressure: bool) {
+        match result {
+            Poll::Ready(Ok(n)) => {
+                self.packets_written.fetch_add(1, Ordering::Relaxed);
+                self.bytes_written.fetch_add(*n as u64, Ordering::Relaxed);
+                if *n < len {
+                    self.short_writes.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Poll::Ready(Err(e)) => self.on_error(e),
+            Poll::Pending if backpressure => {
+                self.would_block.fetch_add(1, Ordering::Relaxed);
+            }
+            Poll::Pending => {}
+        }
+    }
+
+    fn on_error(&self, error: &io::Error) {
+        if error.kind() == io::ErrorKind::WouldBlock {
+            self.would_block.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Represents a [`TunDevice`](trait.TunDevice.html) which counts the packets received from and
+/// sent to the inner device.
+pub struct Counted<D> {
+    device: D,
+    counters: Arc<QueueCounters>,
+}
+
+impl<D: TunDevice> Counted<D> {
+    /// Creates a new instance of [`Counted`](struct.Counted.html) wrapping `device`.
+    pub fn new(device: D) -> Self {
+        Self {
+            device,
+            counters: Default::default(),
+        }
+    }
+
+    /// Returns the counters, which can be observed while the device is in use.
+    pub fn counters(&self) -> Arc<QueueCounters> {
+        self.counters.clone()
+    }
+
+    /// Returns a snapshot of the counters.
+    pub fn stats(&self) -> QueueStats {
+        self.counters.snapshot()
+    }
+
+    /// Returns the underlying device.
+    pub fn get_ref(&self) -> &D {
+        &self.device
+    }
+
+    /// Returns the underlying device.
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+}
+
+impl<D: TunDevice> TunDevice for Counted<D> {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn mtu(&self) -> Result<i32> {
+        self.device.mtu()
+    }
+
+    fn kind(&self) -> DeviceKind {
+        self.device.kind()
+    }
+
+    fn packet_info(&self) -> bool {
+        self.device.packet_info()
+    }
+
+    fn header_len(&self) -> usize {
+        self.device.header_len()
+    }
+
+    fn poll_recv(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let result = self.device.poll_recv(cx, buf);
+        self.counters.on_recv(&result, buf.len());
+        result
+    }
+
+    fn poll_send(&self, cx: &mut Context<'_>, packet: &[u8]) -> Poll<io::Result<usize>> {
+        let result = self.device.poll_send(cx, packet);
+        self.counters.on_send(&result, packet.len(), false);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTun;
+    use async_std::task;
+    use std::task::Waker;
+
+    #[test]
+    fn counted() {
+        task::block_on(async {
+            let device = Counted::new(MockTun::new("mock0"));
+            let kernel = device.get_ref().kernel();
+            kernel.inject(&[0u8; 10]).await;
+            kernel.inject(&[0u8; 40]).await;
+
+            let mut buf = [0u8; 32];
+            assert_eq!(device.recv(&mut buf).await.unwrap(), 10);
+            assert_eq!(device.recv(&mut buf).await.unwrap(), 32);
+            device.send(&buf[..20]).await.unwrap();
+
+            let stats = device.stats();
+            assert_eq!(stats.packets_read, 2);
+            assert_eq!(stats.bytes_read, 42);
+            assert_eq!(stats.oversize, 1);
+            assert_eq!(stats.packets_written, 1);
+            assert_eq!(stats.bytes_written, 20);
+            assert_eq!(stats.short_writes, 0);
+            assert_eq!(stats.errors, 0);
+
+            // Waiting for a packet is not blocking.
+            let mut cx = Context::from_waker(Waker::noop());
+            assert!(device.poll_recv(&mut cx, &mut buf).is_pending());
+            assert_eq!(device.stats().would_block, 0);
+        })
+    }
+
+    #[test]
+    fn would_block() {
+        let counters = QueueCounters::default();
+        counters.on_recv(&Poll::Pending, 1500);
+        counters.on_send(&Poll::Pending, 1500, false);
+        assert_eq!(counters.snapshot().would_block, 0);
+        counters.on_send(&Poll::Pending, 1500, true);
+        let error = io::Error::from(io::ErrorKind::WouldBlock);
+        counters.on_recv(&Poll::Ready(Err(error)), 1500);
+        counters.on_send(&Poll::Ready(Err(io::ErrorKind::Other.into())), 1500, false);
+        let stats = counters.snapshot();
+        assert_eq!(stats.would_block, 2);
+        assert_eq!(stats.errors, 1);
+    }
+}
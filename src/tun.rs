@@ -1,25 +1,46 @@
+use crate::capture::{Capture, CaptureReader, CaptureWriter};
+use crate::device::{DeviceKind, TunDevice};
 #[cfg(target_os = "linux")]
 use crate::linux::interface::Interface;
 #[cfg(target_os = "linux")]
 use crate::linux::params::Params;
+#[cfg(target_os = "linux")]
+use crate::linux::sysfs;
+use crate::net::Ipv6Cidr;
+use crate::packet::PacketInfo;
 use crate::result::Result;
+use crate::stats::{DeviceStats, QueueCounters, QueueStats};
+use async_io::Async;
 use async_std::fs::File;
 use async_std::fs::OpenOptions;
-use async_std::io::{BufReader, BufWriter};
+use async_std::io::{BufReader, BufWriter, Read, Write};
 #[cfg(target_family = "unix")]
-use async_std::os::unix::io::{AsRawFd, RawFd};
+use async_std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use async_std::sync::Arc;
 use mac_address::{mac_address_by_name, MacAddress};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd::{Gid, Uid};
+use std::io;
 use std::net::Ipv4Addr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
 
 /// Represents a Tun/Tap device. Use [`TunBuilder`](struct.TunBuilder.html) to create a new instance of [`Tun`](struct.Tun.html).
 pub struct Tun {
     file: File,
+    io: Async<std::fs::File>,
+    backpressure: bool,
     iface: Arc<Interface>,
+    queue: usize,
+    counters: Arc<QueueCounters>,
+    /// A packet read while waiting for smoltcp, see [`Tun::wait`].
+    #[cfg(feature = "smoltcp")]
+    pub(crate) pending: Option<Vec<u8>>,
 }
 
 impl Tun {
     #[cfg(target_os = "linux")]
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", err))]
     async fn alloc(params: Params, queues: usize) -> Result<(Vec<File>, Interface)> {
         let mut files = Vec::with_capacity(queues);
         for _ in 0..queues {
@@ -39,6 +60,15 @@ impl Tun {
         if let Some(mtu) = params.mtu {
             iface.mtu(Some(mtu))?;
         }
+        if let Some(txqueuelen) = params.txqueuelen {
+            iface.txqueuelen(Some(txqueuelen))?;
+        }
+        if let Some(link_type) = params.link_type {
+            iface.set_link_type(link_type)?;
+        }
+        if let Some(sndbuf) = params.sndbuf {
+            iface.sndbuf(files[0].as_raw_fd(), Some(sndbuf))?;
+        }
         if let Some(owner) = params.owner {
             iface.owner(owner)?;
         }
@@ -60,12 +90,21 @@ impl Tun {
         if let Some(mac) = params.mac {
             iface.set_mac(mac)?;
         }
+        for (address, prefix) in params.ipv6.iter() {
+            iface.add_address_v6(*address, *prefix)?;
+        }
+        if let Some(master) = params.master.as_deref() {
+            iface.set_master(Some(master))?;
+        }
         if params.persist {
-            iface.persist()?;
+            iface.persist(true)?;
         }
         if params.up {
             iface.flags(Some(libc::IFF_UP as i16 | libc::IFF_RUNNING as i16))?;
         }
+        for route in params.routes.iter() {
+            iface.add_route(route)?;
+        }
         Ok((files, iface))
     }
 
@@ -74,27 +113,61 @@ impl Tun {
         unimplemented!()
     }
 
+    fn with_queue(
+        file: File,
+        iface: Arc<Interface>,
+        queue: usize,
+        backpressure: bool,
+    ) -> Result<Self> {
+        // Packets go through a duplicate of the queue, read and written directly rather than
+        // through the cache of `File`, which would merge or hold back packets. It shares the
+        // open file description, which only backpressure switches to `O_NONBLOCK`.
+        let fd = nix::unistd::dup(file.as_raw_fd())?;
+        let dup = unsafe { std::fs::File::from_raw_fd(fd) };
+        let io = if backpressure {
+            Async::new(dup)?
+        } else {
+            Async::new_nonblocking(dup)?
+        };
+        Ok(Self {
+            file,
+            io,
+            backpressure,
+            iface,
+            queue,
+            counters: Default::default(),
+            #[cfg(feature = "smoltcp")]
+            pending: None,
+        })
+    }
+
     /// Creates a new instance of Tun/Tap device.
     pub(crate) async fn new(params: Params) -> Result<Self> {
+        let backpressure = params.backpressure;
         let (files, iface) = Self::alloc(params, 1).await?;
         let file = files.into_iter().next().unwrap();
-        Ok(Self {
-            file,
-            iface: Arc::new(iface),
-        })
+        Self::with_queue(file, Arc::new(iface), 0, backpressure)
     }
 
     /// Creates a new instance of Tun/Tap device.
     #[cfg(target_os = "linux")]
     pub(crate) async fn new_mq(params: Params, queues: usize) -> Result<Vec<Self>> {
+        let backpressure = params.backpressure;
         let (files, iface) = Self::alloc(params, queues).await?;
-        let mut tuns = Vec::with_capacity(queues);
+        Self::with_queues(files, iface, backpressure)
+    }
+
+    /// Creates an instance for each queue of `files` of the device `iface`.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn with_queues(
+        files: Vec<File>,
+        iface: Interface,
+        backpressure: bool,
+    ) -> Result<Vec<Self>> {
+        let mut tuns = Vec::with_capacity(files.len());
         let iface = Arc::new(iface);
-        for file in files.into_iter() {
-            tuns.push(Self {
-                file,
-                iface: iface.clone(),
-            })
+        for (queue, file) in files.into_iter().enumerate() {
+            tuns.push(Self::with_queue(file, iface.clone(), queue, backpressure)?)
         }
         Ok(tuns)
     }
@@ -104,6 +177,32 @@ impl Tun {
         self.iface.name()
     }
 
+    /// Returns `true` if the device is a TAP device.
+    pub fn is_tap(&self) -> bool {
+        self.iface.tun_flags() & libc::IFF_TAP as i16 != 0
+    }
+
+    /// Returns `true` if packets are prefixed with packet information, i.e. `IFF_NO_PI` is not set.
+    pub fn packet_info(&self) -> bool {
+        self.iface.tun_flags() & libc::IFF_NO_PI as i16 == 0
+    }
+
+    /// Returns `true` if packets are prefixed with a `virtio_net_hdr`, i.e. `IFF_VNET_HDR` is set.
+    pub fn vnet_hdr(&self) -> bool {
+        self.iface.tun_flags() & libc::IFF_VNET_HDR as i16 != 0
+    }
+
+    /// Returns the length of the `virtio_net_hdr` prefixing packets, or zero if `IFF_VNET_HDR` is not set.
+    pub fn vnet_hdr_len(&self) -> usize {
+        self.iface.vnet_hdr_len()
+    }
+
+    /// Returns the length of the transmit queue of device.
+    #[cfg(target_os = "linux")]
+    pub fn txqueuelen(&self) -> Result<u32> {
+        self.iface.txqueuelen(None)
+    }
+
     /// Returns the value of MTU.
     pub fn mtu(&self) -> Result<i32> {
         self.iface.mtu(None)
@@ -129,6 +228,12 @@ impl Tun {
         self.iface.netmask(None)
     }
 
+    /// Returns the IPv6 addresses of device with their prefix lengths.
+    #[cfg(target_os = "linux")]
+    pub fn ipv6_addresses(&self) -> Result<Vec<Ipv6Cidr>> {
+        sysfs::ipv6_addresses(self.name())
+    }
+
     /// Returns to Ethernet MAC address.
     pub fn mac(&self) -> Result<Option<MacAddress>> {
         Ok(mac_address_by_name(self.name())?)
@@ -139,7 +244,99 @@ impl Tun {
         self.iface.flags(None)
     }
 
+    /// Returns the owner of device, or `None` if any user may attach to it.
+    #[cfg(target_os = "linux")]
+    pub fn owner(&self) -> Result<Option<Uid>> {
+        sysfs::owner(self.name())
+    }
+
+    /// Returns the group of device, or `None` if any group may attach to it.
+    #[cfg(target_os = "linux")]
+    pub fn group(&self) -> Result<Option<Gid>> {
+        sysfs::group(self.name())
+    }
+
+    /// Returns the `IFF_*` flags supported by the kernel, as reported by `TUNGETFEATURES`.
+    #[cfg(target_os = "linux")]
+    pub fn features(&self) -> Result<i32> {
+        self.iface.features()
+    }
+
+    /// Makes the device persistent, or lets the kernel delete it once all its queues are closed.
+    #[cfg(target_os = "linux")]
+    pub fn set_persist(&self, persist: bool) -> Result<()> {
+        self.iface.persist(persist)
+    }
+
+    /// Returns the name of the bridge, bond or VRF device is enslaved to, if any.
+    #[cfg(target_os = "linux")]
+    pub fn master(&self) -> Result<Option<String>> {
+        sysfs::master(self.name())
+    }
+
+    /// Enslaves the device to the bridge, bond or VRF `master`.
+    #[cfg(target_os = "linux")]
+    pub fn set_master(&self, master: &str) -> Result<()> {
+        self.iface.set_master(Some(master))
+    }
+
+    /// Releases the device from its bridge, bond or VRF.
+    #[cfg(target_os = "linux")]
+    pub fn release_master(&self) -> Result<()> {
+        self.iface.set_master(None)
+    }
+
+    /// Returns the size of the send buffer of the queue of this instance, which bounds the
+    /// packets written to the device but not yet consumed by the kernel.
+    #[cfg(target_os = "linux")]
+    pub fn sndbuf(&self) -> Result<i32> {
+        self.iface.sndbuf(self.file.as_raw_fd(), None)
+    }
+
+    /// Sets the size of the send buffer of device, shared by all queues.
+    #[cfg(target_os = "linux")]
+    pub fn set_sndbuf(&self, sndbuf: i32) -> Result<()> {
+        self.iface.sndbuf(self.file.as_raw_fd(), Some(sndbuf))?;
+        Ok(())
+    }
+
+    /// Returns `true` if the device was built with [`backpressure`](struct.TunBuilder.html#method.backpressure).
+    pub fn backpressure(&self) -> bool {
+        self.backpressure
+    }
+
+    /// Returns the index of the queue of this instance, as created by `try_build_mq`.
+    pub fn queue(&self) -> usize {
+        self.queue
+    }
+
+    /// Returns the interface counters kept by the kernel, shared by all queues.
+    #[cfg(target_os = "linux")]
+    pub fn stats(&self) -> Result<DeviceStats> {
+        DeviceStats::read(self.name())
+    }
+
+    /// Returns the counters kept by this crate for the queue of this instance.
+    pub fn queue_stats(&self) -> QueueStats {
+        self.counters.snapshot()
+    }
+
+    /// Returns the live counters of the queue of this instance.
+    pub fn queue_counters(&self) -> Arc<QueueCounters> {
+        self.counters.clone()
+    }
+
+    /// Returns the queue of this instance, which reads and writes one packet per call and waits
+    /// for the device to be ready rather than fails with `WouldBlock` under
+    /// [`backpressure`](#method.backpressure).
+    pub fn io(&self) -> TunIo<'_> {
+        TunIo { tun: self }
+    }
+
     /// Splits self to reader and writer pairs.
+    ///
+    /// They go through the cache of [`File`], which fails with `WouldBlock` under
+    /// [`backpressure`](#method.backpressure); see [`io_split`](#method.io_split).
     pub fn split(&self) -> (BufReader<&File>, BufWriter<&File>) {
         (BufReader::new(&self.file), BufWriter::new(&self.file))
     }
@@ -153,6 +350,173 @@ impl Tun {
     pub fn writer(&self) -> BufWriter<&File> {
         BufWriter::new(&self.file)
     }
+
+    /// Splits self to reader and writer pairs going through [`io`](#method.io), which wait for
+    /// the device under [`backpressure`](#method.backpressure).
+    pub fn io_split(&self) -> (BufReader<TunIo<'_>>, BufWriter<TunIo<'_>>) {
+        (self.io_reader(), self.io_writer())
+    }
+
+    /// Returns a reader to read from tun through [`io`](#method.io).
+    pub fn io_reader(&self) -> BufReader<TunIo<'_>> {
+        BufReader::new(self.io())
+    }
+
+    /// Returns a writer to write to tun through [`io`](#method.io).
+    pub fn io_writer(&self) -> BufWriter<TunIo<'_>> {
+        BufWriter::new(self.io())
+    }
+
+    /// Returns a [`Capture`](capture/struct.Capture.html) writing to `sink`, configured with
+    /// the link type, header length and name of the device.
+    pub fn capture<W: std::io::Write + Send>(&self, sink: W) -> Capture<W> {
+        Capture::for_device(self, sink)
+    }
+
+    /// Splits self to reader and writer pairs which record every packet into `capture`, see
+    /// [`capture`](#method.capture).
+    pub fn with_capture<W: std::io::Write + Send>(
+        &self,
+        capture: Capture<W>,
+    ) -> (CaptureReader<TunIo<'_>, W>, CaptureWriter<TunIo<'_>, W>) {
+        let capture = Arc::new(capture);
+        (capture.reader(self.io()), capture.writer(self.io()))
+    }
+}
+
+impl Tun {
+    fn poll_read(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        loop {
+            // A blocking queue is read only once a packet is waiting.
+            if self.backpressure || readable(self.io.as_raw_fd())? {
+                match std::io::Read::read(&mut self.io.get_ref(), buf) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    result => return Poll::Ready(result),
+                }
+            }
+            ready!(self.io.poll_readable(cx))?;
+        }
+    }
+
+    fn poll_write(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        loop {
+            match std::io::Write::write(&mut self.io.get_ref(), buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    ready!(self.io.poll_writable(cx))?
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+}
+
+/// Reads from and writes to the queue of a [`Tun`](struct.Tun.html), see [`Tun::io`](struct.Tun.html#method.io).
+///
+/// Every read or write is one `read(2)` or `write(2)`, so one packet. Without backpressure, reads
+/// wait for a packet through the reactor, while writes are done in place as the kernel does not
+/// hold them back unless the send buffer was lowered.
+#[derive(Clone, Copy)]
+pub struct TunIo<'a> {
+    tun: &'a Tun,
+}
+
+impl Read for TunIo<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = self.tun.poll_read(cx, buf);
+        self.tun.counters.on_recv(&result, buf.len());
+        #[cfg(feature = "tracing")]
+        if let Poll::Ready(Ok(len)) = result {
+            tracing::trace!(
+                name = self.tun.name(),
+                queue = self.tun.queue,
+                len,
+                "packet read"
+            );
+        }
+        result
+    }
+}
+
+impl Write for TunIo<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = self.tun.poll_write(cx, buf);
+        self.tun
+            .counters
+            .on_send(&result, buf.len(), self.tun.backpressure);
+        #[cfg(feature = "tracing")]
+        if let Poll::Ready(Ok(len)) = result {
+            tracing::trace!(
+                name = self.tun.name(),
+                queue = self.tun.queue,
+                len,
+                "packet written"
+            );
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Returns `true` if a read of `fd` would not block.
+fn readable(fd: RawFd) -> io::Result<bool> {
+    let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+    Ok(poll(&mut fds, 0)? > 0)
+}
+
+impl TunDevice for Tun {
+    fn name(&self) -> &str {
+        self.name()
+    }
+
+    fn mtu(&self) -> Result<i32> {
+        self.mtu()
+    }
+
+    fn kind(&self) -> DeviceKind {
+        if self.is_tap() {
+            DeviceKind::Tap
+        } else {
+            DeviceKind::Tun
+        }
+    }
+
+    fn packet_info(&self) -> bool {
+        self.packet_info()
+    }
+
+    fn header_len(&self) -> usize {
+        let packet_info = if self.packet_info() {
+            PacketInfo::<&[u8]>::LEN
+        } else {
+            0
+        };
+        packet_info + self.vnet_hdr_len()
+    }
+
+    fn poll_recv(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io()).poll_read(cx, buf)
+    }
+
+    fn poll_send(&self, cx: &mut Context<'_>, packet: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io()).poll_write(cx, packet)
+    }
 }
 
 #[cfg(target_family = "unix")]
@@ -161,3 +525,133 @@ impl AsRawFd for Tun {
         self.file.as_raw_fd()
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::builder::TunBuilder;
+    use crate::packet::tests::{ipv4, udp};
+    use crate::packet::{protocol, IpPacket};
+    use async_std::channel;
+    use async_std::future::timeout;
+    use async_std::io::prelude::*;
+    use async_std::net::UdpSocket;
+    use async_std::task;
+    use std::net::IpAddr;
+    use std::path::Path;
+    use std::time::Duration;
+
+    /// Returns `true` if devices can be created, otherwise tests using them are skipped.
+    pub(crate) fn net_admin() -> bool {
+        const CAP_NET_ADMIN: u32 = 12;
+        let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+        let caps = status
+            .lines()
+            .find_map(|line| line.strip_prefix("CapEff:"))
+            .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+            .unwrap_or_default();
+        let allowed = caps & 1 << CAP_NET_ADMIN != 0 && Path::new("/dev/net/tun").exists();
+        if !allowed {
+            eprintln!("skipped: requires CAP_NET_ADMIN and /dev/net/tun");
+        }
+        allowed
+    }
+
+    async fn exchange(backpressure: bool, subnet: u8) {
+        let address = Ipv4Addr::new(10, 251, subnet, 1);
+        let peer = Ipv4Addr::new(10, 251, subnet, 2);
+        let tun = TunBuilder::new()
+            .name("tunio%d")
+            .packet_info(false)
+            .backpressure(backpressure)
+            .address(address)
+            .netmask(Ipv4Addr::new(255, 255, 255, 0))
+            .up()
+            .try_build()
+            .await
+            .unwrap();
+        let tun = Arc::new(tun);
+
+        // A pending read neither holds back nor merges the packets sent meanwhile.
+        let (sender, packets) = channel::unbounded();
+        let reader = tun.clone();
+        let reading = task::spawn(async move {
+            let mut buf = vec![0u8; 2048];
+            while let Ok(n) = reader.recv(&mut buf).await {
+                if sender.send(buf[..n].to_vec()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let before = tun.stats().unwrap();
+        let small = ipv4(protocol::UDP, &udp(peer.into(), address.into(), &[]));
+        let large = ipv4(
+            protocol::UDP,
+            &udp(peer.into(), address.into(), &[0xab; 1400]),
+        );
+        assert_eq!(tun.send(&small).await.unwrap(), small.len());
+        assert_eq!(tun.send(&large).await.unwrap(), large.len());
+        let after = tun.stats().unwrap();
+        assert_eq!(after.rx_packets - before.rx_packets, 2);
+        assert_eq!(
+            after.rx_bytes - before.rx_bytes,
+            (small.len() + large.len()) as u64
+        );
+
+        // Packets written through the writer of the queue are counted as well.
+        let mut writer = tun.io_writer();
+        writer.write_all(&small).await.unwrap();
+        writer.flush().await.unwrap();
+        let stats = tun.queue_stats();
+        assert_eq!(stats.packets_written, 3);
+        assert_eq!(stats.bytes_written, (2 * small.len() + large.len()) as u64);
+        assert_eq!(tun.stats().unwrap().rx_packets - before.rx_packets, 3);
+
+        // Each read returns one packet routed to the device.
+        let socket = UdpSocket::bind((address, 0)).await.unwrap();
+        socket.send_to(b"ping", (peer, 9)).await.unwrap();
+        let packet = timeout(Duration::from_secs(5), async {
+            loop {
+                let packet = packets.recv().await.unwrap();
+                let ip = IpPacket::new_checked(&packet[..]).unwrap();
+                if ip.dst_addr() == IpAddr::V4(peer) && ip.protocol() == Ok(protocol::UDP) {
+                    return packet;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(packet.len(), 20 + 8 + 4);
+        assert_eq!(&packet[28..], b"ping");
+        reading.cancel().await;
+        assert!(tun.queue_stats().packets_read >= 1);
+
+        // So are packets read through the reader of the queue.
+        socket.send_to(b"pong", (peer, 9)).await.unwrap();
+        let read = tun.queue_stats().packets_read;
+        let mut reader = tun.io_reader();
+        let mut buf = vec![0u8; 2048];
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let n = reader.read(&mut buf).await.unwrap();
+                if buf[..n].ends_with(b"pong") {
+                    break;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(tun.queue_stats().packets_read > read);
+    }
+
+    #[test]
+    fn one_packet_per_call() {
+        if !net_admin() {
+            return;
+        }
+        task::block_on(async {
+            exchange(false, 30).await;
+            exchange(true, 31).await;
+        });
+    }
+}
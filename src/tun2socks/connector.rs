@@ -0,0 +1,65 @@
+use async_std::net::{TcpStream, UdpSocket};
+use std::future::Future;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+
+/// An owned future returned by the methods of [`Connector`](trait.Connector.html) and
+/// [`UdpRelay`](trait.UdpRelay.html).
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Opens the outbound connections of the flows captured by [`Tun2Socks`](struct.Tun2Socks.html).
+pub trait Connector: Send + Sync + 'static {
+    /// Opens a TCP connection to `dst`, the destination of a TCP flow.
+    fn connect(&self, dst: SocketAddr) -> BoxFuture<'_, io::Result<TcpStream>>;
+
+    /// Opens a relay for the datagrams of a UDP flow to `dst`.
+    fn associate(&self, dst: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn UdpRelay>>>;
+}
+
+/// Relays the datagrams of a UDP flow to and from its destination.
+pub trait UdpRelay: Send + Sync {
+    /// Sends `payload` to the destination of the flow.
+    fn send<'a>(&'a self, payload: &'a [u8]) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Receives the payload of a datagram of the destination into `buf`, returning its length.
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>>;
+}
+
+/// Connects flows directly to their destinations.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Direct;
+
+impl Connector for Direct {
+    fn connect(&self, dst: SocketAddr) -> BoxFuture<'_, io::Result<TcpStream>> {
+        Box::pin(TcpStream::connect(dst))
+    }
+
+    fn associate(&self, dst: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn UdpRelay>>> {
+        Box::pin(async move {
+            let socket = UdpSocket::bind(unspecified(dst)).await?;
+            socket.connect(dst).await?;
+            Ok(Box::new(DirectRelay(socket)) as Box<dyn UdpRelay>)
+        })
+    }
+}
+
+struct DirectRelay(UdpSocket);
+
+impl UdpRelay for DirectRelay {
+    fn send<'a>(&'a self, payload: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move { self.0.send(payload).await.map(|_| ()) })
+    }
+
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(self.0.recv(buf))
+    }
+}
+
+/// Returns the unspecified address of the family of `addr`, with an ephemeral port.
+pub(crate) fn unspecified(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
@@ -0,0 +1,631 @@
+//! A tun2socks-style proxy, which captures the TCP and UDP flows routed to a TUN device and
+//! forwards each of them through an outbound connection.
+//!
+//! TCP flows are terminated by a userspace stack, so their data is relayed over a TCP connection
+//! opened by a [`Connector`](trait.Connector.html) to the destination of the flow, e.g.
+//! [`Direct`](struct.Direct.html)ly or through a [`Socks5`](struct.Socks5.html) proxy. UDP flows
+//! are relayed datagram by datagram, each flow with its own association. Flows are dropped once
+//! they are idle for a while:
+//!
+//! ```no_run
+//! # async fn run() -> async_tun::result::Result<()> {
+//! use async_std::sync::Arc;
+//! use async_tun::tun2socks::{Socks5, Tun2Socks};
+//! use async_tun::TunBuilder;
+//! use std::net::Ipv4Addr;
+//! use std::time::Duration;
+//!
+//! let tun = TunBuilder::new()
+//!     .packet_info(false)
+//!     .backpressure(true)
+//!     .address(Ipv4Addr::new(10, 0, 0, 1))
+//!     .netmask(Ipv4Addr::new(255, 255, 255, 0))
+//!     .up()
+//!     .try_build()
+//!     .await?;
+//! Tun2Socks::new(Arc::new(tun), Socks5::new("127.0.0.1:1080".parse()?))?
+//!     .udp_timeout(Duration::from_secs(30))
+//!     .run()
+//!     .await
+//! # }
+//! ```
+
+mod connector;
+mod socks5;
+mod udp;
+
+pub use self::connector::{BoxFuture, Connector, Direct, UdpRelay};
+pub use self::socks5::Socks5;
+
+use self::udp::Sessions;
+use crate::device::{check_tun, TunDevice};
+use crate::future::{join, race};
+use crate::packet::{ipv4, protocol, IpPacket, PacketInfo, TcpFlags, TcpPacket, UdpPacket};
+use crate::phy;
+use crate::result::Result;
+use async_io::Timer;
+use async_std::channel::{self, Receiver, Sender, TryRecvError};
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::Shutdown;
+use async_std::sync::Arc;
+use async_std::task::{self, JoinHandle};
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{Device, DeviceCapabilities, Medium};
+use smoltcp::socket::tcp;
+use smoltcp::time::Instant;
+use smoltcp::wire::{HardwareAddress, IpCidr, IpListenEndpoint};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The addresses of the userspace stack, which routes every other address to itself.
+const STACK_IPV4: Ipv4Addr = Ipv4Addr::new(198, 18, 0, 1);
+const STACK_IPV6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+const DEFAULT_MTU: usize = 1500;
+const MAX_PACKET_LEN: usize = 65535;
+const TCP_BUFFER_LEN: usize = 65536;
+/// The largest chunk of data passed between a TCP flow and its relay.
+const CHUNK_LEN: usize = 16384;
+/// The number of chunks queued in each direction of a TCP flow.
+const CHUNK_QUEUE_LEN: usize = 8;
+/// The interval at which idle flows are looked for.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Represents a tun2socks proxy serving the TCP and UDP flows of a TUN device.
+///
+/// The device must be a TUN device without vnet header, e.g. a [`Tun`](../struct.Tun.html)
+/// built with `tap(false)`; packet information is handled if present.
+pub struct Tun2Socks<D> {
+    device: Arc<D>,
+    connector: Arc<dyn Connector>,
+    tcp_timeout: Duration,
+    udp_timeout: Duration,
+}
+
+impl<D: TunDevice + Send + Sync + 'static> Tun2Socks<D> {
+    /// Creates a new instance of [`Tun2Socks`](struct.Tun2Socks.html) forwarding the flows of
+    /// `device` through `connector`.
+    pub fn new(device: Arc<D>, connector: impl Connector) -> Result<Self> {
+        check_tun(&*device)?;
+        Ok(Self {
+            device,
+            connector: Arc::new(connector),
+            tcp_timeout: Duration::from_secs(600),
+            udp_timeout: Duration::from_secs(60),
+        })
+    }
+
+    /// Sets the time after which an idle TCP flow is reset. Default value is 10 minutes.
+    pub fn tcp_timeout(mut self, timeout: Duration) -> Self {
+        self.tcp_timeout = timeout;
+        self
+    }
+
+    /// Sets the time after which an idle UDP flow is dropped. Default value is 60 seconds.
+    pub fn udp_timeout(mut self, timeout: Duration) -> Self {
+        self.udp_timeout = timeout;
+        self
+    }
+
+    /// Forwards the flows of device until reading from it fails.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(name = self.device.name()), err))]
+    pub async fn run(self) -> Result<()> {
+        Stack::new(self).run().await
+    }
+}
+
+/// Represents the packets exchanged between smoltcp and the device.
+struct Queue {
+    rx: VecDeque<Vec<u8>>,
+    tx: Vec<Vec<u8>>,
+    mtu: usize,
+}
+
+impl Queue {
+    fn new(mtu: usize) -> Self {
+        Self {
+            rx: VecDeque::new(),
+            tx: Vec::new(),
+            mtu,
+        }
+    }
+}
+
+struct QueueRxToken(Vec<u8>);
+
+impl smoltcp::phy::RxToken for QueueRxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+struct QueueTxToken<'a>(&'a mut Vec<Vec<u8>>);
+
+impl smoltcp::phy::TxToken for QueueTxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0; len];
+        let result = f(&mut packet);
+        self.0.push(packet);
+        result
+    }
+}
+
+impl Device for Queue {
+    type RxToken<'a> = QueueRxToken;
+    type TxToken<'a> = QueueTxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let packet = self.rx.pop_front()?;
+        Some((QueueRxToken(packet), QueueTxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(QueueTxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
+
+/// Represents a TCP flow terminated by the stack.
+struct Flow {
+    src: SocketAddr,
+    dst: SocketAddr,
+    /// Queues the data of client for the relay, until client closes its side.
+    up: Option<Sender<Vec<u8>>>,
+    /// Receives the data of the destination, or an error to reset the flow with.
+    down: Receiver<io::Result<Vec<u8>>>,
+    /// The data received from `down` which did not fit the send buffer of the socket yet.
+    pending: Vec<u8>,
+    closing: bool,
+    last_active: std::time::Instant,
+    relay: JoinHandle<()>,
+}
+
+enum Event {
+    Packet(io::Result<usize>),
+    Wake,
+}
+
+struct Stack<D> {
+    device: Arc<D>,
+    connector: Arc<dyn Connector>,
+    tcp_timeout: Duration,
+    queue: Queue,
+    iface: Interface,
+    sockets: SocketSet<'static>,
+    flows: HashMap<SocketHandle, Flow>,
+    /// The sockets of the flows, to tell retransmitted SYN segments from new flows.
+    endpoints: HashMap<(SocketAddr, SocketAddr), SocketHandle>,
+    udp: Sessions<D>,
+    /// Wakes the stack once relays queued data or finished.
+    wake: (Sender<()>, Receiver<()>),
+}
+
+impl<D: TunDevice + Send + Sync + 'static> Stack<D> {
+    fn new(proxy: Tun2Socks<D>) -> Self {
+        let mtu = proxy.device.mtu().map_or(DEFAULT_MTU, |mtu| mtu as usize);
+        let mut queue = Queue::new(mtu);
+        let mut config = Config::new(HardwareAddress::Ip);
+        config.random_seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64);
+        let mut iface = Interface::new(config, &mut queue, Instant::now());
+        iface.set_any_ip(true);
+        iface.update_ip_addrs(|addrs| {
+            let _ = addrs.push(IpCidr::new(STACK_IPV4.into(), 32));
+            let _ = addrs.push(IpCidr::new(STACK_IPV6.into(), 128));
+        });
+        let _ = iface.routes_mut().add_default_ipv4_route(STACK_IPV4);
+        let _ = iface.routes_mut().add_default_ipv6_route(STACK_IPV6);
+        Self {
+            udp: Sessions::new(
+                proxy.device.clone(),
+                proxy.connector.clone(),
+                proxy.udp_timeout,
+            ),
+            device: proxy.device,
+            connector: proxy.connector,
+            tcp_timeout: proxy.tcp_timeout,
+            queue,
+            iface,
+            sockets: SocketSet::new(vec![]),
+            flows: HashMap::new(),
+            endpoints: HashMap::new(),
+            wake: channel::bounded(1),
+        }
+    }
+
+    async fn run(mut self) -> Result<()> {
+        let mut buf = vec![0; self.device.header_len() + MAX_PACKET_LEN];
+        let mut sweep = std::time::Instant::now() + SWEEP_INTERVAL;
+        loop {
+            let delay = self
+                .iface
+                .poll_delay(Instant::now(), &self.sockets)
+                .map_or(SWEEP_INTERVAL, |delay| SWEEP_INTERVAL.min(delay.into()));
+            let device = &self.device;
+            let wake = &self.wake.1;
+            let packet = async { Event::Packet(device.recv(&mut buf).await) };
+            let woken = async {
+                let _ = wake.recv().await;
+                Event::Wake
+            };
+            let timer = async {
+                Timer::after(delay).await;
+                Event::Wake
+            };
+            if let Event::Packet(result) = race(race(packet, woken), timer).await {
+                match result? {
+                    0 => return Ok(()),
+                    len => self.intake(&buf[..len]),
+                }
+            }
+            self.poll();
+            self.service();
+            self.poll();
+            for packet in std::mem::take(&mut self.queue.tx) {
+                // Packets are dropped like on a link without carrier, as TCP retransmits them.
+                let _ = self.device.send(&self.frame(packet)).await;
+            }
+            if std::time::Instant::now() >= sweep {
+                self.udp.sweep();
+                sweep = std::time::Instant::now() + SWEEP_INTERVAL;
+            }
+        }
+    }
+
+    fn poll(&mut self) {
+        self.iface
+            .poll(Instant::now(), &mut self.queue, &mut self.sockets);
+    }
+
+    /// Prepends packet information to `packet` if device expects it.
+    fn frame(&self, packet: Vec<u8>) -> Vec<u8> {
+        if !self.device.packet_info() {
+            return packet;
+        }
+        let mut frame = vec![0; PacketInfo::<&[u8]>::LEN];
+        PacketInfo::new_unchecked(&mut frame[..]).set_protocol(phy::protocol(false, &packet));
+        frame.extend_from_slice(&packet);
+        frame
+    }
+
+    /// Passes the TCP segments of `packet` to smoltcp and the UDP datagrams to their sessions.
+    /// Other packets are dropped.
+    fn intake(&mut self, packet: &[u8]) {
+        let Some(packet) = packet.get(self.device.header_len()..) else {
+            return;
+        };
+        let Ok(ip) = IpPacket::new_checked(packet) else {
+            return;
+        };
+        let (Ok(protocol), Ok(transport)) = (ip.protocol(), ip.transport()) else {
+            return;
+        };
+        match protocol {
+            protocol::TCP => {
+                let Ok(segment) = TcpPacket::new_checked(transport) else {
+                    return;
+                };
+                let src = SocketAddr::new(ip.src_addr(), segment.src_port());
+                let dst = SocketAddr::new(ip.dst_addr(), segment.dst_port());
+                let flags = segment.flags();
+                if flags.contains(TcpFlags::SYN)
+                    && !flags.contains(TcpFlags::ACK)
+                    && !self.endpoints.contains_key(&(src, dst))
+                {
+                    self.accept(src, dst);
+                }
+                self.queue.rx.push_back(packet.to_vec());
+                // Every SYN is processed before the next, so each listening socket gets its own.
+                self.poll();
+            }
+            protocol::UDP => {
+                // Fragmented datagrams are not reassembled.
+                if matches!(&ip, IpPacket::V4(packet) if packet.flags() & ipv4::MORE_FRAGMENTS != 0)
+                {
+                    return;
+                }
+                let Ok(datagram) = UdpPacket::new_checked(transport) else {
+                    return;
+                };
+                let src = SocketAddr::new(ip.src_addr(), datagram.src_port());
+                let dst = SocketAddr::new(ip.dst_addr(), datagram.dst_port());
+                self.udp.dispatch(src, dst, datagram.payload());
+            }
+            _ => {}
+        }
+    }
+
+    /// Listens for the TCP flow from `src` to `dst` and opens its outbound connection.
+    fn accept(&mut self, src: SocketAddr, dst: SocketAddr) {
+        if is_unspecified(dst.ip()) {
+            return;
+        }
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_LEN]),
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_LEN]),
+        );
+        socket.set_nagle_enabled(false);
+        if socket.listen(IpListenEndpoint::from(dst)).is_err() {
+            return;
+        }
+        let handle = self.sockets.add(socket);
+        let (up, up_receiver) = channel::bounded(CHUNK_QUEUE_LEN);
+        let (down_sender, down) = channel::bounded(CHUNK_QUEUE_LEN);
+        let relay = task::spawn(relay(
+            self.connector.clone(),
+            dst,
+            up_receiver,
+            down_sender,
+            self.wake.0.clone(),
+        ));
+        self.flows.insert(
+            handle,
+            Flow {
+                src,
+                dst,
+                up: Some(up),
+                down,
+                pending: Vec::new(),
+                closing: false,
+                last_active: std::time::Instant::now(),
+                relay,
+            },
+        );
+        self.endpoints.insert((src, dst), handle);
+    }
+
+    /// Moves data between the sockets and relays of the flows, and drops closed flows.
+    fn service(&mut self) {
+        let now = std::time::Instant::now();
+        let mut closed = Vec::new();
+        for (handle, flow) in self.flows.iter_mut() {
+            let socket = self.sockets.get_mut::<tcp::Socket>(*handle);
+            if let Some(up) = &flow.up {
+                while socket.can_recv() && !up.is_full() {
+                    let Ok(data) = socket.recv(|buf| {
+                        let len = buf.len().min(CHUNK_LEN);
+                        (len, buf[..len].to_vec())
+                    }) else {
+                        break;
+                    };
+                    flow.last_active = now;
+                    let _ = up.try_send(data);
+                }
+                let connected =
+                    !matches!(socket.state(), tcp::State::Listen | tcp::State::SynReceived);
+                if connected && !socket.may_recv() && !socket.can_recv() {
+                    // Client closed its side, which the relay passes on once the queue drained.
+                    flow.up = None;
+                }
+            }
+            loop {
+                if flow.pending.is_empty() {
+                    match flow.down.try_recv() {
+                        Ok(Ok(data)) => flow.pending = data,
+                        Ok(Err(_)) => {
+                            socket.abort();
+                            break;
+                        }
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Closed) => {
+                            if !flow.closing {
+                                socket.close();
+                                flow.closing = true;
+                            }
+                            break;
+                        }
+                    }
+                }
+                if !socket.can_send() {
+                    break;
+                }
+                match socket.send_slice(&flow.pending) {
+                    Ok(0) | Err(_) => break,
+                    Ok(len) => {
+                        flow.pending.drain(..len);
+                        flow.last_active = now;
+                    }
+                }
+            }
+            if now.duration_since(flow.last_active) > self.tcp_timeout {
+                socket.abort();
+            }
+            if socket.state() == tcp::State::Closed {
+                closed.push(*handle);
+            }
+        }
+        for handle in closed {
+            self.sockets.remove(handle);
+            if let Some(flow) = self.flows.remove(&handle) {
+                self.endpoints.remove(&(flow.src, flow.dst));
+                task::spawn(flow.relay.cancel());
+            }
+        }
+    }
+}
+
+fn is_unspecified(addr: IpAddr) -> bool {
+    addr.is_unspecified() || addr.is_multicast()
+}
+
+/// Connects to `dst` through `connector` and relays the data of a TCP flow in both directions,
+/// passing an error to `down` if either fails.
+async fn relay(
+    connector: Arc<dyn Connector>,
+    dst: SocketAddr,
+    up: Receiver<Vec<u8>>,
+    down: Sender<io::Result<Vec<u8>>>,
+    wake: Sender<()>,
+) {
+    let stream = match connector.connect(dst).await {
+        Ok(stream) => stream,
+        Err(error) => {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(%dst, %error, "TCP relay failed");
+            let _ = down.send(Err(error)).await;
+            let _ = wake.try_send(());
+            return;
+        }
+    };
+    let _ = stream.set_nodelay(true);
+    let upload = async {
+        let mut writer = &stream;
+        while let Ok(data) = up.recv().await {
+            writer.write_all(&data).await?;
+        }
+        stream.shutdown(Shutdown::Write)
+    };
+    let download = async {
+        let mut reader = &stream;
+        let mut buf = vec![0; CHUNK_LEN];
+        loop {
+            let len = reader.read(&mut buf).await?;
+            if len == 0 || down.send(Ok(buf[..len].to_vec())).await.is_err() {
+                return Ok(());
+            }
+            let _ = wake.try_send(());
+        }
+    };
+    let (uploaded, downloaded) = join(upload, download).await;
+    if let Err(error) = uploaded.and(downloaded) {
+        let _ = down.send(Err(error)).await;
+    }
+    drop(down);
+    let _ = wake.try_send(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::udp::udp_packet;
+    use super::*;
+    use crate::mock::{MockKernel, MockTun};
+    use smoltcp::wire::{IpAddress, Ipv4Address};
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    fn spawn(connector: impl Connector) -> MockKernel {
+        let tun = MockTun::new("tun0").with_packet_info(false);
+        let kernel = tun.kernel();
+        let proxy = Tun2Socks::new(Arc::new(tun), connector).unwrap();
+        task::spawn(proxy.run());
+        kernel
+    }
+
+    /// Connects a client stack to `SERVER` through `kernel`, sends `request` and returns the
+    /// data received until the flow is closed or reset.
+    async fn exchange(kernel: &MockKernel, request: &[u8]) -> Vec<u8> {
+        let mut queue = Queue::new(DEFAULT_MTU);
+        let mut iface =
+            Interface::new(Config::new(HardwareAddress::Ip), &mut queue, Instant::now());
+        iface.update_ip_addrs(|addrs| {
+            addrs.push(IpCidr::new(CLIENT.into(), 24)).unwrap();
+        });
+        iface
+            .routes_mut()
+            .add_default_ipv4_route(Ipv4Address::new(10, 0, 0, 1))
+            .unwrap();
+        let mut sockets = SocketSet::new(vec![]);
+        let handle = sockets.add(tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; 4096]),
+            tcp::SocketBuffer::new(vec![0; 4096]),
+        ));
+        sockets
+            .get_mut::<tcp::Socket>(handle)
+            .connect(iface.context(), (IpAddress::from(SERVER), 80), 49152)
+            .unwrap();
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        let mut sent = false;
+        let mut received = Vec::new();
+        loop {
+            assert!(std::time::Instant::now() < deadline, "flow timed out");
+            iface.poll(Instant::now(), &mut queue, &mut sockets);
+            for packet in queue.tx.drain(..) {
+                kernel.inject(&packet).await;
+            }
+            while let Some(packet) = kernel.try_recv() {
+                queue.rx.push_back(packet);
+            }
+            let socket = sockets.get_mut::<tcp::Socket>(handle);
+            if socket.may_send() && !sent {
+                socket.send_slice(request).unwrap();
+                sent = true;
+            }
+            if socket.can_recv() {
+                socket
+                    .recv(|buf| (buf.len(), received.extend_from_slice(buf)))
+                    .unwrap();
+                if received.len() >= request.len() {
+                    socket.close();
+                }
+            }
+            if matches!(socket.state(), tcp::State::Closed | tcp::State::TimeWait) {
+                return received;
+            }
+            task::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[test]
+    fn tcp() {
+        task::block_on(async {
+            let proxy = socks5::tests::stand_in(None).await;
+            let kernel = spawn(Socks5::new(proxy));
+            let request = b"GET / HTTP/1.0\r\n\r\n".repeat(100);
+            assert_eq!(exchange(&kernel, &request).await, request);
+        });
+    }
+
+    #[test]
+    fn tcp_refused() {
+        struct Refused;
+
+        impl Connector for Refused {
+            fn connect(
+                &self,
+                _dst: SocketAddr,
+            ) -> BoxFuture<'_, io::Result<async_std::net::TcpStream>> {
+                Box::pin(async { Err(io::ErrorKind::ConnectionRefused.into()) })
+            }
+
+            fn associate(&self, _dst: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn UdpRelay>>> {
+                Box::pin(async { Err(io::ErrorKind::ConnectionRefused.into()) })
+            }
+        }
+
+        task::block_on(async {
+            let kernel = spawn(Refused);
+            assert!(exchange(&kernel, b"hello").await.is_empty());
+        });
+    }
+
+    #[test]
+    fn udp() {
+        task::block_on(async {
+            let proxy = socks5::tests::stand_in(None).await;
+            let kernel = spawn(Socks5::new(proxy));
+            let src = SocketAddr::new(CLIENT.into(), 5000);
+            let dst = SocketAddr::new(SERVER.into(), 53);
+            kernel
+                .inject(&udp_packet(src, dst, b"query", false).unwrap())
+                .await;
+            let reply = kernel.recv().await.unwrap();
+            assert_eq!(reply, udp_packet(dst, src, b"query", false).unwrap());
+        });
+    }
+}
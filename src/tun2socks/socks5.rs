@@ -0,0 +1,389 @@
+use super::connector::{unspecified, BoxFuture, Connector, UdpRelay};
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::{TcpStream, UdpSocket};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+const METHOD_NONE: u8 = 0;
+const METHOD_PASSWORD: u8 = 2;
+const METHOD_UNACCEPTABLE: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+const REP_SUCCEEDED: u8 = 0;
+/// The length of the header prefixing UDP datagrams, up to the address.
+const UDP_HEADER_LEN: usize = 3;
+/// The longest header of a relayed datagram, with a domain name of 255 bytes.
+const MAX_UDP_HEADER_LEN: usize = UDP_HEADER_LEN + 2 + 255 + 2;
+
+/// Connects flows through a SOCKS5 proxy (RFC 1928), using `CONNECT` for TCP flows and
+/// `UDP ASSOCIATE` for UDP flows.
+#[derive(Debug, Clone)]
+pub struct Socks5 {
+    proxy: SocketAddr,
+    auth: Option<(String, String)>,
+}
+
+impl Socks5 {
+    /// Creates a new instance of [`Socks5`](struct.Socks5.html) connecting through the proxy at `proxy`.
+    pub fn new(proxy: SocketAddr) -> Self {
+        Self { proxy, auth: None }
+    }
+
+    /// Authenticates with `username` and `password` (RFC 1929).
+    pub fn auth(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.auth = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Connects to the proxy and sends `command` for `dst`, returning the control connection
+    /// and the address bound by the proxy.
+    async fn request(&self, command: u8, dst: SocketAddr) -> io::Result<(TcpStream, SocketAddr)> {
+        let mut stream = TcpStream::connect(self.proxy).await?;
+        stream.set_nodelay(true)?;
+        let method = if self.auth.is_some() {
+            METHOD_PASSWORD
+        } else {
+            METHOD_NONE
+        };
+        stream.write_all(&[VERSION, 1, method]).await?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != VERSION || reply[1] == METHOD_UNACCEPTABLE || reply[1] != method {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "SOCKS5 proxy rejected the authentication method",
+            ));
+        }
+        if let Some((username, password)) = &self.auth {
+            if username.len() > u8::MAX as usize || password.len() > u8::MAX as usize {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "SOCKS5 username or password is too long",
+                ));
+            }
+            let mut request = vec![AUTH_VERSION, username.len() as u8];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "SOCKS5 proxy rejected the credentials",
+                ));
+            }
+        }
+        let mut request = vec![VERSION, command, 0];
+        write_addr(&mut request, dst);
+        stream.write_all(&request).await?;
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).await?;
+        if header[1] != REP_SUCCEEDED {
+            return Err(io::Error::other(format!(
+                "SOCKS5 proxy replied with error {}",
+                header[1]
+            )));
+        }
+        let bound = match header[3] {
+            ATYP_IPV4 => {
+                let mut addr = [0; 6];
+                stream.read_exact(&mut addr).await?;
+                parse_addr(ATYP_IPV4, &addr)
+            }
+            ATYP_IPV6 => {
+                let mut addr = [0; 18];
+                stream.read_exact(&mut addr).await?;
+                parse_addr(ATYP_IPV6, &addr)
+            }
+            ATYP_DOMAIN => {
+                // The name is skipped; the relay is assumed to be on the host of proxy.
+                let mut len = [0; 1];
+                stream.read_exact(&mut len).await?;
+                let mut addr = vec![0; len[0] as usize + 2];
+                stream.read_exact(&mut addr).await?;
+                let port = u16::from_be_bytes([addr[len[0] as usize], addr[len[0] as usize + 1]]);
+                Some(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port))
+            }
+            _ => None,
+        }
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Invalid SOCKS5 address"))?;
+        Ok((stream, bound))
+    }
+}
+
+impl Connector for Socks5 {
+    fn connect(&self, dst: SocketAddr) -> BoxFuture<'_, io::Result<TcpStream>> {
+        Box::pin(async move {
+            let (stream, _) = self.request(CMD_CONNECT, dst).await?;
+            Ok(stream)
+        })
+    }
+
+    fn associate(&self, dst: SocketAddr) -> BoxFuture<'_, io::Result<Box<dyn UdpRelay>>> {
+        Box::pin(async move {
+            // The address the datagrams are sent from is unknown until the socket is bound.
+            let (control, mut relay) = self.request(CMD_UDP_ASSOCIATE, unspecified(dst)).await?;
+            if relay.ip().is_unspecified() {
+                relay.set_ip(self.proxy.ip());
+            }
+            let socket = UdpSocket::bind(unspecified(relay)).await?;
+            socket.connect(relay).await?;
+            let mut header = vec![0; UDP_HEADER_LEN];
+            write_addr(&mut header, dst);
+            Ok(Box::new(Socks5Relay {
+                _control: control,
+                socket,
+                header,
+            }) as Box<dyn UdpRelay>)
+        })
+    }
+}
+
+/// Represents a UDP association, which lasts as long as its control connection.
+struct Socks5Relay {
+    _control: TcpStream,
+    socket: UdpSocket,
+    header: Vec<u8>,
+}
+
+impl UdpRelay for Socks5Relay {
+    fn send<'a>(&'a self, payload: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut datagram = Vec::with_capacity(self.header.len() + payload.len());
+            datagram.extend_from_slice(&self.header);
+            datagram.extend_from_slice(payload);
+            self.socket.send(&datagram).await.map(|_| ())
+        })
+    }
+
+    fn recv<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(async move {
+            let mut datagram = vec![0; MAX_UDP_HEADER_LEN + buf.len()];
+            loop {
+                let len = self.socket.recv(&mut datagram).await?;
+                // Fragmented datagrams are dropped, as allowed by RFC 1928.
+                let Some(offset) = udp_payload_offset(&datagram[..len]) else {
+                    continue;
+                };
+                let payload = &datagram[offset..len];
+                let len = payload.len().min(buf.len());
+                buf[..len].copy_from_slice(&payload[..len]);
+                return Ok(len);
+            }
+        })
+    }
+}
+
+/// Appends the address type, address and port of `addr`.
+fn write_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Parses the address and port following the address type `atyp`.
+fn parse_addr(atyp: u8, buf: &[u8]) -> Option<SocketAddr> {
+    let (ip, port): (IpAddr, _) = match atyp {
+        ATYP_IPV4 => {
+            let octets: [u8; 4] = buf.get(..4)?.try_into().ok()?;
+            (Ipv4Addr::from(octets).into(), buf.get(4..6)?)
+        }
+        ATYP_IPV6 => {
+            let octets: [u8; 16] = buf.get(..16)?.try_into().ok()?;
+            (Ipv6Addr::from(octets).into(), buf.get(16..18)?)
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
+}
+
+/// Returns the offset of the payload of a relayed datagram, or `None` if it is fragmented or
+/// malformed.
+fn udp_payload_offset(datagram: &[u8]) -> Option<usize> {
+    if datagram.get(2)? != &0 {
+        return None;
+    }
+    let len = match *datagram.get(UDP_HEADER_LEN)? {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => 1 + *datagram.get(UDP_HEADER_LEN + 1)? as usize,
+        _ => return None,
+    };
+    let offset = UDP_HEADER_LEN + 1 + len + 2;
+    (offset <= datagram.len()).then_some(offset)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+    use async_std::task;
+
+    /// Serves a SOCKS5 stand-in on localhost, which is also the destination of every request:
+    /// it echoes the data of `CONNECT` requests and the datagrams of `UDP ASSOCIATE` requests.
+    pub(in crate::tun2socks) async fn stand_in(auth: Option<(&str, &str)>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let auth = auth.map(|(username, password)| (username.to_string(), password.to_string()));
+        task::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let auth = auth.clone();
+                task::spawn(async move {
+                    let _ = serve(stream, auth).await;
+                });
+            }
+        });
+        addr
+    }
+
+    async fn serve(mut stream: TcpStream, auth: Option<(String, String)>) -> io::Result<()> {
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).await?;
+        let mut methods = vec![0; header[1] as usize];
+        stream.read_exact(&mut methods).await?;
+        let method = if auth.is_some() {
+            METHOD_PASSWORD
+        } else {
+            METHOD_NONE
+        };
+        if !methods.contains(&method) {
+            return stream.write_all(&[VERSION, METHOD_UNACCEPTABLE]).await;
+        }
+        stream.write_all(&[VERSION, method]).await?;
+        if let Some((username, password)) = auth {
+            let mut buf = [0; 2];
+            stream.read_exact(&mut buf).await?;
+            let mut user = vec![0; buf[1] as usize];
+            stream.read_exact(&mut user).await?;
+            stream.read_exact(&mut buf[..1]).await?;
+            let mut pass = vec![0; buf[0] as usize];
+            stream.read_exact(&mut pass).await?;
+            let ok = user == username.as_bytes() && pass == password.as_bytes();
+            stream.write_all(&[AUTH_VERSION, !ok as u8]).await?;
+            if !ok {
+                return Ok(());
+            }
+        }
+        let mut request = [0; 4];
+        stream.read_exact(&mut request).await?;
+        let mut addr = vec![0; if request[3] == ATYP_IPV4 { 6 } else { 18 }];
+        stream.read_exact(&mut addr).await?;
+        let mut reply = vec![VERSION, REP_SUCCEEDED, 0];
+        match request[1] {
+            CMD_CONNECT => {
+                write_addr(&mut reply, stream.local_addr()?);
+                stream.write_all(&reply).await?;
+                let mut buf = vec![0; 4096];
+                loop {
+                    let len = stream.read(&mut buf).await?;
+                    if len == 0 {
+                        return Ok(());
+                    }
+                    stream.write_all(&buf[..len]).await?;
+                }
+            }
+            CMD_UDP_ASSOCIATE => {
+                let socket = UdpSocket::bind("127.0.0.1:0").await?;
+                write_addr(&mut reply, socket.local_addr()?);
+                stream.write_all(&reply).await?;
+                let relay = task::spawn(async move {
+                    let mut buf = vec![0; 65536];
+                    while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                        let _ = socket.send_to(&buf[..len], peer).await;
+                    }
+                });
+                // The association ends with its control connection.
+                let _ = stream.read(&mut [0; 1]).await;
+                relay.cancel().await;
+                Ok(())
+            }
+            _ => {
+                reply[1] = 7;
+                stream.write_all(&reply).await
+            }
+        }
+    }
+
+    #[test]
+    fn addresses() {
+        let mut buf = Vec::new();
+        let v4: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        write_addr(&mut buf, v4);
+        assert_eq!(buf, [ATYP_IPV4, 192, 0, 2, 1, 0, 53]);
+        assert_eq!(parse_addr(buf[0], &buf[1..]), Some(v4));
+        let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        buf.clear();
+        write_addr(&mut buf, v6);
+        assert_eq!(buf.len(), 19);
+        assert_eq!(parse_addr(buf[0], &buf[1..]), Some(v6));
+        assert_eq!(parse_addr(ATYP_IPV4, &[127, 0, 0]), None);
+
+        let datagram = [0, 0, 0, ATYP_IPV4, 192, 0, 2, 1, 0, 53, 0xab];
+        assert_eq!(udp_payload_offset(&datagram), Some(10));
+        assert_eq!(
+            udp_payload_offset(&[0, 0, 1, ATYP_IPV4, 192, 0, 2, 1, 0, 53]),
+            None
+        );
+        assert_eq!(
+            udp_payload_offset(&[0, 0, 0, ATYP_DOMAIN, 3, b'a', b'.', b'b', 0, 53]),
+            Some(10)
+        );
+        assert_eq!(udp_payload_offset(&[0, 0, 0, ATYP_IPV6, 0]), None);
+    }
+
+    #[test]
+    fn connect() {
+        task::block_on(async {
+            let dst = "192.0.2.1:80".parse().unwrap();
+            let proxy = stand_in(Some(("user", "secret"))).await;
+            let mut stream = Socks5::new(proxy)
+                .auth("user", "secret")
+                .connect(dst)
+                .await
+                .unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            let err = Socks5::new(proxy)
+                .auth("user", "wrong")
+                .connect(dst)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+            let err = Socks5::new(proxy).connect(dst).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        });
+    }
+
+    #[test]
+    fn associate() {
+        task::block_on(async {
+            let proxy = stand_in(None).await;
+            let relay = Socks5::new(proxy)
+                .associate("192.0.2.1:53".parse().unwrap())
+                .await
+                .unwrap();
+            relay.send(b"query").await.unwrap();
+            let mut buf = [0; 16];
+            let len = relay.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"query");
+        });
+    }
+}
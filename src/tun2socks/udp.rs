@@ -0,0 +1,194 @@
+use super::connector::Connector;
+use crate::device::TunDevice;
+use crate::future::race;
+use crate::packet::{ethertype, ipv4, ipv6, protocol, udp, Ipv4Packet, PacketInfo, UdpPacket};
+use async_io::Timer;
+use async_std::channel::{self, Receiver, Sender, TrySendError};
+use async_std::sync::Arc;
+use async_std::task;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+const HOP_LIMIT: u8 = 64;
+/// The number of datagrams queued per flow while its relay is busy or still being opened.
+const QUEUE_LEN: usize = 64;
+const MAX_PAYLOAD_LEN: usize = 65535;
+
+/// Represents the UDP flows of a device, keyed by their source and destination, each served by
+/// a task relaying its datagrams until it is idle for `timeout`.
+pub(crate) struct Sessions<D> {
+    device: Arc<D>,
+    connector: Arc<dyn Connector>,
+    timeout: Duration,
+    sessions: HashMap<(SocketAddr, SocketAddr), Sender<Vec<u8>>>,
+}
+
+impl<D: TunDevice + Send + Sync + 'static> Sessions<D> {
+    pub(crate) fn new(device: Arc<D>, connector: Arc<dyn Connector>, timeout: Duration) -> Self {
+        Self {
+            device,
+            connector,
+            timeout,
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Relays `payload` sent from `src` to `dst`, opening a session for a new flow. Datagrams
+    /// are dropped while the queue of the flow is full.
+    pub(crate) fn dispatch(&mut self, src: SocketAddr, dst: SocketAddr, payload: &[u8]) {
+        if let Some(session) = self.sessions.get(&(src, dst)) {
+            match session.try_send(payload.to_vec()) {
+                Ok(()) | Err(TrySendError::Full(_)) => return,
+                Err(TrySendError::Closed(_)) => {}
+            }
+        }
+        let (sender, receiver) = channel::bounded(QUEUE_LEN);
+        let _ = sender.try_send(payload.to_vec());
+        self.sessions.insert((src, dst), sender);
+        task::spawn(session(
+            self.device.clone(),
+            self.connector.clone(),
+            src,
+            dst,
+            receiver,
+            self.timeout,
+        ));
+    }
+
+    /// Forgets the flows whose sessions ended.
+    pub(crate) fn sweep(&mut self) {
+        self.sessions.retain(|_, session| !session.is_closed());
+    }
+}
+
+enum Event {
+    Sent(Option<Vec<u8>>),
+    Received(std::io::Result<usize>),
+    Idle,
+}
+
+async fn session<D: TunDevice>(
+    device: Arc<D>,
+    connector: Arc<dyn Connector>,
+    src: SocketAddr,
+    dst: SocketAddr,
+    datagrams: Receiver<Vec<u8>>,
+    timeout: Duration,
+) {
+    let relay = match connector.associate(dst).await {
+        Ok(relay) => relay,
+        Err(_error) => {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(%src, %dst, error = %_error, "UDP relay failed");
+            return;
+        }
+    };
+    let mut buf = vec![0; MAX_PAYLOAD_LEN];
+    loop {
+        let sent = async { Event::Sent(datagrams.recv().await.ok()) };
+        let received = async { Event::Received(relay.recv(&mut buf).await) };
+        let idle = async {
+            Timer::after(timeout).await;
+            Event::Idle
+        };
+        let event = race(race(sent, received), idle).await;
+        match event {
+            Event::Sent(Some(payload)) => {
+                let _ = relay.send(&payload).await;
+            }
+            Event::Received(Ok(len)) => {
+                if let Some(packet) = udp_packet(dst, src, &buf[..len], device.packet_info()) {
+                    let _ = device.send(&packet).await;
+                }
+            }
+            Event::Sent(None) | Event::Received(Err(_)) | Event::Idle => return,
+        }
+    }
+}
+
+/// Builds an IP packet carrying a UDP datagram of `payload` from `src` to `dst`, prefixed with
+/// packet information if `packet_info` is true.
+pub(crate) fn udp_packet(
+    src: SocketAddr,
+    dst: SocketAddr,
+    payload: &[u8],
+    packet_info: bool,
+) -> Option<Vec<u8>> {
+    let info_len = if packet_info {
+        PacketInfo::<&[u8]>::LEN
+    } else {
+        0
+    };
+    let (header_len, protocol_type) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(_), IpAddr::V4(_)) => (ipv4::HEADER_LEN, ethertype::IPV4),
+        (IpAddr::V6(_), IpAddr::V6(_)) => (ipv6::HEADER_LEN, ethertype::IPV6),
+        _ => return None,
+    };
+    let udp_len = udp::HEADER_LEN + payload.len();
+    if header_len + udp_len > u16::MAX as usize {
+        return None;
+    }
+    let mut buf = vec![0u8; info_len + header_len + udp_len];
+    if packet_info {
+        PacketInfo::new_unchecked(&mut buf[..]).set_protocol(protocol_type);
+    }
+    let packet = &mut buf[info_len..];
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            packet[0] = 0x45;
+            packet[2..4].copy_from_slice(&((header_len + udp_len) as u16).to_be_bytes());
+            packet[8] = HOP_LIMIT;
+            packet[9] = protocol::UDP;
+            packet[12..16].copy_from_slice(&src.octets());
+            packet[16..20].copy_from_slice(&dst.octets());
+            Ipv4Packet::new_unchecked(&mut packet[..]).fill_checksum();
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            packet[0] = 0x60;
+            packet[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+            packet[6] = protocol::UDP;
+            packet[7] = HOP_LIMIT;
+            packet[8..24].copy_from_slice(&src.octets());
+            packet[24..40].copy_from_slice(&dst.octets());
+        }
+        _ => unreachable!(),
+    }
+    let mut datagram = UdpPacket::new_unchecked(&mut packet[header_len..]);
+    datagram.set_src_port(src.port());
+    datagram.set_dst_port(dst.port());
+    datagram.set_len(udp_len as u16);
+    datagram.payload_mut().copy_from_slice(payload);
+    datagram.fill_checksum(src.ip(), dst.ip());
+    Some(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::IpPacket;
+
+    #[test]
+    fn packets() {
+        let src: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let buf = udp_packet(src, dst, b"reply", true).unwrap();
+        let info = PacketInfo::new_checked(&buf[..]).unwrap();
+        assert_eq!(info.protocol(), ethertype::IPV4);
+        let packet = Ipv4Packet::new_checked(info.payload()).unwrap();
+        assert!(packet.verify_checksum());
+        let datagram = UdpPacket::new_checked(packet.payload()).unwrap();
+        assert!(datagram.verify_checksum(src.ip(), dst.ip()));
+        assert_eq!((datagram.src_port(), datagram.dst_port()), (53, 5000));
+        assert_eq!(datagram.payload(), b"reply");
+
+        let src: SocketAddr = "[2001:db8::1]:53".parse().unwrap();
+        let dst: SocketAddr = "[fd00::2]:5000".parse().unwrap();
+        let buf = udp_packet(src, dst, b"reply", false).unwrap();
+        let packet = IpPacket::new_checked(&buf[..]).unwrap();
+        assert_eq!(packet.src_addr(), src.ip());
+        let datagram = UdpPacket::new_checked(packet.transport().unwrap()).unwrap();
+        assert!(datagram.verify_checksum(src.ip(), dst.ip()));
+        assert!(udp_packet(src, "10.0.0.2:1".parse().unwrap(), b"", false).is_none());
+    }
+}
@@ -0,0 +1,567 @@
+use super::window::ReplayWindow;
+use super::{encode, Peer, Tunnel, ENCRYPTED};
+use crate::device::TunDevice;
+use crate::packet::write_u16;
+use crate::result::Result;
+use aes_gcm::Aes256Gcm;
+use async_std::sync::Arc;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit, Nonce, Tag};
+use chacha20poly1305::ChaCha20Poly1305;
+use std::collections::HashSet;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// The number of messages after which keys are no longer used to send, leaving room below the
+/// end of the counter (WireGuard).
+const REJECT_AFTER_MESSAGES: u64 = u64::MAX - (1 << 13);
+const REKEY_AFTER_MESSAGES: u64 = 1 << 60;
+const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
+/// The counter of the tag identifying keys, which is never used to send as it is beyond
+/// `REJECT_AFTER_MESSAGES`.
+const FINGERPRINT_COUNTER: u64 = u64::MAX;
+
+/// Represents an AEAD algorithm protecting the packets of a tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Algorithm {
+    /// ChaCha20-Poly1305 (RFC 8439), fast without AES instructions.
+    ChaCha20Poly1305,
+    /// AES-256 in Galois/Counter Mode.
+    Aes256Gcm,
+}
+
+/// Represents the keys of a session with a peer, one per direction.
+pub struct Keys {
+    algorithm: Algorithm,
+    id: u32,
+    send: [u8; KEY_LEN],
+    recv: [u8; KEY_LEN],
+}
+
+impl Keys {
+    /// Creates a new instance of [`Keys`](struct.Keys.html) identified by `id`, encrypting the
+    /// packets sent to the peer with `send` and decrypting the ones received from it with
+    /// `recv`.
+    ///
+    /// Both peers use the same identifier, which must be unique among the keys in use by a
+    /// tunnel, with `send` and `recv` swapped. As the counter used as nonce starts at 0 in every
+    /// session, keys must be fresh for each session, e.g. negotiated by a
+    /// [`KeyExchange`](trait.KeyExchange.html), and never stored: a tunnel refuses the keys it
+    /// already used, but cannot tell the ones used before it was created.
+    pub fn new(algorithm: Algorithm, id: u32, send: [u8; KEY_LEN], recv: [u8; KEY_LEN]) -> Self {
+        Self {
+            algorithm,
+            id,
+            send,
+            recv,
+        }
+    }
+
+    /// Returns the algorithm.
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Returns the identifier.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keys")
+            .field("algorithm", &self.algorithm)
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Represents a key exchange, which negotiates the keys of the peers of a
+/// [`Tunnel`](struct.Tunnel.html) and installs them with
+/// [`install_keys`](struct.Tunnel.html#method.install_keys).
+pub trait KeyExchange: Send + Sync {
+    /// Called once the `peer`th peer of the tunnel needs new keys: when a packet is sent to an
+    /// encrypted peer without keys, or once its keys protected too many messages or are too
+    /// old. It is called once until keys are installed, from the task running the tunnel, so it
+    /// should only start the negotiation. The current keys, if any, are used until new ones are
+    /// installed, which may happen from within this call.
+    fn rekey(&self, peer: usize);
+}
+
+impl<F: Fn(usize) + Send + Sync> KeyExchange for F {
+    fn rekey(&self, peer: usize) {
+        self(peer)
+    }
+}
+
+impl Peer {
+    /// Sets whether the packets exchanged with the peer are encrypted. Nothing is sent to nor
+    /// accepted from an encrypted peer until keys are installed with
+    /// [`install_keys`](struct.Tunnel.html#method.install_keys). Peers become encrypted once
+    /// keys are installed anyway. Default value is `false`. Available with the `crypto`
+    /// feature.
+    pub fn encrypted(mut self, encrypted: bool) -> Self {
+        self.encrypted = encrypted;
+        self
+    }
+}
+
+impl<D: TunDevice> Tunnel<D> {
+    /// Sets the key exchange asked to rotate the keys of the peers. Available with the `crypto`
+    /// feature.
+    pub fn key_exchange(mut self, exchange: impl KeyExchange + 'static) -> Self {
+        self.rekey.exchange = Some(Arc::new(exchange));
+        self
+    }
+
+    /// Sets the number of messages sent with keys after which they are rotated. Default value
+    /// is 2^60. Available with the `crypto` feature.
+    pub fn rekey_after_messages(mut self, messages: u64) -> Self {
+        self.rekey.after_messages = messages;
+        self
+    }
+
+    /// Sets the time after which keys are rotated. Default value is 2 minutes. Available with
+    /// the `crypto` feature.
+    pub fn rekey_after_time(mut self, time: Duration) -> Self {
+        self.rekey.after_time = time;
+        self
+    }
+
+    /// Installs `keys` for the `peer`th peer added, which are used to send from then on. The
+    /// previous keys still decrypt the packets the peer sent before it installed the new ones.
+    /// Keys already installed for any peer of the tunnel are refused. Available with the
+    /// `crypto` feature.
+    pub fn install_keys(&self, peer: usize, keys: Keys) -> Result<()> {
+        let mut peers = self.peers();
+        if peers.iter().any(|state| state.sessions.contains(keys.id)) {
+            return Err(format!("Keys {} are already in use", keys.id).into());
+        }
+        let session = Session::new(keys);
+        if peers.iter().any(|state| state.sessions.used(&session)) {
+            return Err(format!("Keys {} were already used", session.id).into());
+        }
+        let state = peers
+            .get_mut(peer)
+            .ok_or_else(|| format!("No peer at index {}", peer))?;
+        state.sessions.install(session);
+        Ok(())
+    }
+}
+
+/// The rekeying configuration of a tunnel.
+pub(crate) struct Rekey {
+    exchange: Option<Arc<dyn KeyExchange>>,
+    after_messages: u64,
+    after_time: Duration,
+}
+
+impl Default for Rekey {
+    fn default() -> Self {
+        Self {
+            exchange: None,
+            after_messages: REKEY_AFTER_MESSAGES,
+            after_time: REKEY_AFTER_TIME,
+        }
+    }
+}
+
+impl Rekey {
+    /// Asks the key exchange to rotate the keys of the `peer`th peer.
+    pub(crate) fn request(&self, peer: usize) {
+        if let Some(exchange) = &self.exchange {
+            exchange.rekey(peer);
+        }
+    }
+}
+
+enum Cipher {
+    ChaCha20Poly1305(ChaCha20Poly1305),
+    Aes256Gcm(Box<Aes256Gcm>),
+}
+
+impl Cipher {
+    fn new(algorithm: Algorithm, key: &[u8; KEY_LEN]) -> Self {
+        match algorithm {
+            Algorithm::ChaCha20Poly1305 => {
+                Self::ChaCha20Poly1305(ChaCha20Poly1305::new(key.into()))
+            }
+            Algorithm::Aes256Gcm => Self::Aes256Gcm(Box::new(Aes256Gcm::new(key.into()))),
+        }
+    }
+
+    /// Encrypts `buf` in place and returns the tag.
+    fn seal(&self, counter: u64, aad: &[u8], buf: &mut [u8]) -> Option<[u8; TAG_LEN]> {
+        fn seal<A: AeadInPlace>(
+            aead: &A,
+            nonce: &[u8],
+            aad: &[u8],
+            buf: &mut [u8],
+        ) -> Option<[u8; TAG_LEN]> {
+            let tag = aead
+                .encrypt_in_place_detached(Nonce::<A>::from_slice(nonce), aad, buf)
+                .ok()?;
+            tag.as_slice().try_into().ok()
+        }
+        let nonce = nonce(counter);
+        match self {
+            Self::ChaCha20Poly1305(aead) => seal(aead, &nonce, aad, buf),
+            Self::Aes256Gcm(aead) => seal(&**aead, &nonce, aad, buf),
+        }
+    }
+
+    /// Decrypts `buf` in place, or returns `false` if it is not authentic.
+    fn open(&self, counter: u64, aad: &[u8], buf: &mut [u8], tag: &[u8]) -> bool {
+        fn open<A: AeadInPlace>(
+            aead: &A,
+            nonce: &[u8],
+            aad: &[u8],
+            buf: &mut [u8],
+            tag: &[u8],
+        ) -> bool {
+            aead.decrypt_in_place_detached(
+                Nonce::<A>::from_slice(nonce),
+                aad,
+                buf,
+                Tag::<A>::from_slice(tag),
+            )
+            .is_ok()
+        }
+        let nonce = nonce(counter);
+        match self {
+            Self::ChaCha20Poly1305(aead) => open(aead, &nonce, aad, buf, tag),
+            Self::Aes256Gcm(aead) => open(&**aead, &nonce, aad, buf, tag),
+        }
+    }
+}
+
+/// Returns the nonce of the message numbered `counter`: 32 zero bits followed by the counter.
+fn nonce(counter: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[NONCE_LEN - 8..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+/// Represents the state of a session, i.e. of a set of keys.
+pub(crate) struct Session {
+    id: u32,
+    sender: Cipher,
+    receiver: Cipher,
+    counter: u64,
+    window: ReplayWindow,
+    created: Instant,
+}
+
+impl Session {
+    fn new(keys: Keys) -> Self {
+        Self {
+            id: keys.id,
+            sender: Cipher::new(keys.algorithm, &keys.send),
+            receiver: Cipher::new(keys.algorithm, &keys.recv),
+            counter: 0,
+            window: Default::default(),
+            created: Instant::now(),
+        }
+    }
+
+    /// Returns a tag identifying the keys used to send, without revealing them.
+    fn fingerprint(&self) -> Option<[u8; TAG_LEN]> {
+        self.sender.seal(FINGERPRINT_COUNTER, &[], &mut [])
+    }
+
+    /// Writes an encrypted message of `msg_type` carrying `packet` to `buf`. Returns `false` if
+    /// the keys are exhausted or the packet is too long.
+    pub(crate) fn seal(&mut self, buf: &mut Vec<u8>, msg_type: u8, packet: &[u8]) -> bool {
+        let Ok(len) = u16::try_from(packet.len() + TAG_LEN) else {
+            return false;
+        };
+        if self.counter >= REJECT_AFTER_MESSAGES {
+            return false;
+        }
+        let counter = self.counter;
+        self.counter += 1;
+        encode(buf, msg_type, Some(counter), &[]);
+        buf[1] |= ENCRYPTED;
+        write_u16(buf, 2, len);
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        let aad_len = buf.len();
+        buf.extend_from_slice(packet);
+        let (aad, body) = buf.split_at_mut(aad_len);
+        let Some(tag) = self.sender.seal(counter, aad, body) else {
+            return false;
+        };
+        buf.extend_from_slice(&tag);
+        true
+    }
+
+    /// Decrypts `body` received with `header` in place and returns the packet, or `None` if it
+    /// is not authentic or was replayed.
+    pub(crate) fn open<'a>(
+        &mut self,
+        header: &[u8],
+        counter: u64,
+        body: &'a mut [u8],
+    ) -> Option<&'a [u8]> {
+        if !self.window.check(counter) {
+            return None;
+        }
+        let (packet, tag) = body.split_at_mut(body.len().checked_sub(TAG_LEN)?);
+        if !self.receiver.open(counter, header, packet, tag) {
+            return None;
+        }
+        self.window.update(counter);
+        Some(packet)
+    }
+}
+
+/// Represents the sessions of a peer: the current one, used to send, and the previous one,
+/// kept to decrypt the packets in flight while the keys are rotated.
+#[derive(Default)]
+pub(crate) struct Sessions {
+    encrypted: bool,
+    current: Option<Session>,
+    previous: Option<Session>,
+    /// The fingerprints of the keys ever installed.
+    used: HashSet<[u8; TAG_LEN]>,
+    /// Whether the key exchange was asked for new keys since the last ones were installed.
+    requested: bool,
+}
+
+impl fmt::Debug for Sessions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sessions")
+            .field("encrypted", &self.encrypted)
+            .field("current", &self.current.as_ref().map(|s| s.id))
+            .field("previous", &self.previous.as_ref().map(|s| s.id))
+            .finish()
+    }
+}
+
+impl Sessions {
+    pub(crate) fn new(encrypted: bool) -> Self {
+        Self {
+            encrypted,
+            ..Default::default()
+        }
+    }
+
+    /// Returns `true` if the packets exchanged with the peer are encrypted.
+    pub(crate) fn encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    /// Returns `true` if the keys of `session` were installed before.
+    fn used(&self, session: &Session) -> bool {
+        session
+            .fingerprint()
+            .is_some_and(|fingerprint| self.used.contains(&fingerprint))
+    }
+
+    fn install(&mut self, session: Session) {
+        self.used.extend(session.fingerprint());
+        self.encrypted = true;
+        self.requested = false;
+        self.previous = self.current.replace(session);
+    }
+
+    /// Returns the session used to send, if the peer has keys.
+    pub(crate) fn current(&mut self) -> Option<&mut Session> {
+        self.current.as_mut()
+    }
+
+    /// Returns the session of the keys identified by `id`.
+    pub(crate) fn get(&mut self, id: u32) -> Option<&mut Session> {
+        [&mut self.current, &mut self.previous]
+            .into_iter()
+            .flatten()
+            .find(|session| session.id == id)
+    }
+
+    pub(crate) fn contains(&self, id: u32) -> bool {
+        [&self.current, &self.previous]
+            .into_iter()
+            .flatten()
+            .any(|session| session.id == id)
+    }
+
+    /// Returns `true` once an encrypted peer needs keys, or its current keys are due to be
+    /// rotated, until new keys are installed.
+    pub(crate) fn rekey_due(&mut self, rekey: &Rekey) -> bool {
+        if !self.encrypted || self.requested {
+            return false;
+        }
+        self.requested = self.current.as_ref().is_none_or(|session| {
+            session.counter >= rekey.after_messages || session.created.elapsed() >= rekey.after_time
+        });
+        self.requested
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{decode, DATA};
+    use super::*;
+    use crate::flow::tests::udp;
+    use crate::mock::MockTun;
+    use async_std::channel;
+    use async_std::task;
+
+    fn keys(algorithm: Algorithm, id: u32) -> (Keys, Keys) {
+        let (a, b) = ([id as u8; KEY_LEN], [!id as u8; KEY_LEN]);
+        (
+            Keys::new(algorithm, id, a, b),
+            Keys::new(algorithm, id, b, a),
+        )
+    }
+
+    #[test]
+    fn sessions() {
+        for algorithm in [Algorithm::ChaCha20Poly1305, Algorithm::Aes256Gcm] {
+            let (a, b) = keys(algorithm, 7);
+            let (mut a, mut b) = (Session::new(a), Session::new(b));
+            let mut buf = Vec::new();
+            assert!(a.seal(&mut buf, DATA, b"packet"));
+            assert_eq!(buf.len(), 16 + 6 + TAG_LEN);
+            assert_ne!(&buf[16..22], b"packet");
+
+            let mut received = buf.clone();
+            let message = decode(&mut received).unwrap();
+            assert_eq!(message.key_id, Some(7));
+            assert_eq!(message.sequence, Some(0));
+            let counter = message.sequence.unwrap();
+            assert_eq!(
+                b.open(message.header, counter, message.body),
+                Some(&b"packet"[..])
+            );
+
+            // Replayed and tampered messages are dropped.
+            let mut replayed = buf.clone();
+            let message = decode(&mut replayed).unwrap();
+            assert_eq!(b.open(message.header, counter, message.body), None);
+            assert!(a.seal(&mut buf, DATA, b"packet"));
+            for index in [0, 3, 12, 16, buf.len() - 1] {
+                let mut tampered = buf.clone();
+                tampered[index] ^= 1;
+                if let Some(message) = decode(&mut tampered) {
+                    let counter = message.sequence.unwrap();
+                    assert_eq!(b.open(message.header, counter, message.body), None);
+                }
+            }
+            let message = decode(&mut buf).unwrap();
+            let counter = message.sequence.unwrap();
+            assert!(b.open(message.header, counter, message.body).is_some());
+        }
+    }
+
+    #[test]
+    fn rekey() {
+        let rekey = Rekey {
+            after_messages: 2,
+            ..Default::default()
+        };
+        assert!(!Sessions::new(false).rekey_due(&rekey));
+
+        // Encrypted peers ask for their first keys.
+        let mut sessions = Sessions::new(true);
+        assert!(sessions.rekey_due(&rekey));
+        assert!(!sessions.rekey_due(&rekey));
+        let session = |id| Session::new(keys(Algorithm::Aes256Gcm, id).0);
+        sessions.install(session(1));
+        let mut buf = Vec::new();
+        assert!(sessions.current().unwrap().seal(&mut buf, DATA, &[]));
+        assert!(!sessions.rekey_due(&rekey));
+        assert!(sessions.current().unwrap().seal(&mut buf, DATA, &[]));
+        assert!(sessions.rekey_due(&rekey));
+        assert!(!sessions.rekey_due(&rekey));
+
+        sessions.install(session(2));
+        assert!(!sessions.rekey_due(&rekey));
+        assert_eq!(sessions.current().unwrap().id, 2);
+        assert!(sessions.get(1).is_some());
+        sessions.install(session(3));
+        assert!(sessions.get(1).is_none());
+        assert!(sessions.used(&session(1)));
+        assert!(!sessions.used(&session(4)));
+    }
+
+    #[test]
+    fn tunnel() {
+        task::block_on(async {
+            let (a, b) = (
+                MockTun::new("a0").with_packet_info(false),
+                MockTun::new("b0").with_packet_info(false),
+            );
+            let (a_kernel, b_kernel) = (a.kernel(), b.kernel());
+            let (a_keys, b_keys) = keys(Algorithm::ChaCha20Poly1305, 1);
+            let (sender, receiver) = channel::unbounded();
+            let b = Tunnel::bind(Arc::new(b), "127.0.0.1:0")
+                .await
+                .unwrap()
+                .peer(
+                    Peer::new()
+                        .allowed_ip("10.0.0.0/24".parse().unwrap())
+                        .encrypted(true),
+                );
+            let a = Tunnel::bind(Arc::new(a), "127.0.0.1:0")
+                .await
+                .unwrap()
+                .peer(
+                    Peer::new()
+                        .endpoint(b.local_addr().unwrap())
+                        .allowed_ip("10.0.1.0/24".parse().unwrap())
+                        .encrypted(true),
+                )
+                .rekey_after_messages(2)
+                .key_exchange(move |peer| sender.try_send(peer).unwrap());
+            let (a, b) = (Arc::new(a), Arc::new(b));
+            for tunnel in [a.clone(), b.clone()] {
+                task::spawn(async move { tunnel.run().await });
+            }
+
+            // Nothing is sent before the first keys are installed.
+            let request = udp("10.0.0.2:5000", "10.0.1.1:53");
+            a_kernel.inject(&request).await;
+            assert_eq!(receiver.recv().await.unwrap(), 0);
+            b.install_keys(0, b_keys).unwrap();
+            a.install_keys(0, a_keys).unwrap();
+            a_kernel.inject(&request).await;
+            assert_eq!(b_kernel.recv().await.unwrap(), request);
+            let reply = udp("10.0.1.1:53", "10.0.0.2:5000");
+            b_kernel.inject(&reply).await;
+            assert_eq!(a_kernel.recv().await.unwrap(), reply);
+
+            // Plain messages are dropped by encrypted peers.
+            let socket = async_std::net::UdpSocket::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let mut message = Vec::new();
+            encode(&mut message, DATA, None, &request);
+            socket
+                .send_to(&message, b.local_addr().unwrap())
+                .await
+                .unwrap();
+
+            // The keys are rotated after the second message.
+            a_kernel.inject(&request).await;
+            assert_eq!(b_kernel.recv().await.unwrap(), request);
+            assert_eq!(receiver.recv().await.unwrap(), 0);
+            let (a_keys, b_keys) = keys(Algorithm::Aes256Gcm, 2);
+            b.install_keys(0, b_keys).unwrap();
+            a.install_keys(0, a_keys).unwrap();
+            assert!(a.install_keys(1, keys(Algorithm::Aes256Gcm, 3).0).is_err());
+            // Keys are refused once used, even under another identifier.
+            assert!(a.install_keys(0, keys(Algorithm::Aes256Gcm, 2).0).is_err());
+            let (reused, _) = keys(Algorithm::ChaCha20Poly1305, 1);
+            let reused = Keys::new(Algorithm::ChaCha20Poly1305, 4, reused.send, reused.recv);
+            assert!(a.install_keys(0, reused).is_err());
+            b_kernel.inject(&reply).await;
+            assert_eq!(a_kernel.recv().await.unwrap(), reply);
+            a_kernel.inject(&request).await;
+            assert_eq!(b_kernel.recv().await.unwrap(), request);
+            assert!(b_kernel.try_recv().is_none());
+        });
+    }
+}
@@ -0,0 +1,694 @@
+//! A tunnel endpoint, which carries the packets of a TUN device to its peers over UDP.
+//!
+//! Every [`Peer`](struct.Peer.html) is assigned the prefixes of the inner addresses behind it.
+//! A packet read from the device is sent to the peer with the longest prefix matching its
+//! destination, and a packet received from a peer is written to the device only if its source
+//! belongs to the prefixes of that peer. The endpoint of an encrypted peer follows the address
+//! its authenticated messages come from, e.g. behind a NAT which changed its mapping. As plain
+//! messages are easily spoofed, the endpoint of other peers only does so if they allow
+//! [`roaming`](struct.Peer.html#method.roaming), and a configured endpoint is never replaced.
+//! Keepalives keep NAT mappings open while the tunnel is idle:
+//!
+//! ```no_run
+//! # async fn run() -> async_tun::result::Result<()> {
+//! use async_std::sync::Arc;
+//! use async_tun::tunnel::{Peer, Tunnel};
+//! use async_tun::TunBuilder;
+//! use std::net::Ipv4Addr;
+//! use std::time::Duration;
+//!
+//! let tun = TunBuilder::new()
+//!     .packet_info(false)
+//!     .backpressure(true)
+//!     .address(Ipv4Addr::new(10, 0, 0, 1))
+//!     .netmask(Ipv4Addr::new(255, 255, 255, 0))
+//!     .up()
+//!     .try_build()
+//!     .await?;
+//! let peer = Peer::new()
+//!     .endpoint("198.51.100.1:5555".parse()?)
+//!     .allowed_ip("10.0.1.0/24".parse()?);
+//! Tunnel::bind(Arc::new(tun), "0.0.0.0:5555")
+//!     .await?
+//!     .peer(peer)
+//!     .keepalive(Duration::from_secs(25))
+//!     .run()
+//!     .await
+//! # }
+//! ```
+//!
+//! Every packet is carried by a UDP datagram, prefixed with a header holding the type of the
+//! message, its flags and the length of the packet, in network byte order. Messages carrying a
+//! sequence number have the `0x01` flag set, and the number follows the header:
+//!
+//! ```text
+//! 0        8        16                32                                96
+//! +--------+--------+-----------------+----------------------------------+--------+
+//! |  type  | flags  |     length      |     sequence number (64 bits)    | packet |
+//! +--------+--------+-----------------+----------------------------------+--------+
+//! ```
+//!
+//! Type 1 messages carry a packet, type 2 messages are empty keepalives. The upper 32 bits of a
+//! sequence number are the epoch of the sender, picked at random when its tunnel is created,
+//! and the lower ones count its messages. Numbers too far behind the highest one received are
+//! dropped, as well as the numbers of the previous epoch of the peer, while a new epoch tells
+//! the peer restarted and resets the numbers received.
+//!
+//! With the `crypto` feature, the packets exchanged with an encrypted peer are encrypted and
+//! authenticated with ChaCha20-Poly1305 or AES-256-GCM, using the [`Keys`](struct.Keys.html) of
+//! the current session. Encrypted messages have the `0x02` flag set and always carry a sequence
+//! number, the counter used as nonce, which starts at 0 in every session, followed by the 32-bit
+//! identifier of the keys. The header is authenticated along with the packet, whose length
+//! includes the 16-byte tag. Replayed messages are dropped, and a
+//! [`KeyExchange`](trait.KeyExchange.html) is asked for the first keys, then to rotate them
+//! once they protected too many messages or are too old.
+
+#[cfg(feature = "crypto")]
+mod crypto;
+mod window;
+
+#[cfg(feature = "crypto")]
+pub use self::crypto::{Algorithm, KeyExchange, Keys};
+
+use self::window::ReplayWindow;
+use crate::device::{check_tun, TunDevice};
+use crate::future::race;
+use crate::net::IpCidr;
+use crate::packet::{ethertype, read_u16, write_u16, IpPacket, PacketInfo};
+use crate::result::Result;
+use async_io::Timer;
+use async_std::net::{ToSocketAddrs, UdpSocket};
+use async_std::sync::Arc;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The type of the messages carrying a packet.
+const DATA: u8 = 1;
+/// The type of the empty messages keeping the path to a peer open.
+const KEEPALIVE: u8 = 2;
+/// The flag of the messages carrying a sequence number.
+const SEQUENCE: u8 = 0x01;
+/// The flag of the encrypted messages, which carry the identifier of their keys.
+const ENCRYPTED: u8 = 0x02;
+const HEADER_LEN: usize = 4;
+const SEQUENCE_LEN: usize = 8;
+const KEY_ID_LEN: usize = 4;
+const MAX_MESSAGE_LEN: usize = HEADER_LEN + SEQUENCE_LEN + KEY_ID_LEN + u16::MAX as usize;
+
+/// Represents a remote tunnel endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct Peer {
+    endpoint: Option<SocketAddr>,
+    allowed_ips: Vec<IpCidr>,
+    roaming: bool,
+    #[cfg(feature = "crypto")]
+    encrypted: bool,
+}
+
+impl Peer {
+    /// Creates a new instance of [`Peer`](struct.Peer.html) without endpoint nor inner
+    /// addresses.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the address of the UDP socket of the peer. If not set, it is learnt from the
+    /// messages received from the peer if it is encrypted or allows
+    /// [`roaming`](#method.roaming).
+    pub fn endpoint(mut self, endpoint: SocketAddr) -> Self {
+        self.endpoint = Some(endpoint);
+        self
+    }
+
+    /// Sets whether the endpoint of the peer follows the address its plain messages are
+    /// received from, which lets anyone able to send a datagram with a source address allowed
+    /// for the peer redirect the packets sent to it. The endpoint of encrypted peers always
+    /// follows their authenticated messages, while an endpoint set with
+    /// [`endpoint`](#method.endpoint) never changes. Default value is `false`.
+    pub fn roaming(mut self, roaming: bool) -> Self {
+        self.roaming = roaming;
+        self
+    }
+
+    /// Adds `cidr` to the inner addresses behind the peer. Packets are sent to the peer if their
+    /// destination belongs to one of them, and accepted from the peer if their source does.
+    pub fn allowed_ip(mut self, cidr: IpCidr) -> Self {
+        self.allowed_ips.push(cidr);
+        self
+    }
+}
+
+#[derive(Debug)]
+struct PeerState {
+    peer: Peer,
+    /// Whether the endpoint of the peer was configured rather than learnt.
+    configured: bool,
+    sequence: u64,
+    /// The epoch of the sequence numbers received from the peer, and the one before it.
+    epochs: [Option<u32>; 2],
+    window: ReplayWindow,
+    last_sent: Option<Instant>,
+    #[cfg(feature = "crypto")]
+    sessions: crypto::Sessions,
+}
+
+impl PeerState {
+    /// Writes a message of `msg_type` carrying `packet` to `buf`, encrypted if the peer is.
+    /// Returns `false` if the message cannot be sent.
+    fn encode(&mut self, buf: &mut Vec<u8>, msg_type: u8, packet: &[u8], sequence: bool) -> bool {
+        #[cfg(feature = "crypto")]
+        if self.sessions.encrypted() {
+            return self
+                .sessions
+                .current()
+                .is_some_and(|session| session.seal(buf, msg_type, packet));
+        }
+        let sequence = sequence.then(|| {
+            self.sequence = self.sequence.wrapping_add(1);
+            self.sequence
+        });
+        encode(buf, msg_type, sequence, packet);
+        true
+    }
+
+    /// Records `sequence` as received from the peer. Returns `false` if it was received before,
+    /// is too old to tell, or belongs to the previous epoch of the peer.
+    fn receive(&mut self, sequence: u64) -> bool {
+        let epoch = Some((sequence >> 32) as u32);
+        if epoch != self.epochs[0] {
+            if epoch == self.epochs[1] {
+                return false;
+            }
+            self.epochs = [epoch, self.epochs[0]];
+            self.window = Default::default();
+        }
+        if !self.window.check(sequence) {
+            return false;
+        }
+        self.window.update(sequence);
+        true
+    }
+}
+
+/// Represents a tunnel endpoint forwarding the packets of a TUN device to its peers over UDP.
+///
+/// The device must be a TUN device without vnet header, e.g. a [`Tun`](../struct.Tun.html)
+/// built with `tap(false)`; packet information is handled if present. A packet is read from the
+/// device only once the previous one was sent, and a datagram is received only once the
+/// previous packet was written to the device, so a slow side slows the other one down instead
+/// of queueing packets.
+pub struct Tunnel<D> {
+    device: Arc<D>,
+    socket: UdpSocket,
+    peers: Mutex<Vec<PeerState>>,
+    keepalive: Option<Duration>,
+    sequence: bool,
+    epoch: u32,
+    #[cfg(feature = "crypto")]
+    rekey: crypto::Rekey,
+}
+
+impl<D: TunDevice> Tunnel<D> {
+    /// Creates a new instance of [`Tunnel`](struct.Tunnel.html) forwarding the packets of
+    /// `device` through a UDP socket bound to `addr`.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(name = device.name()), err))]
+    pub async fn bind(device: Arc<D>, addr: impl ToSocketAddrs) -> Result<Self> {
+        check_tun(&*device)?;
+        Ok(Self {
+            socket: UdpSocket::bind(addr).await?,
+            device,
+            peers: Default::default(),
+            keepalive: None,
+            sequence: false,
+            epoch: epoch(),
+            #[cfg(feature = "crypto")]
+            rekey: Default::default(),
+        })
+    }
+
+    /// Adds `peer` to the peers of the tunnel.
+    pub fn peer(mut self, peer: Peer) -> Self {
+        self.peers
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(PeerState {
+                #[cfg(feature = "crypto")]
+                sessions: crypto::Sessions::new(peer.encrypted),
+                configured: peer.endpoint.is_some(),
+                peer,
+                sequence: (self.epoch as u64) << 32,
+                epochs: Default::default(),
+                window: Default::default(),
+                last_sent: None,
+            });
+        self
+    }
+
+    /// Sets the interval at which a keepalive is sent to the peers with a known endpoint, when
+    /// nothing else was sent to them meanwhile. Default is no keepalive.
+    pub fn keepalive(mut self, interval: Duration) -> Self {
+        self.keepalive = Some(interval);
+        self
+    }
+
+    /// Sets whether packets are sent with a sequence number, which lets the peer drop the
+    /// duplicates among the last 128 packets. Encrypted packets always carry one. Default value
+    /// is `false`.
+    pub fn sequence(mut self, sequence: bool) -> Self {
+        self.sequence = sequence;
+        self
+    }
+
+    /// Returns the address the UDP socket is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Returns the current endpoint of the `index`th peer added, if known.
+    pub fn endpoint(&self, index: usize) -> Option<SocketAddr> {
+        self.peers().get(index)?.peer.endpoint
+    }
+
+    /// Forwards packets in both directions until reading from the device or the socket fails.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(name = self.device.name()), err))]
+    pub async fn run(&self) -> Result<()> {
+        race(self.outbound(), race(self.inbound(), self.keepalives())).await
+    }
+
+    async fn outbound(&self) -> Result<()> {
+        let header_len = self.device.header_len();
+        let mut buf = vec![0u8; header_len + u16::MAX as usize];
+        let mut message = Vec::with_capacity(MAX_MESSAGE_LEN);
+        loop {
+            let len = self.device.recv(&mut buf).await?;
+            if len == 0 {
+                return Ok(());
+            }
+            let Some(packet) = buf.get(header_len..len) else {
+                continue;
+            };
+            let Ok(ip) = IpPacket::new_checked(packet) else {
+                continue;
+            };
+            #[cfg(feature = "crypto")]
+            let mut rekey = None;
+            let endpoint = {
+                let mut peers = self.peers();
+                let Some(index) = route(&peers, ip.dst_addr()) else {
+                    #[cfg(feature = "tracing")]
+                    tracing::debug!(dst = %ip.dst_addr(), "no peer for packet");
+                    continue;
+                };
+                let state = &mut peers[index];
+                let endpoint = state
+                    .peer
+                    .endpoint
+                    .filter(|_| state.encode(&mut message, DATA, packet, self.sequence));
+                if endpoint.is_some() {
+                    state.last_sent = Some(Instant::now());
+                }
+                #[cfg(feature = "crypto")]
+                if state.sessions.rekey_due(&self.rekey) {
+                    rekey = Some(index);
+                }
+                endpoint
+            };
+            // The key exchange is called without lock, so it may install keys right away.
+            #[cfg(feature = "crypto")]
+            if let Some(index) = rekey {
+                self.rekey.request(index);
+            }
+            let Some(endpoint) = endpoint else {
+                continue;
+            };
+            // Packets which cannot be sent are dropped, as a router does.
+            if let Err(_error) = self.socket.send_to(&message, endpoint).await {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(%endpoint, error = %_error, "dropped packet");
+            }
+        }
+    }
+
+    async fn inbound(&self) -> Result<()> {
+        let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+        let mut frame = Vec::with_capacity(self.device.header_len() + u16::MAX as usize);
+        loop {
+            let (len, from) = self.socket.recv_from(&mut buf).await?;
+            let Some(message) = decode(&mut buf[..len]) else {
+                continue;
+            };
+            let Some(packet) = self.accept(from, message) else {
+                continue;
+            };
+            frame.clear();
+            if self.device.packet_info() {
+                frame.resize(PacketInfo::<&[u8]>::LEN, 0);
+                let protocol = match packet[0] >> 4 {
+                    6 => ethertype::IPV6,
+                    _ => ethertype::IPV4,
+                };
+                PacketInfo::new_unchecked(&mut frame[..]).set_protocol(protocol);
+            }
+            frame.extend_from_slice(packet);
+            if let Err(_error) = self.device.send(&frame).await {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(name = self.device.name(), error = %_error, "dropped packet");
+            }
+        }
+    }
+
+    /// Returns the packet carried by `message` received from `from`, or `None` if it is a
+    /// keepalive or must be dropped. The endpoint of the sending peer is updated if the message
+    /// is authenticated, or if the peer allows roaming.
+    fn accept<'a>(&self, from: SocketAddr, message: Message<'a>) -> Option<&'a [u8]> {
+        let mut peers = self.peers();
+        let (state, packet, authenticated) = match message.key_id {
+            None => {
+                let state = match message.msg_type {
+                    DATA => {
+                        let ip = IpPacket::new_checked(&*message.body).ok()?;
+                        let index = route(&peers, ip.src_addr())?;
+                        &mut peers[index]
+                    }
+                    _ => peers.iter_mut().find(|s| s.peer.endpoint == Some(from))?,
+                };
+                // Encrypted peers only send encrypted messages.
+                #[cfg(feature = "crypto")]
+                if state.sessions.encrypted() {
+                    return None;
+                }
+                if let Some(sequence) = message.sequence {
+                    if !state.receive(sequence) {
+                        return None;
+                    }
+                }
+                (state, &*message.body, false)
+            }
+            #[cfg(feature = "crypto")]
+            Some(key_id) => {
+                let state = peers.iter_mut().find(|s| s.sessions.contains(key_id))?;
+                let session = state.sessions.get(key_id)?;
+                let packet = session.open(message.header, message.sequence?, message.body)?;
+                if message.msg_type == DATA {
+                    let src = IpPacket::new_checked(packet).ok()?.src_addr();
+                    if !state.peer.allowed_ips.iter().any(|cidr| cidr.contains(src)) {
+                        return None;
+                    }
+                }
+                (state, packet, true)
+            }
+            #[cfg(not(feature = "crypto"))]
+            Some(_) => return None,
+        };
+        if authenticated || (state.peer.roaming && !state.configured) {
+            state.peer.endpoint = Some(from);
+        }
+        (message.msg_type == DATA).then_some(packet)
+    }
+
+    async fn keepalives(&self) -> Result<()> {
+        let Some(interval) = self.keepalive else {
+            return std::future::pending().await;
+        };
+        loop {
+            let now = Instant::now();
+            let mut next = now + interval;
+            let mut due = Vec::new();
+            for state in self.peers().iter_mut() {
+                let Some(endpoint) = state.peer.endpoint else {
+                    continue;
+                };
+                match state.last_sent.map(|sent| sent + interval) {
+                    Some(deadline) if deadline > now => next = next.min(deadline),
+                    _ => {
+                        let mut message = Vec::new();
+                        if state.encode(&mut message, KEEPALIVE, &[], self.sequence) {
+                            state.last_sent = Some(now);
+                            due.push((endpoint, message));
+                        }
+                    }
+                }
+            }
+            for (endpoint, message) in due {
+                if let Err(_error) = self.socket.send_to(&message, endpoint).await {
+                    #[cfg(feature = "tracing")]
+                    tracing::debug!(%endpoint, error = %_error, "dropped keepalive");
+                }
+            }
+            Timer::at(next).await;
+        }
+    }
+
+    fn peers(&self) -> MutexGuard<'_, Vec<PeerState>> {
+        self.peers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Returns the index of the peer with the longest prefix matching `addr`.
+fn route(peers: &[PeerState], addr: IpAddr) -> Option<usize> {
+    peers
+        .iter()
+        .enumerate()
+        .filter_map(|(index, state)| {
+            let prefix = state
+                .peer
+                .allowed_ips
+                .iter()
+                .filter(|cidr| cidr.contains(addr))
+                .map(|cidr| cidr.prefix())
+                .max()?;
+            Some((prefix, index))
+        })
+        .max_by_key(|(prefix, _)| *prefix)
+        .map(|(_, index)| index)
+}
+
+/// Returns a random epoch for the sequence numbers of a tunnel.
+fn epoch() -> u32 {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(elapsed) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        hasher.write_u128(elapsed.as_nanos());
+    }
+    hasher.finish() as u32
+}
+
+/// Writes a message of `msg_type` carrying `packet` to `buf`.
+fn encode(buf: &mut Vec<u8>, msg_type: u8, sequence: Option<u64>, packet: &[u8]) {
+    buf.clear();
+    buf.resize(HEADER_LEN, 0);
+    buf[0] = msg_type;
+    write_u16(buf, 2, packet.len() as u16);
+    if let Some(sequence) = sequence {
+        buf[1] |= SEQUENCE;
+        buf.extend_from_slice(&sequence.to_be_bytes());
+    }
+    buf.extend_from_slice(packet);
+}
+
+/// Represents a received message.
+struct Message<'a> {
+    msg_type: u8,
+    sequence: Option<u64>,
+    key_id: Option<u32>,
+    /// The header and the fields following it, which are authenticated with the packet.
+    #[cfg_attr(not(feature = "crypto"), allow(dead_code))]
+    header: &'a [u8],
+    /// The packet, encrypted if the message has a key identifier.
+    body: &'a mut [u8],
+}
+
+/// Parses `message`, or returns `None` if it is truncated.
+fn decode(message: &mut [u8]) -> Option<Message<'_>> {
+    let flags = *message.get(1)?;
+    let mut header_len = HEADER_LEN;
+    if flags & SEQUENCE != 0 {
+        header_len += SEQUENCE_LEN;
+    }
+    if flags & ENCRYPTED != 0 {
+        header_len += KEY_ID_LEN;
+    }
+    if message.len() < header_len {
+        return None;
+    }
+    let (header, rest) = message.split_at_mut(header_len);
+    let sequence = (flags & SEQUENCE != 0).then(|| {
+        u64::from_be_bytes(
+            header[HEADER_LEN..HEADER_LEN + SEQUENCE_LEN]
+                .try_into()
+                .unwrap(),
+        )
+    });
+    let key_id = (flags & ENCRYPTED != 0)
+        .then(|| u32::from_be_bytes(header[header_len - KEY_ID_LEN..].try_into().unwrap()));
+    Some(Message {
+        msg_type: header[0],
+        sequence,
+        key_id,
+        body: rest.get_mut(..read_u16(header, 2) as usize)?,
+        header,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::tests::{addr, endpoints, udp};
+    use crate::mock::MockTun;
+    use async_std::task;
+
+    fn parse(message: &[u8]) -> Option<(u8, Option<u64>, Vec<u8>)> {
+        let mut message = message.to_vec();
+        let message = decode(&mut message)?;
+        Some((message.msg_type, message.sequence, message.body.to_vec()))
+    }
+
+    #[test]
+    fn messages() {
+        let mut buf = Vec::new();
+        encode(&mut buf, DATA, None, b"packet");
+        assert_eq!(buf, b"\x01\x00\x00\x06packet");
+        assert_eq!(parse(&buf), Some((DATA, None, b"packet".to_vec())));
+        encode(&mut buf, DATA, Some(0x0102), b"packet");
+        assert_eq!(
+            &buf[..HEADER_LEN + SEQUENCE_LEN],
+            b"\x01\x01\x00\x06\0\0\0\0\0\0\x01\x02"
+        );
+        assert_eq!(parse(&buf), Some((DATA, Some(0x0102), b"packet".to_vec())));
+        assert_eq!(parse(&buf[..buf.len() - 1]), None);
+        assert_eq!(parse(&buf[..HEADER_LEN + 4]), None);
+        assert_eq!(parse(&[KEEPALIVE, 0, 0]), None);
+        assert_eq!(parse(&[KEEPALIVE, ENCRYPTED, 0, 0, 0, 0, 0]), None);
+    }
+
+    fn bind(tun: MockTun, peer: Peer) -> Arc<Tunnel<MockTun>> {
+        task::block_on(async {
+            let tunnel = Tunnel::bind(Arc::new(tun), "127.0.0.1:0").await.unwrap();
+            Arc::new(tunnel.peer(peer).sequence(true))
+        })
+    }
+
+    #[test]
+    fn tunnel() {
+        task::block_on(async {
+            let (a, b) = (
+                MockTun::new("a0").with_packet_info(false),
+                MockTun::new("b0"),
+            );
+            let (a_kernel, b_kernel) = (a.kernel(), b.kernel());
+            let to_a = Peer::new()
+                .allowed_ip("10.0.0.0/24".parse().unwrap())
+                .roaming(true);
+            let b = bind(b, to_a);
+            let to_b = Peer::new()
+                .endpoint(b.local_addr().unwrap())
+                .allowed_ip("10.0.1.0/24".parse().unwrap());
+            let a = bind(a, to_b.clone());
+            for tunnel in [a.clone(), b.clone()] {
+                task::spawn(async move { tunnel.run().await });
+            }
+
+            // Packets are routed to the peer by destination, and its endpoint is learnt.
+            a_kernel.inject(&udp("10.0.0.2:5000", "10.0.2.1:53")).await;
+            let request = udp("10.0.0.2:5000", "10.0.1.1:53");
+            a_kernel.inject(&request).await;
+            let received = b_kernel.recv().await.unwrap();
+            let info = PacketInfo::new_checked(&received[..]).unwrap();
+            assert_eq!(info.protocol(), ethertype::IPV4);
+            assert_eq!(info.payload(), &request[..]);
+            assert_eq!(b.endpoint(0), Some(a.local_addr().unwrap()));
+
+            let reply = udp("10.0.1.1:53", "10.0.0.2:5000");
+            b_kernel.inject(&with_info(&reply)).await;
+            let received = a_kernel.recv().await.unwrap();
+            assert_eq!(
+                endpoints(&received),
+                (addr("10.0.1.1:53"), addr("10.0.0.2:5000"))
+            );
+
+            // A configured endpoint is not replaced by the source of plain messages.
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut message = Vec::new();
+            encode(&mut message, DATA, None, &reply);
+            socket
+                .send_to(&message, a.local_addr().unwrap())
+                .await
+                .unwrap();
+            assert_eq!(a_kernel.recv().await.unwrap(), reply);
+            assert_eq!(a.endpoint(0), Some(b.local_addr().unwrap()));
+
+            // The peer roams to another endpoint.
+            let c = MockTun::new("c0").with_packet_info(false);
+            let c_kernel = c.kernel();
+            let c = bind(c, to_b);
+            let run = c.clone();
+            task::spawn(async move { run.run().await });
+            c_kernel.inject(&request).await;
+            assert!(b_kernel.recv().await.is_some());
+            assert_eq!(b.endpoint(0), Some(c.local_addr().unwrap()));
+            b_kernel.inject(&with_info(&reply)).await;
+            assert_eq!(c_kernel.recv().await.unwrap(), reply);
+
+            // Packets from outside the prefixes of the peer are dropped.
+            c_kernel.inject(&udp("10.0.3.2:5000", "10.0.1.1:53")).await;
+            c_kernel.inject(&request).await;
+            let received = b_kernel.recv().await.unwrap();
+            assert_eq!(&received[PacketInfo::<&[u8]>::LEN..], &request[..]);
+        });
+    }
+
+    #[test]
+    fn sequence() {
+        let tunnel = task::block_on(Tunnel::bind(Arc::new(MockTun::new("tun0")), "127.0.0.1:0"))
+            .unwrap()
+            .peer(Peer::new());
+        let mut peers = tunnel.peers();
+        let state = &mut peers[0];
+        assert_eq!(state.sequence >> 32, tunnel.epoch as u64);
+
+        let epoch = 7 << 32;
+        assert!(state.receive(epoch + 5));
+        assert!(!state.receive(epoch + 5));
+        assert!(state.receive(epoch + 4));
+        assert!(state.receive(epoch + 1000));
+        // Numbers too old to tell are dropped rather than reset the window.
+        assert!(!state.receive(epoch + 5));
+        assert!(!state.receive(epoch + 6));
+
+        // The peer restarted with a lower epoch, whose numbers are now accepted.
+        let restarted = 3 << 32;
+        assert!(state.receive(restarted));
+        assert!(!state.receive(restarted));
+        assert!(!state.receive(epoch + 1001));
+        assert!(state.receive(restarted + 1));
+    }
+
+    fn with_info(packet: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0, 0, 0x08, 0x00];
+        frame.extend_from_slice(packet);
+        frame
+    }
+
+    #[test]
+    fn keepalive() {
+        task::block_on(async {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let peer = Peer::new().endpoint(socket.local_addr().unwrap());
+            let tunnel = Tunnel::bind(Arc::new(MockTun::new("tun0")), "127.0.0.1:0")
+                .await
+                .unwrap()
+                .peer(peer)
+                .keepalive(Duration::from_millis(10));
+            let local_addr = tunnel.local_addr().unwrap();
+            task::spawn(async move { tunnel.run().await });
+            let mut buf = [0u8; 16];
+            for _ in 0..2 {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                assert_eq!(from, local_addr);
+                assert_eq!(&buf[..len], [KEEPALIVE, 0, 0, 0]);
+            }
+        });
+    }
+}
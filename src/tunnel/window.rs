@@ -0,0 +1,74 @@
+/// The number of sequence numbers below the highest one which are remembered.
+const WINDOW_LEN: u64 = u128::BITS as u64;
+
+/// Represents a sliding window of the sequence numbers received from a peer, which rejects the
+/// repeated ones (RFC 6479).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ReplayWindow {
+    /// One more than the highest sequence number received, or 0 if none was.
+    next: u64,
+    /// The bit `i` is set if `next - 1 - i` was received.
+    bitmap: u128,
+}
+
+impl ReplayWindow {
+    /// Returns `true` if `seq` was not received yet and is not too old to tell.
+    pub(crate) fn check(&self, seq: u64) -> bool {
+        match self.offset(seq) {
+            None => true,
+            Some(offset) => offset < WINDOW_LEN && self.bitmap & (1 << offset) == 0,
+        }
+    }
+
+    /// Records `seq` as received.
+    pub(crate) fn update(&mut self, seq: u64) {
+        match self.offset(seq) {
+            None => {
+                let shift = seq - self.next + 1;
+                self.bitmap = if shift >= WINDOW_LEN {
+                    1
+                } else {
+                    self.bitmap << shift | 1
+                };
+                self.next = seq + 1;
+            }
+            Some(offset) if offset < WINDOW_LEN => self.bitmap |= 1 << offset,
+            Some(_) => {}
+        }
+    }
+
+    /// Returns how far `seq` is below the highest sequence number received, or `None` if it is
+    /// higher.
+    fn offset(&self, seq: u64) -> Option<u64> {
+        (seq < self.next).then(|| self.next - 1 - seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(window: &mut ReplayWindow, seq: u64) -> bool {
+        let fresh = window.check(seq);
+        if fresh {
+            window.update(seq);
+        }
+        fresh
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::default();
+        assert!(accept(&mut window, 0));
+        assert!(!accept(&mut window, 0));
+        assert!(accept(&mut window, 2));
+        assert!(accept(&mut window, 1));
+        assert!(!accept(&mut window, 2));
+        assert!(accept(&mut window, 200));
+        assert!(!accept(&mut window, 200 - WINDOW_LEN));
+        assert!(accept(&mut window, 201 - WINDOW_LEN));
+        assert!(!accept(&mut window, 201 - WINDOW_LEN));
+        assert!(accept(&mut window, 1000));
+        assert!(!accept(&mut window, 201));
+    }
+}
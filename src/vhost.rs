@@ -0,0 +1,453 @@
+//! Hand-off of TAP queues to the in-kernel vhost-net accelerator, which then moves packets
+//! directly between a [`Tun`](../struct.Tun.html) queue and the virtqueues of a guest.
+//!
+//! A virtual machine monitor describes the memory of guest with [`MemoryRegion`]s, sets up the
+//! receive and transmit virtqueues with their [`VringAddr`]esses and [`EventFd`]s, then binds a
+//! queue of a TAP device without packet information as their backend:
+//!
+//! ```no_run
+//! # async fn run(memory: &mut [u8], tun: async_tun::Tun) -> async_tun::result::Result<()> {
+//! use async_tun::vhost::{EventFd, MemoryRegion, VhostNet, VringAddr, RX_QUEUE, TX_QUEUE};
+//!
+//! let mut vhost = VhostNet::new()?;
+//! vhost.set_features(vhost.features()?)?;
+//! // Safety: `memory` outlives `vhost`, which is dropped first.
+//! unsafe { vhost.set_mem_table(&[MemoryRegion::from_slice(0, memory)]) }?;
+//! let mut eventfds = Vec::new();
+//! for (queue, base) in [(RX_QUEUE, 0x1000), (TX_QUEUE, 0x5000)] {
+//!     let (kick, call) = (EventFd::new()?, EventFd::new()?);
+//!     vhost.set_vring_num(queue, 256)?;
+//!     vhost.set_vring_base(queue, 0)?;
+//!     vhost.set_vring_addr(queue, &VringAddr::new(base, base + 0x1000, base + 0x2000))?;
+//!     vhost.set_vring_kick(queue, &kick)?;
+//!     vhost.set_vring_call(queue, &call)?;
+//!     vhost.set_backend(queue, &tun)?;
+//!     eventfds.push((kick, call));
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::linux::request::{vhost_memory, vhost_vring_addr, vhost_vring_file, vhost_vring_state};
+use crate::result::Result;
+use crate::tun::Tun;
+use nix::sys::eventfd::{eventfd, EfdFlags};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+nix::ioctl_read!(vhost_get_features, 0xAF, 0x00, u64);
+nix::ioctl_write_ptr!(vhost_set_features, 0xAF, 0x00, u64);
+nix::ioctl_none!(vhost_set_owner, 0xAF, 0x01);
+nix::ioctl_write_ptr!(vhost_set_mem_table, 0xAF, 0x03, vhost_memory);
+nix::ioctl_write_ptr!(vhost_set_vring_num, 0xAF, 0x10, vhost_vring_state);
+nix::ioctl_write_ptr!(vhost_set_vring_addr, 0xAF, 0x11, vhost_vring_addr);
+nix::ioctl_write_ptr!(vhost_set_vring_base, 0xAF, 0x12, vhost_vring_state);
+nix::ioctl_readwrite!(vhost_get_vring_base, 0xAF, 0x12, vhost_vring_state);
+nix::ioctl_write_ptr!(vhost_set_vring_kick, 0xAF, 0x20, vhost_vring_file);
+nix::ioctl_write_ptr!(vhost_set_vring_call, 0xAF, 0x21, vhost_vring_file);
+nix::ioctl_write_ptr!(vhost_net_set_backend, 0xAF, 0x30, vhost_vring_file);
+
+/// The index of the receive virtqueue, filled with the packets read from device.
+pub const RX_QUEUE: u32 = 0;
+
+/// The index of the transmit virtqueue, drained into device.
+pub const TX_QUEUE: u32 = 1;
+
+/// Represents a contiguous range of the physical memory of guest, mapped into this process.
+///
+/// A region only describes memory; it is accessed once installed with
+/// [`VhostNet::set_mem_table`](struct.VhostNet.html#method.set_mem_table), whose caller vouches
+/// for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    /// The guest physical address of the start of region.
+    pub guest_addr: u64,
+    /// The size of region in bytes.
+    pub size: u64,
+    /// The address of the start of region in this process.
+    pub userspace_addr: u64,
+}
+
+impl MemoryRegion {
+    /// Creates a new instance of [`MemoryRegion`](struct.MemoryRegion.html).
+    pub fn new(guest_addr: u64, size: u64, userspace_addr: u64) -> Self {
+        Self {
+            guest_addr,
+            size,
+            userspace_addr,
+        }
+    }
+
+    /// Creates a region mapping `memory` at `guest_addr`. Once installed, the kernel accesses
+    /// `memory` until the memory table is replaced or the [`VhostNet`](struct.VhostNet.html) is
+    /// dropped, so it must outlive both.
+    pub fn from_slice(guest_addr: u64, memory: &mut [u8]) -> Self {
+        Self::new(guest_addr, memory.len() as _, memory.as_mut_ptr() as _)
+    }
+
+    /// Returns the address in this process of the guest physical address `addr`, if it is
+    /// within region.
+    pub fn translate(&self, addr: u64) -> Option<u64> {
+        self.translate_range(addr, 1)
+    }
+
+    /// Returns the address in this process of the `len` bytes at the guest physical address
+    /// `addr`, if they are all within region.
+    fn translate_range(&self, addr: u64, len: u64) -> Option<u64> {
+        let offset = addr.checked_sub(self.guest_addr)?;
+        if offset.checked_add(len)? <= self.size {
+            Some(self.userspace_addr + offset)
+        } else {
+            None
+        }
+    }
+}
+
+/// Represents the guest physical addresses of the descriptor table, the available ring and the
+/// used ring of a virtqueue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VringAddr {
+    /// The address of the descriptor table.
+    pub desc: u64,
+    /// The address of the available ring, written by guest.
+    pub avail: u64,
+    /// The address of the used ring, written by the kernel.
+    pub used: u64,
+}
+
+impl VringAddr {
+    /// Creates a new instance of [`VringAddr`](struct.VringAddr.html).
+    pub fn new(desc: u64, avail: u64, used: u64) -> Self {
+        Self { desc, avail, used }
+    }
+}
+
+/// Represents an eventfd, which guest signals to kick a virtqueue and the kernel signals to
+/// call guest once it used buffers.
+#[derive(Debug)]
+pub struct EventFd(File);
+
+impl EventFd {
+    /// Creates a new instance of [`EventFd`](struct.EventFd.html) with a counter of zero.
+    pub fn new() -> Result<Self> {
+        let fd = eventfd(0, EfdFlags::EFD_CLOEXEC)?;
+        Ok(Self(unsafe { File::from_raw_fd(fd) }))
+    }
+
+    /// Adds `value` to the counter, waking up the readers.
+    pub fn write(&self, value: u64) -> Result<()> {
+        (&self.0).write_all(&value.to_ne_bytes())?;
+        Ok(())
+    }
+
+    /// Returns the counter and resets it to zero, blocking while it is zero.
+    pub fn read(&self) -> Result<u64> {
+        let mut value = [0; 8];
+        (&self.0).read_exact(&mut value)?;
+        Ok(u64::from_ne_bytes(value))
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl FromRawFd for EventFd {
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        Self(File::from_raw_fd(fd))
+    }
+}
+
+/// Represents an instance of `/dev/vhost-net`, which serves the receive and transmit
+/// virtqueues of a guest from the queues of a TAP device.
+#[derive(Debug)]
+pub struct VhostNet {
+    file: File,
+    regions: Vec<MemoryRegion>,
+    /// The number of descriptors of the virtqueues, by index.
+    nums: HashMap<u32, u16>,
+}
+
+impl VhostNet {
+    /// Opens `/dev/vhost-net` and makes the calling process its owner (`VHOST_SET_OWNER`).
+    pub fn new() -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC)
+            .open("/dev/vhost-net")?;
+        unsafe { vhost_set_owner(file.as_raw_fd()) }?;
+        Ok(Self {
+            file,
+            regions: Vec::new(),
+            nums: HashMap::new(),
+        })
+    }
+
+    /// Returns the virtio and vhost feature bits supported by the kernel.
+    pub fn features(&self) -> Result<u64> {
+        let mut features = 0;
+        unsafe { vhost_get_features(self.file.as_raw_fd(), &mut features) }?;
+        Ok(features)
+    }
+
+    /// Sets the feature bits negotiated with guest.
+    pub fn set_features(&self, features: u64) -> Result<()> {
+        unsafe { vhost_set_features(self.file.as_raw_fd(), &features) }?;
+        Ok(())
+    }
+
+    /// Sets the memory of guest, through which the virtqueues and their buffers are accessed.
+    ///
+    /// # Safety
+    ///
+    /// The `userspace_addr` and `size` of every region must describe memory mapped into this
+    /// process and not otherwise referenced by Rust, e.g. the memory of guest mapped by the
+    /// monitor. The kernel reads and writes it from other threads until the memory table is
+    /// replaced or `self` is dropped, so it must stay mapped until then.
+    pub unsafe fn set_mem_table(&mut self, regions: &[MemoryRegion]) -> Result<()> {
+        let table = mem_table(regions);
+        unsafe { vhost_set_mem_table(self.file.as_raw_fd(), table.as_ptr() as _) }?;
+        self.regions = regions.to_vec();
+        Ok(())
+    }
+
+    /// Sets the number of descriptors of the virtqueue `queue`.
+    pub fn set_vring_num(&mut self, queue: u32, num: u16) -> Result<()> {
+        let state = vhost_vring_state {
+            index: queue,
+            num: num as _,
+        };
+        unsafe { vhost_set_vring_num(self.file.as_raw_fd(), &state) }?;
+        self.nums.insert(queue, num);
+        Ok(())
+    }
+
+    /// Sets the index of the next available descriptor of the virtqueue `queue`.
+    pub fn set_vring_base(&self, queue: u32, base: u16) -> Result<()> {
+        let state = vhost_vring_state {
+            index: queue,
+            num: base as _,
+        };
+        unsafe { vhost_set_vring_base(self.file.as_raw_fd(), &state) }?;
+        Ok(())
+    }
+
+    /// Stops the virtqueue `queue` and returns the index of its next available descriptor,
+    /// e.g. to migrate guest.
+    pub fn vring_base(&self, queue: u32) -> Result<u16> {
+        let mut state = vhost_vring_state {
+            index: queue,
+            num: 0,
+        };
+        unsafe { vhost_get_vring_base(self.file.as_raw_fd(), &mut state) }?;
+        Ok(state.num as _)
+    }
+
+    /// Sets the addresses of the virtqueue `queue`, whose rings must be within the memory table.
+    /// The number of descriptors must be set first.
+    pub fn set_vring_addr(&self, queue: u32, addr: &VringAddr) -> Result<()> {
+        let num = self
+            .nums
+            .get(&queue)
+            .ok_or_else(|| format!("The size of virtqueue {} is not set", queue))?;
+        let addr = vring_addr(&self.regions, queue, *num, addr)?;
+        unsafe { vhost_set_vring_addr(self.file.as_raw_fd(), &addr) }?;
+        Ok(())
+    }
+
+    /// Sets the eventfd which guest signals once it made buffers available in `queue`.
+    pub fn set_vring_kick(&self, queue: u32, kick: &EventFd) -> Result<()> {
+        let file = vhost_vring_file {
+            index: queue,
+            fd: kick.as_raw_fd(),
+        };
+        unsafe { vhost_set_vring_kick(self.file.as_raw_fd(), &file) }?;
+        Ok(())
+    }
+
+    /// Sets the eventfd which the kernel signals once it used buffers of `queue`.
+    pub fn set_vring_call(&self, queue: u32, call: &EventFd) -> Result<()> {
+        let file = vhost_vring_file {
+            index: queue,
+            fd: call.as_raw_fd(),
+        };
+        unsafe { vhost_set_vring_call(self.file.as_raw_fd(), &file) }?;
+        Ok(())
+    }
+
+    /// Binds the queue of `tun` as the backend of the virtqueue `queue`
+    /// (`VHOST_NET_SET_BACKEND`). The device must be a TAP device without packet information,
+    /// and the queue must not be read or written while it is bound.
+    pub fn set_backend(&self, queue: u32, tun: &Tun) -> Result<()> {
+        if !tun.is_tap() || tun.packet_info() {
+            return Err(format!(
+                "{} is not a TAP device without packet information",
+                tun.name()
+            )
+            .into());
+        }
+        self.bind(queue, tun.as_raw_fd())
+    }
+
+    /// Unbinds the backend of the virtqueue `queue`, stopping it.
+    pub fn remove_backend(&self, queue: u32) -> Result<()> {
+        self.bind(queue, -1)
+    }
+
+    fn bind(&self, queue: u32, fd: RawFd) -> Result<()> {
+        let file = vhost_vring_file { index: queue, fd };
+        unsafe { vhost_net_set_backend(self.file.as_raw_fd(), &file) }?;
+        Ok(())
+    }
+}
+
+impl AsRawFd for VhostNet {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// Encodes `regions` as a `vhost_memory` followed by its `vhost_memory_region`s.
+fn mem_table(regions: &[MemoryRegion]) -> Vec<u64> {
+    let mut table = Vec::with_capacity(1 + regions.len() * 4);
+    let mut header = [0; 8];
+    header[..4].copy_from_slice(&(regions.len() as u32).to_ne_bytes());
+    table.push(u64::from_ne_bytes(header));
+    for region in regions {
+        table.extend_from_slice(&[region.guest_addr, region.size, region.userspace_addr, 0]);
+    }
+    table
+}
+
+/// Translates the guest physical addresses of the rings of a virtqueue of `num` descriptors
+/// through `regions`, each ring being wholly within a region.
+fn vring_addr(
+    regions: &[MemoryRegion],
+    queue: u32,
+    num: u16,
+    addr: &VringAddr,
+) -> Result<vhost_vring_addr> {
+    let num = num as u64;
+    let translate = |addr: u64, len: u64| {
+        regions
+            .iter()
+            .find_map(|region| region.translate_range(addr, len))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{:#x}+{:#x} is not within the memory table", addr, len),
+                )
+            })
+    };
+    // The layouts of the split virtqueue, with the event index fields (virtio 1.1, 2.6).
+    Ok(vhost_vring_addr {
+        index: queue,
+        flags: 0,
+        desc_user_addr: translate(addr.desc, 16 * num)?,
+        used_user_addr: translate(addr.used, 6 + 8 * num)?,
+        avail_user_addr: translate(addr.avail, 6 + 2 * num)?,
+        log_guest_addr: 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::TunBuilder;
+    use crate::tun::tests::net_admin;
+    use async_std::task;
+
+    #[test]
+    fn guest_memory() {
+        let mut memory = vec![0u8; 0x4000];
+        let base = memory.as_ptr() as u64;
+        let regions = [
+            MemoryRegion::from_slice(0x10_0000, &mut memory[..0x2000]),
+            MemoryRegion::from_slice(0x20_0000, &mut memory[0x2000..]),
+        ];
+        let addr = VringAddr::new(0x10_0000, 0x10_1000, 0x20_0010);
+        let raw = vring_addr(&regions, TX_QUEUE, 256, &addr).unwrap();
+        assert_eq!(raw.index, TX_QUEUE);
+        assert_eq!(raw.desc_user_addr, base);
+        assert_eq!(raw.avail_user_addr, base + 0x1000);
+        assert_eq!(raw.used_user_addr, base + 0x2010);
+        for addr in [0xf_ffff, 0x10_2000, 0x20_2000] {
+            let addr = VringAddr::new(0x10_0000, 0x10_1000, addr);
+            assert!(vring_addr(&regions, RX_QUEUE, 256, &addr).is_err());
+        }
+        // Rings must fit within a region, not only start within it.
+        let addr = VringAddr::new(0x10_0000, 0x10_1000, 0x20_1000);
+        assert!(vring_addr(&regions, RX_QUEUE, 256, &addr).is_ok());
+        assert!(vring_addr(&regions, RX_QUEUE, 512, &addr).is_err());
+        let addr = VringAddr::new(0x10_1800, 0x10_1000, 0x20_0000);
+        assert!(vring_addr(&regions, RX_QUEUE, 256, &addr).is_err());
+
+        let table = mem_table(&regions);
+        assert_eq!(table.len(), 9);
+        assert_eq!(table[0].to_ne_bytes()[..4], 2u32.to_ne_bytes());
+        assert_eq!(table[1..5], [0x10_0000, 0x2000, base, 0]);
+        assert_eq!(table[5..], [0x20_0000, 0x2000, base + 0x2000, 0]);
+    }
+
+    #[test]
+    fn eventfd() {
+        let eventfd = EventFd::new().unwrap();
+        eventfd.write(2).unwrap();
+        eventfd.write(3).unwrap();
+        assert_eq!(eventfd.read().unwrap(), 5);
+    }
+
+    #[test]
+    fn backend() {
+        if !net_admin() {
+            return;
+        }
+        // The memory of guest is declared first, so that it outlives `vhost`.
+        let mut memory = vec![0u64; 0x2000];
+        let memory = unsafe {
+            std::slice::from_raw_parts_mut(memory.as_mut_ptr() as *mut u8, memory.len() * 8)
+        };
+        let mut vhost = match VhostNet::new() {
+            Ok(vhost) => vhost,
+            Err(err) => {
+                eprintln!("skipped: /dev/vhost-net is not available: {}", err);
+                return;
+            }
+        };
+        let tun = task::block_on(
+            TunBuilder::new()
+                .name("vhost%d")
+                .tap(true)
+                .packet_info(false)
+                .try_build(),
+        )
+        .unwrap();
+        vhost.set_features(vhost.features().unwrap()).unwrap();
+        unsafe { vhost.set_mem_table(&[MemoryRegion::from_slice(0, memory)]) }.unwrap();
+        let mut eventfds = Vec::new();
+        for (queue, base) in [(RX_QUEUE, 0x1000), (TX_QUEUE, 0x5000)] {
+            let (kick, call) = (EventFd::new().unwrap(), EventFd::new().unwrap());
+            vhost.set_vring_num(queue, 256).unwrap();
+            vhost.set_vring_base(queue, 0).unwrap();
+            let addr = VringAddr::new(base, base + 0x1000, base + 0x2000);
+            vhost.set_vring_addr(queue, &addr).unwrap();
+            vhost.set_vring_kick(queue, &kick).unwrap();
+            vhost.set_vring_call(queue, &call).unwrap();
+            vhost.set_backend(queue, &tun).unwrap();
+            eventfds.push((kick, call));
+        }
+        // Rings outside of the memory table are rejected before reaching the kernel.
+        let addr = VringAddr::new(0x10000, 0x1000, 0x2000);
+        assert!(vhost.set_vring_addr(RX_QUEUE, &addr).is_err());
+        for queue in [RX_QUEUE, TX_QUEUE] {
+            vhost.remove_backend(queue).unwrap();
+            assert_eq!(vhost.vring_base(queue).unwrap(), 0);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use crate::result::Result;
+use crate::vhost::MemoryRegion;
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+/// Represents a region of the memory table sent by the frontend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionDesc {
+    pub guest_addr: u64,
+    pub size: u64,
+    pub frontend_addr: u64,
+    pub mmap_offset: u64,
+}
+
+struct Mapping {
+    addr: *mut u8,
+    len: usize,
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        let _ = unsafe { munmap(self.addr as _, self.len) };
+    }
+}
+
+/// Represents the memory of guest, mapped into this process from the fds of the frontend.
+pub struct GuestMemory {
+    _mappings: Vec<Mapping>,
+    /// Translates guest physical addresses, used by descriptors.
+    guest: Vec<MemoryRegion>,
+    /// Translates the addresses in the frontend process, used by the vring addresses.
+    frontend: Vec<MemoryRegion>,
+}
+
+// The mappings are only accessed through the bounds-checked methods below.
+unsafe impl Send for GuestMemory {}
+unsafe impl Sync for GuestMemory {}
+
+impl GuestMemory {
+    /// Maps each of `regions` from the matching fd of `fds`.
+    pub fn map(regions: &[RegionDesc], fds: &[RawFd]) -> Result<Self> {
+        if regions.len() != fds.len() {
+            return Err(format!("{} regions but {} fds", regions.len(), fds.len()).into());
+        }
+        let mut memory = Self {
+            _mappings: Vec::with_capacity(regions.len()),
+            guest: Vec::with_capacity(regions.len()),
+            frontend: Vec::with_capacity(regions.len()),
+        };
+        for (region, fd) in regions.iter().zip(fds) {
+            let len = region
+                .size
+                .checked_add(region.mmap_offset)
+                .ok_or("invalid region")?;
+            let addr = unsafe {
+                mmap(
+                    ptr::null_mut(),
+                    len as _,
+                    ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                    MapFlags::MAP_SHARED,
+                    *fd,
+                    0,
+                )
+            }? as *mut u8;
+            memory._mappings.push(Mapping {
+                addr,
+                len: len as _,
+            });
+            let host = addr as u64 + region.mmap_offset;
+            memory
+                .guest
+                .push(MemoryRegion::new(region.guest_addr, region.size, host));
+            memory
+                .frontend
+                .push(MemoryRegion::new(region.frontend_addr, region.size, host));
+        }
+        Ok(memory)
+    }
+
+    /// Returns a pointer to the `len` bytes at the guest physical address `addr`.
+    pub fn ptr(&self, addr: u64, len: usize) -> Option<*mut u8> {
+        find(&self.guest, addr, len)
+    }
+
+    /// Returns a pointer to the `len` bytes at the address `addr` of the frontend process.
+    pub fn frontend_ptr(&self, addr: u64, len: usize) -> Option<*mut u8> {
+        find(&self.frontend, addr, len)
+    }
+
+    /// Copies the bytes at the guest physical address `addr` into `buf`.
+    pub fn read(&self, addr: u64, buf: &mut [u8]) -> Option<()> {
+        let src = self.ptr(addr, buf.len())?;
+        unsafe { ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+        Some(())
+    }
+
+    /// Copies `data` to the guest physical address `addr`.
+    pub fn write(&self, addr: u64, data: &[u8]) -> Option<()> {
+        let dst = self.ptr(addr, data.len())?;
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len()) };
+        Some(())
+    }
+}
+
+/// Translates the `len` bytes at `addr`, which must be within a single region of `regions`.
+fn find(regions: &[MemoryRegion], addr: u64, len: usize) -> Option<*mut u8> {
+    regions.iter().find_map(|region| {
+        let start = region.translate(addr)?;
+        let last = addr.checked_add(len.max(1) as u64 - 1)?;
+        region.translate(last)?;
+        Some(start as *mut u8)
+    })
+}
@@ -0,0 +1,820 @@
+//! A vhost-user-net backend, which serves the virtqueues of a guest of QEMU or cloud-hypervisor
+//! from a TAP device.
+//!
+//! The frontend connects to a Unix socket, negotiates features, shares the memory of guest and
+//! sets up the receive and transmit virtqueues. Frames read from device are written to the
+//! receive virtqueue, and frames made available in the transmit virtqueue are written to device.
+//! The `virtio_net_hdr` of frames is passed through to a device built with `vnet_hdr`, which
+//! lets guest offload checksums and segmentation to the host:
+//!
+//! ```no_run
+//! # async fn run() -> async_tun::result::Result<()> {
+//! use async_std::sync::Arc;
+//! use async_tun::vhost_user::VhostUserNet;
+//! use async_tun::TunBuilder;
+//!
+//! let tun = TunBuilder::new()
+//!     .tap(true)
+//!     .packet_info(false)
+//!     .vnet_hdr(true)
+//!     .backpressure(true)
+//!     .up()
+//!     .try_build()
+//!     .await?;
+//! VhostUserNet::new(Arc::new(tun))?
+//!     .serve("/run/vhost-user-net.sock")
+//!     .await
+//! # }
+//! ```
+
+mod memory;
+mod queue;
+
+use self::memory::{GuestMemory, RegionDesc};
+use self::queue::Vring;
+use crate::device::{DeviceKind, TunDevice};
+use crate::result::Result;
+use crate::vhost::{EventFd, VringAddr};
+use async_io::Async;
+use async_std::io::{ReadExt, WriteExt};
+use async_std::sync::Arc;
+use async_std::task::{self, JoinHandle};
+use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags};
+use std::fs::File;
+use std::io::{self, IoSliceMut};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicU16, Ordering};
+
+const VHOST_USER_GET_FEATURES: u32 = 1;
+const VHOST_USER_SET_FEATURES: u32 = 2;
+const VHOST_USER_SET_OWNER: u32 = 3;
+const VHOST_USER_RESET_OWNER: u32 = 4;
+const VHOST_USER_SET_MEM_TABLE: u32 = 5;
+const VHOST_USER_SET_VRING_NUM: u32 = 8;
+const VHOST_USER_SET_VRING_ADDR: u32 = 9;
+const VHOST_USER_SET_VRING_BASE: u32 = 10;
+const VHOST_USER_GET_VRING_BASE: u32 = 11;
+const VHOST_USER_SET_VRING_KICK: u32 = 12;
+const VHOST_USER_SET_VRING_CALL: u32 = 13;
+const VHOST_USER_SET_VRING_ERR: u32 = 14;
+const VHOST_USER_GET_PROTOCOL_FEATURES: u32 = 15;
+const VHOST_USER_SET_PROTOCOL_FEATURES: u32 = 16;
+const VHOST_USER_GET_QUEUE_NUM: u32 = 17;
+const VHOST_USER_SET_VRING_ENABLE: u32 = 18;
+
+const VHOST_USER_VERSION: u32 = 0x1;
+const VHOST_USER_REPLY: u32 = 0x4;
+const VHOST_USER_NEED_REPLY: u32 = 0x8;
+const VHOST_USER_VRING_INDEX_MASK: u64 = 0xff;
+const VHOST_USER_VRING_NOFD: u64 = 0x100;
+
+const VIRTIO_NET_F_CSUM: u64 = 1 << 0;
+const VIRTIO_NET_F_HOST_TSO4: u64 = 1 << 11;
+const VIRTIO_NET_F_HOST_TSO6: u64 = 1 << 12;
+const VIRTIO_NET_F_HOST_ECN: u64 = 1 << 13;
+const VHOST_USER_F_PROTOCOL_FEATURES: u64 = 1 << 30;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const VHOST_USER_PROTOCOL_F_REPLY_ACK: u64 = 1 << 3;
+
+const HEADER_LEN: usize = 12;
+const MAX_PAYLOAD_LEN: usize = 4096;
+const MAX_FDS: usize = 8;
+const MEM_REGION_LEN: usize = 32;
+/// Length of the `virtio_net_hdr` without `num_buffers`, which guest and device share.
+const VIRTIO_NET_HDR_LEN: usize = 10;
+/// Length of the `virtio_net_hdr` of guest with `VIRTIO_F_VERSION_1`, including `num_buffers`.
+const VIRTIO_NET_HDR_MRG_LEN: usize = 12;
+const RX_BUFFER_LEN: usize = 65536 + 64;
+/// The longest frame accepted from the transmit virtqueue, besides its header.
+const MAX_FRAME_LEN: usize = 65536;
+
+/// The index of the receive virtqueue.
+const RX_QUEUE: usize = 0;
+
+/// Represents a vhost-user-net backend, which connects a TAP device to the virtqueues of guest.
+///
+/// The device must be a TAP device without packet information, e.g. a
+/// [`Tun`](../struct.Tun.html) built with `tap(true)` and `packet_info(false)`. With `vnet_hdr`,
+/// guest may send frames with partial checksums and TSO.
+pub struct VhostUserNet<D> {
+    device: Arc<D>,
+}
+
+impl<D: TunDevice + Send + Sync + 'static> VhostUserNet<D> {
+    /// Creates a new instance of [`VhostUserNet`](struct.VhostUserNet.html) serving `device`.
+    pub fn new(device: Arc<D>) -> Result<Self> {
+        if device.kind() != DeviceKind::Tap || device.packet_info() {
+            return Err(format!(
+                "{} is not a TAP device without packet information",
+                device.name()
+            )
+            .into());
+        }
+        if !matches!(
+            device.header_len(),
+            0 | VIRTIO_NET_HDR_LEN | VIRTIO_NET_HDR_MRG_LEN
+        ) {
+            return Err(format!(
+                "Unsupported vnet header length of {}: {}",
+                device.name(),
+                device.header_len()
+            )
+            .into());
+        }
+        Ok(Self { device })
+    }
+
+    /// Returns the virtio feature bits offered to the frontend.
+    pub fn features(&self) -> u64 {
+        let mut features = VIRTIO_F_VERSION_1 | VHOST_USER_F_PROTOCOL_FEATURES;
+        if self.device.header_len() > 0 {
+            features |= VIRTIO_NET_F_CSUM
+                | VIRTIO_NET_F_HOST_TSO4
+                | VIRTIO_NET_F_HOST_TSO6
+                | VIRTIO_NET_F_HOST_ECN;
+        }
+        features
+    }
+
+    /// Listens on the Unix socket `path` and serves the frontends connecting to it, one at a time.
+    pub async fn serve(&self, path: impl AsRef<Path>) -> Result<()> {
+        let listener = Async::new(UnixListener::bind(path)?)?;
+        loop {
+            let (stream, _) = listener.accept().await?;
+            self.handle(stream.into_inner()?).await?;
+        }
+    }
+
+    /// Serves the frontend connected to `stream` until it disconnects.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all, fields(name = self.device.name()), err))]
+    pub async fn handle(&self, stream: UnixStream) -> Result<()> {
+        let stream = Async::new(stream)?;
+        let mut session = Session {
+            device: self.device.clone(),
+            offered: self.features(),
+            features: 0,
+            protocol_features: 0,
+            memory: None,
+            queues: Default::default(),
+        };
+        let result = session.run(&stream).await;
+        for index in 0..session.queues.len() {
+            session.stop(index).await;
+        }
+        result
+    }
+}
+
+/// Represents a virtqueue as set up by the frontend.
+#[derive(Default)]
+struct Queue {
+    num: u16,
+    addr: Option<VringAddr>,
+    next: Arc<AtomicU16>,
+    kick: Option<File>,
+    call: Option<Arc<EventFd>>,
+    enabled: bool,
+    worker: Option<JoinHandle<()>>,
+}
+
+/// Represents a message of the frontend.
+struct Message {
+    request: u32,
+    flags: u32,
+    payload: Vec<u8>,
+    fds: Vec<File>,
+}
+
+impl Message {
+    fn u32(&self, offset: usize) -> Result<u32> {
+        let bytes = self
+            .payload
+            .get(offset..offset + 4)
+            .ok_or("Truncated message")?;
+        Ok(u32::from_ne_bytes(bytes.try_into()?))
+    }
+
+    fn u64(&self, offset: usize) -> Result<u64> {
+        let bytes = self
+            .payload
+            .get(offset..offset + 8)
+            .ok_or("Truncated message")?;
+        Ok(u64::from_ne_bytes(bytes.try_into()?))
+    }
+}
+
+/// Represents the state of a connection to a frontend.
+struct Session<D> {
+    device: Arc<D>,
+    offered: u64,
+    features: u64,
+    protocol_features: u64,
+    memory: Option<Arc<GuestMemory>>,
+    queues: [Queue; 2],
+}
+
+impl<D: TunDevice + Send + Sync + 'static> Session<D> {
+    async fn run(&mut self, stream: &Async<UnixStream>) -> Result<()> {
+        while let Some(mut message) = recv_message(stream).await? {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(request = message.request, "vhost-user request");
+            let result = self.request(&mut message).await;
+            let ack = message.flags & VHOST_USER_NEED_REPLY != 0
+                && self.protocol_features & VHOST_USER_PROTOCOL_F_REPLY_ACK != 0;
+            match result {
+                Ok(Some(reply)) => send_reply(stream, message.request, &reply).await?,
+                Ok(None) if ack => send_reply(stream, message.request, &0u64.to_ne_bytes()).await?,
+                Ok(None) => {}
+                Err(_error) if ack => {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(request = message.request, error = %_error, "vhost-user request failed");
+                    send_reply(stream, message.request, &1u64.to_ne_bytes()).await?
+                }
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles `message` and returns its reply, if the request has one.
+    async fn request(&mut self, message: &mut Message) -> Result<Option<Vec<u8>>> {
+        match message.request {
+            VHOST_USER_GET_FEATURES => return Ok(Some(self.offered.to_ne_bytes().to_vec())),
+            VHOST_USER_SET_FEATURES => {
+                let features = message.u64(0)?;
+                if features & !self.offered != 0 {
+                    return Err(format!("Unsupported features: {:#x}", features).into());
+                }
+                self.features = features;
+            }
+            VHOST_USER_GET_PROTOCOL_FEATURES => {
+                return Ok(Some(VHOST_USER_PROTOCOL_F_REPLY_ACK.to_ne_bytes().to_vec()))
+            }
+            VHOST_USER_SET_PROTOCOL_FEATURES => {
+                self.protocol_features = message.u64(0)? & VHOST_USER_PROTOCOL_F_REPLY_ACK;
+            }
+            VHOST_USER_GET_QUEUE_NUM => return Ok(Some(1u64.to_ne_bytes().to_vec())),
+            VHOST_USER_SET_OWNER => {}
+            VHOST_USER_RESET_OWNER => {
+                for index in 0..self.queues.len() {
+                    self.stop(index).await;
+                    self.queues[index] = Default::default();
+                }
+                self.features = 0;
+                self.memory = None;
+            }
+            VHOST_USER_SET_MEM_TABLE => {
+                let count = message.u32(0)? as usize;
+                let mut regions = Vec::with_capacity(count);
+                for i in 0..count.min(MAX_FDS) {
+                    let offset = 8 + i * MEM_REGION_LEN;
+                    regions.push(RegionDesc {
+                        guest_addr: message.u64(offset)?,
+                        size: message.u64(offset + 8)?,
+                        frontend_addr: message.u64(offset + 16)?,
+                        mmap_offset: message.u64(offset + 24)?,
+                    });
+                }
+                let fds: Vec<RawFd> = message.fds.iter().map(|fd| fd.as_raw_fd()).collect();
+                let memory = Arc::new(GuestMemory::map(&regions, &fds)?);
+                let running: Vec<usize> = (0..self.queues.len())
+                    .filter(|index| self.queues[*index].worker.is_some())
+                    .collect();
+                for index in running.iter() {
+                    self.stop(*index).await;
+                }
+                self.memory = Some(memory);
+                for index in running {
+                    self.start(index)?;
+                }
+            }
+            VHOST_USER_SET_VRING_NUM => {
+                let index = self.stopped(message.u32(0)?).await?;
+                self.queues[index].num = message.u32(4)?.try_into()?;
+            }
+            VHOST_USER_SET_VRING_ADDR => {
+                let index = self.stopped(message.u32(0)?).await?;
+                self.queues[index].addr = Some(VringAddr::new(
+                    message.u64(8)?,
+                    message.u64(24)?,
+                    message.u64(16)?,
+                ));
+            }
+            VHOST_USER_SET_VRING_BASE => {
+                let index = self.stopped(message.u32(0)?).await?;
+                let base: u16 = message.u32(4)?.try_into()?;
+                self.queues[index].next.store(base, Ordering::Release);
+            }
+            VHOST_USER_GET_VRING_BASE => {
+                let index = self.stopped(message.u32(0)?).await?;
+                let queue = &mut self.queues[index];
+                queue.kick = None;
+                let mut reply = (index as u32).to_ne_bytes().to_vec();
+                reply.extend_from_slice(&(queue.next.load(Ordering::Acquire) as u32).to_ne_bytes());
+                return Ok(Some(reply));
+            }
+            VHOST_USER_SET_VRING_KICK | VHOST_USER_SET_VRING_CALL | VHOST_USER_SET_VRING_ERR => {
+                let value = message.u64(0)?;
+                let fd = if value & VHOST_USER_VRING_NOFD == 0 {
+                    Some(message.fds.pop().ok_or("Missing vring fd")?)
+                } else {
+                    None
+                };
+                let index = self
+                    .stopped((value & VHOST_USER_VRING_INDEX_MASK) as u32)
+                    .await?;
+                let queue = &mut self.queues[index];
+                match message.request {
+                    VHOST_USER_SET_VRING_KICK => queue.kick = fd,
+                    VHOST_USER_SET_VRING_CALL => {
+                        queue.call =
+                            fd.map(|fd| Arc::new(unsafe { EventFd::from_raw_fd(fd.into_raw_fd()) }))
+                    }
+                    _ => {}
+                }
+                self.start(index)?;
+            }
+            VHOST_USER_SET_VRING_ENABLE => {
+                let index = self.stopped(message.u32(0)?).await?;
+                self.queues[index].enabled = message.u32(4)? != 0;
+                self.start(index)?;
+            }
+            request => return Err(format!("Unsupported vhost-user request: {}", request).into()),
+        }
+        Ok(None)
+    }
+
+    /// Stops the virtqueue `index` for reconfiguration and returns its index.
+    async fn stopped(&mut self, index: u32) -> Result<usize> {
+        let index = index as usize;
+        if index >= self.queues.len() {
+            return Err(format!("Invalid virtqueue: {}", index).into());
+        }
+        self.stop(index).await;
+        Ok(index)
+    }
+
+    async fn stop(&mut self, index: usize) {
+        if let Some(worker) = self.queues[index].worker.take() {
+            worker.cancel().await;
+        }
+    }
+
+    /// Starts serving the virtqueue `index` once it is completely set up and enabled.
+    fn start(&mut self, index: usize) -> Result<()> {
+        let enabled =
+            self.queues[index].enabled || self.features & VHOST_USER_F_PROTOCOL_FEATURES == 0;
+        let queue = &mut self.queues[index];
+        let (Some(memory), Some(addr), Some(kick), true, None) = (
+            &self.memory,
+            &queue.addr,
+            &queue.kick,
+            enabled,
+            &queue.worker,
+        ) else {
+            return Ok(());
+        };
+        let vring = Vring::new(memory.clone(), queue.num, addr, queue.next.clone())?;
+        let kick = Async::new(kick.try_clone()?)?;
+        let call = queue.call.clone();
+        let device = self.device.clone();
+        let header = Header {
+            guest: if self.features & VIRTIO_F_VERSION_1 != 0 {
+                VIRTIO_NET_HDR_MRG_LEN
+            } else {
+                VIRTIO_NET_HDR_LEN
+            },
+            device: device.header_len(),
+        };
+        queue.worker = Some(task::spawn(async move {
+            let _result = if index == RX_QUEUE {
+                receive(device, vring, kick, call, header).await
+            } else {
+                transmit(device, vring, kick, call, header).await
+            };
+            #[cfg(feature = "tracing")]
+            if let Err(error) = _result {
+                tracing::warn!(queue = index, %error, "vhost-user virtqueue stopped");
+            }
+        }));
+        Ok(())
+    }
+}
+
+/// Represents the lengths of the `virtio_net_hdr` prefixing the frames of guest and device.
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    guest: usize,
+    device: usize,
+}
+
+/// Replaces the header of `from` bytes prefixing `frame` with a header of `to` bytes, keeping
+/// the shared fields and setting `num_buffers` to one, or returns `None` if `frame` is too short.
+fn rewrite_header(frame: &[u8], from: usize, to: usize) -> Option<Vec<u8>> {
+    let payload = frame.get(from..)?;
+    let mut rewritten = vec![0; to + payload.len()];
+    let shared = from.min(to).min(VIRTIO_NET_HDR_LEN);
+    rewritten[..shared].copy_from_slice(&frame[..shared]);
+    if to == VIRTIO_NET_HDR_MRG_LEN {
+        rewritten[VIRTIO_NET_HDR_LEN..to].copy_from_slice(&1u16.to_le_bytes());
+    }
+    rewritten[to..].copy_from_slice(payload);
+    Some(rewritten)
+}
+
+/// Writes the frames read from `device` to the receive virtqueue.
+async fn receive<D: TunDevice>(
+    device: Arc<D>,
+    vring: Vring,
+    kick: Async<File>,
+    call: Option<Arc<EventFd>>,
+    header: Header,
+) -> Result<()> {
+    let mut buf = vec![0; RX_BUFFER_LEN];
+    loop {
+        let len = device.recv(&mut buf).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        let Some(frame) = rewrite_header(&buf[..len], header.device, header.guest) else {
+            continue;
+        };
+        loop {
+            match vring.peek() {
+                Some(chain) => {
+                    let chain = chain?;
+                    // Frames which do not fit the buffers of guest are dropped.
+                    if let Some(len) = vring.write(&chain, &frame) {
+                        vring.push(&chain, len);
+                        notify(&vring, &call)?;
+                    }
+                    break;
+                }
+                None => wait(&kick).await?,
+            }
+        }
+    }
+}
+
+/// Writes the frames of the transmit virtqueue to `device`.
+async fn transmit<D: TunDevice>(
+    device: Arc<D>,
+    vring: Vring,
+    kick: Async<File>,
+    call: Option<Arc<EventFd>>,
+    header: Header,
+) -> Result<()> {
+    loop {
+        let mut used = false;
+        while let Some(chain) = vring.peek() {
+            let chain = chain?;
+            // Chains longer than any frame are dropped before anything is allocated for them.
+            if chain.readable_len() > (header.guest + MAX_FRAME_LEN) as u64 {
+                vring.push(&chain, 0);
+                used = true;
+                continue;
+            }
+            let frame = vring
+                .read(&chain)
+                .ok_or("Buffer is not within the memory table")?;
+            if let Some(frame) = rewrite_header(&frame, header.guest, header.device) {
+                // Frames are dropped like on a link without carrier, e.g. while device is down.
+                let _ = device.send(&frame).await;
+            }
+            vring.push(&chain, 0);
+            used = true;
+        }
+        if used {
+            notify(&vring, &call)?;
+        }
+        wait(&kick).await?;
+    }
+}
+
+fn notify(vring: &Vring, call: &Option<Arc<EventFd>>) -> Result<()> {
+    match call {
+        Some(call) if vring.needs_notification() => call.write(1),
+        _ => Ok(()),
+    }
+}
+
+/// Waits until guest kicks the virtqueue.
+async fn wait(kick: &Async<File>) -> io::Result<()> {
+    kick.read_with(|file| {
+        let mut value = [0; 8];
+        std::io::Read::read(&mut &*file, &mut value).map(|_| ())
+    })
+    .await
+}
+
+/// Receives a message and its fds, or returns `None` once the frontend disconnected.
+async fn recv_message(stream: &Async<UnixStream>) -> Result<Option<Message>> {
+    let mut header = [0; HEADER_LEN];
+    let mut cmsg = nix::cmsg_space!([RawFd; MAX_FDS]);
+    let (len, fds) = stream
+        .read_with(|stream| {
+            let mut iov = [IoSliceMut::new(&mut header)];
+            let msg = recvmsg::<()>(
+                stream.as_raw_fd(),
+                &mut iov,
+                Some(&mut cmsg),
+                MsgFlags::MSG_CMSG_CLOEXEC,
+            )?;
+            let mut fds = Vec::new();
+            for cmsg in msg.cmsgs() {
+                if let ControlMessageOwned::ScmRights(rights) = cmsg {
+                    fds.extend(
+                        rights
+                            .into_iter()
+                            .map(|fd| unsafe { File::from_raw_fd(fd) }),
+                    );
+                }
+            }
+            Ok((msg.bytes, fds))
+        })
+        .await?;
+    if len == 0 {
+        return Ok(None);
+    }
+    (&*stream).read_exact(&mut header[len..]).await?;
+    let size = u32::from_ne_bytes(header[8..12].try_into()?) as usize;
+    if size > MAX_PAYLOAD_LEN {
+        return Err(format!("vhost-user message too long: {}", size).into());
+    }
+    let mut payload = vec![0; size];
+    (&*stream).read_exact(&mut payload).await?;
+    Ok(Some(Message {
+        request: u32::from_ne_bytes(header[..4].try_into()?),
+        flags: u32::from_ne_bytes(header[4..8].try_into()?),
+        payload,
+        fds,
+    }))
+}
+
+async fn send_reply(stream: &Async<UnixStream>, request: u32, payload: &[u8]) -> Result<()> {
+    let mut reply = Vec::with_capacity(HEADER_LEN + payload.len());
+    reply.extend_from_slice(&request.to_ne_bytes());
+    reply.extend_from_slice(&(VHOST_USER_VERSION | VHOST_USER_REPLY).to_ne_bytes());
+    reply.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+    reply.extend_from_slice(payload);
+    (&*stream).write_all(&reply).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTun;
+    use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+    use nix::sys::mman::{mmap, MapFlags, ProtFlags};
+    use nix::sys::socket::{sendmsg, ControlMessage};
+    use nix::unistd::ftruncate;
+    use std::ffi::CString;
+    use std::io::{IoSlice, Read};
+    use std::ptr;
+    use std::time::{Duration, Instant};
+
+    const MEMORY_LEN: usize = 0x10000;
+    const QUEUE_SIZE: u32 = 8;
+    /// The guest addresses of the virtqueues, each with its descriptor table, available ring
+    /// and used ring 0x100 bytes apart.
+    const RINGS: [u64; 2] = [0x0000, 0x1000];
+    const RX_BUFFER: u64 = 0x4000;
+    const TX_BUFFER: u64 = 0x8000;
+
+    /// Represents a frontend stand-in, whose guest memory starts at guest address zero.
+    struct Frontend {
+        stream: UnixStream,
+        memfd: File,
+        memory: *mut u8,
+    }
+
+    impl Frontend {
+        fn new(stream: UnixStream) -> Self {
+            let name = CString::new("guest").unwrap();
+            let fd = memfd_create(&name, MemFdCreateFlag::MFD_CLOEXEC).unwrap();
+            let memfd = unsafe { File::from_raw_fd(fd) };
+            ftruncate(fd, MEMORY_LEN as _).unwrap();
+            let memory = unsafe {
+                mmap(
+                    ptr::null_mut(),
+                    MEMORY_LEN,
+                    ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                    MapFlags::MAP_SHARED,
+                    fd,
+                    0,
+                )
+            }
+            .unwrap() as *mut u8;
+            Self {
+                stream,
+                memfd,
+                memory,
+            }
+        }
+
+        /// Sends `request` and returns its reply, which is an ack unless the request has one.
+        fn request(&mut self, request: u32, payload: &[u8], fds: &[RawFd]) -> Vec<u8> {
+            let mut message = request.to_ne_bytes().to_vec();
+            message.extend_from_slice(&(VHOST_USER_VERSION | VHOST_USER_NEED_REPLY).to_ne_bytes());
+            message.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+            message.extend_from_slice(payload);
+            let rights = [ControlMessage::ScmRights(fds)];
+            let cmsgs = if fds.is_empty() {
+                &rights[..0]
+            } else {
+                &rights[..]
+            };
+            let iov = [IoSlice::new(&message)];
+            sendmsg::<()>(
+                self.stream.as_raw_fd(),
+                &iov,
+                cmsgs,
+                MsgFlags::empty(),
+                None,
+            )
+            .unwrap();
+            let mut header = [0; HEADER_LEN];
+            self.stream.read_exact(&mut header).unwrap();
+            assert_eq!(header[..4], request.to_ne_bytes());
+            let flags = u32::from_ne_bytes(header[4..8].try_into().unwrap());
+            assert_eq!(flags, VHOST_USER_VERSION | VHOST_USER_REPLY);
+            let mut reply = vec![0; u32::from_ne_bytes(header[8..].try_into().unwrap()) as usize];
+            self.stream.read_exact(&mut reply).unwrap();
+            reply
+        }
+
+        fn ack(&mut self, request: u32, payload: &[u8], fds: &[RawFd]) {
+            assert_eq!(self.request(request, payload, fds), 0u64.to_ne_bytes());
+        }
+
+        fn write(&self, addr: u64, data: &[u8]) {
+            assert!(addr as usize + data.len() <= MEMORY_LEN);
+            let dst = unsafe { self.memory.add(addr as _) };
+            unsafe { ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len()) };
+        }
+
+        fn read(&self, addr: u64, len: usize) -> Vec<u8> {
+            assert!(addr as usize + len <= MEMORY_LEN);
+            let src = unsafe { self.memory.add(addr as _) };
+            let mut data = vec![0; len];
+            unsafe { ptr::copy_nonoverlapping(src, data.as_mut_ptr(), len) };
+            data
+        }
+
+        /// Makes the buffer at `addr` available as the first chain of the virtqueue `queue`.
+        fn add_buffer(&self, queue: usize, addr: u64, len: u32, flags: u16) {
+            let mut desc = addr.to_le_bytes().to_vec();
+            desc.extend_from_slice(&len.to_le_bytes());
+            desc.extend_from_slice(&flags.to_le_bytes());
+            desc.extend_from_slice(&0u16.to_le_bytes());
+            self.write(RINGS[queue], &desc);
+            self.write(RINGS[queue] + 0x104, &0u16.to_le_bytes());
+            self.write(RINGS[queue] + 0x102, &1u16.to_le_bytes());
+        }
+
+        /// Waits until the first chain of the virtqueue `queue` is used, returning its length.
+        fn used(&self, queue: usize) -> u32 {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while self.read(RINGS[queue] + 0x202, 2) != 1u16.to_le_bytes() {
+                assert!(Instant::now() < deadline, "virtqueue {} not used", queue);
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(self.read(RINGS[queue] + 0x204, 4), 0u32.to_le_bytes());
+            u32::from_le_bytes(self.read(RINGS[queue] + 0x208, 4).try_into().unwrap())
+        }
+    }
+
+    fn frame(ethertype: u16) -> Vec<u8> {
+        let mut frame = vec![0xff; 6];
+        frame.extend_from_slice(&[2, 0, 0, 0, 0, 1]);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.resize(60, 0xab);
+        frame
+    }
+
+    #[test]
+    fn headers() {
+        let frame = [[1u8; 10].as_slice(), &[0, 0], &[7; 4]].concat();
+        let device = rewrite_header(&frame, 12, 10).unwrap();
+        assert_eq!(device, [[1u8; 10].as_slice(), &[7; 4]].concat());
+        assert_eq!(rewrite_header(&device, 10, 12).unwrap()[10..12], [1, 0]);
+        assert_eq!(rewrite_header(&frame, 12, 0).unwrap(), [7; 4]);
+        let guest = rewrite_header(&[7; 4], 0, 12).unwrap();
+        assert_eq!(guest, [[0u8; 10].as_slice(), &[1, 0], &[7; 4]].concat());
+        assert!(rewrite_header(&[0; 8], 10, 12).is_none());
+    }
+
+    #[test]
+    fn frontend() {
+        task::block_on(async {
+            let tun = MockTun::new("vhost0")
+                .with_tap(true)
+                .with_packet_info(false);
+            let kernel = tun.kernel();
+            let backend = VhostUserNet::new(Arc::new(tun)).unwrap();
+            let (stream, backend_stream) = UnixStream::pair().unwrap();
+            let session = task::spawn(async move { backend.handle(backend_stream).await });
+            let mut frontend = Frontend::new(stream);
+
+            let reply = frontend.request(VHOST_USER_GET_FEATURES, &[], &[]);
+            let features = u64::from_ne_bytes(reply.try_into().unwrap());
+            assert_eq!(
+                features,
+                VIRTIO_F_VERSION_1 | VHOST_USER_F_PROTOCOL_FEATURES
+            );
+            let reply = frontend.request(VHOST_USER_GET_PROTOCOL_FEATURES, &[], &[]);
+            assert_eq!(reply, VHOST_USER_PROTOCOL_F_REPLY_ACK.to_ne_bytes());
+            // Acks are only sent once REPLY_ACK is negotiated, which has no reply itself.
+            let mut message = VHOST_USER_SET_PROTOCOL_FEATURES.to_ne_bytes().to_vec();
+            message.extend_from_slice(&VHOST_USER_VERSION.to_ne_bytes());
+            message.extend_from_slice(&8u32.to_ne_bytes());
+            message.extend_from_slice(&VHOST_USER_PROTOCOL_F_REPLY_ACK.to_ne_bytes());
+            std::io::Write::write_all(&mut frontend.stream, &message).unwrap();
+            frontend.ack(VHOST_USER_SET_FEATURES, &features.to_ne_bytes(), &[]);
+            frontend.ack(VHOST_USER_SET_OWNER, &[], &[]);
+
+            let mut table = 1u32.to_ne_bytes().to_vec();
+            table.extend_from_slice(&0u32.to_ne_bytes());
+            for value in [0, MEMORY_LEN as u64, frontend.memory as u64, 0] {
+                table.extend_from_slice(&value.to_ne_bytes());
+            }
+            let memfd = frontend.memfd.as_raw_fd();
+            frontend.ack(VHOST_USER_SET_MEM_TABLE, &table, &[memfd]);
+
+            let kicks = [EventFd::new().unwrap(), EventFd::new().unwrap()];
+            let calls = [EventFd::new().unwrap(), EventFd::new().unwrap()];
+            for (index, ring) in RINGS.iter().enumerate() {
+                let state = |num: u32| [(index as u32).to_ne_bytes(), num.to_ne_bytes()].concat();
+                frontend.ack(VHOST_USER_SET_VRING_NUM, &state(QUEUE_SIZE), &[]);
+                let mut addr = state(0);
+                let base = frontend.memory as u64 + ring;
+                for value in [base, base + 0x200, base + 0x100, 0] {
+                    addr.extend_from_slice(&value.to_ne_bytes());
+                }
+                frontend.ack(VHOST_USER_SET_VRING_ADDR, &addr, &[]);
+                frontend.ack(VHOST_USER_SET_VRING_BASE, &state(0), &[]);
+                let value = (index as u64).to_ne_bytes();
+                frontend.ack(
+                    VHOST_USER_SET_VRING_KICK,
+                    &value,
+                    &[kicks[index].as_raw_fd()],
+                );
+                frontend.ack(
+                    VHOST_USER_SET_VRING_CALL,
+                    &value,
+                    &[calls[index].as_raw_fd()],
+                );
+                frontend.ack(VHOST_USER_SET_VRING_ENABLE, &state(1), &[]);
+            }
+
+            // A frame read from device is written to the receive virtqueue with a header.
+            let received = frame(0x0800);
+            frontend.add_buffer(RX_QUEUE, RX_BUFFER, 2048, 2);
+            kernel.inject(&received).await;
+            assert_eq!(frontend.used(RX_QUEUE), 12 + received.len() as u32);
+            assert_eq!(
+                frontend.read(RX_BUFFER, 12 + received.len()),
+                [[0u8; 10].as_slice(), &[1, 0], &received].concat()
+            );
+            assert_eq!(calls[0].read().unwrap(), 1);
+
+            // A frame of the transmit virtqueue is written to device without its header.
+            let sent = frame(0x86dd);
+            frontend.write(TX_BUFFER, &[0; 12]);
+            frontend.write(TX_BUFFER + 12, &sent);
+            frontend.add_buffer(1, TX_BUFFER, 12 + sent.len() as u32, 0);
+            kicks[1].write(1).unwrap();
+            assert_eq!(kernel.recv().await.unwrap(), sent);
+            assert_eq!(frontend.used(1), 0);
+
+            // A chain longer than any frame is used without being read.
+            let mut desc = TX_BUFFER.to_le_bytes().to_vec();
+            desc.extend_from_slice(&u32::MAX.to_le_bytes());
+            desc.extend_from_slice(&[0; 4]);
+            frontend.write(RINGS[1], &desc);
+            frontend.write(RINGS[1] + 0x106, &0u16.to_le_bytes());
+            frontend.write(RINGS[1] + 0x102, &2u16.to_le_bytes());
+            kicks[1].write(1).unwrap();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while frontend.read(RINGS[1] + 0x202, 2) != 2u16.to_le_bytes() {
+                assert!(Instant::now() < deadline, "oversized chain not used");
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(frontend.read(RINGS[1] + 0x20c, 8), [0; 8]);
+            assert!(kernel.try_recv().is_none());
+
+            let state = [1u32.to_ne_bytes(), 0u32.to_ne_bytes()].concat();
+            let reply = frontend.request(VHOST_USER_GET_VRING_BASE, &state, &[]);
+            assert_eq!(reply, [1u32.to_ne_bytes(), 2u32.to_ne_bytes()].concat());
+            // SET_LOG_BASE is not supported, which is acked as a failure.
+            let reply = frontend.request(6, &[0; 8], &[]);
+            assert_eq!(reply, 1u64.to_ne_bytes());
+
+            drop(frontend);
+            session.await.unwrap();
+        });
+    }
+}
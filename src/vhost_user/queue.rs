@@ -0,0 +1,194 @@
+use super::memory::GuestMemory;
+use crate::result::Result;
+use crate::vhost::VringAddr;
+use async_std::sync::Arc;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU16, Ordering};
+
+const VRING_DESC_F_NEXT: u16 = 1;
+const VRING_DESC_F_WRITE: u16 = 2;
+const VRING_DESC_F_INDIRECT: u16 = 4;
+const VRING_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+const DESC_LEN: usize = 16;
+const RING_HEADER_LEN: usize = 4;
+const USED_ELEM_LEN: usize = 8;
+
+/// Represents a buffer of a descriptor chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer {
+    pub addr: u64,
+    pub len: u32,
+    pub writable: bool,
+}
+
+/// Represents a descriptor chain made available by guest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chain {
+    pub head: u16,
+    pub buffers: Vec<Buffer>,
+}
+
+impl Chain {
+    /// Returns the total length of the readable buffers, as claimed by guest.
+    pub fn readable_len(&self) -> u64 {
+        self.buffers
+            .iter()
+            .filter(|buffer| !buffer.writable)
+            .map(|buffer| buffer.len as u64)
+            .sum()
+    }
+}
+
+/// Represents a split virtqueue in the memory of guest, whose buffers are used in order.
+///
+/// As every available chain is used exactly once, the index of the next available chain is
+/// also the index of the used ring, which is shared through `next` to survive the worker.
+pub struct Vring {
+    memory: Arc<GuestMemory>,
+    num: u16,
+    desc: *mut u8,
+    avail: *mut u8,
+    used: *mut u8,
+    next: Arc<AtomicU16>,
+}
+
+// The rings are within `memory`, which the instance keeps mapped.
+unsafe impl Send for Vring {}
+unsafe impl Sync for Vring {}
+
+impl Vring {
+    /// Maps the virtqueue of `num` descriptors at the frontend addresses `addr`.
+    pub fn new(
+        memory: Arc<GuestMemory>,
+        num: u16,
+        addr: &VringAddr,
+        next: Arc<AtomicU16>,
+    ) -> Result<Self> {
+        if num == 0 || !num.is_power_of_two() {
+            return Err(format!("Invalid virtqueue size: {}", num).into());
+        }
+        let size = num as usize;
+        let ring = |addr: u64, len: usize, align: usize| match memory.frontend_ptr(addr, len) {
+            Some(ptr) if (ptr as usize).is_multiple_of(align) => Ok(ptr),
+            Some(_) => Err(format!("{:#x} is not aligned to {} bytes", addr, align)),
+            None => Err(format!("{:#x} is not within the memory table", addr)),
+        };
+        let desc = ring(addr.desc, size * DESC_LEN, 16)?;
+        let avail = ring(addr.avail, RING_HEADER_LEN + size * 2 + 2, 2)?;
+        let used = ring(addr.used, RING_HEADER_LEN + size * USED_ELEM_LEN + 2, 4)?;
+        Ok(Self {
+            memory,
+            num,
+            desc,
+            avail,
+            used,
+            next,
+        })
+    }
+
+    fn avail_idx(&self) -> u16 {
+        let idx = unsafe { ptr::read_volatile(self.avail.add(2) as *const u16) };
+        fence(Ordering::Acquire);
+        u16::from_le(idx)
+    }
+
+    /// Returns the next available chain without consuming it, or `None` if guest made no
+    /// buffers available. A chain outside the memory of guest is an error.
+    pub fn peek(&self) -> Option<Result<Chain>> {
+        let next = self.next.load(Ordering::Acquire);
+        if next == self.avail_idx() {
+            return None;
+        }
+        let slot = (next % self.num) as usize;
+        let head =
+            unsafe { ptr::read_volatile(self.avail.add(RING_HEADER_LEN + slot * 2) as *const u16) };
+        Some(self.chain(u16::from_le(head)))
+    }
+
+    fn chain(&self, head: u16) -> Result<Chain> {
+        let mut buffers = Vec::new();
+        let mut index = head;
+        loop {
+            if index >= self.num || buffers.len() >= self.num as usize {
+                return Err(format!("Invalid descriptor chain at {}", head).into());
+            }
+            let mut desc = [0; DESC_LEN];
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    self.desc.add(index as usize * DESC_LEN),
+                    desc.as_mut_ptr(),
+                    DESC_LEN,
+                )
+            };
+            let flags = u16::from_le_bytes([desc[12], desc[13]]);
+            if flags & VRING_DESC_F_INDIRECT != 0 {
+                return Err("Indirect descriptors are not supported".into());
+            }
+            buffers.push(Buffer {
+                addr: u64::from_le_bytes(desc[..8].try_into()?),
+                len: u32::from_le_bytes(desc[8..12].try_into()?),
+                writable: flags & VRING_DESC_F_WRITE != 0,
+            });
+            if flags & VRING_DESC_F_NEXT == 0 {
+                return Ok(Chain { head, buffers });
+            }
+            index = u16::from_le_bytes([desc[14], desc[15]]);
+        }
+    }
+
+    /// Returns the concatenated readable buffers of `chain`.
+    pub fn read(&self, chain: &Chain) -> Option<Vec<u8>> {
+        let mut data = Vec::new();
+        for buffer in chain.buffers.iter().filter(|buffer| !buffer.writable) {
+            let start = data.len();
+            data.resize(start + buffer.len as usize, 0);
+            self.memory.read(buffer.addr, &mut data[start..])?;
+        }
+        Some(data)
+    }
+
+    /// Writes `data` across the writable buffers of `chain`, or returns `None` if it does not fit.
+    pub fn write(&self, chain: &Chain, mut data: &[u8]) -> Option<usize> {
+        let len = data.len();
+        let capacity: usize = chain
+            .buffers
+            .iter()
+            .filter(|buffer| buffer.writable)
+            .map(|buffer| buffer.len as usize)
+            .sum();
+        if capacity < len {
+            return None;
+        }
+        for buffer in chain.buffers.iter().filter(|buffer| buffer.writable) {
+            let n = data.len().min(buffer.len as usize);
+            self.memory.write(buffer.addr, &data[..n])?;
+            data = &data[n..];
+        }
+        Some(len)
+    }
+
+    /// Consumes the chain returned by `peek`, reporting `len` bytes written to it.
+    pub fn push(&self, chain: &Chain, len: usize) {
+        let next = self.next.load(Ordering::Acquire);
+        let slot = (next % self.num) as usize;
+        let mut elem = [0; USED_ELEM_LEN];
+        elem[..4].copy_from_slice(&(chain.head as u32).to_le_bytes());
+        elem[4..].copy_from_slice(&(len as u32).to_le_bytes());
+        let next = next.wrapping_add(1);
+        unsafe {
+            let dst = self.used.add(RING_HEADER_LEN + slot * USED_ELEM_LEN);
+            ptr::copy_nonoverlapping(elem.as_ptr(), dst, USED_ELEM_LEN);
+            fence(Ordering::Release);
+            ptr::write_volatile(self.used.add(2) as *mut u16, next.to_le());
+        }
+        self.next.store(next, Ordering::Release);
+    }
+
+    /// Returns `false` if guest asked not to be notified of used buffers.
+    pub fn needs_notification(&self) -> bool {
+        fence(Ordering::SeqCst);
+        let flags = unsafe { ptr::read_volatile(self.avail as *const u16) };
+        u16::from_le(flags) & VRING_AVAIL_F_NO_INTERRUPT == 0
+    }
+}